@@ -0,0 +1,546 @@
+use chrono::{NaiveDateTime, NaiveDate, Utc, Duration};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::utils;
+use crate::errors::FitbitError;
+use crate::models::{ActiveMinutes, DeadLetter, Device, Goals, HeartRateZones, Profile, Range, RateLimitInfo, Spo2Summary};
+use super::{Cache, CacheHandler, RefreshLock, ReplyClaim};
+
+/// A `Cache` kept in process memory, for tests and for running the engine without Redis. Clones share the same
+/// storage. Cached values never expire, and replies are kept until they are taken with `take_reply`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCache {
+  state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  steps: HashMap<String, BTreeMap<NaiveDate, u32>>,
+  heart_rate: HashMap<String, BTreeMap<NaiveDate, u32>>,
+  calories: HashMap<String, BTreeMap<NaiveDate, u32>>,
+  floors: HashMap<String, BTreeMap<NaiveDate, u32>>,
+  distance: HashMap<String, BTreeMap<NaiveDate, f64>>,
+  active_minutes: HashMap<String, BTreeMap<NaiveDate, ActiveMinutes>>,
+  spo2: HashMap<String, BTreeMap<NaiveDate, Spo2Summary>>,
+  heart_rate_zones: HashMap<String, BTreeMap<NaiveDate, HeartRateZones>>,
+  /// The times of each user's queries in the current rate limit window, oldest first, and when the window ends.
+  user_queries: HashMap<String, (Vec<NaiveDateTime>, NaiveDateTime)>,
+  /// The reset time of each user's rate limit, and when it stops being reported.
+  ratelimit_resets: HashMap<String, (NaiveDateTime, NaiveDateTime)>,
+  /// The rate limit state Fitbit last reported for each user, and when the window it was reported in ends.
+  reported_ratelimits: HashMap<String, (RateLimitInfo, NaiveDateTime)>,
+  /// The holder of each user's refresh lock, and when the lock expires.
+  refresh_locks: HashMap<String, (String, NaiveDateTime)>,
+  profiles: HashMap<String, Profile>,
+  goals: HashMap<String, Goals>,
+  devices: HashMap<String, Vec<Device>>,
+  /// The ETags Fitbit sent with each user's time series, by request.
+  etags: HashMap<String, HashMap<String, String>>,
+  replies: HashMap<String, Vec<u8>>,
+  /// The claimed commands, with the reply each was answered with once it is sent.
+  processed: HashMap<String, Option<Vec<u8>>>,
+  dead_letters: Vec<DeadLetter>,
+  /// The IDs of the acknowledged commands, in the order they were acknowledged.
+  acked: Vec<String>,
+  /// The deferred commands, with the user each waits for and when it is due.
+  deferred: Vec<(String, String, NaiveDateTime)>,
+}
+
+impl MemoryCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Removes and returns the reply sent for a command, if there is one.
+  pub fn take_reply(&self, coordination_id: &str) -> Option<Vec<u8>> {
+    self.state().replies.remove(coordination_id)
+  }
+
+  /// The failed commands kept so far, oldest first.
+  pub fn dead_letters(&self) -> Vec<DeadLetter> {
+    self.state().dead_letters.clone()
+  }
+
+  /// The IDs of the commands acknowledged so far, oldest first.
+  pub fn acked(&self) -> Vec<String> {
+    self.state().acked.clone()
+  }
+
+  /// A panic while the lock was held cannot leave the maps half-updated, so a poisoned lock is still usable.
+  fn state(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+impl Cache for MemoryCache {
+  async fn ping(&self) -> Result<(), FitbitError> {
+    Ok(())
+  }
+
+  async fn send_message(&self, coordination_id: &str, message: Vec<u8>) -> Result<(), FitbitError> {
+    let mut state = self.state();
+
+    state.processed.insert(coordination_id.to_string(), Some(message.clone()));
+    state.replies.insert(coordination_id.to_string(), message);
+
+    Ok(())
+  }
+
+  async fn claim_reply(&self, coordination_id: &str) -> Result<ReplyClaim, FitbitError> {
+    let mut state = self.state();
+
+    Ok(match state.processed.get(coordination_id) {
+      None => {
+        state.processed.insert(coordination_id.to_string(), None);
+        ReplyClaim::New
+      },
+      Some(None) => ReplyClaim::InFlight,
+      Some(Some(reply)) => ReplyClaim::Sent(reply.clone()),
+    })
+  }
+
+  async fn release_reply(&self, coordination_id: &str) -> Result<(), FitbitError> {
+    self.state().processed.remove(coordination_id);
+
+    Ok(())
+  }
+
+  async fn ack(&self, id: &str) -> Result<(), FitbitError> {
+    self.state().acked.push(id.to_string());
+
+    Ok(())
+  }
+
+  /// Deferred commands are only kept, as there is no request stream to add them back to.
+  async fn defer_command(&self, user_id: &str, message: &str, due: NaiveDateTime) -> Result<bool, FitbitError> {
+    self.state().deferred.push((user_id.to_string(), message.to_string(), due));
+
+    Ok(true)
+  }
+
+  async fn dead_letter(&self, entry: &DeadLetter) -> Result<(), FitbitError> {
+    self.state().dead_letters.push(entry.clone());
+
+    Ok(())
+  }
+
+  async fn add_steps(&self, user_id: &str, date: NaiveDate, steps: u32) -> Result<(), FitbitError> {
+    self.state().steps.entry(user_id.to_string()).or_default().insert(date, steps);
+
+    Ok(())
+  }
+
+  async fn add_heart_rate(&self, user_id: &str, date: NaiveDate, heart_rate: u32) -> Result<(), FitbitError> {
+    self.state().heart_rate.entry(user_id.to_string()).or_default().insert(date, heart_rate);
+
+    Ok(())
+  }
+
+  async fn add_steps_bulk(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.state().steps.entry(user_id.to_string()).or_default().extend(steps);
+
+    Ok(())
+  }
+
+  async fn add_heart_rate_bulk(&self, user_id: &str, heart_rate: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.state().heart_rate.entry(user_id.to_string()).or_default().extend(heart_rate);
+
+    Ok(())
+  }
+
+  async fn add_calories_bulk(&self, user_id: &str, calories: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.state().calories.entry(user_id.to_string()).or_default().extend(calories);
+
+    Ok(())
+  }
+
+  async fn add_floors_bulk(&self, user_id: &str, floors: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.state().floors.entry(user_id.to_string()).or_default().extend(floors);
+
+    Ok(())
+  }
+
+  async fn add_distance_bulk(&self, user_id: &str, distance: &HashMap<NaiveDate, f64>) -> Result<(), FitbitError> {
+    self.state().distance.entry(user_id.to_string()).or_default().extend(distance);
+
+    Ok(())
+  }
+
+  async fn add_active_minutes_bulk(&self, user_id: &str, active_minutes: &HashMap<NaiveDate, ActiveMinutes>) -> Result<(), FitbitError> {
+    self.state().active_minutes.entry(user_id.to_string()).or_default().extend(active_minutes);
+
+    Ok(())
+  }
+
+  async fn add_spo2_bulk(&self, user_id: &str, spo2: &HashMap<NaiveDate, Spo2Summary>) -> Result<(), FitbitError> {
+    self.state().spo2.entry(user_id.to_string()).or_default().extend(spo2);
+
+    Ok(())
+  }
+
+  async fn add_heart_rate_zones_bulk(&self, user_id: &str, zones: &HashMap<NaiveDate, HeartRateZones>) -> Result<(), FitbitError> {
+    self.state().heart_rate_zones.entry(user_id.to_string()).or_default().extend(zones);
+
+    Ok(())
+  }
+
+  async fn get_steps(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    Ok(daily_values(self.state().steps.get(user_id), start_date, end_date))
+  }
+
+  async fn get_steps_coverage(&self, user_id: &str) -> Result<Vec<Range>, FitbitError> {
+    let dates = self.state().steps.get(user_id)
+      .map(|steps| steps.keys().copied().collect())
+      .unwrap_or_default();
+
+    Ok(utils::coalesce_dates(dates))
+  }
+
+  async fn get_heart_rate(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    Ok(daily_values(self.state().heart_rate.get(user_id), start_date, end_date))
+  }
+
+  async fn get_calories(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    Ok(daily_values(self.state().calories.get(user_id), start_date, end_date))
+  }
+
+  async fn get_floors(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    Ok(daily_values(self.state().floors.get(user_id), start_date, end_date))
+  }
+
+  async fn get_distance(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, f64>, FitbitError> {
+    Ok(daily_values(self.state().distance.get(user_id), start_date, end_date))
+  }
+
+  async fn get_active_minutes(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, ActiveMinutes>, FitbitError> {
+    Ok(daily_values(self.state().active_minutes.get(user_id), start_date, end_date))
+  }
+
+  async fn get_spo2(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, Spo2Summary>, FitbitError> {
+    Ok(daily_values(self.state().spo2.get(user_id), start_date, end_date))
+  }
+
+  async fn get_heart_rate_zones(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, HeartRateZones>, FitbitError> {
+    Ok(daily_values(self.state().heart_rate_zones.get(user_id), start_date, end_date))
+  }
+
+  async fn add_user_query(&self, user_id: &str, date: NaiveDateTime, ratelimit_reset: usize) -> Result<(), FitbitError> {
+    let duration: i64 = match ratelimit_reset.try_into() {
+      Ok(duration) => duration,
+      Err(err) => return Err(FitbitError::TypeConversionError(err.to_string())),
+    };
+
+    let now = Utc::now().naive_utc();
+    let window_end = now + Duration::seconds(duration);
+
+    let mut state = self.state();
+
+    state.ratelimit_resets.insert(user_id.to_string(), (window_end, window_end));
+
+    let (queries, expires_at) = state.user_queries.entry(user_id.to_string()).or_insert_with(|| (Vec::new(), window_end));
+
+    if *expires_at <= now {
+      queries.clear();
+    }
+
+    queries.push(date);
+    *expires_at = window_end;
+
+    Ok(())
+  }
+
+  async fn get_last_user_query(&self, user_id: &str) -> Result<Option<NaiveDateTime>, FitbitError> {
+    let now = Utc::now().naive_utc();
+
+    let last_query = match self.state().user_queries.get(user_id) {
+      Some((queries, expires_at)) if *expires_at > now => queries.last().copied(),
+      _ => None,
+    };
+
+    Ok(last_query)
+  }
+
+  async fn set_profile(&self, user_id: &str, profile: &Profile) -> Result<(), FitbitError> {
+    self.state().profiles.insert(user_id.to_string(), profile.clone());
+
+    Ok(())
+  }
+
+  async fn get_profile(&self, user_id: &str) -> Result<Option<Profile>, FitbitError> {
+    Ok(self.state().profiles.get(user_id).cloned())
+  }
+
+  async fn set_goals(&self, user_id: &str, goals: &Goals) -> Result<(), FitbitError> {
+    self.state().goals.insert(user_id.to_string(), goals.clone());
+
+    Ok(())
+  }
+
+  async fn get_goals(&self, user_id: &str) -> Result<Option<Goals>, FitbitError> {
+    Ok(self.state().goals.get(user_id).cloned())
+  }
+
+  async fn set_devices(&self, user_id: &str, devices: &[Device]) -> Result<(), FitbitError> {
+    self.state().devices.insert(user_id.to_string(), devices.to_vec());
+
+    Ok(())
+  }
+
+  async fn get_devices(&self, user_id: &str) -> Result<Option<Vec<Device>>, FitbitError> {
+    Ok(self.state().devices.get(user_id).cloned())
+  }
+
+  async fn set_etag(&self, user_id: &str, request: &str, etag: &str) -> Result<(), FitbitError> {
+    self.state().etags.entry(user_id.to_string()).or_default().insert(request.to_string(), etag.to_string());
+
+    Ok(())
+  }
+
+  async fn get_etag(&self, user_id: &str, request: &str) -> Result<Option<String>, FitbitError> {
+    Ok(self.state().etags.get(user_id).and_then(|etags| etags.get(request)).cloned())
+  }
+
+  async fn clear_goals(&self, user_id: &str) -> Result<(), FitbitError> {
+    self.state().goals.remove(user_id);
+
+    Ok(())
+  }
+
+  async fn set_ratelimit_reset(&self, user_id: &str, reset_seconds: u64) -> Result<(), FitbitError> {
+    let duration: i64 = match reset_seconds.try_into() {
+      Ok(duration) => duration,
+      Err(err) => return Err(FitbitError::TypeConversionError(format!("{err}"))),
+    };
+
+    let now = Utc::now().naive_utc();
+    let reset = now + Duration::seconds(duration);
+
+    self.state().ratelimit_resets.insert(user_id.to_string(), (reset, now + Duration::seconds(duration.max(1))));
+
+    Ok(())
+  }
+
+  async fn get_ratelimit_reset(&self, user_id: &str) -> Result<NaiveDateTime, FitbitError> {
+    let now = Utc::now().naive_utc();
+
+    match self.state().ratelimit_resets.get(user_id) {
+      Some((reset, expires_at)) if *expires_at > now => Ok(*reset),
+      _ => utils::stored_datetime(0),
+    }
+  }
+
+  async fn set_reported_ratelimit(&self, user_id: &str, rate_limit: RateLimitInfo, ratelimit_reset: usize) -> Result<(), FitbitError> {
+    let duration: i64 = match ratelimit_reset.try_into() {
+      Ok(duration) => duration,
+      Err(err) => return Err(FitbitError::TypeConversionError(err.to_string())),
+    };
+
+    let mut state = self.state();
+
+    if rate_limit.remaining.is_none() {
+      state.reported_ratelimits.remove(user_id);
+      return Ok(());
+    }
+
+    let reported = RateLimitInfo { reset_seconds: None, ..rate_limit };
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(duration.max(1));
+
+    state.reported_ratelimits.insert(user_id.to_string(), (reported, expires_at));
+
+    Ok(())
+  }
+
+  async fn get_reported_ratelimit(&self, user_id: &str) -> Result<RateLimitInfo, FitbitError> {
+    let now = Utc::now().naive_utc();
+
+    let reported = match self.state().reported_ratelimits.get(user_id) {
+      Some((reported, expires_at)) if *expires_at > now => *reported,
+      _ => RateLimitInfo::default(),
+    };
+
+    Ok(reported)
+  }
+
+  async fn get_user_queries(&self, user_id: &str) -> Result<usize, FitbitError> {
+    let now = Utc::now().naive_utc();
+
+    let queries = match self.state().user_queries.get(user_id) {
+      Some((queries, expires_at)) if *expires_at > now => queries.len(),
+      _ => 0,
+    };
+
+    Ok(queries)
+  }
+
+  async fn clear_user(&self, user_id: &str) -> Result<usize, FitbitError> {
+    let mut state = self.state();
+
+    let removed = [
+      state.steps.remove(user_id).is_some(),
+      state.heart_rate.remove(user_id).is_some(),
+      state.calories.remove(user_id).is_some(),
+      state.floors.remove(user_id).is_some(),
+      state.distance.remove(user_id).is_some(),
+      state.active_minutes.remove(user_id).is_some(),
+      state.spo2.remove(user_id).is_some(),
+      state.heart_rate_zones.remove(user_id).is_some(),
+      state.user_queries.remove(user_id).is_some(),
+      state.ratelimit_resets.remove(user_id).is_some(),
+      state.reported_ratelimits.remove(user_id).is_some(),
+      state.profiles.remove(user_id).is_some(),
+      state.goals.remove(user_id).is_some(),
+      state.devices.remove(user_id).is_some(),
+      state.etags.remove(user_id).is_some(),
+    ];
+
+    Ok(removed.into_iter().filter(|removed| *removed).count())
+  }
+}
+
+impl RefreshLock for MemoryCache {
+  async fn acquire_refresh_lock(&self, user_id: &str, token: &str) -> Result<bool, FitbitError> {
+    let now = Utc::now().naive_utc();
+    let mut state = self.state();
+
+    if state.refresh_locks.get(user_id).is_some_and(|(_, expires_at)| *expires_at > now) {
+      return Ok(false);
+    }
+
+    let ttl = Duration::from_std(CacheHandler::REFRESH_LOCK_TTL).unwrap_or(Duration::zero());
+
+    state.refresh_locks.insert(user_id.to_string(), (token.to_string(), now + ttl));
+
+    Ok(true)
+  }
+
+  async fn release_refresh_lock(&self, user_id: &str, token: &str) -> Result<(), FitbitError> {
+    let mut state = self.state();
+
+    if state.refresh_locks.get(user_id).is_some_and(|(holder, _)| holder == token) {
+      state.refresh_locks.remove(user_id);
+    }
+
+    Ok(())
+  }
+
+  async fn refresh_lock_held(&self, user_id: &str) -> Result<bool, FitbitError> {
+    let now = Utc::now().naive_utc();
+
+    Ok(self.state().refresh_locks.get(user_id).is_some_and(|(_, expires_at)| *expires_at > now))
+  }
+}
+
+/// The values from `values` within the given range, inclusive.
+fn daily_values<V: Copy>(values: Option<&BTreeMap<NaiveDate, V>>, start_date: NaiveDate, end_date: NaiveDate) -> HashMap<NaiveDate, V> {
+  match values {
+    Some(values) if start_date <= end_date => values.range(start_date..=end_date).map(|(date, value)| (*date, *value)).collect(),
+    _ => HashMap::new(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn date(day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(2023, 1, day).unwrap()
+  }
+
+  #[tokio::test]
+  async fn daily_values_are_read_back_by_range() {
+    let cache = MemoryCache::new();
+
+    cache.add_steps_bulk("user", &HashMap::from([(date(1), 100), (date(2), 200), (date(5), 500)])).await.unwrap();
+    cache.add_heart_rate("user", date(2), 60).await.unwrap();
+
+    assert_eq!(cache.get_steps("user", date(2), date(5)).await.unwrap(), HashMap::from([(date(2), 200), (date(5), 500)]));
+    assert_eq!(cache.get_steps("user", date(3), date(4)).await.unwrap(), HashMap::new());
+    assert_eq!(cache.get_steps("other", date(1), date(5)).await.unwrap(), HashMap::new());
+    assert_eq!(cache.get_heart_rate("user", date(1), date(31)).await.unwrap(), HashMap::from([(date(2), 60)]));
+
+    let coverage = cache.get_steps_coverage("user").await.unwrap();
+
+    assert_eq!(coverage, vec![Range { start: date(1), end: date(2) }, Range { start: date(5), end: date(5) }]);
+  }
+
+  #[tokio::test]
+  async fn queries_count_against_the_window() {
+    let cache = MemoryCache::new();
+    let now = Utc::now().naive_utc();
+
+    assert_eq!(cache.get_user_queries("user").await.unwrap(), 0);
+    assert_eq!(cache.get_last_user_query("user").await.unwrap(), None);
+    assert_eq!(cache.get_ratelimit_reset("user").await.unwrap().timestamp(), 0);
+
+    cache.add_user_query("user", now - Duration::seconds(10), 3600).await.unwrap();
+    cache.add_user_query("user", now, 3600).await.unwrap();
+
+    assert_eq!(cache.get_user_queries("user").await.unwrap(), 2);
+    assert_eq!(cache.get_last_user_query("user").await.unwrap(), Some(now));
+    assert!(cache.get_ratelimit_reset("user").await.unwrap() > now);
+
+    // A window that has already ended no longer counts.
+    cache.add_user_query("expired", now, 0).await.unwrap();
+
+    assert_eq!(cache.get_user_queries("expired").await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn clearing_a_user_removes_only_their_data() {
+    let cache = MemoryCache::new();
+
+    cache.add_steps("user", date(1), 100).await.unwrap();
+    cache.add_steps("other", date(1), 100).await.unwrap();
+    cache.set_ratelimit_reset("user", 60).await.unwrap();
+
+    assert_eq!(cache.clear_user("user").await.unwrap(), 2);
+    assert_eq!(cache.clear_user("user").await.unwrap(), 0);
+    assert_eq!(cache.get_steps("user", date(1), date(1)).await.unwrap(), HashMap::new());
+    assert_eq!(cache.get_steps("other", date(1), date(1)).await.unwrap(), HashMap::from([(date(1), 100)]));
+  }
+
+  #[tokio::test]
+  async fn profiles_are_cleared_with_the_user() {
+    let cache = MemoryCache::new();
+    let profile = Profile { display_name: "User".to_string(), timezone: "UTC".to_string(), ..Default::default() };
+
+    assert_eq!(cache.get_profile("user").await.unwrap(), None);
+
+    cache.set_profile("user", &profile).await.unwrap();
+
+    assert_eq!(cache.get_profile("user").await.unwrap(), Some(profile));
+    assert_eq!(cache.clear_user("user").await.unwrap(), 1);
+    assert_eq!(cache.get_profile("user").await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn failed_commands_are_dead_lettered() {
+    let cache = MemoryCache::new();
+    let failed_at = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let entry = DeadLetter::new("01H2XK", "01H2XK:refresh:user:1690000000", &FitbitError::UserNotFound, failed_at);
+
+    cache.dead_letter(&entry).await.unwrap();
+
+    assert_eq!(cache.dead_letters(), vec![entry]);
+
+    let json = serde_json::to_value(&cache.dead_letters()[0]).unwrap();
+
+    assert_eq!(json, serde_json::json!({
+      "coordination_id": "01H2XK",
+      "message": "01H2XK:refresh:user:1690000000",
+      "code": "user_not_found",
+      "error": "User not found",
+      "failed_at": 1672574400,
+    }));
+  }
+
+  #[tokio::test]
+  async fn replies_are_taken_once() {
+    let cache = MemoryCache::new();
+
+    cache.send_message("01H2XK", b"0:ok".to_vec()).await.unwrap();
+
+    assert_eq!(cache.clone().take_reply("01H2XK"), Some(b"0:ok".to_vec()));
+    assert_eq!(cache.take_reply("01H2XK"), None);
+  }
+}
@@ -1,255 +1,2290 @@
 use chrono::{NaiveDateTime, NaiveDate, Utc, Duration};
 use redis::{AsyncCommands, RedisError};
+use redis::streams::{StreamId, StreamReadOptions, StreamReadReply};
 use bb8::Pool;
 use bb8_redis::RedisConnectionManager;
-use tokio_stream::{wrappers::ReceiverStream};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
 use tokio::sync::mpsc;
 use std::env;
 use std::collections::HashMap;
+use std::future::Future;
+use std::fmt;
+use std::str::FromStr;
 use crate::utils;
 use crate::errors::FitbitError;
-use log::{info, error};
+use crate::models::{ActiveMinutes, DeadLetter, Device, Goals, HeartRateZones, Profile, Range, RateLimitInfo, Spo2Summary};
+use crate::fitbit::HISTORICAL_AFTER_DAYS;
+use tracing::{info, error};
+
+#[cfg(any(test, feature = "memory-cache"))]
+mod memory;
+
+#[cfg(any(test, feature = "memory-cache"))]
+pub use memory::MemoryCache;
 
 #[derive(Debug, Clone)]
 pub struct CacheHandler {
   pool: Pool<RedisConnectionManager>,
+  request_queue: String,
+  dead_letter_queue: String,
+  dead_letter_limit: usize,
+  reply_prefix: String,
+  key_prefix: String,
+  reply_mode: ReplyMode,
+  reply_ttl: u64,
+  cache_ttl: CacheTtl,
+  profile_ttl: u64,
+  goals_ttl: u64,
+  devices_ttl: u64,
+  deferred_limit: usize,
+  consumer_group: String,
+  consumer_name: String,
+}
+
+/// A command read from the request stream. It stays pending until acknowledged with `CacheHandler::ack`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedCommand {
+  /// The stream entry ID.
+  pub id: String,
+  /// The framed command, as accepted by `utils::decode_message`.
+  pub message: String,
+  /// Whether the command should wait for the user's rate limit to reset instead of failing when it is rate limited.
+  /// Set by sending the command with a `defer` field of `1`.
+  pub defer: bool,
+}
+
+impl QueuedCommand {
+  /// The key commands are sharded by in `process_sharded`: the user the command is for, or the stream entry ID for
+  /// commands without a single user, such as batches and commands that cannot be decoded.
+  pub fn shard_key(&self) -> String {
+    utils::decode_message(self.message.clone())
+      .and_then(|(_, command)| command.ok())
+      .and_then(|command| command.user_id().map(str::to_string))
+      .unwrap_or_else(|| self.id.clone())
+  }
+}
+
+/// What became of earlier deliveries of a command, as recorded by `Cache::claim_reply`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyClaim {
+  /// The command has not been seen before, so it should be executed.
+  New,
+  /// The command is being executed elsewhere and has not been answered yet.
+  InFlight,
+  /// The command has been answered with this reply, already encoded.
+  Sent(Vec<u8>),
+}
+
+/// How replies are delivered to the website.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+  /// Replies are stored under `replies:{coordination_id}` for `REPLY_TTL_SECONDS`, and must be polled for.
+  Store,
+  /// Replies are published on the `replies:{coordination_id}` channel. A reply is lost if no one is subscribed.
+  Publish,
+  /// Replies are published, and also stored for subscribers that arrive late.
+  Both,
+}
+
+impl ReplyMode {
+  /// Parses `store`, `publish`, or `both`.
+  pub fn parse(mode: &str) -> Option<Self> {
+    match mode {
+      "store" => Some(ReplyMode::Store),
+      "publish" => Some(ReplyMode::Publish),
+      "both" => Some(ReplyMode::Both),
+      _ => None,
+    }
+  }
+}
+
+/// A daily series cached in its own sorted set per user. Every series key is built by `CacheHandler::series_key` from
+/// the series' name, so two series cannot share a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Series {
+  Steps,
+  /// Resting heart rate.
+  HeartRate,
+  Calories,
+  Floors,
+  /// Kilometers covered.
+  Distance,
+  ActiveMinutesFairly,
+  ActiveMinutesVery,
+  Spo2,
+  /// Minutes in each heart rate zone.
+  HeartRateZones,
+}
+
+impl Series {
+  const ALL: [Series; 9] = [
+    Series::Steps,
+    Series::HeartRate,
+    Series::Calories,
+    Series::Floors,
+    Series::Distance,
+    Series::ActiveMinutesFairly,
+    Series::ActiveMinutesVery,
+    Series::Spo2,
+    Series::HeartRateZones,
+  ];
+
+  /// The series' segment of its keys. These are part of the cached data's keys, so changing one orphans its cache.
+  fn name(&self) -> &'static str {
+    match self {
+      Series::Steps => "steps",
+      Series::HeartRate => "heart_rate",
+      Series::Calories => "calories",
+      Series::Floors => "floors",
+      Series::Distance => "distance",
+      Series::ActiveMinutesFairly => "active_minutes_fairly",
+      Series::ActiveMinutesVery => "active_minutes_very",
+      Series::Spo2 => "spo2",
+      Series::HeartRateZones => "heart_rate_zones",
+    }
+  }
+}
+
+/// How long cached daily values live. Days before `HISTORICAL_AFTER_DAYS` are final at Fitbit, so they are kept much
+/// longer than recent days, which Fitbit may still correct.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheTtl {
+  /// Seconds to keep the last `HISTORICAL_AFTER_DAYS` days.
+  pub recent: u64,
+  /// Seconds to keep older days.
+  pub historical: u64,
+}
+
+impl CacheTtl {
+  /// The TTL, in seconds, of the value for `date`.
+  pub fn for_date(&self, date: NaiveDate, today: NaiveDate) -> u64 {
+    if date < today - Duration::days(HISTORICAL_AFTER_DAYS) {
+      self.historical
+    } else {
+      self.recent
+    }
+  }
+
+  /// The longest TTL, which the set holding the values is given so it outlives every value in it.
+  fn longest(&self) -> u64 {
+    std::cmp::max(self.recent, self.historical)
+  }
+}
+
+/// A daily value in one of the cache's sorted sets, stored as `value,date,expires_at` with ISO dates, such as
+/// `1200,2023-01-01,2023-01-03T12:00:00`. Entries written in the older `value:timestamp:expire` format, with UNIX
+/// timestamps, are still read. Values are whole numbers, except distances, which are decimals.
+#[derive(Debug, Clone, PartialEq)]
+struct CacheEntry<V = u32> {
+  value: V,
+  date: NaiveDate,
+  expires_at: NaiveDateTime,
+}
+
+impl<V: fmt::Display> fmt::Display for CacheEntry<V> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{},{},{}", self.value, self.date.format("%Y-%m-%d"), self.expires_at.format("%Y-%m-%dT%H:%M:%S"))
+  }
+}
+
+impl<V: FromStr> FromStr for CacheEntry<V> {
+  type Err = FitbitError;
+
+  fn from_str(entry: &str) -> Result<Self, Self::Err> {
+    let invalid = || FitbitError::ParsingError(format!("Invalid cache entry, expected value,date,expires_at, got {}", entry));
+
+    match entry.split(',').collect::<Vec<&str>>()[..] {
+      [value, date, expires_at] => Ok(CacheEntry {
+        value: value.parse().map_err(|_| invalid())?,
+        date: NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| invalid())?,
+        expires_at: NaiveDateTime::parse_from_str(expires_at, "%Y-%m-%dT%H:%M:%S").map_err(|_| invalid())?,
+      }),
+      [legacy] => {
+        let [value, date, expires_at] = legacy.split(':').collect::<Vec<&str>>()[..] else {
+          return Err(invalid());
+        };
+
+        let timestamp = |timestamp: &str| {
+          timestamp.parse::<i64>().ok().and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0)).ok_or_else(invalid)
+        };
+
+        Ok(CacheEntry {
+          value: value.parse().map_err(|_| invalid())?,
+          date: timestamp(date)?.date(),
+          expires_at: timestamp(expires_at)?,
+        })
+      },
+      _ => Err(invalid()),
+    }
+  }
+}
+
+/// A night's SpO2 summary as the value of a `CacheEntry`, stored as `avg/min/max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Spo2Value(Spo2Summary);
+
+impl fmt::Display for Spo2Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}/{}/{}", self.0.avg, self.0.min, self.0.max)
+  }
+}
+
+impl FromStr for Spo2Value {
+  type Err = std::num::ParseFloatError;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let mut parts = value.splitn(3, '/');
+    let mut next = || parts.next().unwrap_or_default().parse::<f64>();
+
+    Ok(Spo2Value(Spo2Summary { avg: next()?, min: next()?, max: next()? }))
+  }
+}
+
+/// A day's heart rate zone minutes as the value of a `CacheEntry`, stored as `out_of_range/fat_burn/cardio/peak`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct HeartRateZonesValue(HeartRateZones);
+
+impl fmt::Display for HeartRateZonesValue {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}/{}/{}/{}", self.0.out_of_range, self.0.fat_burn, self.0.cardio, self.0.peak)
+  }
+}
+
+impl FromStr for HeartRateZonesValue {
+  type Err = std::num::ParseIntError;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let mut parts = value.splitn(4, '/');
+    let mut next = || parts.next().unwrap_or_default().parse::<u32>();
+
+    Ok(HeartRateZonesValue(HeartRateZones { out_of_range: next()?, fat_burn: next()?, cardio: next()?, peak: next()? }))
+  }
 }
 
 impl CacheHandler {
   const REDIS_PREFIX: &'static str = "fitbit:";
 
+  const DEFAULT_REQUEST_QUEUE: &'static str = "requests";
+  const DEFAULT_DEAD_LETTER_QUEUE: &'static str = "dead_letter";
+  const DEFAULT_DEAD_LETTER_LIMIT: usize = 1000;
+  const DEFAULT_REPLY_PREFIX: &'static str = "replies";
+  const DEFAULT_CONSUMER_GROUP: &'static str = "engine";
+  const DEFAULT_REPLY_TTL_SECONDS: u64 = 60;
+  const DEFAULT_RECENT_TTL_SECONDS: u64 = 60 * 60 * 24 * 2;
+  const DEFAULT_HISTORICAL_TTL_SECONDS: u64 = 60 * 60 * 24 * 30;
+  const DEFAULT_PROFILE_TTL_SECONDS: u64 = 60 * 60 * 24;
+  const DEFAULT_GOALS_TTL_SECONDS: u64 = 60 * 60 * 6;
+  const DEFAULT_DEVICES_TTL_SECONDS: u64 = 60 * 5;
+  /// The stream entry field holding the framed command.
+  const MESSAGE_FIELD: &'static str = "message";
+  /// The stream entry field that opts a command into being deferred when rate limited.
+  const DEFER_FIELD: &'static str = "defer";
+  const DEFAULT_DEFERRED_LIMIT: usize = 20;
+  /// How often to look for deferred commands that are due.
+  const DEFERRED_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+  /// How long a command may stay unacknowledged before another consumer claims it.
+  const CLAIM_MIN_IDLE: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+  /// How often to look for unacknowledged commands to claim.
+  const CLAIM_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+  const MIN_STREAM_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+  const MAX_STREAM_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+  /// How long a token refresh may hold its lock before the lock expires on its own.
+  pub(crate) const REFRESH_LOCK_TTL: std::time::Duration = std::time::Duration::from_secs(10);
+  /// How long a command's coordination ID is remembered after it is claimed, so redeliveries are answered from the
+  /// first reply. This outlasts both `CLAIM_MIN_IDLE` and the longest a rate-limited command is deferred.
+  pub(crate) const PROCESSED_TTL: std::time::Duration = std::time::Duration::from_secs(2 * 60 * 60);
+
+  /// Creates a new cache handler. The request queue key and reply key prefix are read from
+  /// `REDIS_REQUEST_QUEUE` and `REDIS_REPLY_PREFIX`, defaulting to `requests` and `replies`.
+  /// Every other key is namespaced under `REDIS_KEY_PREFIX`, defaulting to `REDIS_PREFIX`.
+  /// Replies are delivered according to `REDIS_REPLY_MODE`, defaulting to `both`, and stored replies expire after
+  /// `REPLY_TTL_SECONDS`, defaulting to 60.
+  /// Failed commands are kept in the `REDIS_DEAD_LETTER_QUEUE` list, defaulting to `dead_letter`, which holds the
+  /// newest `DEAD_LETTER_MAX_LENGTH` of them, at least 1 and defaulting to 1000.
+  /// At most `DEFERRED_COMMANDS_PER_USER` commands, defaulting to 20, wait for each user's rate limit at a time.
+  /// Cached days expire after `CACHE_RECENT_TTL_SECONDS` (two days) if recent, or `CACHE_HISTORICAL_TTL_SECONDS`
+  /// (30 days) otherwise. Cached profiles expire after `CACHE_PROFILE_TTL_SECONDS`, defaulting to a day, and cached
+  /// goals after `CACHE_GOALS_TTL_SECONDS`, defaulting to six hours. Devices sync often, so cached device lists expire
+  /// after `CACHE_DEVICES_TTL_SECONDS`, defaulting to five minutes.
+  /// Commands are read as part of the `REDIS_CONSUMER_GROUP` consumer group, defaulting to `engine`, under the
+  /// consumer name `REDIS_CONSUMER_NAME`, which defaults to a random name for each process.
   pub fn new(pool: Pool<RedisConnectionManager>) -> Self {
+    let request_queue = env::var("REDIS_REQUEST_QUEUE").unwrap_or_else(|_| Self::DEFAULT_REQUEST_QUEUE.to_string());
+    let dead_letter_queue = env::var("REDIS_DEAD_LETTER_QUEUE").unwrap_or_else(|_| Self::DEFAULT_DEAD_LETTER_QUEUE.to_string());
+    let reply_prefix = env::var("REDIS_REPLY_PREFIX").unwrap_or_else(|_| Self::DEFAULT_REPLY_PREFIX.to_string());
+    let key_prefix = env::var("REDIS_KEY_PREFIX").unwrap_or_else(|_| Self::REDIS_PREFIX.to_string());
+
+    let reply_mode = match env::var("REDIS_REPLY_MODE") {
+      Ok(mode) => ReplyMode::parse(&mode).unwrap_or_else(|| {
+        error!("Invalid REDIS_REPLY_MODE, expected store, publish, or both, got {}", mode);
+        ReplyMode::Both
+      }),
+      Err(_) => ReplyMode::Both,
+    };
+
+    let reply_ttl = env_seconds("REPLY_TTL_SECONDS", Self::DEFAULT_REPLY_TTL_SECONDS);
+
+    let cache_ttl = CacheTtl {
+      recent: env_seconds("CACHE_RECENT_TTL_SECONDS", Self::DEFAULT_RECENT_TTL_SECONDS),
+      historical: env_seconds("CACHE_HISTORICAL_TTL_SECONDS", Self::DEFAULT_HISTORICAL_TTL_SECONDS),
+    };
+
+    let profile_ttl = env_seconds("CACHE_PROFILE_TTL_SECONDS", Self::DEFAULT_PROFILE_TTL_SECONDS);
+    let goals_ttl = env_seconds("CACHE_GOALS_TTL_SECONDS", Self::DEFAULT_GOALS_TTL_SECONDS);
+    let devices_ttl = env_seconds("CACHE_DEVICES_TTL_SECONDS", Self::DEFAULT_DEVICES_TTL_SECONDS);
+
+    let deferred_limit = env::var("DEFERRED_COMMANDS_PER_USER").ok()
+      .and_then(|limit| limit.parse::<usize>().ok())
+      .unwrap_or(Self::DEFAULT_DEFERRED_LIMIT);
+
+    // An empty queue could not be trimmed to, so a limit of 0 falls back to the default like an invalid one.
+    let dead_letter_limit = env::var("DEAD_LETTER_MAX_LENGTH").ok()
+      .and_then(|limit| limit.parse::<usize>().ok())
+      .filter(|limit| *limit > 0)
+      .unwrap_or(Self::DEFAULT_DEAD_LETTER_LIMIT);
+
+    let consumer_group = env::var("REDIS_CONSUMER_GROUP").unwrap_or_else(|_| Self::DEFAULT_CONSUMER_GROUP.to_string());
+    let consumer_name = env::var("REDIS_CONSUMER_NAME").unwrap_or_else(|_| ulid::Ulid::new().to_string());
+
     Self {
       pool,
+      request_queue,
+      dead_letter_queue,
+      dead_letter_limit,
+      reply_prefix,
+      key_prefix,
+      reply_mode,
+      reply_ttl,
+      cache_ttl,
+      profile_ttl,
+      goals_ttl,
+      devices_ttl,
+      deferred_limit,
+      consumer_group,
+      consumer_name,
     }
   }
 
-  pub async fn build_pool() -> Pool<RedisConnectionManager> {
-    let redis_url: String = env::var("REDIS_URL").expect("REDIS_URL not set");
+  /// Prepends the key prefix to a cache key.
+  fn key(&self, suffix: &str) -> String {
+    format!("{}{}", self.key_prefix, suffix)
+  }
 
-    let manager = RedisConnectionManager::new(redis_url).unwrap();
-    
-    let pool = Pool::builder()
-      .build(manager)
-      .await
-      .expect("Failed to create Redis pool");
+  /// The sorted set holding a user's cached daily values of a series.
+  fn series_key(&self, series: Series, user_id: &str) -> String {
+    self.key(&format!("fitbit_{}:{}", series.name(), user_id))
+  }
 
-    pool
+  /// The sorted set holding a user's cached daily step counts.
+  fn steps_key(&self, user_id: &str) -> String {
+    self.series_key(Series::Steps, user_id)
   }
 
-  pub async fn get_stream(pool: &Pool<RedisConnectionManager>) -> ReceiverStream<String> {
-    let (tx, rx) = mpsc::channel(100);
-    let pool = pool.clone();
-
-    tokio::spawn(async move {
-      let mut conn = pool.get().await.unwrap();
-
-      loop {
-        let data: Option<(String, String)> = match conn.brpop("requests", 0).await {
-          Ok(data) => Some(data),
-          Err(e) => {
-            error!("Error: {:?}", e);
-            None
-          },
-        };
+  /// The key holding when a user's rate limit window resets.
+  fn ratelimit_reset_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_ratelimit_reset:{}", user_id))
+  }
 
-        if let Some(data) = data {
-          tx.send(data.1).await.unwrap();
-        }
-      }
-    });
+  /// The hash holding the remaining requests and limit Fitbit last reported for a user.
+  fn ratelimit_reported_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_ratelimit_reported:{}", user_id))
+  }
 
-    ReceiverStream::new(rx)
+  /// The key holding a user's cached Fitbit profile, as JSON.
+  fn profile_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_profile:{}", user_id))
   }
 
-  pub async fn send_message(&self, coordination_id: &str, message: String) -> Result<(), FitbitError> {
-    let mut conn: bb8::PooledConnection<'_, RedisConnectionManager> = self.pool.get().await?;
+  /// The key holding a user's cached daily goals, as JSON.
+  fn goals_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_goals:{}", user_id))
+  }
 
-    let result = conn.set_ex(format!("replies:{coordination_id}"), message, 60).await;
+  /// The key holding a user's cached devices, as JSON.
+  fn devices_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_devices:{}", user_id))
+  }
 
-    Ok(result?)
+  /// The hash holding the ETags Fitbit sent with a user's time series, by series and range.
+  fn etags_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_etags:{}", user_id))
   }
 
-  /// Adds a step count to the user's step count set.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// * `date` - The date of the step count.
-  /// * `steps` - The number of steps.
+  /// The sorted set of a user's deferred commands, scored by when they are due.
+  fn deferred_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_deferred:{}", user_id))
+  }
+
+  /// The set of users with deferred commands.
+  fn deferred_users_key(&self) -> String {
+    self.key("fitbit_deferred_users")
+  }
+
+  /// The key holding the lock on refreshing a user's tokens.
+  fn refresh_lock_key(&self, user_id: &str) -> String {
+    self.key(&format!("fitbit_refresh_lock:{}", user_id))
+  }
+
+  /// Builds a pool for `REDIS_URL`. Connections are opened lazily, so a reachable URL is not checked until the pool is
+  /// first used.
   /// 
   /// # Returns
   /// 
-  /// * `Ok(())` - If the step count was added successfully.
-  /// * `Err(e)` - If the step count could not be added.
-  pub async fn add_steps(&self, user_id: &str, date: NaiveDate, steps: u32) -> Result<(), FitbitError> {
-    let mut conn = self.pool.get().await?;
+  /// * `Ok(pool)` - If the pool was built.
+  /// * `Err(message)` - If `REDIS_URL` is not set or is not a valid Redis URL.
+  pub async fn build_pool() -> Result<Pool<RedisConnectionManager>, String> {
+    let redis_url: String = env::var("REDIS_URL").map_err(|_| "REDIS_URL is not set".to_string())?;
 
-    let date = NaiveDateTime::new(date, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()).timestamp();
-    let expire = Utc::now().timestamp() + 60 * 60 * 24 * 2;
-    let value = format!("{}:{}:{}", steps, date, expire);
+    let manager = RedisConnectionManager::new(redis_url).map_err(|e| format!("REDIS_URL is not a valid Redis URL: {}", e))?;
 
-    let mut pipe = redis::pipe();
+    Pool::builder()
+      .build(manager)
+      .await
+      .map_err(|e| format!("Failed to create Redis pool: {}", e))
+  }
 
-    let result = pipe.atomic()
-      .zadd(format!("fitbit_steps:{}", user_id), value, date)
-      .expire(format!("fitbit_steps:{}", user_id), 60 * 60 * 24 * 2)
-      .query_async(&mut *conn).await;
+  /// Reads commands from the request stream as a member of the consumer group, creating the stream and group if needed.
+  /// Every command stays pending until it is acknowledged with `ack`, and commands left pending by a consumer that
+  /// has been idle for `CLAIM_MIN_IDLE` are claimed and redelivered, so a crash never loses a command.
+  pub async fn get_stream(&self) -> ReceiverStream<QueuedCommand> {
+    let (tx, rx) = mpsc::channel(100);
 
-    Ok(result?)
+    if let Err(e) = self.create_consumer_group().await {
+      error!("Error creating consumer group {}: {}", self.consumer_group, e);
+    }
+
+    let cache = self.clone();
+
+    tokio::spawn(Self::consume(tx.clone(), move || {
+      let cache = cache.clone();
+
+      async move { cache.read_commands().await }
+    }));
+
+    tokio::spawn(self.clone().claim_abandoned(tx));
+    tokio::spawn(self.clone().release_deferred_commands());
+
+    ReceiverStream::new(rx)
   }
 
-  /// Gets the longest range of consecutive days for which the user has step counts in the cache.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `start_date` - The start date of the range.
-  /// * `end_date` - The end date of the range.
-  /// 
-  /// # Returns
-  /// 
-  /// * `Vec<(NaiveDateTime, u32)>` - A vector of tuples containing the date and the number of steps for that date.
-  /// * `Err(e)` - If the step counts could not be retrieved.
-  pub async fn get_steps(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+  /// Creates the consumer group on the request stream, and the stream itself if it does not exist yet.
+  /// The group starts from the beginning of the stream, so commands sent before the first start are not skipped.
+  async fn create_consumer_group(&self) -> Result<(), FitbitError> {
     let mut conn = self.pool.get().await?;
-    let mut expired: Vec<String> = Vec::new();
-    
-    let start_date_timestamp = NaiveDateTime::new(start_date, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()).timestamp();
-    let end_date_timestamp = NaiveDateTime::new(end_date, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()).timestamp();
 
-    let steps: Vec<String> = match conn.zrangebyscore(format!("fitbit_steps:{}", user_id), start_date_timestamp, end_date_timestamp).await {
-      Ok(steps) => steps,
-      Err(e) => {
-        match e.kind() {
-          redis::ErrorKind::TypeError => return Ok(HashMap::new()),
-          _ => return Err(FitbitError::RedisError(e)),
-        }
-      },
-    };
+    let result: Result<(), RedisError> = conn.xgroup_create_mkstream(&self.request_queue, &self.consumer_group, "0").await;
 
-    let now: i64 = Utc::now().timestamp();
+    match result {
+      Err(e) if e.code() != Some("BUSYGROUP") => Err(FitbitError::RedisError(e)),
+      _ => Ok(()),
+    }
+  }
 
-    let steps: Vec<(u32, i64)> = steps.into_iter().filter_map(| value | {
-      let split_values: Vec<&str> = value.split(':').collect();
+  /// Blocks until new commands arrive on the request stream, and returns them.
+  async fn read_commands(&self) -> Result<Vec<QueuedCommand>, FitbitError> {
+    // The connection is reacquired for every read so a broken connection is replaced by the pool.
+    let mut conn = self.pool.get().await?;
 
-      let steps = split_values[0].parse::<u32>().unwrap();
-      let timestamp = split_values[1].parse::<i64>().unwrap();
-      let expire = split_values[2].parse::<i64>().unwrap();
+    let options = StreamReadOptions::default()
+      .group(&self.consumer_group, &self.consumer_name)
+      .count(10)
+      .block(0);
 
-      if expire < now {
-        expired.push(value);
-        None
-      } else {
-        Some((steps, timestamp))
+    let reply: StreamReadReply = conn.xread_options(&[&self.request_queue], &[">"], &options).await?;
+
+    Ok(queued_commands(reply.keys.into_iter().flat_map(|key| key.ids).collect()))
+  }
+
+  /// Claims one page of commands that other consumers have left pending for at least `CLAIM_MIN_IDLE`, starting at
+  /// `cursor`. Returns the cursor of the next page, which is `0-0` once the whole pending list has been swept.
+  async fn claim_commands(&self, cursor: &str) -> Result<(String, Vec<QueuedCommand>), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let reply: redis::Value = redis::cmd("XAUTOCLAIM")
+      .arg(&self.request_queue)
+      .arg(&self.consumer_group)
+      .arg(&self.consumer_name)
+      .arg(Self::CLAIM_MIN_IDLE.as_millis() as u64)
+      .arg(cursor)
+      .arg("COUNT")
+      .arg(100)
+      .query_async(&mut *conn).await?;
+
+    Ok(parse_autoclaim(&reply)?)
+  }
+
+  /// Periodically claims commands left pending by consumers that have died, and forwards them to `tx`.
+  async fn claim_abandoned(self, tx: mpsc::Sender<QueuedCommand>) {
+    let mut cursor = String::from("0-0");
+
+    loop {
+      if cursor == "0-0" {
+        tokio::time::sleep(Self::CLAIM_INTERVAL).await;
       }
-    }).collect();
 
-    if !expired.is_empty() {
-      let _: usize = match conn.zrem(format!("fitbit_steps:{}", user_id), expired).await {
-        Ok(deleted) => {
-          info!("{} entries removed from cache [expired]", deleted);
-          deleted
+      match self.claim_commands(&cursor).await {
+        Ok((next, claimed)) => {
+          if !claimed.is_empty() {
+            info!("Claimed {} abandoned commands", claimed.len());
+          }
+
+          for command in claimed {
+            if let Err(e) = tx.send(command).await {
+              error!("Command stream receiver is gone, dropping message: {:?}", e.0);
+            }
+          }
+
+          cursor = next;
         },
-        Err(e) => return Err(FitbitError::RedisError(e)),
-      };
+        Err(e) => {
+          error!("Error claiming abandoned commands: {}", e);
+          cursor = String::from("0-0");
+        },
+      }
     }
+  }
 
-    let steps = utils::parse_steps(steps);
-    let steps = utils::longest_range(start_date, steps);
+  /// Periodically moves deferred commands that are due back onto the request stream.
+  async fn release_deferred_commands(self) {
+    loop {
+      tokio::time::sleep(Self::DEFERRED_POLL_INTERVAL).await;
 
-    Ok(steps)
+      match self.release_due_commands().await {
+        Ok(0) => (),
+        Ok(released) => info!("Released {} deferred commands", released),
+        Err(e) => error!("Error releasing deferred commands: {}", e),
+      }
+    }
   }
-  
-  /// Stores when a user queries the Fitbit API
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// * `date` - The date of the query.
-  /// * `ratelimit_reset` - The seconds until the ratelimit resets.
-  /// 
-  /// # Returns
-  /// 
-  /// * `Ok(())` - If the query was stored successfully.
-  /// * `Err(e)` - If the query could not be stored.
-  pub async fn add_user_query(&self, user_id: &str, date: NaiveDateTime, ratelimit_reset: usize) -> Result<(), FitbitError> {
+
+  /// Moves every deferred command that is due back onto the request stream, and returns how many were moved.
+  async fn release_due_commands(&self) -> Result<usize, FitbitError> {
     let mut conn = self.pool.get().await?;
 
-    let date = date.timestamp();
+    let users: Vec<String> = conn.smembers(self.deferred_users_key()).await?;
+    let now = Utc::now().timestamp();
 
-    let duration: i64 = match ratelimit_reset.try_into() {
-      Ok(duration) => duration,
-      Err(err) => return Err(FitbitError::TypeConversionError(err.to_string())),
-    };
+    // Removing the command and adding it to the stream happen together, so a command is released by exactly one
+    // engine and is never lost in between.
+    let release = redis::Script::new(r"
+      if redis.call('ZREM', KEYS[1], ARGV[1]) == 1 then
+        redis.call('XADD', KEYS[2], '*', ARGV[2], ARGV[1], ARGV[3], '1')
+        return 1
+      end
 
-    let reset_datetime = (Utc::now() + Duration::seconds(duration)).timestamp();
+      return 0
+    ");
 
-    // Buffer in case of latency
-    let reset_datetime = reset_datetime - 2;
+    let forget = redis::Script::new(r"
+      if redis.call('ZCARD', KEYS[1]) == 0 then
+        redis.call('SREM', KEYS[2], ARGV[1])
+      end
+    ");
 
-    let mut pipe = redis::pipe();
+    let mut released = 0;
 
-    let query = pipe.atomic()
-      .set_ex("fitbit_ratelimit_reset", reset_datetime, ratelimit_reset)
-      .lpush(format!("fitbit_user_queries:{}", user_id), date)
-      .expire(format!("fitbit_user_queries:{}", user_id), ratelimit_reset)
-      .query_async(&mut *conn).await;
+    for user_id in users {
+      let key = self.deferred_key(&user_id);
+      let due: Vec<String> = conn.zrangebyscore(&key, "-inf", now).await?;
 
-    Ok(query?)
+      for message in due {
+        let moved: i32 = release
+          .key(&key)
+          .key(&self.request_queue)
+          .arg(&message)
+          .arg(Self::MESSAGE_FIELD)
+          .arg(Self::DEFER_FIELD)
+          .invoke_async(&mut *conn).await?;
+
+        released += usize::try_from(moved).unwrap_or(0);
+      }
+
+      let _: () = forget
+        .key(&key)
+        .key(self.deferred_users_key())
+        .arg(&user_id)
+        .invoke_async(&mut *conn).await?;
+    }
+
+    Ok(released)
   }
 
-  /// Gets the last time a user queried the Fitbit API
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// 
-  /// # Returns
-  /// 
-  /// * `Ok(Some(date))` - The last time the user queried the Fitbit API.
-  pub async fn get_last_user_query(&self, user_id: &str) -> Result<Option<NaiveDateTime>, FitbitError> {
-    let mut conn = self.pool.get().await?;
+  /// Forwards messages from `pop` to `tx` forever. Errors from `pop` are logged and retried with
+  /// exponential backoff, and messages are logged and dropped if the receiver has gone away.
+  async fn consume<T, F, Fut>(tx: mpsc::Sender<T>, mut pop: F)
+  where
+    T: std::fmt::Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Vec<T>, FitbitError>>,
+  {
+    let mut backoff = Self::MIN_STREAM_BACKOFF;
 
-    let last_query: i64 = match conn.lindex(format!("fitbit_user_queries:{}", user_id), 0).await {
-      Ok(Some(last_query)) => last_query,
-      Ok(None) => return Ok(None),
-      Err(e) => return Err(FitbitError::RedisError(e)),
-    };
-    let last_query = NaiveDateTime::from_timestamp_opt(last_query, 0).unwrap();
+    loop {
+      match pop().await {
+        Ok(messages) => {
+          backoff = Self::MIN_STREAM_BACKOFF;
 
-    Ok(Some(last_query))
+          for message in messages {
+            if let Err(e) = tx.send(message).await {
+              error!("Command stream receiver is gone, dropping message: {:?}", e.0);
+            }
+          }
+        },
+        Err(e) => {
+          error!("Error reading from request queue, retrying in {:?}: {}", backoff, e);
+
+          tokio::time::sleep(backoff).await;
+          backoff = std::cmp::min(backoff * 2, Self::MAX_STREAM_BACKOFF);
+        },
+      }
+    }
   }
 
-  /// Gets the rate limit reset time
-  pub async fn get_ratelimit_reset(&self) -> Result<NaiveDateTime, FitbitError> {
-    let mut conn = self.pool.get().await?;
+  /// Builds the atomic pipeline that stores and/or publishes a reply.
+  fn reply_pipeline(&self, coordination_id: &str, message: &[u8]) -> redis::Pipeline {
+    let key = self.reply_key(coordination_id);
+    let mut pipe = redis::pipe();
 
-    let ratelimit_reset: Result<Option<i64>, RedisError> = conn.get("fitbit_ratelimit_reset").await;
+    pipe.atomic();
 
-    let ratelimit_reset: i64 = match ratelimit_reset {
-      Ok(Some(ratelimit_reset)) => ratelimit_reset,
-      Ok(None) => return Ok(NaiveDateTime::from_timestamp_opt(0, 0).unwrap()),
-      Err(e) => return Err(FitbitError::RedisError(e)),
-    };
+    if self.reply_mode != ReplyMode::Publish {
+      pipe.set_ex(&key, message, self.reply_ttl as usize).ignore();
+    }
+
+    if self.reply_mode != ReplyMode::Store {
+      pipe.publish(&key, message).ignore();
+    }
 
-    let ratelimit_reset = NaiveDateTime::from_timestamp_opt(ratelimit_reset, 0).unwrap();
+    pipe.set_ex(self.processed_key(coordination_id), message, Self::PROCESSED_TTL.as_secs() as usize).ignore();
 
-    Ok(ratelimit_reset)
+    pipe
   }
 
-  /// Gets the number of queries a user has made to the Fitbit API
-  /// As the expiry time is set to the ratelimit reset time, this should be the number of queries the user has made in the last ratelimit reset time.
-  /// 
-  /// # Arguments
-  /// 
-  pub async fn get_user_queries(&self, user_id: &str) -> Result<usize, FitbitError> {
-    let mut conn = self.pool.get().await?;
+  /// Builds a pipeline that pushes a failed command onto the dead letter queue, newest first, and trims the queue to
+  /// its limit.
+  fn dead_letter_pipeline(&self, entry: &str) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    let last = isize::try_from(self.dead_letter_limit.saturating_sub(1)).unwrap_or(isize::MAX);
 
-    let length: Result<usize, RedisError> = conn.llen(format!("fitbit_user_queries:{}", user_id)).await;
+    pipe.atomic()
+      .lpush(&self.dead_letter_queue, entry).ignore()
+      .ltrim(&self.dead_letter_queue, 0, last).ignore();
 
-    match length {
-      Ok(length) => Ok(length),
-      Err(e) => Err(FitbitError::RedisError(e)),
+    pipe
+  }
+
+  /// The key marking that a command has been claimed, holding its reply once it is sent.
+  fn processed_key(&self, coordination_id: &str) -> String {
+    self.key(&format!("processed:{coordination_id}"))
+  }
+
+  /// The key, or channel, a reply is sent on.
+  fn reply_key(&self, coordination_id: &str) -> String {
+    self.key(&format!("{}:{coordination_id}", self.reply_prefix))
+  }
+
+  /// Adds a daily value to the sorted set stored at `key`.
+  async fn add_daily_value(&self, key: &str, date: NaiveDate, value: u32) -> Result<(), FitbitError> {
+    self.add_daily_values(key, &HashMap::from([(date, value)])).await
+  }
+
+  /// Adds daily values to the sorted set stored at `key` with one connection and one atomic pipeline.
+  async fn add_daily_values<V: fmt::Display + Copy + Sync>(&self, key: &str, values: &HashMap<NaiveDate, V>) -> Result<(), FitbitError> {
+    if values.is_empty() {
+      return Ok(());
     }
+
+    let mut conn = self.pool.get().await?;
+
+    let result = self.daily_values_pipeline(key, values, Utc::now().naive_utc()).query_async(&mut *conn).await;
+
+    Ok(result?)
   }
-}
\ No newline at end of file
+
+  /// Builds a pipeline that adds every value to the sorted set at `key` in one `ZADD`, scored by the date's timestamp,
+  /// and then extends the set's expiry once. Each value expires according to the cache TTL for its date. Any value
+  /// already cached for one of the dates is removed first, so a refetched day replaces the old one.
+  fn daily_values_pipeline<V: fmt::Display + Copy>(&self, key: &str, values: &HashMap<NaiveDate, V>, now: NaiveDateTime) -> redis::Pipeline {
+    let mut members: Vec<(i64, String)> = values.iter().map(|(date, value)| {
+      let ttl = self.cache_ttl.for_date(*date, now.date());
+
+      let entry = CacheEntry {
+        value: *value,
+        date: *date,
+        expires_at: expires_at(now, ttl),
+      };
+
+      let score = NaiveDateTime::new(*date, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()).timestamp();
+
+      (score, entry.to_string())
+    }).collect();
+
+    members.sort();
+
+    let mut pipe = redis::pipe();
+
+    pipe.atomic();
+
+    for (score, _) in &members {
+      pipe.zrembyscore(key, *score, *score).ignore();
+    }
+
+    pipe
+      .zadd_multiple(key, &members).ignore()
+      .expire(key, self.cache_ttl.longest() as usize).ignore();
+
+    pipe
+  }
+
+  /// Gets every day stored in the sorted set at `key` within the given range, inclusive, removing any expired entries.
+  async fn get_daily_values<V: FromStr + Send>(&self, key: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, V>, FitbitError> {
+    let start_date_timestamp = NaiveDateTime::new(start_date, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()).timestamp();
+    let end_date_timestamp = NaiveDateTime::new(end_date, chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap()).timestamp();
+
+    let values = self.get_daily_entries(key, start_date_timestamp, end_date_timestamp).await?;
+
+    Ok(values.into_iter().collect())
+  }
+
+  /// Gets every unexpired day stored in the sorted set at `key` with a timestamp between `min` and `max`, in order,
+  /// removing any expired entries.
+  async fn get_daily_entries<V: FromStr + Send, M: redis::ToRedisArgs + Send + Sync, MM: redis::ToRedisArgs + Send + Sync>(&self, key: &str, min: M, max: MM) -> Result<Vec<(NaiveDate, V)>, FitbitError> {
+    let mut conn = self.pool.get().await?;
+    let mut expired: Vec<String> = Vec::new();
+
+    let values: Vec<String> = match conn.zrangebyscore(key, min, max).await {
+      Ok(values) => values,
+      Err(e) => {
+        match e.kind() {
+          redis::ErrorKind::TypeError => return Ok(Vec::new()),
+          _ => return Err(FitbitError::RedisError(e)),
+        }
+      },
+    };
+
+    let now = Utc::now().naive_utc();
+    let mut entries: Vec<(NaiveDate, V)> = Vec::new();
+
+    for value in values {
+      let entry = value.parse::<CacheEntry<V>>()?;
+
+      if entry.expires_at < now {
+        expired.push(value);
+      } else {
+        entries.push((entry.date, entry.value));
+      }
+    }
+
+    if !expired.is_empty() {
+      let _: usize = match conn.zrem(key, expired).await {
+        Ok(deleted) => {
+          info!("{} entries removed from cache [expired]", deleted);
+          deleted
+        },
+        Err(e) => return Err(FitbitError::RedisError(e)),
+      };
+    }
+
+    Ok(entries)
+  }
+  
+  /// Every key that holds data for a single user.
+  fn user_keys(&self, user_id: &str) -> Vec<String> {
+    let mut keys: Vec<String> = Series::ALL.iter().map(|series| self.series_key(*series, user_id)).collect();
+
+    keys.extend([
+      self.key(&format!("fitbit_user_queries:{}", user_id)),
+      self.ratelimit_reset_key(user_id),
+      self.ratelimit_reported_key(user_id),
+      self.profile_key(user_id),
+      self.goals_key(user_id),
+      self.devices_key(user_id),
+      self.etags_key(user_id),
+    ]);
+
+    keys
+  }
+}
+
+/// The cache the engine keeps in front of Fitbit: cached daily values, per-user rate limit state, the refresh lock, and
+/// replies to commands. `CacheHandler` keeps it in Redis; `MemoryCache` keeps it in process for tests and deployments
+/// without Redis.
+pub trait Cache: RefreshLock + Clone + Send + Sync + 'static {
+  /// Makes a round trip to the cache, to check that it is reachable.
+  fn ping(&self) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Sends a reply, encoded by `utils::encode_reply`, according to the reply mode. Published replies use the same
+  /// name for the channel as stored replies use for the key. The reply is also recorded for `claim_reply`.
+  fn send_message(&self, coordination_id: &str, message: Vec<u8>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Claims a command by its coordination ID before it is executed, so a redelivered command is not executed twice.
+  /// 
+  /// # Returns
+  /// 
+  /// * `ReplyClaim::New` - If the command was not claimed before, and now is.
+  /// * `ReplyClaim::InFlight` - If the command was claimed but has not been answered.
+  /// * `ReplyClaim::Sent(reply)` - If the command was answered, with the reply it was answered with.
+  /// * `Err(e)` - If the claim could not be checked.
+  fn claim_reply(&self, coordination_id: &str) -> impl Future<Output = Result<ReplyClaim, FitbitError>> + Send;
+
+  /// Releases the claim on a command that was not answered, so it is executed when it is next delivered.
+  fn release_reply(&self, coordination_id: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Acknowledges a command once its reply has been sent, and removes it from the stream.
+  fn ack(&self, id: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Holds a rate-limited command until `due`, when it is added back to the request stream with the same framing and
+  /// coordination ID. A user's deferred commands are bounded by `DEFERRED_COMMANDS_PER_USER`.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user whose rate limit the command is waiting for.
+  /// * `message` - The framed command.
+  /// * `due` - When the command should run again.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(true)` - If the command was deferred.
+  /// * `Ok(false)` - If the user already has as many deferred commands as allowed.
+  /// * `Err(e)` - If the command could not be deferred.
+  fn defer_command(&self, user_id: &str, message: &str, due: NaiveDateTime) -> impl Future<Output = Result<bool, FitbitError>> + Send;
+
+  /// Keeps a failed command in the dead letter queue, as JSON. Only the newest failures are kept.
+  fn dead_letter(&self, entry: &DeadLetter) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Adds a step count to the user's step count set.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `date` - The date of the step count.
+  /// * `steps` - The number of steps.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the step count was added successfully.
+  /// * `Err(e)` - If the step count could not be added.
+  fn add_steps(&self, user_id: &str, date: NaiveDate, steps: u32) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Adds a resting heart rate to the user's heart rate set.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `date` - The date of the heart rate.
+  /// * `heart_rate` - The resting heart rate, in beats per minute.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the heart rate was added successfully.
+  /// * `Err(e)` - If the heart rate could not be added.
+  fn add_heart_rate(&self, user_id: &str, date: NaiveDate, heart_rate: u32) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Adds step counts for any number of days to the user's step count set in a single round trip.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `steps` - The step counts, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the step counts were added successfully, or there were none.
+  /// * `Err(e)` - If the step counts could not be added.
+  fn add_steps_bulk(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Adds resting heart rates for any number of days to the user's heart rate set in a single round trip.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `heart_rate` - The resting heart rates, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the heart rates were added successfully, or there were none.
+  /// * `Err(e)` - If the heart rates could not be added.
+  fn add_heart_rate_bulk(&self, user_id: &str, heart_rate: &HashMap<NaiveDate, u32>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Adds daily calories burned to the user's calorie set with a single connection.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `calories` - The calories burned, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the calories were added successfully, or there were none.
+  /// * `Err(e)` - If the calories could not be added.
+  fn add_calories_bulk(&self, user_id: &str, calories: &HashMap<NaiveDate, u32>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Adds daily floors climbed to the user's floors set with a single connection.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `floors` - The floors climbed, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the floors were added successfully, or there were none.
+  /// * `Err(e)` - If the floors could not be added.
+  fn add_floors_bulk(&self, user_id: &str, floors: &HashMap<NaiveDate, u32>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the cached step counts for the user within the given range, inclusive. Days that are not cached are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding step counts.
+  /// * `Err(e)` - If the step counts could not be retrieved.
+  fn get_steps(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>> + Send;
+
+  /// Gets the ranges of consecutive days for which the user has step counts in the cache.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Vec<Range>` - The cached ranges, inclusive and sorted by start date.
+  /// * `Err(e)` - If the step counts could not be retrieved.
+  fn get_steps_coverage(&self, user_id: &str) -> impl Future<Output = Result<Vec<Range>, FitbitError>> + Send;
+
+  /// Gets the cached resting heart rates for the user within the given range, inclusive. Days that are not cached are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding resting heart rates.
+  /// * `Err(e)` - If the heart rates could not be retrieved.
+  fn get_heart_rate(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>> + Send;
+
+  /// Gets the cached calories burned by the user within the given range, inclusive. Days that are not cached are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and the calories burned on them.
+  /// * `Err(e)` - If the calories could not be retrieved.
+  fn get_calories(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>> + Send;
+
+  /// Gets the cached floors climbed by the user within the given range, inclusive. Days that are not cached are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and the floors climbed on them.
+  /// * `Err(e)` - If the floors could not be retrieved.
+  fn get_floors(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>> + Send;
+
+  /// Adds daily distances, in kilometers, to the user's distance set with a single connection.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `distance` - The distances in kilometers, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the distances were added successfully, or there were none.
+  /// * `Err(e)` - If the distances could not be added.
+  fn add_distance_bulk(&self, user_id: &str, distance: &HashMap<NaiveDate, f64>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the cached distances covered by the user within the given range, inclusive, in kilometers. Days that are
+  /// not cached are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, f64>` - A hashmap of dates and the distances covered on them.
+  /// * `Err(e)` - If the distances could not be retrieved.
+  fn get_distance(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, f64>, FitbitError>> + Send;
+
+  /// Adds daily active minutes to the user's active minute sets.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `active_minutes` - The active minutes, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the active minutes were added successfully, or there were none.
+  /// * `Err(e)` - If the active minutes could not be added.
+  fn add_active_minutes_bulk(&self, user_id: &str, active_minutes: &HashMap<NaiveDate, ActiveMinutes>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the cached active minutes of the user within the given range, inclusive. Days that are not cached are
+  /// omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, ActiveMinutes>` - A hashmap of dates and their active minutes.
+  /// * `Err(e)` - If the active minutes could not be retrieved.
+  fn get_active_minutes(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, ActiveMinutes>, FitbitError>> + Send;
+
+  /// Adds nightly SpO2 summaries to the user's SpO2 set with a single connection.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `spo2` - The SpO2 summaries, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the summaries were added successfully, or there were none.
+  /// * `Err(e)` - If the summaries could not be added.
+  fn add_spo2_bulk(&self, user_id: &str, spo2: &HashMap<NaiveDate, Spo2Summary>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the cached SpO2 summaries of the user within the given range, inclusive. Days that are not cached are
+  /// omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, Spo2Summary>` - A hashmap of dates and their SpO2 summaries.
+  /// * `Err(e)` - If the summaries could not be retrieved.
+  fn get_spo2(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, Spo2Summary>, FitbitError>> + Send;
+
+  /// Adds the minutes spent in each heart rate zone to the user's zones set with a single connection.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `zones` - The zone minutes, keyed by date.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the zone minutes were added successfully, or there were none.
+  /// * `Err(e)` - If the zone minutes could not be added.
+  fn add_heart_rate_zones_bulk(&self, user_id: &str, zones: &HashMap<NaiveDate, HeartRateZones>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the cached heart rate zone minutes of the user within the given range, inclusive. Days that are not cached
+  /// are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start_date` - The start date of the range.
+  /// * `end_date` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, HeartRateZones>` - A hashmap of dates and the minutes spent in each zone.
+  /// * `Err(e)` - If the zone minutes could not be retrieved.
+  fn get_heart_rate_zones(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, HeartRateZones>, FitbitError>> + Send;
+
+  /// Stores when a user queries the Fitbit API
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `date` - The date of the query.
+  /// * `ratelimit_reset` - The seconds until the ratelimit resets.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the query was stored successfully.
+  /// * `Err(e)` - If the query could not be stored.
+  fn add_user_query(&self, user_id: &str, date: NaiveDateTime, ratelimit_reset: usize) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the last time a user queried the Fitbit API
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(date))` - The last time the user queried the Fitbit API.
+  fn get_last_user_query(&self, user_id: &str) -> impl Future<Output = Result<Option<NaiveDateTime>, FitbitError>> + Send;
+
+  /// Caches a user's Fitbit profile.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `profile` - The profile to cache.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the profile was cached successfully.
+  /// * `Err(e)` - If the profile could not be cached.
+  fn set_profile(&self, user_id: &str, profile: &Profile) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets a user's cached Fitbit profile.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(profile))` - If a profile is cached.
+  /// * `Ok(None)` - If no profile is cached, or the cached profile could not be read.
+  /// * `Err(e)` - If the cache could not be reached.
+  fn get_profile(&self, user_id: &str) -> impl Future<Output = Result<Option<Profile>, FitbitError>> + Send;
+
+  /// Caches a user's daily activity goals.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `goals` - The goals to cache.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the goals were cached successfully.
+  /// * `Err(e)` - If the goals could not be cached.
+  fn set_goals(&self, user_id: &str, goals: &Goals) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets a user's cached daily activity goals.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(goals))` - If goals are cached.
+  /// * `Ok(None)` - If no goals are cached, or the cached goals could not be read.
+  /// * `Err(e)` - If the cache could not be reached.
+  fn get_goals(&self, user_id: &str) -> impl Future<Output = Result<Option<Goals>, FitbitError>> + Send;
+
+  /// Removes a user's cached daily activity goals, so the next read fetches them from Fitbit.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the goals were removed, or none were cached.
+  /// * `Err(e)` - If the goals could not be removed.
+  fn clear_goals(&self, user_id: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Caches the devices paired with a user's account.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `devices` - The devices to cache.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the devices were cached successfully.
+  /// * `Err(e)` - If the devices could not be cached.
+  fn set_devices(&self, user_id: &str, devices: &[Device]) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the cached devices paired with a user's account.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(devices))` - If devices are cached.
+  /// * `Ok(None)` - If no devices are cached, or the cached devices could not be read.
+  /// * `Err(e)` - If the cache could not be reached.
+  fn get_devices(&self, user_id: &str) -> impl Future<Output = Result<Option<Vec<Device>>, FitbitError>> + Send;
+
+  /// Stores the ETag Fitbit sent with a user's time series, so the same request can later be sent conditionally.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `request` - Identifies the series and range the ETag was sent with.
+  /// * `etag` - The ETag.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the ETag was stored successfully.
+  /// * `Err(e)` - If the ETag could not be stored.
+  fn set_etag(&self, user_id: &str, request: &str, etag: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the ETag Fitbit last sent with a user's time series.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `request` - Identifies the series and range, as passed to `set_etag`.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(etag))` - If an ETag is stored.
+  /// * `Ok(None)` - If none is.
+  /// * `Err(e)` - If the cache could not be reached.
+  fn get_etag(&self, user_id: &str, request: &str) -> impl Future<Output = Result<Option<String>, FitbitError>> + Send;
+
+  /// Stores the rate limit reset time reported by Fitbit for a user.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `reset_seconds` - The seconds until the rate limit resets.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the reset time was stored successfully.
+  /// * `Err(e)` - If the reset time could not be stored.
+  fn set_ratelimit_reset(&self, user_id: &str, reset_seconds: u64) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the rate limit reset time for a user. Fitbit rate limits each user separately.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  fn get_ratelimit_reset(&self, user_id: &str) -> impl Future<Output = Result<NaiveDateTime, FitbitError>> + Send;
+
+  /// Stores the remaining requests and limit Fitbit reported for a user until the rate limit window resets. If Fitbit
+  /// did not report the remaining requests, any earlier report is removed, so it is not mistaken for a current one.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `rate_limit` - The rate limit state from the response headers.
+  /// * `ratelimit_reset` - The seconds until the ratelimit resets.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the report was stored or removed successfully.
+  /// * `Err(e)` - If the report could not be stored.
+  fn set_reported_ratelimit(&self, user_id: &str, rate_limit: RateLimitInfo, ratelimit_reset: usize) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the remaining requests and limit Fitbit last reported for a user in the current window. The reset is kept
+  /// separately, by `get_ratelimit_reset`, so `reset_seconds` is always `None`.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(rate_limit)` - The reported state, with `remaining` and `limit` `None` if nothing was reported.
+  /// * `Err(e)` - If the report could not be read.
+  fn get_reported_ratelimit(&self, user_id: &str) -> impl Future<Output = Result<RateLimitInfo, FitbitError>> + Send;
+
+  /// Gets the number of queries a user has made to the Fitbit API
+  /// As the expiry time is set to the ratelimit reset time, this should be the number of queries the user has made in the last ratelimit reset time.
+  /// 
+  /// # Arguments
+  /// 
+  fn get_user_queries(&self, user_id: &str) -> impl Future<Output = Result<usize, FitbitError>> + Send;
+
+  /// Removes all cached data, query history, and rate limit state for a user. Keys that are already absent are ignored.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(count)` - The number of keys that were removed.
+  /// * `Err(e)` - If the keys could not be removed.
+  fn clear_user(&self, user_id: &str) -> impl Future<Output = Result<usize, FitbitError>> + Send;
+}
+
+impl Cache for CacheHandler {
+  async fn ping(&self) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let _: String = redis::cmd("PING").query_async(&mut *conn).await?;
+
+    Ok(())
+  }
+
+  async fn send_message(&self, coordination_id: &str, message: Vec<u8>) -> Result<(), FitbitError> {
+    let mut conn: bb8::PooledConnection<'_, RedisConnectionManager> = self.pool.get().await?;
+
+    let result = self.reply_pipeline(coordination_id, &message).query_async(&mut *conn).await;
+
+    Ok(result?)
+  }
+
+  async fn claim_reply(&self, coordination_id: &str) -> Result<ReplyClaim, FitbitError> {
+    let mut conn = self.pool.get().await?;
+    let key = self.processed_key(coordination_id);
+
+    let claimed: Option<String> = redis::cmd("SET")
+      .arg(&key)
+      .arg("")
+      .arg("NX")
+      .arg("EX")
+      .arg(Self::PROCESSED_TTL.as_secs())
+      .query_async(&mut *conn).await?;
+
+    if claimed.is_some() {
+      return Ok(ReplyClaim::New);
+    }
+
+    let reply: Option<Vec<u8>> = conn.get(&key).await?;
+
+    // The claim may have expired between the two commands, in which case the command is treated as new.
+    Ok(match reply {
+      None => ReplyClaim::New,
+      Some(reply) if reply.is_empty() => ReplyClaim::InFlight,
+      Some(reply) => ReplyClaim::Sent(reply),
+    })
+  }
+
+  async fn release_reply(&self, coordination_id: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    conn.del::<_, ()>(self.processed_key(coordination_id)).await?;
+
+    Ok(())
+  }
+
+  async fn ack(&self, id: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let mut pipe = redis::pipe();
+
+    let result = pipe.atomic()
+      .xack(&self.request_queue, &self.consumer_group, &[id]).ignore()
+      .xdel(&self.request_queue, &[id]).ignore()
+      .query_async(&mut *conn).await;
+
+    Ok(result?)
+  }
+
+  async fn defer_command(&self, user_id: &str, message: &str, due: NaiveDateTime) -> Result<bool, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    // A command that is already deferred is only rescheduled, so it never counts against the limit twice.
+    let script = redis::Script::new(r"
+      if redis.call('ZSCORE', KEYS[1], ARGV[2]) == false and redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[3]) then
+        return 0
+      end
+
+      redis.call('ZADD', KEYS[1], ARGV[1], ARGV[2])
+      redis.call('SADD', KEYS[2], ARGV[4])
+
+      return 1
+    ");
+
+    let deferred: i32 = script
+      .key(self.deferred_key(user_id))
+      .key(self.deferred_users_key())
+      .arg(due.timestamp())
+      .arg(message)
+      .arg(self.deferred_limit)
+      .arg(user_id)
+      .invoke_async(&mut *conn).await?;
+
+    Ok(deferred == 1)
+  }
+
+  async fn dead_letter(&self, entry: &DeadLetter) -> Result<(), FitbitError> {
+    let entry = serde_json::to_string(entry).map_err(|e| FitbitError::ParsingError(e.to_string()))?;
+
+    let mut conn = self.pool.get().await?;
+
+    let result = self.dead_letter_pipeline(&entry).query_async(&mut *conn).await;
+
+    Ok(result?)
+  }
+
+  async fn add_steps(&self, user_id: &str, date: NaiveDate, steps: u32) -> Result<(), FitbitError> {
+    self.add_daily_value(&self.steps_key(user_id), date, steps).await
+  }
+
+  async fn add_heart_rate(&self, user_id: &str, date: NaiveDate, heart_rate: u32) -> Result<(), FitbitError> {
+    self.add_daily_value(&self.series_key(Series::HeartRate, user_id), date, heart_rate).await
+  }
+
+  async fn add_steps_bulk(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.add_daily_values(&self.steps_key(user_id), steps).await
+  }
+
+  async fn add_heart_rate_bulk(&self, user_id: &str, heart_rate: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.add_daily_values(&self.series_key(Series::HeartRate, user_id), heart_rate).await
+  }
+
+  async fn add_calories_bulk(&self, user_id: &str, calories: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.add_daily_values(&self.series_key(Series::Calories, user_id), calories).await
+  }
+
+  async fn add_floors_bulk(&self, user_id: &str, floors: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.add_daily_values(&self.series_key(Series::Floors, user_id), floors).await
+  }
+
+  async fn add_distance_bulk(&self, user_id: &str, distance: &HashMap<NaiveDate, f64>) -> Result<(), FitbitError> {
+    self.add_daily_values(&self.series_key(Series::Distance, user_id), distance).await
+  }
+
+  async fn add_active_minutes_bulk(&self, user_id: &str, active_minutes: &HashMap<NaiveDate, ActiveMinutes>) -> Result<(), FitbitError> {
+    let fairly = active_minutes.iter().map(|(date, minutes)| (*date, minutes.fairly)).collect();
+    let very = active_minutes.iter().map(|(date, minutes)| (*date, minutes.very)).collect();
+
+    self.add_daily_values(&self.series_key(Series::ActiveMinutesFairly, user_id), &fairly).await?;
+    self.add_daily_values(&self.series_key(Series::ActiveMinutesVery, user_id), &very).await
+  }
+
+  async fn add_spo2_bulk(&self, user_id: &str, spo2: &HashMap<NaiveDate, Spo2Summary>) -> Result<(), FitbitError> {
+    let spo2 = spo2.iter().map(|(date, summary)| (*date, Spo2Value(*summary))).collect();
+
+    self.add_daily_values(&self.series_key(Series::Spo2, user_id), &spo2).await
+  }
+
+  async fn add_heart_rate_zones_bulk(&self, user_id: &str, zones: &HashMap<NaiveDate, HeartRateZones>) -> Result<(), FitbitError> {
+    let zones = zones.iter().map(|(date, zones)| (*date, HeartRateZonesValue(*zones))).collect();
+
+    self.add_daily_values(&self.series_key(Series::HeartRateZones, user_id), &zones).await
+  }
+
+  async fn get_steps(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    self.get_daily_values(&self.steps_key(user_id), start_date, end_date).await
+  }
+
+  async fn get_steps_coverage(&self, user_id: &str) -> Result<Vec<Range>, FitbitError> {
+    let entries: Vec<(NaiveDate, u32)> = self.get_daily_entries(&self.steps_key(user_id), "-inf", "+inf").await?;
+
+    Ok(utils::coalesce_dates(entries.into_iter().map(|(date, _)| date).collect()))
+  }
+
+  async fn get_heart_rate(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    self.get_daily_values(&self.series_key(Series::HeartRate, user_id), start_date, end_date).await
+  }
+
+  async fn get_calories(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    self.get_daily_values(&self.series_key(Series::Calories, user_id), start_date, end_date).await
+  }
+
+  async fn get_floors(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    self.get_daily_values(&self.series_key(Series::Floors, user_id), start_date, end_date).await
+  }
+
+  async fn get_distance(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, f64>, FitbitError> {
+    self.get_daily_values(&self.series_key(Series::Distance, user_id), start_date, end_date).await
+  }
+
+  async fn get_active_minutes(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, ActiveMinutes>, FitbitError> {
+    let fairly = self.get_daily_values(&self.series_key(Series::ActiveMinutesFairly, user_id), start_date, end_date).await?;
+    let very = self.get_daily_values(&self.series_key(Series::ActiveMinutesVery, user_id), start_date, end_date).await?;
+
+    Ok(utils::merge_active_minutes(fairly, very))
+  }
+
+  async fn get_spo2(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, Spo2Summary>, FitbitError> {
+    let spo2: HashMap<NaiveDate, Spo2Value> = self.get_daily_values(&self.series_key(Series::Spo2, user_id), start_date, end_date).await?;
+
+    Ok(spo2.into_iter().map(|(date, Spo2Value(summary))| (date, summary)).collect())
+  }
+
+  async fn get_heart_rate_zones(&self, user_id: &str, start_date: NaiveDate, end_date: NaiveDate) -> Result<HashMap<NaiveDate, HeartRateZones>, FitbitError> {
+    let zones: HashMap<NaiveDate, HeartRateZonesValue> = self.get_daily_values(&self.series_key(Series::HeartRateZones, user_id), start_date, end_date).await?;
+
+    Ok(zones.into_iter().map(|(date, HeartRateZonesValue(zones))| (date, zones)).collect())
+  }
+
+  async fn add_user_query(&self, user_id: &str, date: NaiveDateTime, ratelimit_reset: usize) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let date = date.timestamp();
+
+    let duration: i64 = match ratelimit_reset.try_into() {
+      Ok(duration) => duration,
+      Err(err) => return Err(FitbitError::TypeConversionError(err.to_string())),
+    };
+
+    let reset_datetime = (Utc::now() + Duration::seconds(duration)).timestamp();
+
+    let mut pipe = redis::pipe();
+
+    let query = pipe.atomic()
+      .set_ex(self.ratelimit_reset_key(user_id), reset_datetime, ratelimit_reset)
+      .lpush(self.key(&format!("fitbit_user_queries:{}", user_id)), date)
+      .expire(self.key(&format!("fitbit_user_queries:{}", user_id)), ratelimit_reset)
+      .query_async(&mut *conn).await;
+
+    Ok(query?)
+  }
+
+  async fn get_last_user_query(&self, user_id: &str) -> Result<Option<NaiveDateTime>, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let last_query: i64 = match conn.lindex(self.key(&format!("fitbit_user_queries:{}", user_id)), 0).await {
+      Ok(Some(last_query)) => last_query,
+      Ok(None) => return Ok(None),
+      Err(e) => return Err(FitbitError::RedisError(e)),
+    };
+    let last_query = utils::stored_datetime(last_query)?;
+
+    Ok(Some(last_query))
+  }
+
+  async fn set_profile(&self, user_id: &str, profile: &Profile) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let profile = serde_json::to_string(profile).map_err(|e| FitbitError::ParsingError(e.to_string()))?;
+
+    conn.set_ex::<_, _, ()>(self.profile_key(user_id), profile, self.profile_ttl as usize).await?;
+
+    Ok(())
+  }
+
+  async fn get_profile(&self, user_id: &str) -> Result<Option<Profile>, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let profile: Option<String> = conn.get(self.profile_key(user_id)).await?;
+
+    // A profile that cannot be read, for example one cached before a field was added, is fetched again.
+    Ok(profile.and_then(|profile| serde_json::from_str(&profile).ok()))
+  }
+
+  async fn set_goals(&self, user_id: &str, goals: &Goals) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let goals = serde_json::to_string(goals).map_err(|e| FitbitError::ParsingError(e.to_string()))?;
+
+    conn.set_ex::<_, _, ()>(self.goals_key(user_id), goals, self.goals_ttl as usize).await?;
+
+    Ok(())
+  }
+
+  async fn get_goals(&self, user_id: &str) -> Result<Option<Goals>, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let goals: Option<String> = conn.get(self.goals_key(user_id)).await?;
+
+    Ok(goals.and_then(|goals| serde_json::from_str(&goals).ok()))
+  }
+
+  async fn clear_goals(&self, user_id: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    conn.del::<_, ()>(self.goals_key(user_id)).await?;
+
+    Ok(())
+  }
+
+  async fn set_devices(&self, user_id: &str, devices: &[Device]) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let devices = serde_json::to_string(devices).map_err(|e| FitbitError::ParsingError(e.to_string()))?;
+
+    conn.set_ex::<_, _, ()>(self.devices_key(user_id), devices, self.devices_ttl as usize).await?;
+
+    Ok(())
+  }
+
+  async fn get_devices(&self, user_id: &str) -> Result<Option<Vec<Device>>, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let devices: Option<String> = conn.get(self.devices_key(user_id)).await?;
+
+    Ok(devices.and_then(|devices| serde_json::from_str(&devices).ok()))
+  }
+
+  async fn set_etag(&self, user_id: &str, request: &str, etag: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let key = self.etags_key(user_id);
+
+    // The ETags are only used while the values they were sent with are cached, so they need not outlive them.
+    redis::pipe().atomic()
+      .hset(&key, request, etag).ignore()
+      .expire(&key, self.cache_ttl.longest() as usize).ignore()
+      .query_async::<_, ()>(&mut *conn).await?;
+
+    Ok(())
+  }
+
+  async fn get_etag(&self, user_id: &str, request: &str) -> Result<Option<String>, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    Ok(conn.hget(self.etags_key(user_id), request).await?)
+  }
+
+  async fn set_ratelimit_reset(&self, user_id: &str, reset_seconds: u64) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let duration: i64 = match reset_seconds.try_into() {
+      Ok(duration) => duration,
+      Err(err) => return Err(FitbitError::TypeConversionError(format!("{err}"))),
+    };
+
+    let reset_datetime = (Utc::now() + Duration::seconds(duration)).timestamp();
+
+    // Redis rejects a zero expiry, so keep the key for at least a second.
+    let result = conn.set_ex(self.ratelimit_reset_key(user_id), reset_datetime, std::cmp::max(reset_seconds, 1) as usize).await;
+
+    Ok(result?)
+  }
+
+  async fn get_ratelimit_reset(&self, user_id: &str) -> Result<NaiveDateTime, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let ratelimit_reset: Result<Option<i64>, RedisError> = conn.get(self.ratelimit_reset_key(user_id)).await;
+
+    let ratelimit_reset: i64 = match ratelimit_reset {
+      Ok(Some(ratelimit_reset)) => ratelimit_reset,
+      Ok(None) => return utils::stored_datetime(0),
+      Err(e) => return Err(FitbitError::RedisError(e)),
+    };
+
+    utils::stored_datetime(ratelimit_reset)
+  }
+
+  async fn set_reported_ratelimit(&self, user_id: &str, rate_limit: RateLimitInfo, ratelimit_reset: usize) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let key = self.ratelimit_reported_key(user_id);
+
+    let Some(remaining) = rate_limit.remaining else {
+      conn.del::<_, ()>(&key).await?;
+      return Ok(());
+    };
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+      .del(&key).ignore()
+      .hset(&key, "remaining", remaining).ignore();
+
+    if let Some(limit) = rate_limit.limit {
+      pipe.hset(&key, "limit", limit).ignore();
+    }
+
+    // Redis rejects a zero expiry, so keep the key for at least a second.
+    pipe.expire(&key, std::cmp::max(ratelimit_reset, 1)).ignore();
+
+    pipe.query_async::<_, ()>(&mut *conn).await?;
+
+    Ok(())
+  }
+
+  async fn get_reported_ratelimit(&self, user_id: &str) -> Result<RateLimitInfo, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let (remaining, limit): (Option<u32>, Option<u32>) = redis::cmd("HMGET")
+      .arg(self.ratelimit_reported_key(user_id))
+      .arg("remaining")
+      .arg("limit")
+      .query_async(&mut *conn)
+      .await?;
+
+    Ok(RateLimitInfo { remaining, limit, reset_seconds: None })
+  }
+
+  async fn get_user_queries(&self, user_id: &str) -> Result<usize, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let length: Result<usize, RedisError> = conn.llen(self.key(&format!("fitbit_user_queries:{}", user_id))).await;
+
+    match length {
+      Ok(length) => Ok(length),
+      Err(e) => Err(FitbitError::RedisError(e)),
+    }
+  }
+
+  async fn clear_user(&self, user_id: &str) -> Result<usize, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let mut pipe = redis::pipe();
+
+    let (removed,): (usize,) = pipe.atomic()
+      .del(self.user_keys(user_id))
+      .query_async(&mut *conn).await?;
+
+    Ok(removed)
+  }
+}
+
+/// When a value written at `now` with a TTL of `ttl` seconds expires, saturating at the latest representable time.
+fn expires_at(now: NaiveDateTime, ttl: u64) -> NaiveDateTime {
+  i64::try_from(ttl).ok()
+    .and_then(|ttl| now.checked_add_signed(Duration::seconds(ttl.min(i64::MAX / 1000))))
+    .unwrap_or(NaiveDateTime::MAX)
+}
+
+/// Reads a positive number of seconds from the environment variable `name`, falling back to `default` if it is unset
+/// or invalid.
+fn env_seconds(name: &str, default: u64) -> u64 {
+  match env::var(name) {
+    Ok(seconds) => match seconds.parse::<u64>() {
+      Ok(seconds) if seconds > 0 => seconds,
+      _ => {
+        error!("Invalid {}, expected a positive number of seconds, got {}", name, seconds);
+        default
+      },
+    },
+    Err(_) => default,
+  }
+}
+
+/// Runs `handle` on every item from `items` across `shards` workers, routing each item to a worker by the hash of its
+/// key. Each worker handles its items one at a time in the order they arrived, so items with the same key never run
+/// concurrently or out of order, while items on different workers run in parallel. Returns once `items` ends and
+/// every worker has finished.
+pub async fn process_sharded<T, S, K, H, Fut>(mut items: S, shards: usize, key: K, handle: H)
+where
+  T: Send + 'static,
+  S: Stream<Item = T> + Unpin,
+  K: Fn(&T) -> String,
+  H: Fn(T) -> Fut + Clone + Send + 'static,
+  Fut: Future<Output = ()> + Send,
+{
+  let shards = shards.max(1);
+  let mut senders = Vec::with_capacity(shards);
+  let mut workers = Vec::with_capacity(shards);
+
+  for _ in 0..shards {
+    let (tx, mut rx) = mpsc::channel::<T>(100);
+    let handle = handle.clone();
+
+    workers.push(tokio::spawn(async move {
+      while let Some(item) = rx.recv().await {
+        handle(item).await;
+      }
+    }));
+
+    senders.push(tx);
+  }
+
+  while let Some(item) = items.next().await {
+    if senders[shard_of(&key(&item), shards)].send(item).await.is_err() {
+      error!("Command shard worker is gone, dropping command");
+    }
+  }
+
+  drop(senders);
+
+  for worker in workers {
+    if let Err(e) = worker.await {
+      error!("Command shard worker failed: {}", e);
+    }
+  }
+}
+
+/// The shard a key belongs to. `DefaultHasher::new` always uses the same keys, so a key maps to the same shard for
+/// the life of the process.
+fn shard_of(key: &str, shards: usize) -> usize {
+  use std::hash::{Hash, Hasher};
+
+  let mut hasher = std::collections::hash_map::DefaultHasher::new();
+  key.hash(&mut hasher);
+
+  (hasher.finish() % shards as u64) as usize
+}
+
+/// Converts stream entries to commands. An entry without a message field becomes an empty command, which fails to
+/// decode and is acknowledged like any other malformed command.
+fn queued_commands(entries: Vec<StreamId>) -> Vec<QueuedCommand> {
+  entries.into_iter().map(|entry| QueuedCommand {
+    message: entry.get(CacheHandler::MESSAGE_FIELD).unwrap_or_default(),
+    defer: entry.get::<String>(CacheHandler::DEFER_FIELD).is_some_and(|defer| defer == "1"),
+    id: entry.id,
+  }).collect()
+}
+
+/// Parses an `XAUTOCLAIM` reply into the next cursor and the claimed commands. Entries that were deleted while
+/// pending are reported as nil by some Redis versions and are skipped.
+fn parse_autoclaim(reply: &redis::Value) -> Result<(String, Vec<QueuedCommand>), RedisError> {
+  let redis::Value::Bulk(items) = reply else {
+    return Err(RedisError::from((redis::ErrorKind::TypeError, "Expected an XAUTOCLAIM reply")));
+  };
+
+  let (Some(cursor), Some(entries)) = (items.first(), items.get(1)) else {
+    return Err(RedisError::from((redis::ErrorKind::TypeError, "Expected a cursor and entries")));
+  };
+
+  let cursor: String = redis::from_redis_value(cursor)?;
+  let entries: Vec<Option<(String, HashMap<String, String>)>> = redis::from_redis_value(entries)?;
+
+  let commands = entries.into_iter().flatten().map(|(id, mut fields)| QueuedCommand {
+    message: fields.remove(CacheHandler::MESSAGE_FIELD).unwrap_or_default(),
+    defer: fields.get(CacheHandler::DEFER_FIELD).is_some_and(|defer| defer == "1"),
+    id,
+  }).collect();
+
+  Ok((cursor, commands))
+}
+
+/// A per-user lock that lets only one command at a time refresh a user's tokens. Fitbit invalidates a refresh
+/// token once it is used, so two concurrent refreshes would leave the loser holding a dead token.
+pub trait RefreshLock {
+  /// Tries to take the lock for `user_id`, identifying the holder by `token`. Returns false if it is already held.
+  fn acquire_refresh_lock(&self, user_id: &str, token: &str) -> impl Future<Output = Result<bool, FitbitError>> + Send;
+
+  /// Releases the lock for `user_id`, but only if it is still held by `token`.
+  fn release_refresh_lock(&self, user_id: &str, token: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Whether anyone holds the lock for `user_id`.
+  fn refresh_lock_held(&self, user_id: &str) -> impl Future<Output = Result<bool, FitbitError>> + Send;
+}
+
+impl RefreshLock for CacheHandler {
+  async fn acquire_refresh_lock(&self, user_id: &str, token: &str) -> Result<bool, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    let acquired: Option<String> = redis::cmd("SET")
+      .arg(self.refresh_lock_key(user_id))
+      .arg(token)
+      .arg("NX")
+      .arg("PX")
+      .arg(CacheHandler::REFRESH_LOCK_TTL.as_millis() as u64)
+      .query_async(&mut *conn).await?;
+
+    Ok(acquired.is_some())
+  }
+
+  async fn release_refresh_lock(&self, user_id: &str, token: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    // Only delete the lock if it is still ours; it may have expired and been taken by someone else.
+    let script = redis::Script::new(r"
+      if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('DEL', KEYS[1])
+      end
+      return 0
+    ");
+
+    let _: i64 = script.key(self.refresh_lock_key(user_id)).arg(token).invoke_async(&mut *conn).await?;
+
+    Ok(())
+  }
+
+  async fn refresh_lock_held(&self, user_id: &str) -> Result<bool, FitbitError> {
+    let mut conn = self.pool.get().await?;
+
+    Ok(conn.exists(self.refresh_lock_key(user_id)).await?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn handler(key_prefix: &str) -> CacheHandler {
+    let manager = RedisConnectionManager::new("redis://127.0.0.1/").unwrap();
+
+    CacheHandler {
+      pool: Pool::builder().build_unchecked(manager),
+      request_queue: CacheHandler::DEFAULT_REQUEST_QUEUE.to_string(),
+      dead_letter_queue: CacheHandler::DEFAULT_DEAD_LETTER_QUEUE.to_string(),
+      dead_letter_limit: CacheHandler::DEFAULT_DEAD_LETTER_LIMIT,
+      reply_prefix: CacheHandler::DEFAULT_REPLY_PREFIX.to_string(),
+      key_prefix: key_prefix.to_string(),
+      reply_mode: ReplyMode::Both,
+      reply_ttl: CacheHandler::DEFAULT_REPLY_TTL_SECONDS,
+      cache_ttl: CacheTtl {
+        recent: CacheHandler::DEFAULT_RECENT_TTL_SECONDS,
+        historical: CacheHandler::DEFAULT_HISTORICAL_TTL_SECONDS,
+      },
+      profile_ttl: CacheHandler::DEFAULT_PROFILE_TTL_SECONDS,
+      goals_ttl: CacheHandler::DEFAULT_GOALS_TTL_SECONDS,
+      devices_ttl: CacheHandler::DEFAULT_DEVICES_TTL_SECONDS,
+      deferred_limit: CacheHandler::DEFAULT_DEFERRED_LIMIT,
+      consumer_group: CacheHandler::DEFAULT_CONSUMER_GROUP.to_string(),
+      consumer_name: "test".to_string(),
+    }
+  }
+
+  #[tokio::test]
+  async fn keys_are_prefixed() {
+    let cache = handler(CacheHandler::REDIS_PREFIX);
+
+    assert_eq!(cache.key("fitbit_steps:user"), "fitbit:fitbit_steps:user");
+    assert_eq!(cache.key("fitbit_user_queries:user"), "fitbit:fitbit_user_queries:user");
+    assert_eq!(cache.ratelimit_reset_key("user"), "fitbit:fitbit_ratelimit_reset:user");
+    assert_eq!(cache.key("replies:01H0000000000000000000000"), "fitbit:replies:01H0000000000000000000000");
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn consume_survives_errors() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+
+    let consumer = tokio::spawn(CacheHandler::consume(tx, move || {
+      let call = counter.fetch_add(1, Ordering::SeqCst);
+
+      async move {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        match call {
+          0 | 1 => Err(FitbitError::CacheError("connection refused".to_string())),
+          _ => Ok(vec![format!("message {call}")]),
+        }
+      }
+    }));
+
+    assert_eq!(rx.recv().await.unwrap(), "message 2");
+
+    // Dropping the receiver must not stop the consumer either.
+    drop(rx);
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+    assert!(calls.load(Ordering::SeqCst) > 3);
+    assert!(!consumer.is_finished());
+
+    consumer.abort();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn sharded_commands_are_serialized_per_user() {
+    use std::sync::{Arc, Mutex};
+
+    // With 16 shards, "a" and "b" land on different workers.
+    assert_ne!(shard_of("a", 16), shard_of("b", 16));
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let log = events.clone();
+    let started = tokio::time::Instant::now();
+
+    let commands = tokio_stream::iter(vec![("a", 1), ("a", 2), ("b", 1)]);
+
+    process_sharded(commands, 16, |(user, _)| user.to_string(), move |(user, n)| {
+      let log = log.clone();
+
+      async move {
+        log.lock().unwrap().push(format!("start {user}{n}"));
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        log.lock().unwrap().push(format!("end {user}{n}"));
+      }
+    }).await;
+
+    let events = events.lock().unwrap().clone();
+    let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+
+    // The second command for "a" waits for the first, while "b" runs alongside it.
+    assert!(position("end a1") < position("start a2"));
+    assert!(position("start b1") < position("end a1"));
+    assert_eq!(started.elapsed(), std::time::Duration::from_millis(20));
+  }
+
+  #[test]
+  fn commands_are_sharded_by_user() {
+    let command = |id: &str, message: String| QueuedCommand { id: id.to_string(), message, defer: false };
+    let ttl = Utc::now().timestamp() + 60;
+
+    assert_eq!(command("1690000000000-0", format!("{}:refresh:user:{}", ulid::Ulid::new(), ttl)).shard_key(), "user");
+    assert_eq!(command("1690000000000-1", format!("{}:get_steps:user,1672531200,1672617600:{}", ulid::Ulid::new(), ttl)).shard_key(), "user");
+    assert_eq!(command("1690000000000-2", String::new()).shard_key(), "1690000000000-2");
+  }
+
+  #[tokio::test]
+  async fn ratelimit_reset_keys_are_per_user() {
+    let cache = handler(CacheHandler::REDIS_PREFIX);
+
+    assert_ne!(cache.ratelimit_reset_key("light"), cache.ratelimit_reset_key("heavy"));
+  }
+
+  #[tokio::test]
+  async fn empty_prefix_keeps_legacy_keys() {
+    let cache = handler("");
+
+    assert_eq!(cache.key("fitbit_steps:user"), "fitbit_steps:user");
+    assert_eq!(cache.ratelimit_reset_key("user"), "fitbit_ratelimit_reset:user");
+  }
+
+  #[tokio::test]
+  async fn user_keys_belong_to_one_user() {
+    let cache = handler("fitbit:");
+
+    let keys = cache.user_keys("user");
+
+    assert!(keys.contains(&"fitbit:fitbit_steps:user".to_string()));
+    assert!(keys.contains(&"fitbit:fitbit_user_queries:user".to_string()));
+    assert!(keys.contains(&cache.ratelimit_reset_key("user")));
+    assert!(keys.contains(&cache.ratelimit_reported_key("user")));
+    assert!(keys.contains(&cache.profile_key("user")));
+    assert!(keys.contains(&cache.goals_key("user")));
+    assert!(keys.contains(&cache.devices_key("user")));
+    assert!(keys.iter().all(|key| key.ends_with(":user")));
+    assert!(cache.user_keys("other").iter().all(|key| !keys.contains(key)));
+  }
+
+  #[tokio::test]
+  async fn series_keys_are_distinct() {
+    let cache = handler("fitbit:");
+
+    let keys: Vec<String> = Series::ALL.iter().map(|series| cache.series_key(*series, "user")).collect();
+
+    assert_eq!(keys, vec![
+      "fitbit:fitbit_steps:user",
+      "fitbit:fitbit_heart_rate:user",
+      "fitbit:fitbit_calories:user",
+      "fitbit:fitbit_floors:user",
+      "fitbit:fitbit_distance:user",
+      "fitbit:fitbit_active_minutes_fairly:user",
+      "fitbit:fitbit_active_minutes_very:user",
+      "fitbit:fitbit_spo2:user",
+      "fitbit:fitbit_heart_rate_zones:user",
+    ]);
+    assert_eq!(cache.steps_key("user"), "fitbit:fitbit_steps:user");
+    assert_eq!(handler("").steps_key("user"), "fitbit_steps:user");
+    assert!(keys.iter().all(|key| cache.user_keys("user").contains(key)));
+  }
+
+  #[test]
+  fn reply_mode_parses() {
+    assert_eq!(ReplyMode::parse("store"), Some(ReplyMode::Store));
+    assert_eq!(ReplyMode::parse("publish"), Some(ReplyMode::Publish));
+    assert_eq!(ReplyMode::parse("both"), Some(ReplyMode::Both));
+    assert_eq!(ReplyMode::parse("PUBLISH"), None);
+  }
+
+  #[tokio::test]
+  async fn deferred_commands_are_queued_per_user() {
+    let cache = handler(CacheHandler::REDIS_PREFIX);
+
+    assert_eq!(cache.deferred_key("user"), "fitbit:fitbit_deferred:user");
+    assert_ne!(cache.deferred_key("user"), cache.deferred_key("other"));
+    assert_eq!(cache.deferred_users_key(), "fitbit:fitbit_deferred_users");
+  }
+
+  #[tokio::test]
+  async fn replies_are_published_on_their_key() {
+    let cache = handler(CacheHandler::REDIS_PREFIX);
+
+    assert_eq!(cache.reply_key("01H2XK"), "fitbit:replies:01H2XK");
+  }
+
+  #[test]
+  fn cache_entries_roundtrip_as_iso_strings() {
+    let entry = CacheEntry {
+      value: 1200,
+      date: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(),
+      expires_at: NaiveDate::from_ymd_opt(2023, 1, 3).unwrap().and_hms_opt(12, 30, 0).unwrap(),
+    };
+
+    assert_eq!(entry.to_string(), "1200,2023-01-01,2023-01-03T12:30:00");
+    assert_eq!(entry.to_string().parse::<CacheEntry>().unwrap(), entry);
+  }
+
+  #[test]
+  fn decimal_cache_entries_roundtrip() {
+    let entry = "5.2345,2023-01-01,2023-01-03T12:30:00".parse::<CacheEntry<f64>>().unwrap();
+
+    assert_eq!(entry.value, 5.2345);
+    assert_eq!(entry.to_string(), "5.2345,2023-01-01,2023-01-03T12:30:00");
+  }
+
+  #[test]
+  fn spo2_cache_entries_roundtrip() {
+    let entry = CacheEntry {
+      value: Spo2Value(Spo2Summary { avg: 97.5, min: 94.0, max: 100.0 }),
+      date: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(),
+      expires_at: NaiveDate::from_ymd_opt(2023, 1, 3).unwrap().and_hms_opt(12, 30, 0).unwrap(),
+    };
+
+    assert_eq!(entry.to_string(), "97.5/94/100,2023-01-01,2023-01-03T12:30:00");
+    assert_eq!(entry.to_string().parse::<CacheEntry<Spo2Value>>().unwrap(), entry);
+    assert!("97.5/94,2023-01-01,2023-01-03T12:30:00".parse::<CacheEntry<Spo2Value>>().is_err());
+  }
+
+  #[test]
+  fn heart_rate_zone_cache_entries_roundtrip() {
+    let entry = CacheEntry {
+      value: HeartRateZonesValue(HeartRateZones { out_of_range: 1338, fat_burn: 86, cardio: 12, peak: 0 }),
+      date: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(),
+      expires_at: NaiveDate::from_ymd_opt(2023, 1, 3).unwrap().and_hms_opt(12, 30, 0).unwrap(),
+    };
+
+    assert_eq!(entry.to_string(), "1338/86/12/0,2023-01-01,2023-01-03T12:30:00");
+    assert_eq!(entry.to_string().parse::<CacheEntry<HeartRateZonesValue>>().unwrap(), entry);
+    assert!("1338/86/12,2023-01-01,2023-01-03T12:30:00".parse::<CacheEntry<HeartRateZonesValue>>().is_err());
+  }
+
+  #[test]
+  fn legacy_cache_entries_are_read() {
+    let entry = "1200:1672531200:1672749000".parse::<CacheEntry>().unwrap();
+
+    assert_eq!(entry.value, 1200);
+    assert_eq!(entry.date, NaiveDate::from_ymd_opt(2023, 1, 1).unwrap());
+    assert_eq!(entry.expires_at, NaiveDate::from_ymd_opt(2023, 1, 3).unwrap().and_hms_opt(12, 30, 0).unwrap());
+  }
+
+  #[test]
+  fn corrupted_cache_entries_are_parsing_errors() {
+    for entry in ["", "1200", "1200:1672531200", "abc:1672531200:1672749000", "1200,2023-13-01,2023-01-03T12:30:00", "-5,2023-01-01,2023-01-03T12:30:00", "1200,2023-01-01"] {
+      assert!(matches!(entry.parse::<CacheEntry>(), Err(FitbitError::ParsingError(_))), "{entry}");
+    }
+  }
+
+  #[tokio::test]
+  async fn bulk_steps_are_added_in_one_pipeline() {
+    let cache = handler(CacheHandler::REDIS_PREFIX);
+    let now = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap().and_hms_opt(12, 0, 0).unwrap();
+
+    let steps: HashMap<NaiveDate, u32> = (1..=10)
+      .map(|day| (NaiveDate::from_ymd_opt(2023, 6, day).unwrap(), day * 100))
+      .collect();
+
+    let packed = cache.daily_values_pipeline("fitbit:fitbit_steps:user", &steps, now).get_packed_pipeline();
+    let packed = String::from_utf8_lossy(&packed);
+
+    assert_eq!(packed.matches("ZADD").count(), 1);
+    assert_eq!(packed.matches("ZREMRANGEBYSCORE").count(), 10);
+    assert_eq!(packed.matches("EXPIRE").count(), 1);
+    assert!(packed.contains("100,2023-06-01,2023-07-10T12:00:00"));
+    assert!(packed.contains("1000,2023-06-10,2023-06-12T12:00:00"));
+  }
+
+  #[tokio::test]
+  async fn refetched_days_replace_cached_values() {
+    let cache = handler(CacheHandler::REDIS_PREFIX);
+    let now = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let date = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+
+    let packed = cache.daily_values_pipeline("fitbit:fitbit_steps:user", &HashMap::from([(date, 250)]), now).get_packed_pipeline();
+    let packed = String::from_utf8_lossy(&packed);
+
+    // The entry for the day is removed by its score, whatever value it held, before the new one is added.
+    let score = date.and_hms_opt(0, 0, 0).unwrap().timestamp().to_string();
+    let removed = packed.find("ZREMRANGEBYSCORE").unwrap();
+
+    assert_eq!(packed[removed..].matches(score.as_str()).count(), 3);
+    assert!(removed < packed.find("ZADD").unwrap());
+    assert!(packed.contains("250,2023-06-01,"));
+  }
+
+  #[test]
+  fn expiry_saturates() {
+    let now = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap().and_hms_opt(12, 0, 0).unwrap();
+
+    assert_eq!(expires_at(now, 60), now + Duration::seconds(60));
+    assert_eq!(expires_at(now, u64::MAX), NaiveDateTime::MAX);
+  }
+
+  #[test]
+  fn cache_ttl_is_longer_for_historical_days() {
+    let ttl = CacheTtl { recent: 60, historical: 3600 };
+    let today = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap();
+    let date = |day| NaiveDate::from_ymd_opt(2023, 6, day).unwrap();
+
+    assert_eq!(ttl.for_date(date(10), today), 60);
+    assert_eq!(ttl.for_date(date(9), today), 60);
+    assert_eq!(ttl.for_date(date(8), today), 60);
+    assert_eq!(ttl.for_date(date(7), today), 3600);
+    assert_eq!(ttl.for_date(NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(), today), 3600);
+    assert_eq!(ttl.longest(), 3600);
+  }
+
+  #[tokio::test]
+  async fn stored_replies_use_the_reply_ttl() {
+    let cache = CacheHandler { reply_ttl: 300, ..handler(CacheHandler::REDIS_PREFIX) };
+
+    let packed = cache.reply_pipeline("01H2XK", b"0:refreshed").get_packed_pipeline();
+    let set_ex = redis::cmd("SETEX").arg("fitbit:replies:01H2XK").arg(300).arg("0:refreshed").get_packed_command();
+
+    assert!(packed.windows(set_ex.len()).any(|window| window == set_ex));
+
+    let cache = CacheHandler { reply_mode: ReplyMode::Publish, ..cache };
+
+    let packed = cache.reply_pipeline("01H2XK", b"0:refreshed").get_packed_pipeline();
+
+    assert!(!packed.windows(set_ex.len()).any(|window| window == set_ex));
+  }
+
+  #[tokio::test]
+  async fn sent_replies_are_recorded_for_redelivery() {
+    let cache = CacheHandler { reply_mode: ReplyMode::Publish, ..handler(CacheHandler::REDIS_PREFIX) };
+
+    let packed = cache.reply_pipeline("01H2XK", b"0:refreshed").get_packed_pipeline();
+    let set_ex = redis::cmd("SETEX").arg("fitbit:processed:01H2XK").arg(CacheHandler::PROCESSED_TTL.as_secs()).arg("0:refreshed").get_packed_command();
+
+    assert!(packed.windows(set_ex.len()).any(|window| window == set_ex));
+  }
+
+  #[tokio::test]
+  async fn dead_letters_are_capped() {
+    let cache = CacheHandler { dead_letter_limit: 50, ..handler(CacheHandler::REDIS_PREFIX) };
+
+    let packed = cache.dead_letter_pipeline("{}").get_packed_pipeline();
+    let lpush = redis::cmd("LPUSH").arg("dead_letter").arg("{}").get_packed_command();
+    let ltrim = redis::cmd("LTRIM").arg("dead_letter").arg(0).arg(49).get_packed_command();
+
+    assert!(packed.windows(lpush.len()).any(|window| window == lpush));
+    assert!(packed.windows(ltrim.len()).any(|window| window == ltrim));
+  }
+
+  #[tokio::test]
+  async fn dead_letters_are_never_left_uncapped() {
+    let cache = CacheHandler { dead_letter_limit: 0, ..handler(CacheHandler::REDIS_PREFIX) };
+
+    let packed = cache.dead_letter_pipeline("{}").get_packed_pipeline();
+    let uncapped = redis::cmd("LTRIM").arg("dead_letter").arg(0).arg(-1).get_packed_command();
+
+    assert!(!packed.windows(uncapped.len()).any(|window| window == uncapped));
+  }
+
+  fn bulk(values: Vec<redis::Value>) -> redis::Value {
+    redis::Value::Bulk(values)
+  }
+
+  fn data(value: &str) -> redis::Value {
+    redis::Value::Data(value.as_bytes().to_vec())
+  }
+
+  #[test]
+  fn autoclaim_reply_is_parsed() {
+    let reply = bulk(vec![
+      data("1690000000000-5"),
+      bulk(vec![
+        bulk(vec![data("1690000000000-1"), bulk(vec![data("message"), data("01H2XK:refresh:user:1690000000"), data("defer"), data("1")])]),
+        redis::Value::Nil,
+        bulk(vec![data("1690000000000-3"), bulk(vec![data("other"), data("field")])]),
+      ]),
+      bulk(vec![]),
+    ]);
+
+    let (cursor, commands) = parse_autoclaim(&reply).unwrap();
+
+    assert_eq!(cursor, "1690000000000-5");
+    assert_eq!(commands, vec![
+      QueuedCommand { id: "1690000000000-1".to_string(), message: "01H2XK:refresh:user:1690000000".to_string(), defer: true },
+      QueuedCommand { id: "1690000000000-3".to_string(), message: String::new(), defer: false },
+    ]);
+
+    assert!(parse_autoclaim(&redis::Value::Nil).is_err());
+  }
+
+  #[test]
+  fn stream_entries_become_commands() {
+    let entry = StreamId {
+      id: "1690000000000-0".to_string(),
+      map: HashMap::from([("message".to_string(), data("01H2XK:revoke:user:1690000000"))]),
+    };
+
+    assert_eq!(queued_commands(vec![entry]), vec![
+      QueuedCommand { id: "1690000000000-0".to_string(), message: "01H2XK:revoke:user:1690000000".to_string(), defer: false },
+    ]);
+  }
+}
@@ -0,0 +1,163 @@
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::{info, error, info_span, Instrument};
+use crate::{metrics, models, utils};
+use crate::cache::{Cache, QueuedCommand, ReplyClaim};
+use crate::database::Database;
+use crate::errors::FitbitError;
+use crate::fitbit::{Fitbit, FitbitApi};
+
+/// How long a deferred command waits when the user's rate limit reset time is unknown.
+const DEFAULT_DEFER_SECS: u64 = 60;
+/// The longest a deferred command waits. Fitbit's rate limit window is an hour.
+const MAX_DEFER_SECS: u64 = 60 * 60;
+
+/// Decodes, executes and answers a command from the request stream, acknowledging it once it has been answered.
+pub async fn handle_message<A: FitbitApi, C: Cache, D: Database>(message: QueuedCommand, cache_client: C, fitbit_client: Fitbit<A, C, D>, semaphore: Arc<Semaphore>, msgpack_replies: bool, command_timeout: std::time::Duration) {
+  info!("Received message: {:?}", message);
+
+  let id = message.id;
+  let defer = message.defer;
+  let raw_message = message.message.clone();
+  let protocol = if msgpack_replies { models::Protocol::MsgPack } else { models::Protocol::detect(&message.message) };
+
+  // A message that cannot be decoded will never succeed, so it is acknowledged rather than redelivered.
+  let Some(message) = utils::decode_timed_message(message.message) else {
+    info!("Error decoding message");
+    ack(&cache_client, &id).await;
+    return;
+  };
+
+  info!("Message parsed: {:?}", message);
+
+  let coordination_id = message.0;
+  let models::TimedCommand { command, deadline } = match message.1 {
+    Ok(command) => command,
+    Err(e) => {
+      metrics::ERRORS.with_label_values(&[e.name()]).inc();
+      fitbit_client.dead_letter(coordination_id, &raw_message, &e).await;
+      send_reply(&fitbit_client, &cache_client, &id, coordination_id, models::Response::Error(e), protocol).await;
+      return;
+    },
+  };
+
+  match fitbit_client.claim_reply(coordination_id).await {
+    ReplyClaim::New => {},
+    ReplyClaim::Sent(reply) => {
+      info!("Command {} was already answered, sending its reply again", coordination_id);
+
+      match fitbit_client.resend_reply(coordination_id, reply).await {
+        Ok(()) => ack(&cache_client, &id).await,
+        Err(e) => error!("Failed to resend reply to {}, leaving command {} pending: {}", coordination_id, id, e),
+      }
+
+      return;
+    },
+    // The first delivery is still being executed, or its consumer stopped before answering. Once the deadline
+    // passes the caller has stopped waiting, so the command is dropped like one that expired.
+    ReplyClaim::InFlight => {
+      if deadline <= chrono::Utc::now().naive_utc() {
+        info!("Command {} expired while another delivery was in flight, dropping it", coordination_id);
+        ack(&cache_client, &id).await;
+      } else {
+        info!("Command {} is already being executed, leaving it pending", coordination_id);
+      }
+
+      return;
+    },
+  }
+
+  let command_name = command.name();
+  let user_id = command.user_id().map(str::to_string);
+
+  let span = info_span!("command", coordination_id = %coordination_id, user_id = user_id.as_deref());
+
+  // Everything logged from here on, including inside the cache and the Fitbit API calls, is tagged with the command.
+  async move {
+    metrics::COMMANDS_RECEIVED.with_label_values(&[command_name]).inc();
+
+    // The permit is held until the task ends, so it is released whether or not the command succeeds.
+    let Ok(_permit) = semaphore.acquire().await else {
+      error!("Command semaphore closed");
+      return;
+    };
+
+    let started = std::time::Instant::now();
+    let timer = metrics::COMMAND_LATENCY.with_label_values(&[command_name]).start_timer();
+    let reply = fitbit_client.execute_command_within(command, deadline, command_timeout).await;
+    timer.observe_duration();
+
+    fitbit_client.audit_command(Some(coordination_id.to_string()), user_id.clone(), command_name, models::CommandOutcome::of(&reply), started.elapsed());
+
+    if let models::Response::Error(e) = &reply {
+      metrics::ERRORS.with_label_values(&[e.name()]).inc();
+
+      // The caller has stopped waiting, so the command is dropped like one that expired before it was decoded.
+      if matches!(e, FitbitError::CommandExpired) {
+        info!("Command expired before it was executed, dropping it");
+        ack(&cache_client, &id).await;
+        return;
+      }
+
+      // Only commands for a user wait on that user's rate limit.
+      let deferrable = defer && matches!(e, FitbitError::RateLimitExceeded(..));
+
+      if let Some(user_id) = user_id.as_deref().filter(|_| deferrable) {
+        if defer_command(&cache_client, user_id, &raw_message, e).await {
+          // The deferred command comes back under the same coordination ID, and must be executed then.
+          fitbit_client.release_reply(coordination_id).await;
+          ack(&cache_client, &id).await;
+          return;
+        }
+      }
+
+      fitbit_client.dead_letter(coordination_id, &raw_message, e).await;
+    }
+
+    info!("Sending reply: {:?}", reply);
+  
+    send_reply(&fitbit_client, &cache_client, &id, coordination_id, reply, protocol).await;
+  }.instrument(span).await;
+}
+
+/// Sends a reply and acknowledges the command it answers. A command whose reply could not be sent stays pending, so
+/// it is claimed and executed again.
+async fn send_reply<A: FitbitApi, C: Cache, D: Database>(fitbit_client: &Fitbit<A, C, D>, cache_client: &C, id: &str, coordination_id: ulid::Ulid, reply: models::Response, protocol: models::Protocol) {
+  match fitbit_client.reply(coordination_id, reply, protocol).await {
+    Ok(()) => ack(cache_client, id).await,
+    Err(e) => {
+      error!("Failed to send reply to {}, leaving command {} pending: {}", coordination_id, id, e);
+      fitbit_client.release_reply(coordination_id).await;
+    },
+  }
+}
+
+/// Defers a rate-limited command until the user's rate limit resets, and returns whether it was deferred. A command
+/// that cannot be deferred is answered with its error as usual.
+async fn defer_command<C: Cache>(cache_client: &C, user_id: &str, message: &str, error: &FitbitError) -> bool {
+  let retry_after = error.retry_after_seconds().unwrap_or(DEFAULT_DEFER_SECS).min(MAX_DEFER_SECS);
+  // Capped at an hour, so this always fits.
+  let retry_after = i64::try_from(retry_after).unwrap_or(i64::MAX);
+  let due = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(retry_after);
+
+  match cache_client.defer_command(user_id, message, due).await {
+    Ok(true) => {
+      info!("Deferred command for {} until {}", user_id, due);
+      true
+    },
+    Ok(false) => {
+      info!("Too many deferred commands for {}, rejecting", user_id);
+      false
+    },
+    Err(e) => {
+      error!("Failed to defer command for {}: {}", user_id, e);
+      false
+    },
+  }
+}
+
+async fn ack<C: Cache>(cache_client: &C, id: &str) {
+  if let Err(e) = cache_client.ack(id).await {
+    error!("Failed to acknowledge command {}: {}", id, e);
+  }
+}
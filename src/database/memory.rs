@@ -0,0 +1,170 @@
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::errors::FitbitError;
+use crate::models::{CommandOutcome, DatabaseUser, TokenExpiry, UserSummary, UserTimezone};
+use super::Database;
+
+/// A `Database` kept in process memory, for tests. Clones share the same storage. Commands are not audited.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDatabase {
+  state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  /// Ordered by internal user ID, as `list_users` pages them.
+  users: BTreeMap<String, StoredUser>,
+  steps: HashMap<String, BTreeMap<NaiveDate, u32>>,
+  step_reads: usize,
+}
+
+#[derive(Debug, Clone)]
+struct StoredUser {
+  user: DatabaseUser,
+  token_rejected: bool,
+  timezone: Option<UserTimezone>,
+}
+
+impl MemoryDatabase {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// How many times steps have been read with `get_steps`.
+  pub fn step_reads(&self) -> usize {
+    self.state().step_reads
+  }
+
+  /// A panic while the lock was held cannot leave the maps half-updated, so a poisoned lock is still usable.
+  fn state(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+impl Database for MemoryDatabase {
+  async fn user_exists(&self, user_id: &str) -> Result<bool, FitbitError> {
+    Ok(self.state().users.contains_key(user_id))
+  }
+
+  async fn ping(&self) -> Result<(), FitbitError> {
+    Ok(())
+  }
+
+  async fn get_user(&self, user_id: &str) -> Result<Option<DatabaseUser>, FitbitError> {
+    Ok(self.state().users.get(user_id).map(|stored| stored.user.clone()))
+  }
+
+  async fn upsert_user(&self, user_id: &str, fitbit_user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> Result<bool, FitbitError> {
+    let user = DatabaseUser {
+      id: user_id.to_string(),
+      fitbit_user_id: fitbit_user_id.to_string(),
+      fitbit_access_token: access_token.to_string(),
+      fitbit_refresh_token: refresh_token.to_string(),
+      fitbit_token_expires_at: expires_at,
+    };
+
+    let mut state = self.state();
+    let timezone = state.users.get(user_id).and_then(|stored| stored.timezone.clone());
+    let replaced = state.users.insert(user_id.to_string(), StoredUser { user, token_rejected: false, timezone });
+
+    Ok(replaced.is_none())
+  }
+
+  async fn user_token_expired(&self, user_id: &str) -> Result<Option<bool>, FitbitError> {
+    let expiry = self.token_expiry(user_id).await?;
+
+    Ok(expiry.map(|expiry| expiry.expired()))
+  }
+
+  async fn token_expiry(&self, user_id: &str) -> Result<Option<TokenExpiry>, FitbitError> {
+    let now = Utc::now().naive_utc();
+
+    Ok(self.state().users.get(user_id).map(|stored| {
+      let expires_at = stored.user.fitbit_token_expires_at;
+
+      TokenExpiry { expires_at, expires_in: (expires_at - now).num_seconds() }
+    }))
+  }
+
+  async fn update_user_token(&self, user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> Result<(), FitbitError> {
+    if let Some(stored) = self.state().users.get_mut(user_id) {
+      stored.user.fitbit_access_token = access_token.to_string();
+      stored.user.fitbit_refresh_token = refresh_token.to_string();
+      stored.user.fitbit_token_expires_at = expires_at;
+      stored.token_rejected = false;
+    }
+
+    Ok(())
+  }
+
+  async fn log_command(&self, _coordination_id: Option<&str>, _user_id: Option<&str>, _command_kind: &str, _outcome: CommandOutcome, _latency_ms: i64) -> Result<(), FitbitError> {
+    Ok(())
+  }
+
+  async fn mark_token_rejected(&self, user_id: &str) -> Result<(), FitbitError> {
+    if let Some(stored) = self.state().users.get_mut(user_id) {
+      stored.token_rejected = true;
+    }
+
+    Ok(())
+  }
+
+  async fn get_timezone(&self, user_id: &str) -> Result<Option<UserTimezone>, FitbitError> {
+    Ok(self.state().users.get(user_id).and_then(|stored| stored.timezone.clone()))
+  }
+
+  async fn set_timezone(&self, user_id: &str, timezone: &UserTimezone) -> Result<(), FitbitError> {
+    if let Some(stored) = self.state().users.get_mut(user_id) {
+      stored.timezone = Some(timezone.clone());
+    }
+
+    Ok(())
+  }
+
+  async fn users_expiring_before(&self, before: NaiveDateTime) -> Result<Vec<String>, FitbitError> {
+    let users = self.state().users.values()
+      .filter(|stored| stored.user.fitbit_token_expires_at < before && !stored.token_rejected)
+      .map(|stored| stored.user.id.clone())
+      .collect();
+
+    Ok(users)
+  }
+
+  async fn list_users(&self, limit: u32, offset: u32) -> Result<Vec<UserSummary>, FitbitError> {
+    let users = self.state().users.values()
+      .skip(offset as usize)
+      .take(limit as usize)
+      .map(|stored| UserSummary { id: stored.user.id.clone(), fitbit_user_id: stored.user.fitbit_user_id.clone(), token_expires_at: stored.user.fitbit_token_expires_at })
+      .collect();
+
+    Ok(users)
+  }
+
+  async fn delete_user(&self, user_id: &str) -> Result<(), FitbitError> {
+    let mut state = self.state();
+
+    state.users.remove(user_id);
+    state.steps.remove(user_id);
+
+    Ok(())
+  }
+
+  async fn upsert_steps(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    self.state().steps.entry(user_id.to_string()).or_default().extend(steps);
+
+    Ok(())
+  }
+
+  async fn get_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let mut state = self.state();
+
+    state.step_reads += 1;
+
+    let steps = state.steps.get(user_id)
+      .map(|steps| steps.range(start..=end).map(|(date, steps)| (*date, *steps)).collect())
+      .unwrap_or_default();
+
+    Ok(steps)
+  }
+}
@@ -1,8 +1,57 @@
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::HashMap;
+use std::future::Future;
 use sqlx::{PgPool, postgres::PgPoolOptions };
 use std::env;
-use crate::{errors::FitbitError, models::DatabaseUser};
-use log::info;
+use std::time::Duration;
+use tracing::{info, error};
+use crate::{errors::FitbitError, models::{CommandOutcome, DatabaseUser, TokenExpiry, UserSummary, UserTimezone}};
+
+#[cfg(test)]
+mod memory;
+
+#[cfg(test)]
+pub use memory::MemoryDatabase;
+
+/// Sizing and timeouts of the Postgres pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolConfig {
+  pub max_connections: u32,
+  pub min_connections: u32,
+  /// How long a query waits for a free connection before failing with `PostgresPoolTimeout`.
+  pub acquire_timeout: Duration,
+}
+
+impl PoolConfig {
+  const DEFAULT_MAX_CONNECTIONS: u32 = 5;
+  const DEFAULT_MIN_CONNECTIONS: u32 = 0;
+  const DEFAULT_ACQUIRE_TIMEOUT_SECONDS: u64 = 30;
+
+  /// Reads the pool configuration from `DATABASE_MAX_CONNECTIONS` (5 by default), `DATABASE_MIN_CONNECTIONS` (0 by
+  /// default) and `DATABASE_ACQUIRE_TIMEOUT_SECONDS` (30 by default). Invalid values are logged and replaced with
+  /// the default.
+  pub fn from_env() -> Self {
+    let max_connections = env_number("DATABASE_MAX_CONNECTIONS", Self::DEFAULT_MAX_CONNECTIONS).max(1);
+    let min_connections = env_number("DATABASE_MIN_CONNECTIONS", Self::DEFAULT_MIN_CONNECTIONS);
+
+    if min_connections > max_connections {
+      error!("DATABASE_MIN_CONNECTIONS ({}) exceeds DATABASE_MAX_CONNECTIONS ({}), using {}", min_connections, max_connections, max_connections);
+    }
+
+    Self {
+      max_connections,
+      min_connections: min_connections.min(max_connections),
+      acquire_timeout: Duration::from_secs(env_number("DATABASE_ACQUIRE_TIMEOUT_SECONDS", Self::DEFAULT_ACQUIRE_TIMEOUT_SECONDS)),
+    }
+  }
+
+  pub fn options(&self) -> PgPoolOptions {
+    PgPoolOptions::new()
+      .max_connections(self.max_connections)
+      .min_connections(self.min_connections)
+      .acquire_timeout(self.acquire_timeout)
+  }
+}
 
 #[derive(Debug, Clone)]
 pub struct DatabaseHandler {
@@ -16,43 +65,49 @@ impl DatabaseHandler {
     }
   }
 
-  pub async fn build_pool() -> PgPool {
-    let database_url = env::var("DATABASE_URL")
-      .expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-      .max_connections(5)
-      .connect(&database_url)
-      .await
-      .expect("Failed to connect to Postgres");
+  /// Builds a pool for `DATABASE_URL`, configured by `PoolConfig::from_env`. Connections are opened lazily, so a
+  /// reachable database is not checked until the pool is first used.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(pool)` - If the pool was built.
+  /// * `Err(message)` - If `DATABASE_URL` is not set or is not a valid Postgres URL.
+  pub fn build_pool() -> Result<PgPool, String> {
+    let database_url = env::var("DATABASE_URL").map_err(|_| "DATABASE_URL is not set".to_string())?;
+
+    let config = PoolConfig::from_env();
+
+    info!("Connecting to Postgres with at most {} connections, waiting up to {:?} for one", config.max_connections, config.acquire_timeout);
 
-    pool
+    config.options()
+      .connect_lazy(&database_url)
+      .map_err(|e| format!("DATABASE_URL is not a valid Postgres URL: {}", e))
   }
+}
 
+/// The engine's permanent storage: connected users and their tokens, the command audit log, and historical step
+/// counts. `DatabaseHandler` keeps it in Postgres; `MemoryDatabase` keeps it in process for tests.
+pub trait Database: Clone + Send + Sync + 'static {
   /// Checks if a user exists in the database.
   /// 
   /// # Arguments
   /// 
-  /// * `user_id` - The user's Fitbit user ID.
+  /// * `user_id` - The user's internal user ID.
   /// 
   /// # Returns
   /// 
   /// * `Ok(true)` - If the user exists.
   /// * `Ok(false)` - If the user does not exist.
   /// * `Err(e)` - If the query failed.
-  // pub async fn user_exists(&self, user_id: &str) -> Result<bool, FitbitError> {
-  //   let mut conn = self.pool.acquire().await?;
-  //   let exists = sqlx::query!("SELECT EXISTS(SELECT 1 FROM fitbit_data WHERE id = $1)", user_id)
-  //     .fetch_one(&mut conn)
-  //     .await?
-  //     .exists;
+  fn user_exists(&self, user_id: &str) -> impl Future<Output = Result<bool, FitbitError>> + Send;
 
-  //   let exists = match exists {
-  //     Some(exists) => exists,
-  //     None => panic!("Unexpected null value for user_exists"),
-  //   };
-
-  //   Ok(exists)
-  // }
+  /// Runs a trivial query, to check that the database is reachable.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the query succeeded.
+  /// * `Err(e)` - If no connection could be made or the query failed.
+  fn ping(&self) -> impl Future<Output = Result<(), FitbitError>> + Send;
 
   /// Gets a user's Fitbit data from the database.
   /// 
@@ -65,15 +120,26 @@ impl DatabaseHandler {
   /// * `Ok(Some(user))` - If the user exists.
   /// * `Ok(None)` - If the user does not exist.
   /// * `Err(e)` - If the query failed.
-  pub async fn get_user(&self, user_id: &str) -> Result<Option<DatabaseUser>, FitbitError> {
-    let mut conn = self.pool.acquire().await?;
-
-    let user = sqlx::query_as!(DatabaseUser, "SELECT * FROM fitbit_data WHERE id = $1", user_id)
-      .fetch_optional(&mut conn)
-      .await?;
+  fn get_user(&self, user_id: &str) -> impl Future<Output = Result<Option<DatabaseUser>, FitbitError>> + Send;
 
-    Ok(user)
-  }
+  /// Stores a user's Fitbit tokens, inserting the user or, if they are already registered, replacing their Fitbit
+  /// account and tokens. Registering twice, such as from a double-submitted OAuth callback, or reconnecting a different
+  /// Fitbit account therefore refreshes the stored tokens instead of failing.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `refresh_token` - The user's Fitbit refresh token.
+  /// * `expires_at` - The time at which the user's Fitbit access token expires.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(true)` - If the user was inserted.
+  /// * `Ok(false)` - If the user already existed and was updated.
+  /// * `Err(e)` - If the query failed.
+  fn upsert_user(&self, user_id: &str, fitbit_user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> impl Future<Output = Result<bool, FitbitError>> + Send;
 
   /// Checks the stored Fitbit token expiry time and returns whether or not it has expired.
   /// 
@@ -87,18 +153,22 @@ impl DatabaseHandler {
   /// * `Ok(Some(false))` - If the token has not expired.
   /// * `Ok(None)` - If the user does not exist.
   /// * `Err(e)` - If the query failed.
-  pub async fn user_token_expired(&self, user_id: &str) -> Result<Option<bool>, FitbitError> {
-    let mut conn = self.pool.acquire().await?;
-    let expired = sqlx::query!("SELECT id, (EXTRACT(EPOCH FROM(fitbit_token_expires_at - now()))::bigint) AS fitbit_token_expires_in FROM fitbit_data WHERE id = $1", user_id)
-      .fetch_one(&mut conn)
-      .await?;
-
-    let expired = expired.fitbit_token_expires_in.map(|expired| expired < 0);
+  fn user_token_expired(&self, user_id: &str) -> impl Future<Output = Result<Option<bool>, FitbitError>> + Send;
 
-    Ok(expired)
-  }
+  /// Reads when the user's Fitbit token expires, measured against the database's clock.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(expiry))` - If the user exists.
+  /// * `Ok(None)` - If the user does not exist.
+  /// * `Err(e)` - If the query failed.
+  fn token_expiry(&self, user_id: &str) -> impl Future<Output = Result<Option<TokenExpiry>, FitbitError>> + Send;
 
-  /// Updates a user's Fitbit token in the database.
+  /// Updates a user's Fitbit token in the database, clearing any earlier rejection.
   /// 
   /// # Arguments
   /// 
@@ -111,12 +181,433 @@ impl DatabaseHandler {
   /// 
   /// * `Ok(())` - If the update was successful.
   /// * `Err(e)` - If the query failed.
-  pub async fn update_user_token(&self, user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> Result<(), FitbitError> {
+  fn update_user_token(&self, user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Records a completed command in the `command_audit` table.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `coordination_id` - The coordination ID the command was sent with, if it came from the request queue.
+  /// * `user_id` - The user's internal user ID, if the command is for a user.
+  /// * `command_kind` - The name of the command, as in `Command::name`.
+  /// * `outcome` - How the command ended.
+  /// * `latency_ms` - How long the command took to execute, in milliseconds.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the row was written.
+  /// * `Err(e)` - If the query failed.
+  fn log_command(&self, coordination_id: Option<&str>, user_id: Option<&str>, command_kind: &str, outcome: CommandOutcome, latency_ms: i64) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Flags a user's token as rejected by Fitbit, so it is no longer refreshed ahead of expiry. The flag is cleared the
+  /// next time the token is updated.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the update was successful.
+  /// * `Err(e)` - If the query failed.
+  fn mark_token_rejected(&self, user_id: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets a user's stored timezone.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(Some(timezone))` - If the user's timezone has been synced.
+  /// * `Ok(None)` - If the user does not exist or has never synced their timezone.
+  /// * `Err(e)` - If the query failed.
+  fn get_timezone(&self, user_id: &str) -> impl Future<Output = Result<Option<UserTimezone>, FitbitError>> + Send;
+
+  /// Stores a user's timezone.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `timezone` - The timezone from the user's Fitbit profile.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the update was successful.
+  /// * `Err(e)` - If the query failed.
+  fn set_timezone(&self, user_id: &str, timezone: &UserTimezone) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the users whose Fitbit access token expires before a given time, including tokens that have already expired.
+  /// Users whose token was rejected are skipped, since refreshing it cannot succeed.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `before` - The time to compare expiry times against.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(user_ids)` - The internal user IDs of the matching users.
+  /// * `Err(e)` - If the query failed.
+  fn users_expiring_before(&self, before: NaiveDateTime) -> impl Future<Output = Result<Vec<String>, FitbitError>> + Send;
+
+  /// Lists a page of the connected users, ordered by internal user ID so pages don't overlap. Tokens are left out.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `limit` - The most users to return.
+  /// * `offset` - The number of users to skip.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(users)` - The users on the page, empty past the last one.
+  /// * `Err(e)` - If the query failed.
+  fn list_users(&self, limit: u32, offset: u32) -> impl Future<Output = Result<Vec<UserSummary>, FitbitError>> + Send;
+
+  /// Deletes a user's Fitbit data from the database.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the delete was successful.
+  /// * `Err(e)` - If the query failed.
+  fn delete_user(&self, user_id: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Stores daily step counts, replacing any count already stored for the same day.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `steps` - The step counts to store, keyed by day.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the upsert was successful.
+  /// * `Err(e)` - If the query failed.
+  fn upsert_steps(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> impl Future<Output = Result<(), FitbitError>> + Send;
+
+  /// Gets the stored daily step counts within a given range, inclusive.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(steps)` - The stored step counts, keyed by day. Days that are not stored are omitted.
+  /// * `Err(e)` - If the query failed.
+  fn get_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> impl Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>> + Send;
+}
+
+impl Database for DatabaseHandler {
+  async fn user_exists(&self, user_id: &str) -> Result<bool, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+
+    let exists = sqlx::query!("SELECT EXISTS(SELECT 1 FROM fitbit_data WHERE id = $1)", user_id)
+      .fetch_one(&mut conn)
+      .await?
+      .exists;
+
+    // Postgres types EXISTS as nullable, but it is never null.
+    Ok(exists.unwrap_or(false))
+  }
+
+  async fn ping(&self) -> Result<(), FitbitError> {
     let mut conn = self.pool.acquire().await?;
-    sqlx::query!("UPDATE fitbit_data SET fitbit_access_token = $1, fitbit_refresh_token = $2, fitbit_token_expires_at = $3 WHERE id = $4", access_token, refresh_token, expires_at, user_id)
+
+    sqlx::query!("SELECT 1 AS ping")
+      .fetch_one(&mut conn)
+      .await?;
+
+    Ok(())
+  }
+
+  async fn get_user(&self, user_id: &str) -> Result<Option<DatabaseUser>, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+
+    let user = sqlx::query_as!(DatabaseUser, "SELECT id, fitbit_user_id, fitbit_access_token, fitbit_refresh_token, fitbit_token_expires_at FROM fitbit_data WHERE id = $1", user_id)
+      .fetch_optional(&mut conn)
+      .await?;
+
+    Ok(user)
+  }
+
+  async fn upsert_user(&self, user_id: &str, fitbit_user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> Result<bool, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    // `xmax` is only set on the row when the conflict turned the insert into an update.
+    let inserted = sqlx::query!(r#"INSERT INTO fitbit_data (id, fitbit_user_id, fitbit_access_token, fitbit_refresh_token, fitbit_token_expires_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO UPDATE SET fitbit_user_id = EXCLUDED.fitbit_user_id, fitbit_access_token = EXCLUDED.fitbit_access_token, fitbit_refresh_token = EXCLUDED.fitbit_refresh_token, fitbit_token_expires_at = EXCLUDED.fitbit_token_expires_at, fitbit_token_rejected_at = NULL RETURNING (xmax = 0) AS "inserted!""#, user_id, fitbit_user_id, access_token, refresh_token, expires_at)
+      .fetch_one(&mut conn)
+      .await?
+      .inserted;
+
+    Ok(inserted)
+  }
+
+  async fn user_token_expired(&self, user_id: &str) -> Result<Option<bool>, FitbitError> {
+    let expiry = self.token_expiry(user_id).await?;
+
+    Ok(expiry.map(|expiry| expiry.expired()))
+  }
+
+  async fn token_expiry(&self, user_id: &str) -> Result<Option<TokenExpiry>, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    let expiry = sqlx::query!(r#"SELECT fitbit_token_expires_at, (EXTRACT(EPOCH FROM(fitbit_token_expires_at - now()))::bigint) AS "fitbit_token_expires_in!" FROM fitbit_data WHERE id = $1"#, user_id)
+      .fetch_optional(&mut conn)
+      .await?;
+
+    Ok(expiry.map(|expiry| TokenExpiry { expires_at: expiry.fitbit_token_expires_at, expires_in: expiry.fitbit_token_expires_in }))
+  }
+
+  async fn update_user_token(&self, user_id: &str, access_token: &str, refresh_token: &str, expires_at: NaiveDateTime) -> Result<(), FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    sqlx::query!("UPDATE fitbit_data SET fitbit_access_token = $1, fitbit_refresh_token = $2, fitbit_token_expires_at = $3, fitbit_token_rejected_at = NULL WHERE id = $4", access_token, refresh_token, expires_at, user_id)
+      .execute(&mut conn)
+      .await?;
+
+    Ok(())
+  }
+
+  async fn log_command(&self, coordination_id: Option<&str>, user_id: Option<&str>, command_kind: &str, outcome: CommandOutcome, latency_ms: i64) -> Result<(), FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    sqlx::query!("INSERT INTO command_audit (coordination_id, user_id, command, outcome, error, latency_ms) VALUES ($1, $2, $3, $4, $5, $6)", coordination_id, user_id, command_kind, outcome.status(), outcome.error(), latency_ms)
+      .execute(&mut conn)
+      .await?;
+
+    Ok(())
+  }
+
+  async fn mark_token_rejected(&self, user_id: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    sqlx::query!("UPDATE fitbit_data SET fitbit_token_rejected_at = now() WHERE id = $1", user_id)
       .execute(&mut conn)
       .await?;
 
     Ok(())
   }
-}
\ No newline at end of file
+
+  async fn get_timezone(&self, user_id: &str) -> Result<Option<UserTimezone>, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    let row = sqlx::query!("SELECT timezone, utc_offset_seconds FROM fitbit_data WHERE id = $1", user_id)
+      .fetch_optional(&mut conn)
+      .await?;
+
+    let timezone = row.and_then(|row| match (row.timezone, row.utc_offset_seconds) {
+      (Some(name), Some(utc_offset_seconds)) => Some(UserTimezone { name, utc_offset_seconds }),
+      _ => None,
+    });
+
+    Ok(timezone)
+  }
+
+  async fn set_timezone(&self, user_id: &str, timezone: &UserTimezone) -> Result<(), FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    sqlx::query!("UPDATE fitbit_data SET timezone = $1, utc_offset_seconds = $2 WHERE id = $3", timezone.name, timezone.utc_offset_seconds, user_id)
+      .execute(&mut conn)
+      .await?;
+
+    Ok(())
+  }
+
+  async fn users_expiring_before(&self, before: NaiveDateTime) -> Result<Vec<String>, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    let users = sqlx::query!("SELECT id FROM fitbit_data WHERE fitbit_token_expires_at < $1 AND fitbit_token_rejected_at IS NULL", before)
+      .fetch_all(&mut conn)
+      .await?;
+
+    Ok(users.into_iter().map(|user| user.id).collect())
+  }
+
+  async fn list_users(&self, limit: u32, offset: u32) -> Result<Vec<UserSummary>, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    let users = sqlx::query!("SELECT id, fitbit_user_id, fitbit_token_expires_at FROM fitbit_data ORDER BY id LIMIT $1 OFFSET $2", i64::from(limit), i64::from(offset))
+      .fetch_all(&mut conn)
+      .await?;
+
+    Ok(users.into_iter().map(|user| UserSummary { id: user.id, fitbit_user_id: user.fitbit_user_id, token_expires_at: user.fitbit_token_expires_at }).collect())
+  }
+
+  async fn delete_user(&self, user_id: &str) -> Result<(), FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    sqlx::query!("DELETE FROM fitbit_data WHERE id = $1", user_id)
+      .execute(&mut conn)
+      .await?;
+
+    Ok(())
+  }
+
+  async fn upsert_steps(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    if steps.is_empty() {
+      return Ok(());
+    }
+
+    let (dates, counts): (Vec<NaiveDate>, Vec<i32>) = steps.iter()
+      .map(|(date, steps)| (*date, i32::try_from(*steps).unwrap_or(i32::MAX)))
+      .unzip();
+
+    let mut conn = self.pool.acquire().await?;
+    sqlx::query!("INSERT INTO fitbit_steps (user_id, date, steps) SELECT $1, * FROM UNNEST($2::date[], $3::int4[]) ON CONFLICT (user_id, date) DO UPDATE SET steps = EXCLUDED.steps", user_id, &dates, &counts)
+      .execute(&mut conn)
+      .await?;
+
+    Ok(())
+  }
+
+  async fn get_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let mut conn = self.pool.acquire().await?;
+    let rows = sqlx::query!("SELECT date, steps FROM fitbit_steps WHERE user_id = $1 AND date BETWEEN $2 AND $3", user_id, start, end)
+      .fetch_all(&mut conn)
+      .await?;
+
+    let steps = rows.into_iter()
+      .map(|row| (row.date, u32::try_from(row.steps).unwrap_or(0)))
+      .collect();
+
+    Ok(steps)
+  }
+}
+/// Reads a number from the environment variable `name`, falling back to `default` if it is unset or invalid.
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+  match env::var(name) {
+    Ok(value) => value.parse::<T>().unwrap_or_else(|_| {
+      error!("Invalid {}, expected a non-negative number, got {}", name, value);
+      default
+    }),
+    Err(_) => default,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The only test that sets these variables, so it cannot race with another test reading them.
+  #[test]
+  fn pool_config_reads_env() {
+    env::set_var("DATABASE_MAX_CONNECTIONS", "20");
+    env::set_var("DATABASE_MIN_CONNECTIONS", "2");
+    env::set_var("DATABASE_ACQUIRE_TIMEOUT_SECONDS", "3");
+
+    assert_eq!(PoolConfig::from_env(), PoolConfig { max_connections: 20, min_connections: 2, acquire_timeout: Duration::from_secs(3) });
+
+    env::set_var("DATABASE_MAX_CONNECTIONS", "many");
+    env::set_var("DATABASE_MIN_CONNECTIONS", "10");
+    env::remove_var("DATABASE_ACQUIRE_TIMEOUT_SECONDS");
+
+    assert_eq!(PoolConfig::from_env(), PoolConfig { max_connections: 5, min_connections: 5, acquire_timeout: Duration::from_secs(30) });
+
+    env::remove_var("DATABASE_MAX_CONNECTIONS");
+    env::remove_var("DATABASE_MIN_CONNECTIONS");
+  }
+
+  #[tokio::test]
+  #[ignore = "requires DATABASE_URL to point at a database with the command_audit table"]
+  async fn log_command_writes_one_row_per_command() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let database_client = DatabaseHandler::new(PgPool::connect(&database_url).await.unwrap());
+    let coordination_id = ulid::Ulid::new().to_string();
+
+    database_client.log_command(Some(&coordination_id), Some("user"), "get_steps", CommandOutcome::Success, 12).await.unwrap();
+    database_client.log_command(Some(&coordination_id), None, "ping", CommandOutcome::Error("redis_error"), 3).await.unwrap();
+
+    let count = sqlx::query!("SELECT COUNT(*) AS count FROM command_audit WHERE coordination_id = $1", coordination_id)
+      .fetch_one(&database_client.pool)
+      .await
+      .unwrap()
+      .count;
+
+    assert_eq!(count, Some(2));
+  }
+
+  #[tokio::test]
+  #[ignore = "requires DATABASE_URL to point at a database with the fitbit_data table"]
+  async fn upsert_user_refreshes_tokens() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let database_client = DatabaseHandler::new(PgPool::connect(&database_url).await.unwrap());
+    let user_id = ulid::Ulid::new().to_string();
+    let expires_at = chrono::Utc::now().naive_utc();
+
+    assert!(database_client.upsert_user(&user_id, "FITBIT", "access", "refresh", expires_at).await.unwrap());
+    assert!(!database_client.upsert_user(&user_id, "OTHER", "new_access", "new_refresh", expires_at).await.unwrap());
+
+    let user = database_client.get_user(&user_id).await.unwrap().unwrap();
+
+    assert_eq!(user.fitbit_user_id, "OTHER");
+    assert_eq!(user.fitbit_access_token, "new_access");
+    assert_eq!(user.fitbit_refresh_token, "new_refresh");
+
+    database_client.delete_user(&user_id).await.unwrap();
+  }
+
+  #[tokio::test]
+  #[ignore = "requires DATABASE_URL to point at a database with the fitbit_data table"]
+  async fn token_expiry_reports_expired_and_valid_tokens() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let database_client = DatabaseHandler::new(PgPool::connect(&database_url).await.unwrap());
+    let user_id = ulid::Ulid::new().to_string();
+    let now = chrono::Utc::now().naive_utc();
+
+    assert_eq!(database_client.token_expiry(&user_id).await.unwrap(), None);
+
+    database_client.upsert_user(&user_id, "FITBIT", "access", "refresh", now - chrono::Duration::hours(1)).await.unwrap();
+
+    let expiry = database_client.token_expiry(&user_id).await.unwrap().unwrap();
+
+    assert!(expiry.expired());
+    assert_eq!(database_client.user_token_expired(&user_id).await.unwrap(), Some(true));
+
+    database_client.update_user_token(&user_id, "access", "refresh", now + chrono::Duration::hours(8)).await.unwrap();
+
+    let expiry = database_client.token_expiry(&user_id).await.unwrap().unwrap();
+
+    assert!(!expiry.expired());
+    assert!(expiry.expires_in > 7 * 3600);
+    assert_eq!(database_client.user_token_expired(&user_id).await.unwrap(), Some(false));
+
+    database_client.delete_user(&user_id).await.unwrap();
+  }
+
+  #[tokio::test]
+  #[ignore = "requires DATABASE_URL to point at a database with the fitbit_data table"]
+  async fn list_users_pages_by_offset() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let database_client = DatabaseHandler::new(PgPool::connect(&database_url).await.unwrap());
+    let expires_at = chrono::Utc::now().naive_utc();
+    let user_ids: Vec<String> = (0..3).map(|_| ulid::Ulid::new().to_string()).collect();
+
+    for user_id in &user_ids {
+      database_client.upsert_user(user_id, "FITBIT", "access", "refresh", expires_at).await.unwrap();
+    }
+
+    let mut listed = Vec::new();
+    let mut offset = 0;
+
+    loop {
+      let page = database_client.list_users(2, offset).await.unwrap();
+
+      assert!(page.len() <= 2);
+
+      if page.is_empty() {
+        break;
+      }
+
+      offset += 2;
+      listed.extend(page);
+    }
+
+    // Every user is on exactly one page, in order.
+    for user_id in &user_ids {
+      assert_eq!(listed.iter().filter(|user| &user.id == user_id).count(), 1);
+    }
+
+    assert!(listed.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    for user_id in &user_ids {
+      database_client.delete_user(user_id).await.unwrap();
+    }
+  }
+}
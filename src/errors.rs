@@ -2,6 +2,8 @@ use std::error::Error;
 use std::fmt;
 use redis::RedisError;
 use bb8::RunError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::models::RateLimitInfo;
 
 /// Errors that can occur when retrieving steps from Fitbit.
 #[derive(Debug)]
@@ -10,21 +12,87 @@ pub enum FitbitError {
   FitbitApiError(String),
   CacheError(String),
   ExpiredToken,
+  /// Fitbit reported the access or refresh token as invalid, typically because the user revoked access.
   RejectedToken,
+  /// The user's token lacks a scope the request needs, named here. The user must connect Fitbit again and grant it.
+  InsufficientScope(String),
   ParsingError(String),
   DateOutOfRange(String),
-  RateLimitExceeded(String),
+  /// The per-user rate limit was reached, with the seconds until it resets if known.
+  RateLimitExceeded(String, Option<u64>),
   RedisError(redis::RedisError),
   RedisPoolError(bb8::RunError<RedisError>),
   PostgresError(sqlx::Error),
+  /// No Postgres connection became free within the pool's acquire timeout.
+  PostgresPoolTimeout,
   TypeConversionError(String),
   InvalidMessage(String),
   UserNotFound,
+  InvalidAuthorizationCode(String),
+  RateLimited(RateLimitInfo),
+  /// The command's TTL passed before Fitbit was queried, so the caller has stopped waiting for it.
+  CommandExpired,
+  /// The command ran for longer than the per-command timeout, in seconds, and was abandoned.
+  Timeout(u64),
+}
+
+impl FitbitError {
+  /// Whether the error means Fitbit could not be reached or would not answer, as opposed to a problem with the
+  /// request itself or with the engine's own storage.
+  pub fn is_unavailable(&self) -> bool {
+    matches!(self, FitbitError::HttpRequestError(_) | FitbitError::RateLimitExceeded(..) | FitbitError::RateLimited(_) | FitbitError::FitbitApiError(_))
+  }
+
+  /// The seconds until a rate limit resets and the command can be retried, if the error is a rate limit with a
+  /// known reset time.
+  pub fn retry_after_seconds(&self) -> Option<u64> {
+    match self {
+      FitbitError::RateLimitExceeded(_, retry_after) => *retry_after,
+      FitbitError::RateLimited(info) => info.reset_seconds,
+      _ => None,
+    }
+  }
+
+  /// The name of the error variant, for use as a metrics label and as the error code in JSON replies. Names are
+  /// stable, so clients can rely on them to tell errors apart.
+  pub fn name(&self) -> &'static str {
+    match self {
+      FitbitError::HttpRequestError(_) => "http_request_error",
+      FitbitError::FitbitApiError(_) => "fitbit_api_error",
+      FitbitError::CacheError(_) => "cache_error",
+      FitbitError::ExpiredToken => "expired_token",
+      FitbitError::RejectedToken => "rejected_token",
+      FitbitError::InsufficientScope(_) => "insufficient_scope",
+      FitbitError::ParsingError(_) => "parsing_error",
+      FitbitError::DateOutOfRange(_) => "date_out_of_range",
+      FitbitError::RateLimitExceeded(..) => "rate_limit_exceeded",
+      FitbitError::RedisError(_) => "redis_error",
+      FitbitError::RedisPoolError(_) => "redis_pool_error",
+      FitbitError::PostgresError(_) => "postgres_error",
+      FitbitError::PostgresPoolTimeout => "postgres_pool_timeout",
+      FitbitError::TypeConversionError(_) => "type_conversion_error",
+      FitbitError::InvalidMessage(_) => "invalid_message",
+      FitbitError::UserNotFound => "user_not_found",
+      FitbitError::InvalidAuthorizationCode(_) => "invalid_authorization_code",
+      FitbitError::RateLimited(_) => "rate_limited",
+      FitbitError::CommandExpired => "command_expired",
+      FitbitError::Timeout(_) => "timeout",
+    }
+  }
+}
+
+impl From<reqwest::Error> for FitbitError {
+  fn from(err: reqwest::Error) -> Self {
+    FitbitError::HttpRequestError(err)
+  }
 }
 
 impl From<sqlx::Error> for FitbitError {
   fn from(err: sqlx::Error) -> Self {
-    FitbitError::PostgresError(err)
+    match err {
+      sqlx::Error::PoolTimedOut => FitbitError::PostgresPoolTimeout,
+      err => FitbitError::PostgresError(err),
+    }
   }
 }
 
@@ -48,15 +116,25 @@ impl fmt::Display for FitbitError {
       FitbitError::CacheError(err) => write!(f, "Cache error: {err}"),
       FitbitError::ExpiredToken => write!(f, "Token expired"),
       FitbitError::RejectedToken => write!(f, "Token rejected"),
+      FitbitError::InsufficientScope(scope) => write!(f, "Token lacks the {scope} scope"),
       FitbitError::ParsingError(err) => write!(f, "Parsing error: {err}"),
       FitbitError::DateOutOfRange(err) => write!(f, "Date out of range: {err}"),
-      FitbitError::RateLimitExceeded(err) => write!(f, "Rate limit exceeded: {err}"),
+      FitbitError::RateLimitExceeded(err, Some(retry_after)) => write!(f, "Rate limit exceeded: {err}, retry after {retry_after} seconds"),
+      FitbitError::RateLimitExceeded(err, None) => write!(f, "Rate limit exceeded: {err}"),
       FitbitError::RedisError(err) => write!(f, "Redis error: {err}"),
       FitbitError::RedisPoolError(err) => write!(f, "Redis pool error: {err}"),
       FitbitError::PostgresError(err) => write!(f, "Postgres error: {err}"),
+      FitbitError::PostgresPoolTimeout => write!(f, "Timed out waiting for a Postgres connection"),
       FitbitError::TypeConversionError(err) => write!(f, "Type conversion error: {err}"),
       FitbitError::InvalidMessage(err) => write!(f, "Invalid message: {err}"),
       FitbitError::UserNotFound => write!(f, "User not found"),
+      FitbitError::InvalidAuthorizationCode(err) => write!(f, "Invalid authorization code: {err}"),
+      FitbitError::RateLimited(info) => match info.reset_seconds {
+        Some(reset_seconds) => write!(f, "Rate limited by Fitbit, resets in {reset_seconds} seconds"),
+        None => write!(f, "Rate limited by Fitbit, reset time unknown"),
+      },
+      FitbitError::CommandExpired => write!(f, "Command expired before it was executed"),
+      FitbitError::Timeout(seconds) => write!(f, "Command timed out after {seconds} seconds"),
     }
   }
 }
@@ -69,3 +147,97 @@ impl Error for FitbitError {
     }
   }
 }
+
+/// How a `FitbitError` is serialized, since the errors it wraps from reqwest, Redis and sqlx aren't serializable. The
+/// variant is tagged with its `name`, and wrapped errors are carried as their messages. Those can't be rebuilt from a
+/// message, so they deserialize as the closest error that carries one: HTTP errors as `FitbitApiError`, Redis errors
+/// as `CacheError`, and Postgres errors as `PostgresError` wrapping `sqlx::Error::Protocol`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "code", content = "detail", rename_all = "snake_case")]
+enum SerializedError {
+  HttpRequestError(String),
+  FitbitApiError(String),
+  CacheError(String),
+  ExpiredToken,
+  RejectedToken,
+  InsufficientScope(String),
+  ParsingError(String),
+  DateOutOfRange(String),
+  RateLimitExceeded(String, Option<u64>),
+  RedisError(String),
+  RedisPoolError(String),
+  PostgresError(String),
+  PostgresPoolTimeout,
+  TypeConversionError(String),
+  InvalidMessage(String),
+  UserNotFound,
+  InvalidAuthorizationCode(String),
+  RateLimited(RateLimitInfo),
+  CommandExpired,
+  Timeout(u64),
+}
+
+impl From<&FitbitError> for SerializedError {
+  fn from(err: &FitbitError) -> Self {
+    match err {
+      FitbitError::HttpRequestError(err) => SerializedError::HttpRequestError(err.to_string()),
+      FitbitError::FitbitApiError(err) => SerializedError::FitbitApiError(err.clone()),
+      FitbitError::CacheError(err) => SerializedError::CacheError(err.clone()),
+      FitbitError::ExpiredToken => SerializedError::ExpiredToken,
+      FitbitError::RejectedToken => SerializedError::RejectedToken,
+      FitbitError::InsufficientScope(scope) => SerializedError::InsufficientScope(scope.clone()),
+      FitbitError::ParsingError(err) => SerializedError::ParsingError(err.clone()),
+      FitbitError::DateOutOfRange(err) => SerializedError::DateOutOfRange(err.clone()),
+      FitbitError::RateLimitExceeded(err, retry_after) => SerializedError::RateLimitExceeded(err.clone(), *retry_after),
+      FitbitError::RedisError(err) => SerializedError::RedisError(err.to_string()),
+      FitbitError::RedisPoolError(err) => SerializedError::RedisPoolError(err.to_string()),
+      // Deserialized Postgres errors are `Protocol` errors, whose message is kept bare so it survives another round.
+      FitbitError::PostgresError(sqlx::Error::Protocol(err)) => SerializedError::PostgresError(err.clone()),
+      FitbitError::PostgresError(err) => SerializedError::PostgresError(err.to_string()),
+      FitbitError::PostgresPoolTimeout => SerializedError::PostgresPoolTimeout,
+      FitbitError::TypeConversionError(err) => SerializedError::TypeConversionError(err.clone()),
+      FitbitError::InvalidMessage(err) => SerializedError::InvalidMessage(err.clone()),
+      FitbitError::UserNotFound => SerializedError::UserNotFound,
+      FitbitError::InvalidAuthorizationCode(err) => SerializedError::InvalidAuthorizationCode(err.clone()),
+      FitbitError::RateLimited(info) => SerializedError::RateLimited(*info),
+      FitbitError::CommandExpired => SerializedError::CommandExpired,
+      FitbitError::Timeout(seconds) => SerializedError::Timeout(*seconds),
+    }
+  }
+}
+
+impl From<SerializedError> for FitbitError {
+  fn from(err: SerializedError) -> Self {
+    match err {
+      SerializedError::HttpRequestError(err) | SerializedError::FitbitApiError(err) => FitbitError::FitbitApiError(err),
+      SerializedError::RedisError(err) | SerializedError::RedisPoolError(err) | SerializedError::CacheError(err) => FitbitError::CacheError(err),
+      SerializedError::ExpiredToken => FitbitError::ExpiredToken,
+      SerializedError::RejectedToken => FitbitError::RejectedToken,
+      SerializedError::InsufficientScope(scope) => FitbitError::InsufficientScope(scope),
+      SerializedError::ParsingError(err) => FitbitError::ParsingError(err),
+      SerializedError::DateOutOfRange(err) => FitbitError::DateOutOfRange(err),
+      SerializedError::RateLimitExceeded(err, retry_after) => FitbitError::RateLimitExceeded(err, retry_after),
+      SerializedError::PostgresError(err) => FitbitError::PostgresError(sqlx::Error::Protocol(err)),
+      SerializedError::PostgresPoolTimeout => FitbitError::PostgresPoolTimeout,
+      SerializedError::TypeConversionError(err) => FitbitError::TypeConversionError(err),
+      SerializedError::InvalidMessage(err) => FitbitError::InvalidMessage(err),
+      SerializedError::UserNotFound => FitbitError::UserNotFound,
+      SerializedError::InvalidAuthorizationCode(err) => FitbitError::InvalidAuthorizationCode(err),
+      SerializedError::RateLimited(info) => FitbitError::RateLimited(info),
+      SerializedError::CommandExpired => FitbitError::CommandExpired,
+      SerializedError::Timeout(seconds) => FitbitError::Timeout(seconds),
+    }
+  }
+}
+
+impl Serialize for FitbitError {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    SerializedError::from(self).serialize(serializer)
+  }
+}
+
+impl<'de> Deserialize<'de> for FitbitError {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    SerializedError::deserialize(deserializer).map(FitbitError::from)
+  }
+}
@@ -1,107 +1,294 @@
 use std::collections::HashMap;
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use reqwest::header::HeaderMap;
 use base64::{Engine as _, engine::general_purpose};
-use crate::models::{Period, FitbitResponse, FitbitSuccess, TokenResponse};
+use crate::models::{Conditional, ErrorDetail, Period, Detail, FitbitResponse, FitbitSuccess, HeartRateDay, HeartRateZones, RateLimitInfo, Resource, RetryConfig, TimeSeriesResult, TokenResponse, Profile, Goals, Device, Spo2Response, Spo2Summary, CardioScore, CardioScoreResponse, Vo2Max, WeightLog, WeightResponse};
 use crate::errors::FitbitError;
-use log::{error, info};
+use tracing::warn;
 
-/// Get steps for a given end date and period. All dates are UTC.
+/// The default base URL for Fitbit's Web API.
+pub const DEFAULT_API_BASE_URL: &str = "https://api.fitbit.com";
+
+/// The default base URL for Fitbit's OAuth endpoints.
+pub const DEFAULT_OAUTH_BASE_URL: &str = "https://api.fitbit.com";
+
+/// Joins a base URL and a path with exactly one slash between them, whether or not either side already has one.
+fn endpoint(base_url: &str, path: &str) -> String {
+  format!("{}/{}", base_url.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+/// Maps the errors Fitbit answered a request with to a `FitbitError`, by the type of the first one.
+/// 
+/// # Arguments
+/// 
+/// * `errors` - The errors from Fitbit's response.
+/// * `scope` - The scope the request needs, reported if the token lacks it.
+fn api_error(errors: &[ErrorDetail], scope: &str) -> FitbitError {
+  let Some(error_detail) = errors.first() else {
+    return FitbitError::ParsingError("Empty error list".to_string());
+  };
+
+  match error_detail.error_type.as_str() {
+    "expired_token" => FitbitError::ExpiredToken,
+    "invalid_token" => FitbitError::RejectedToken,
+    "insufficient_scope" | "insufficient_permissions" => FitbitError::InsufficientScope(scope.to_string()),
+    _ => FitbitError::FitbitApiError(error_detail.message.clone()),
+  }
+}
+
+/// Get a daily time series for a given end date and period. Dates are days in the given timezone.
 /// 
 /// # Arguments
 /// 
-/// * `date` - The end date for which to retrieve steps.
-/// * `period` - The period for which to retrieve steps.
+/// * `base_url` - The base URL of the Fitbit Web API.
+/// * `resource` - The time series to retrieve.
+/// * `date` - The end date for which to retrieve values.
+/// * `period` - The period for which to retrieve values.
+/// * `timezone` - The IANA name of the user's timezone.
 /// 
 /// # Examples
 /// 
 /// This example gets the steps for the week ending on January 1, 2023.
 /// 
+/// ```ignore
+/// let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+/// 
+/// let steps = get_time_series(&client, &retry, DEFAULT_API_BASE_URL, "-", token, Resource::Steps, date, Period::OneWeek, "UTC").await?;
 /// ```
-/// use chrono::NaiveDate;
-/// use fitbit_steps::Period;
 /// 
-/// #[tokio::main]
-/// async fn main() {
-///   let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
-///   let period = Period::OneWeek;
+/// # Errors
 /// 
-///   let steps = fitbit_steps::get_steps(date, period).await.unwrap();
-///   println!("{:?}", steps);
-/// }
-/// ```
+/// Returns an error if the request fails or if the response is malformed.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_time_series(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str) -> Result<TimeSeriesResult, FitbitError> {
+  let (days, rate_limit) = fetch_time_series(client, retry, base_url, user_id, access_token, resource.path(), date, period, timezone).await?;
+
+  time_series_result(resource, days, rate_limit)
+}
+
+/// Gets a daily time series as in `get_time_series`, sending `etag`, if any, as `If-None-Match`. Fitbit answers
+/// `304 Not Modified` without a body when the series has not changed since the ETag was sent with it.
+/// 
+/// # Arguments
+/// 
+/// * `etag` - The ETag Fitbit sent with an earlier response for the same series and period, if any.
+/// 
+/// # Returns
+/// 
+/// * `Ok(Conditional::Modified(result, etag))` - The series, and the ETag Fitbit sent with it, if any.
+/// * `Ok(Conditional::NotModified(rate_limit))` - If Fitbit answered `304 Not Modified`.
 /// 
 /// # Errors
 /// 
 /// Returns an error if the request fails or if the response is malformed.
-pub async fn get_steps(client: &reqwest::Client, user_id: &str, access_token: &str, date: NaiveDate, period: Period) -> Result<(HashMap<NaiveDate, u32>, HeaderMap), FitbitError> {
-  // Test
-  let test_url = format!("https://api.fitbit.com/1/user/{}/profile.json", user_id);
-  let test_auth = format!("Bearer {}", access_token);
+#[allow(clippy::too_many_arguments)]
+pub async fn get_time_series_if_none_match(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str, etag: Option<&str>) -> Result<Conditional<TimeSeriesResult>, FitbitError> {
+  let url = time_series_url(base_url, user_id, resource.path(), date, period, timezone)?;
+  let auth: String = format!("Bearer {}", access_token);
 
-  let test_resp = client.get(test_url)
-    .header("Authorization", test_auth)
-    .send()
-    .await;
+  let resp = send_with_retry(client, reqwest::Method::GET, &url, &auth, etag, retry).await?;
+
+  if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+    return Ok(Conditional::NotModified(RateLimitInfo::from_headers(resp.headers())));
+  }
+
+  let etag = resp.headers()
+    .get(reqwest::header::ETAG)
+    .and_then(|etag| etag.to_str().ok())
+    .map(String::from);
+
+  let (days, rate_limit) = read_time_series(resp, resource.path()).await?;
+
+  Ok(Conditional::Modified(time_series_result(resource, days, rate_limit)?, etag))
+}
 
-  let Ok(test_resp) = test_resp else {
-    error!("Couldn't get profile");
-    return Err(FitbitError::HttpRequestError(test_resp.unwrap_err()));
+/// Parses the days of a time series as read by `read_time_series`.
+fn time_series_result(resource: Resource, days: Option<Vec<serde_json::Value>>, rate_limit: RateLimitInfo) -> Result<TimeSeriesResult, FitbitError> {
+  let days = match days {
+    Some(days) if !days.is_empty() => days,
+    // Trackers without an altimeter leave floors out entirely.
+    _ if resource == Resource::Floors => return Ok(TimeSeriesResult { values: HashMap::new(), rate_limit }),
+    _ => return Err(FitbitError::ParsingError(format!("No {} found", resource))),
   };
 
-  info!("{:?}", test_resp.text().await);
+  match parse_time_series(resource, days) {
+    Ok(values) => Ok(TimeSeriesResult { values, rate_limit }),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+  }
+}
 
+/// Get the distance covered each day for a given end date and period, in kilometers. Fitbit reports distances in
+/// the unit system of the request's `Accept-Language` header, which is never sent, so they are always metric.
+/// 
+/// # Arguments
+/// 
+/// * `base_url` - The base URL of the Fitbit Web API.
+/// * `date` - The end date for which to retrieve distances.
+/// * `period` - The period for which to retrieve distances.
+/// * `timezone` - The IANA name of the user's timezone.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails or if the response is malformed.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_distance(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, date: NaiveDate, period: Period, timezone: &str) -> Result<TimeSeriesResult<f64>, FitbitError> {
+  let (days, rate_limit) = fetch_time_series(client, retry, base_url, user_id, access_token, "distance", date, period, timezone).await?;
 
+  let days = match days {
+    Some(days) if !days.is_empty() => days,
+    _ => return Err(FitbitError::ParsingError("No distance found".to_string())),
+  };
 
-  let date = date.format("%Y-%m-%d").to_string();
-  let url: String = format!("https://api.fitbit.com/1/user/{}/activities/steps/date/{}/{}.json?timezone=UTC", user_id, date, period.to_str());
-  let auth: String = format!("Bearer {}", access_token);
+  match parse_distance(days) {
+    Ok(values) => Ok(TimeSeriesResult { values, rate_limit }),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+  }
+}
 
-  let resp = client.get(url)
-    .header("Authorization", auth)
-    .send()
-    .await;
+/// Get the minutes spent in each heart rate zone each day for a given end date and period. Days without zones, such
+/// as days the device was not worn, are left out.
+/// 
+/// # Arguments
+/// 
+/// * `base_url` - The base URL of the Fitbit Web API.
+/// * `date` - The end date for which to retrieve zones.
+/// * `period` - The period for which to retrieve zones.
+/// * `timezone` - The IANA name of the user's timezone.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails or if the response is malformed.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_heart_rate_zones(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, date: NaiveDate, period: Period, timezone: &str) -> Result<TimeSeriesResult<HeartRateZones>, FitbitError> {
+  let (days, rate_limit) = fetch_time_series(client, retry, base_url, user_id, access_token, Resource::HeartRate.path(), date, period, timezone).await?;
 
-  let resp = match resp {
-    Ok(resp) => resp,
-    Err(e) => return Err(FitbitError::HttpRequestError(e)),
+  let days = match days {
+    Some(days) if !days.is_empty() => days,
+    _ => return Err(FitbitError::ParsingError("No heart rate zones found".to_string())),
   };
 
-  let headers = resp.headers().clone();
+  match parse_heart_rate_zones(days) {
+    Ok(values) => Ok(TimeSeriesResult { values, rate_limit }),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+  }
+}
+
+/// Requests a daily time series by its segment of the endpoint path, returning the days listed under its
+/// `activities-{path}` key, if any, and the rate limit state reported with them.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_time_series(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, path: &str, date: NaiveDate, period: Period, timezone: &str) -> Result<(Option<Vec<serde_json::Value>>, RateLimitInfo), FitbitError> {
+  let url = time_series_url(base_url, user_id, path, date, period, timezone)?;
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  read_time_series(resp, path).await
+}
+
+/// The URL of a daily time series by its segment of the endpoint path, with days in the given timezone.
+fn time_series_url(base_url: &str, user_id: &str, path: &str, date: NaiveDate, period: Period, timezone: &str) -> Result<String, FitbitError> {
+  let date = date.format("%Y-%m-%d").to_string();
+  let url: String = endpoint(base_url, &format!("1/user/{}/activities/{}/date/{}/{}.json", user_id, path, date, period.to_str()));
+
+  reqwest::Url::parse_with_params(&url, &[("timezone", timezone)])
+    .map(String::from)
+    .map_err(|e| FitbitError::ParsingError(e.to_string()))
+}
+
+/// Reads the days listed under the `activities-{path}` key of a time series response, if any, and the rate limit
+/// state reported with them.
+async fn read_time_series(resp: reqwest::Response, path: &str) -> Result<(Option<Vec<serde_json::Value>>, RateLimitInfo), FitbitError> {
+  let rate_limit = RateLimitInfo::from_headers(resp.headers());
 
   let resp = resp
     .json::<FitbitResponse>()
     .await;
 
-  let resp = match resp {
-    Ok(FitbitResponse::Success(FitbitSuccess::Steps(steps))) => steps,
+  let mut resp = match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::TimeSeries(series))) => series,
     Ok(FitbitResponse::Error(e)) => {
-      if let Some(error_detail) = e.errors.get(0) {
-        if error_detail.error_type == "expired_token" {
-          return Err(FitbitError::ExpiredToken);
-        }
-
-        return Err(FitbitError::FitbitApiError(error_detail.message.clone()));
-      }
+      // Heart rate is the only time series outside the `activity` scope.
+      let scope = if path == Resource::HeartRate.path() { "heartrate" } else { "activity" };
 
-      return Err(FitbitError::ParsingError("Empty error list".to_string()));
+      return Err(api_error(&e.errors, scope));
     },
     Err(e) => return Err(FitbitError::ParsingError(e.to_string())),
     _ => return Err(FitbitError::ParsingError("Failed to parse response".to_string())),
   };
 
-  if !resp.contains_key("activities-steps") || resp["activities-steps"].is_empty() {
-    return Err(FitbitError::ParsingError("No steps found".to_string()));
+  Ok((resp.remove(&format!("activities-{}", path)), rate_limit))
+}
+
+/// Parses the days of a time series according to the shape of the resource's values.
+fn parse_time_series(resource: Resource, days: Vec<serde_json::Value>) -> Result<HashMap<NaiveDate, u32>, Box<dyn std::error::Error>> {
+  let days = serde_json::Value::Array(days);
+
+  match resource {
+    Resource::Steps | Resource::Calories | Resource::MinutesFairlyActive | Resource::MinutesVeryActive => parse_steps(&serde_json::from_value(days)?),
+    Resource::HeartRate => parse_heart_rate(&serde_json::from_value(days)?),
+    Resource::Floors => parse_floors(&serde_json::from_value(days)?),
   }
+}
 
-  let steps = parse_steps(&resp["activities-steps"]);
+/// Sends a GET request with `send_with_retry`.
+async fn get_with_retry(client: &reqwest::Client, url: &str, auth: &str, retry: &RetryConfig) -> Result<reqwest::Response, FitbitError> {
+  send_with_retry(client, reqwest::Method::GET, url, auth, None, retry).await
+}
 
-  match steps {
-    Ok(steps) => Ok((steps, headers)),
-    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+/// Sends an idempotent request, retrying with exponential backoff and jitter on connection errors,
+/// timeouts, and 5xx responses. 4xx responses are never retried.
+/// 
+/// # Arguments
+/// 
+/// * `method` - The request method. Only idempotent requests may be sent, since a retried request may have
+///   already taken effect.
+/// * `url` - The URL to request.
+/// * `auth` - The value of the `Authorization` header.
+/// * `if_none_match` - The value of the `If-None-Match` header, if any.
+/// * `retry` - The retry policy.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::RateLimited` on a 429, or an error if the final attempt fails to produce a response.
+/// A final 5xx response is returned as-is.
+async fn send_with_retry(client: &reqwest::Client, method: reqwest::Method, url: &str, auth: &str, if_none_match: Option<&str>, retry: &RetryConfig) -> Result<reqwest::Response, FitbitError> {
+  let mut attempt = 0;
+
+  loop {
+    let mut request = client.request(method.clone(), url)
+      .header("Authorization", auth);
+
+    if let Some(etag) = if_none_match {
+      request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+
+    let resp = request.send().await;
+
+    let retryable = match &resp {
+      Ok(resp) => resp.status().is_server_error(),
+      Err(e) => e.is_connect() || e.is_timeout(),
+    };
+
+    if !retryable || attempt >= retry.max_retries {
+      let resp = resp?;
+
+      if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(FitbitError::RateLimited(RateLimitInfo::from_headers(resp.headers())));
+      }
+
+      return Ok(resp);
+    }
+
+    let delay = retry.delay(attempt);
+
+    warn!("Transient error requesting {}, retrying in {:?} (attempt {} of {})", url, delay, attempt + 1, retry.max_retries);
+
+    tokio::time::sleep(delay).await;
+    attempt += 1;
   }
 }
 
+/// Parses days whose values are whole numbers sent as strings, as step counts, calories and active minutes are.
 fn parse_steps(steps: &Vec<HashMap<String, String>>) -> Result<HashMap<NaiveDate, u32>, Box<dyn std::error::Error>> {
   let mut parsed_steps: HashMap<NaiveDate, u32> = HashMap::new();
 
@@ -117,40 +304,1162 @@ fn parse_steps(steps: &Vec<HashMap<String, String>>) -> Result<HashMap<NaiveDate
   Ok(parsed_steps)
 }
 
-pub async fn refresh_token(client: &reqwest::Client, refresh_token: &str, client_id: &str, client_secret: &str) -> Result<TokenResponse, FitbitError> {
-  let authorization = general_purpose::STANDARD_NO_PAD.encode(format!("{}:{}", client_id, client_secret).as_bytes());
-  let resp = client.post("https://api.fitbit.com/oauth2/token")
-    .form(&[
-      ("grant_type", "refresh_token"),
-      ("refresh_token", refresh_token),
-    ])
-    .header("authorization", format!("Basic {}", authorization))
-    .send()
+/// Parses floors climbed like step counts, except that days without a value are left out rather than failing, as
+/// trackers without an altimeter may report days with no floors data.
+fn parse_floors(floors: &Vec<HashMap<String, serde_json::Value>>) -> Result<HashMap<NaiveDate, u32>, Box<dyn std::error::Error>> {
+  let mut parsed_floors: HashMap<NaiveDate, u32> = HashMap::new();
+
+  for day in floors {
+    let date = day.get("dateTime").and_then(|date| date.as_str()).ok_or("Missing date")?;
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+      .map_err(|_| "Failed to parse date")?;
+
+    let value = match day.get("value").and_then(|value| value.as_str()) {
+      Some(value) if !value.is_empty() => value,
+      _ => continue,
+    };
+
+    parsed_floors.insert(date, value.parse::<u32>().map_err(|_| "Failed to parse value")?);
+  }
+
+  Ok(parsed_floors)
+}
+
+/// Parses days whose values are decimal distances sent as strings.
+fn parse_distance(days: Vec<serde_json::Value>) -> Result<HashMap<NaiveDate, f64>, Box<dyn std::error::Error>> {
+  let days: Vec<HashMap<String, String>> = serde_json::from_value(serde_json::Value::Array(days))?;
+  let mut parsed_distance: HashMap<NaiveDate, f64> = HashMap::new();
+
+  for day in days {
+    let date = day.get("dateTime").ok_or("Missing date")?;
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+      .map_err(|_| "Failed to parse date")?;
+    let value = day.get("value").ok_or("Missing value")?;
+    let value = value.parse::<f64>()
+      .map_err(|_| "Failed to parse value")?;
+
+    parsed_distance.insert(date, value);
+  }
+
+  Ok(parsed_distance)
+}
+
+/// Get the user's profile.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails, if the token lacks the `profile` scope, or if the response is malformed.
+pub async fn get_profile(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str) -> Result<(Profile, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/profile.json", user_id));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<FitbitResponse>()
     .await;
 
-  let resp = match resp {
-    Ok(resp) => resp,
-    Err(e) => return Err(FitbitError::HttpRequestError(e)),
-  };
+  match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::Profile(profile))) => Ok((profile.user, headers)),
+    Ok(FitbitResponse::Error(e)) => Err(api_error(&e.errors, "profile")),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+    _ => Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  }
+}
+
+/// Get the user's daily activity goals.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails or if the response is malformed.
+pub async fn get_goals(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str) -> Result<(Goals, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/activities/goals/daily.json", user_id));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
 
   let resp = resp
     .json::<FitbitResponse>()
     .await;
 
-  let resp = match resp {
-    Ok(FitbitResponse::Success(FitbitSuccess::Refresh(data))) => data,
-    Ok(FitbitResponse::Error(e)) => {
-      if let Some(error_detail) = e.errors.get(0) {
-        return Err(FitbitError::FitbitApiError(error_detail.message.clone()));
-      }
+  match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::Goals(goals))) => Ok((goals.goals, headers)),
+    Ok(FitbitResponse::Error(e)) => Err(api_error(&e.errors, "activity")),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+    _ => Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  }
+}
 
-      return Err(FitbitError::ParsingError("Empty error list".to_string()));
-    },
+/// Get the devices paired with the user's account.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails or if the response is malformed.
+pub async fn get_devices(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str) -> Result<(Vec<Device>, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/devices.json", user_id));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<FitbitResponse>()
+    .await;
+
+  match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::Devices(devices))) => Ok((devices, headers)),
+    Ok(FitbitResponse::Error(e)) => Err(api_error(&e.errors, "settings")),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+    _ => Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  }
+}
+
+/// Get the user's nightly SpO2 summaries for a range of days, inclusive. Reading SpO2 requires the
+/// `oxygen_saturation` scope on the user's token. Days without a reading are left out.
+/// 
+/// # Arguments
+/// 
+/// * `start` - The first day to retrieve.
+/// * `end` - The last day to retrieve, at most `SPO2_MAX_DAYS` days after `start`.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::InsufficientScope` if the token lacks the `oxygen_saturation` scope, or another error if the
+/// request fails or the response is malformed.
+pub async fn get_spo2(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<(HashMap<NaiveDate, Spo2Summary>, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/spo2/date/{}/{}.json", user_id, start.format("%Y-%m-%d"), end.format("%Y-%m-%d")));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<Spo2Response>()
+    .await;
+
+  let days = match resp {
+    Ok(Spo2Response::Success(days)) => days,
+    Ok(Spo2Response::Error(e)) => return Err(api_error(&e.errors, "oxygen_saturation")),
     Err(e) => return Err(FitbitError::ParsingError(e.to_string())),
-    _ => return Err(FitbitError::ParsingError("Failed to parse response".to_string())),
   };
 
-  let data: TokenResponse = TokenResponse { access_token: resp.access_token, expires_in: resp.expires_in, refresh_token: resp.refresh_token, scope: resp.scope, token_type: resp.token_type, user_id: resp.user_id };
+  let mut spo2: HashMap<NaiveDate, Spo2Summary> = HashMap::new();
 
-  Ok(data)
-}
\ No newline at end of file
+  for day in days {
+    let Ok(date) = NaiveDate::parse_from_str(&day.date_time, "%Y-%m-%d") else {
+      return Err(FitbitError::ParsingError("Failed to parse date".to_string()));
+    };
+
+    spo2.insert(date, day.value);
+  }
+
+  Ok((spo2, headers))
+}
+
+/// The most days Fitbit returns in a single SpO2 range request.
+pub const SPO2_MAX_DAYS: i64 = 30;
+
+/// Get the user's daily cardio fitness scores for a range of days, inclusive. Reading cardio fitness requires the
+/// `cardio_fitness` scope on the user's token. Days without a score are left out.
+/// 
+/// # Arguments
+/// 
+/// * `start` - The first day to retrieve.
+/// * `end` - The last day to retrieve, at most `CARDIO_SCORE_MAX_DAYS` days after `start`.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::InsufficientScope` if the token lacks the `cardio_fitness` scope, or another error if the
+/// request fails or the response is malformed, including a `vo2Max` that is neither a number nor a range.
+pub async fn get_cardio_score(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<(HashMap<NaiveDate, CardioScore>, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/cardioscore/date/{}/{}.json", user_id, start.format("%Y-%m-%d"), end.format("%Y-%m-%d")));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<CardioScoreResponse>()
+    .await;
+
+  let days = match resp {
+    Ok(CardioScoreResponse::Success(series)) => series.cardio_score,
+    Ok(CardioScoreResponse::Error(e)) => return Err(api_error(&e.errors, "cardio_fitness")),
+    Err(e) => return Err(FitbitError::ParsingError(e.to_string())),
+  };
+
+  let mut scores: HashMap<NaiveDate, CardioScore> = HashMap::new();
+
+  for day in days {
+    let Ok(date) = NaiveDate::parse_from_str(&day.date_time, "%Y-%m-%d") else {
+      return Err(FitbitError::ParsingError("Failed to parse date".to_string()));
+    };
+
+    let score = match day.value.vo2_max {
+      Vo2Max::Number(value) => CardioScore { low: value, high: value },
+      Vo2Max::Text(value) => match CardioScore::parse(&value) {
+        Some(score) => score,
+        None => return Err(FitbitError::ParsingError(format!("Failed to parse VO2 max {}", value))),
+      },
+    };
+
+    scores.insert(date, score);
+  }
+
+  Ok((scores, headers))
+}
+
+/// The most days Fitbit returns in a single cardio fitness score range request.
+pub const CARDIO_SCORE_MAX_DAYS: i64 = 30;
+
+/// Get the weights the user logged over a range of days, inclusive, in kilograms. Like distances, weights are in the
+/// unit system of the request's `Accept-Language` header, which is never sent, so they are always metric. Reading
+/// weights requires the `weight` scope on the user's token.
+/// 
+/// # Arguments
+/// 
+/// * `start` - The first day to retrieve.
+/// * `end` - The last day to retrieve, at most `WEIGHT_MAX_DAYS` days after `start`.
+/// 
+/// # Returns
+/// 
+/// * `Vec<WeightLog>` - The logs, ordered by date and time.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::InsufficientScope` if the token lacks the `weight` scope, or another error if the request
+/// fails or the response is malformed.
+pub async fn get_weight(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<(Vec<WeightLog>, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/body/log/weight/date/{}/{}.json", user_id, start.format("%Y-%m-%d"), end.format("%Y-%m-%d")));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<WeightResponse>()
+    .await;
+
+  let entries = match resp {
+    Ok(WeightResponse::Success(logs)) => logs.weight,
+    Ok(WeightResponse::Error(e)) => return Err(api_error(&e.errors, "weight")),
+    Err(e) => return Err(FitbitError::ParsingError(e.to_string())),
+  };
+
+  let mut logs: Vec<WeightLog> = Vec::with_capacity(entries.len());
+
+  for entry in entries {
+    let Ok(date) = NaiveDate::parse_from_str(&entry.date, "%Y-%m-%d") else {
+      return Err(FitbitError::ParsingError("Failed to parse date".to_string()));
+    };
+
+    let time = match entry.time {
+      Some(time) => match NaiveTime::parse_from_str(&time, "%H:%M:%S") {
+        Ok(time) => Some(time),
+        Err(_) => return Err(FitbitError::ParsingError("Failed to parse time".to_string())),
+      },
+      None => None,
+    };
+
+    logs.push(WeightLog { date, time, weight: entry.weight, fat: entry.fat });
+  }
+
+  logs.sort_by_key(|log| (log.date, log.time));
+
+  Ok((logs, headers))
+}
+
+/// The most days Fitbit returns in a single weight log range request.
+pub const WEIGHT_MAX_DAYS: i64 = 31;
+
+/// Sets the user's daily step goal. Writing goals requires the `activity` scope on the user's token. Setting a goal
+/// is idempotent, so the request is retried like a read.
+/// 
+/// # Arguments
+/// 
+/// * `goal` - The new daily step goal.
+/// 
+/// # Returns
+/// 
+/// The step goal Fitbit stored, along with the response headers.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::InsufficientScope` if the token lacks the `activity` scope, or another error if the request
+/// fails or the response is malformed.
+pub async fn set_step_goal(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, goal: u32) -> Result<(u32, HeaderMap), FitbitError> {
+  let url: String = endpoint(base_url, &format!("1/user/{}/activities/goals/daily.json?period=daily&type=steps&value={}", user_id, goal));
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = send_with_retry(client, reqwest::Method::POST, &url, &auth, None, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<FitbitResponse>()
+    .await;
+
+  match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::Goals(goals))) => match goals.goals.steps {
+      Some(steps) => Ok((steps, headers)),
+      None => Err(FitbitError::ParsingError("Missing step goal".to_string())),
+    },
+    Ok(FitbitResponse::Error(e)) => Err(api_error(&e.errors, "activity")),
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+    _ => Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  }
+}
+
+/// Get intraday steps for a single day at the given detail level, with the day and times in the given timezone.
+/// Intraday data requires the `activity` scope on the user's token.
+/// 
+/// # Arguments
+/// 
+/// * `date` - The day for which to retrieve steps.
+/// * `detail` - The resolution of the time series.
+/// * `timezone` - The IANA name of the timezone the day is in, e.g. `UTC`.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails, if the token lacks the `activity` scope, or if the response is malformed.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_intraday_steps(client: &reqwest::Client, retry: &RetryConfig, base_url: &str, user_id: &str, access_token: &str, date: NaiveDate, detail: Detail, timezone: &str) -> Result<(HashMap<NaiveDateTime, u32>, HeaderMap), FitbitError> {
+  let formatted_date = date.format("%Y-%m-%d").to_string();
+  let url: String = endpoint(base_url, &format!("1/user/{}/activities/steps/date/{}/1d/{}.json", user_id, formatted_date, detail.to_str()));
+  let url = reqwest::Url::parse_with_params(&url, &[("timezone", timezone)])
+    .map(String::from)
+    .map_err(|e| FitbitError::ParsingError(e.to_string()))?;
+  let auth: String = format!("Bearer {}", access_token);
+
+  let resp = get_with_retry(client, &url, &auth, retry).await?;
+
+  let headers = resp.headers().clone();
+
+  let resp = resp
+    .json::<FitbitResponse>()
+    .await;
+
+  let resp = match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::IntradaySteps(steps))) => steps,
+    Ok(FitbitResponse::Error(e)) => return Err(api_error(&e.errors, "activity")),
+    Err(e) => return Err(FitbitError::ParsingError(e.to_string())),
+    _ => return Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  };
+
+  let mut steps: HashMap<NaiveDateTime, u32> = HashMap::new();
+
+  for point in resp.intraday.dataset {
+    let Ok(time) = NaiveTime::parse_from_str(&point.time, "%H:%M:%S") else {
+      return Err(FitbitError::ParsingError("Failed to parse time".to_string()));
+    };
+
+    steps.insert(NaiveDateTime::new(date, time), point.value);
+  }
+
+  Ok((steps, headers))
+}
+
+fn parse_heart_rate(days: &Vec<HeartRateDay>) -> Result<HashMap<NaiveDate, u32>, Box<dyn std::error::Error>> {
+  let mut parsed_heart_rate: HashMap<NaiveDate, u32> = HashMap::new();
+
+  for day in days {
+    let date = NaiveDate::parse_from_str(&day.date_time, "%Y-%m-%d")
+      .map_err(|_| "Failed to parse date")?;
+
+    if let Some(resting_heart_rate) = day.value.resting_heart_rate {
+      parsed_heart_rate.insert(date, resting_heart_rate);
+    }
+  }
+
+  Ok(parsed_heart_rate)
+}
+
+fn parse_heart_rate_zones(days: Vec<serde_json::Value>) -> Result<HashMap<NaiveDate, HeartRateZones>, Box<dyn std::error::Error>> {
+  let days: Vec<HeartRateDay> = serde_json::from_value(serde_json::Value::Array(days))?;
+  let mut parsed_zones: HashMap<NaiveDate, HeartRateZones> = HashMap::new();
+
+  for day in days {
+    let date = NaiveDate::parse_from_str(&day.date_time, "%Y-%m-%d")
+      .map_err(|_| "Failed to parse date")?;
+
+    if let Some(zones) = HeartRateZones::from_zones(&day.value.heart_rate_zones) {
+      parsed_zones.insert(date, zones);
+    }
+  }
+
+  Ok(parsed_zones)
+}
+
+/// Exchanges a refresh token for a new access token and refresh token.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::RejectedToken` if Fitbit reports the refresh token as invalid, or another error if the
+/// request fails or the response is malformed.
+pub async fn refresh_token(client: &reqwest::Client, oauth_base_url: &str, refresh_token: &str, client_id: &str, client_secret: &str) -> Result<TokenResponse, FitbitError> {
+  let authorization = general_purpose::STANDARD_NO_PAD.encode(format!("{}:{}", client_id, client_secret).as_bytes());
+  let resp = client.post(endpoint(oauth_base_url, "oauth2/token"))
+    .form(&[
+      ("grant_type", "refresh_token"),
+      ("refresh_token", refresh_token),
+    ])
+    .header("authorization", format!("Basic {}", authorization))
+    .send()
+    .await?;
+
+  let resp = resp
+    .json::<FitbitResponse>()
+    .await;
+
+  let resp = match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::Refresh(data))) => data,
+    Ok(FitbitResponse::Error(e)) => {
+      if let Some(error_detail) = e.errors.first() {
+        // The refresh token was revoked, already used, or otherwise invalid, so refreshing again cannot succeed.
+        if error_detail.error_type == "invalid_grant" || error_detail.error_type == "invalid_token" {
+          return Err(FitbitError::RejectedToken);
+        }
+
+        return Err(FitbitError::FitbitApiError(error_detail.message.clone()));
+      }
+
+      return Err(FitbitError::ParsingError("Empty error list".to_string()));
+    },
+    Err(e) => return Err(FitbitError::ParsingError(e.to_string())),
+    _ => return Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  };
+
+  let data: TokenResponse = TokenResponse { access_token: resp.access_token, expires_in: resp.expires_in, refresh_token: resp.refresh_token, scope: resp.scope, token_type: resp.token_type, user_id: resp.user_id };
+
+  Ok(data)
+}
+
+/// Exchanges an OAuth authorization code for an access token and refresh token.
+/// 
+/// # Arguments
+/// 
+/// * `oauth_base_url` - The base URL of Fitbit's OAuth endpoints.
+/// * `code` - The authorization code returned to the redirect URI.
+/// * `client_id` - The Fitbit application's client ID.
+/// * `client_secret` - The Fitbit application's client secret.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::InvalidAuthorizationCode` if Fitbit rejects the code, or another error if the request fails or the response is malformed.
+pub async fn exchange_code(client: &reqwest::Client, oauth_base_url: &str, code: &str, client_id: &str, client_secret: &str) -> Result<TokenResponse, FitbitError> {
+  let authorization = general_purpose::STANDARD_NO_PAD.encode(format!("{}:{}", client_id, client_secret).as_bytes());
+  let resp = client.post(endpoint(oauth_base_url, "oauth2/token"))
+    .form(&[
+      ("client_id", client_id),
+      ("grant_type", "authorization_code"),
+      ("code", code),
+    ])
+    .header("authorization", format!("Basic {}", authorization))
+    .send()
+    .await?;
+
+  let resp = resp
+    .json::<FitbitResponse>()
+    .await;
+
+  match resp {
+    Ok(FitbitResponse::Success(FitbitSuccess::Refresh(data))) => Ok(data),
+    Ok(FitbitResponse::Error(e)) => {
+      if let Some(error_detail) = e.errors.first() {
+        if error_detail.error_type == "invalid_grant" {
+          return Err(FitbitError::InvalidAuthorizationCode(error_detail.message.clone()));
+        }
+
+        return Err(FitbitError::FitbitApiError(error_detail.message.clone()));
+      }
+
+      Err(FitbitError::ParsingError("Empty error list".to_string()))
+    },
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+    _ => Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  }
+}
+
+/// Revokes an access token (and its associated refresh token) at Fitbit.
+/// 
+/// # Arguments
+/// 
+/// * `oauth_base_url` - The base URL of Fitbit's OAuth endpoints.
+/// * `token` - The access token to revoke.
+/// * `client_id` - The Fitbit application's client ID.
+/// * `client_secret` - The Fitbit application's client secret.
+/// 
+/// # Errors
+/// 
+/// Returns an error if the request fails or if Fitbit rejects the revocation.
+pub async fn revoke_token(client: &reqwest::Client, oauth_base_url: &str, token: &str, client_id: &str, client_secret: &str) -> Result<(), FitbitError> {
+  let authorization = general_purpose::STANDARD_NO_PAD.encode(format!("{}:{}", client_id, client_secret).as_bytes());
+  let resp = client.post(endpoint(oauth_base_url, "oauth2/revoke"))
+    .form(&[
+      ("token", token),
+    ])
+    .header("authorization", format!("Basic {}", authorization))
+    .send()
+    .await?;
+
+  if resp.status().is_success() {
+    return Ok(());
+  }
+
+  let resp = resp
+    .json::<FitbitResponse>()
+    .await;
+
+  match resp {
+    Ok(FitbitResponse::Error(e)) => {
+      if let Some(error_detail) = e.errors.first() {
+        return Err(FitbitError::FitbitApiError(error_detail.message.clone()));
+      }
+
+      Err(FitbitError::ParsingError("Empty error list".to_string()))
+    },
+    Err(e) => Err(FitbitError::ParsingError(e.to_string())),
+    _ => Err(FitbitError::ParsingError("Failed to parse response".to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::UserTimezone;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  /// Serves one canned status per connection, in order, repeating the last one. Returns the base URL and a hit counter.
+  async fn serve_statuses(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+
+    tokio::spawn(async move {
+      loop {
+        let Ok((mut socket, _)) = listener.accept().await else {
+          return;
+        };
+
+        let hit = counter.fetch_add(1, Ordering::SeqCst);
+        let status = statuses[std::cmp::min(hit, statuses.len() - 1)];
+
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await;
+
+        let response = format!("HTTP/1.1 {} Status\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", status);
+        let _ = socket.write_all(response.as_bytes()).await;
+      }
+    });
+
+    (format!("http://{}", address), hits)
+  }
+
+  /// Accepts connections but never responds. Returns the base URL and a hit counter.
+  async fn serve_nothing() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+
+    tokio::spawn(async move {
+      let mut sockets = Vec::new();
+
+      while let Ok((socket, _)) = listener.accept().await {
+        counter.fetch_add(1, Ordering::SeqCst);
+        sockets.push(socket);
+      }
+    });
+
+    (format!("http://{}", address), hits)
+  }
+
+  fn retry() -> RetryConfig {
+    RetryConfig { max_retries: 3, base_delay: std::time::Duration::from_millis(1) }
+  }
+
+  #[tokio::test]
+  async fn retries_server_errors_until_success() {
+    let (url, hits) = serve_statuses(vec![503, 503, 200]).await;
+
+    let resp = get_with_retry(&reqwest::Client::new(), &url, "Bearer token", &retry()).await.unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+  }
+
+  #[tokio::test]
+  async fn timed_out_requests_are_http_errors() {
+    let (url, hits) = serve_nothing().await;
+
+    let client = reqwest::Client::builder().timeout(std::time::Duration::from_millis(50)).build().unwrap();
+    let retry = RetryConfig { max_retries: 1, base_delay: std::time::Duration::from_millis(1) };
+
+    let resp = get_with_retry(&client, &url, "Bearer token", &retry).await;
+
+    assert!(matches!(resp, Err(FitbitError::HttpRequestError(ref e)) if e.is_timeout()));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn refresh_token_surfaces_connection_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+
+    let result = refresh_token(&reqwest::Client::new(), &url, "refresh", "client", "secret").await;
+
+    assert!(matches!(result, Err(FitbitError::HttpRequestError(ref e)) if e.is_connect()));
+  }
+
+  #[tokio::test]
+  async fn gives_up_after_max_retries() {
+    let (url, hits) = serve_statuses(vec![503]).await;
+
+    let resp = get_with_retry(&reqwest::Client::new(), &url, "Bearer token", &retry()).await.unwrap();
+
+    assert_eq!(resp.status(), 503);
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+  }
+
+  #[tokio::test]
+  async fn surfaces_too_many_requests() {
+    let (url, hits) = serve_statuses(vec![429]).await;
+
+    let resp = get_with_retry(&reqwest::Client::new(), &url, "Bearer token", &retry()).await;
+
+    assert!(matches!(resp, Err(FitbitError::RateLimited(RateLimitInfo { remaining: None, limit: None, reset_seconds: None }))));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn rate_limit_info_reads_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("fitbit-rate-limit-remaining", "0".parse().unwrap());
+    headers.insert("fitbit-rate-limit-limit", "150".parse().unwrap());
+    headers.insert("fitbit-rate-limit-reset", "1200".parse().unwrap());
+
+    assert_eq!(RateLimitInfo::from_headers(&headers), RateLimitInfo { remaining: Some(0), limit: Some(150), reset_seconds: Some(1200) });
+
+    let mut headers = HeaderMap::new();
+    headers.insert("retry-after", "30".parse().unwrap());
+
+    assert_eq!(RateLimitInfo::from_headers(&headers), RateLimitInfo { remaining: None, limit: None, reset_seconds: Some(30) });
+  }
+
+  #[test]
+  fn rate_limit_info_tolerates_missing_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("fitbit-rate-limit-reset", "soon".parse().unwrap());
+
+    assert_eq!(RateLimitInfo::from_headers(&headers), RateLimitInfo { remaining: None, limit: None, reset_seconds: None });
+  }
+
+  #[tokio::test]
+  async fn does_not_retry_client_errors() {
+    let (url, hits) = serve_statuses(vec![401, 200]).await;
+
+    let resp = get_with_retry(&reqwest::Client::new(), &url, "Bearer token", &retry()).await.unwrap();
+
+    assert_eq!(resp.status(), 401);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn endpoint_joins_with_one_slash() {
+    assert_eq!(endpoint("https://api.fitbit.com", "oauth2/token"), "https://api.fitbit.com/oauth2/token");
+    assert_eq!(endpoint("https://api.fitbit.com/", "oauth2/token"), "https://api.fitbit.com/oauth2/token");
+    assert_eq!(endpoint("https://api.fitbit.com//", "/oauth2/token"), "https://api.fitbit.com/oauth2/token");
+    assert_eq!(endpoint("http://localhost:8080/sandbox/", "1/user"), "http://localhost:8080/sandbox/1/user");
+  }
+
+  #[tokio::test]
+  async fn get_steps_requests_the_users_timezone() {
+    let server = httpmock::MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/steps/date/2023-01-01/1w.json").query_param("timezone", "America/Los_Angeles");
+      then.status(200).body(r#"{"activities-steps":[{"dateTime":"2023-01-01","value":"1234"}]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let steps = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::Steps, date, Period::OneWeek, "America/Los_Angeles").await.unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(steps.values.get(&date), Some(&1234));
+  }
+
+  #[tokio::test]
+  async fn intraday_steps_requests_the_users_timezone() {
+    let server = httpmock::MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/steps/date/2023-01-01/1d/15min.json").query_param("timezone", "Europe/Paris");
+      then.status(200).body(r#"{"activities-steps":[{"dateTime":"2023-01-01","value":"120"}],"activities-steps-intraday":{"dataset":[{"time":"08:15:00","value":120}]}}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let (steps, _) = get_intraday_steps(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", date, Detail::FifteenMin, "Europe/Paris").await.unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(steps.get(&date.and_hms_opt(8, 15, 0).unwrap()), Some(&120));
+  }
+
+  #[tokio::test]
+  async fn distance_parses_decimals() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/distance/date/2023-01-02/1w.json");
+      then.status(200).body(r#"{"activities-distance":[{"dateTime":"2023-01-01","value":"5.2345"},{"dateTime":"2023-01-02","value":"0"}]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+    let distance = get_distance(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", date, Period::OneWeek, "UTC").await.unwrap();
+
+    assert_eq!(distance.values, HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 5.2345), (date, 0.0)]));
+  }
+
+  #[tokio::test]
+  async fn distance_without_a_value_is_a_parsing_error() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/distance/date/2023-01-02/1d.json");
+      then.status(200).body(r#"{"activities-distance":[{"dateTime":"2023-01-02"}]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+    let result = get_distance(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", date, Period::OneDay, "UTC").await;
+
+    assert!(matches!(result, Err(FitbitError::ParsingError(message)) if message == "Missing value"));
+  }
+
+  #[tokio::test]
+  async fn calories_parse_like_steps() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/calories/date/2023-01-02/1w.json");
+      then.status(200).body(r#"{"activities-calories":[{"dateTime":"2023-01-01","value":"2345"},{"dateTime":"2023-01-02","value":"1890"}]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+    let calories = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::Calories, date, Period::OneWeek, "UTC").await.unwrap();
+
+    assert_eq!(calories.values, HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 2345), (date, 1890)]));
+  }
+
+  #[tokio::test]
+  async fn floors_skip_days_without_data() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/floors/date/2023-01-03/1w.json");
+      then.status(200).body(r#"{"activities-floors":[{"dateTime":"2023-01-01","value":"12"},{"dateTime":"2023-01-02","value":"0"},{"dateTime":"2023-01-03","value":""},{"dateTime":"2023-01-04"}]}"#);
+    }).await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/floors/date/2023-01-03/1d.json");
+      then.status(200).body(r#"{}"#);
+    }).await;
+
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+    let floors = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::Floors, date(3), Period::OneWeek, "UTC").await.unwrap();
+
+    assert_eq!(floors.values, HashMap::from([(date(1), 12), (date(2), 0)]));
+
+    let floors = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::Floors, date(3), Period::OneDay, "UTC").await.unwrap();
+
+    assert!(floors.values.is_empty());
+  }
+
+  #[tokio::test]
+  async fn heart_rate_skips_days_without_resting_rate() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/heart/date/2023-01-02/1w.json");
+      then.status(200).body(r#"{"activities-heart":[{"dateTime":"2023-01-01","value":{"restingHeartRate":61}},{"dateTime":"2023-01-02","value":{"heartRateZones":[]}}]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+    let heart_rate = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::HeartRate, date, Period::OneWeek, "UTC").await.unwrap();
+
+    assert_eq!(heart_rate.values, HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 61)]));
+  }
+
+  #[tokio::test]
+  async fn heart_rate_zones_skip_days_without_zones() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/heart/date/2023-01-03/1w.json");
+      then.status(200).body(r#"{"activities-heart":[
+        {"dateTime":"2023-01-01","value":{"customHeartRateZones":[],"heartRateZones":[
+          {"caloriesOut":1733.5,"max":94,"min":30,"minutes":1338,"name":"Out of Range"},
+          {"caloriesOut":439.2,"max":132,"min":94,"minutes":86,"name":"Fat Burn"},
+          {"caloriesOut":120.6,"max":160,"min":132,"minutes":12,"name":"Cardio"},
+          {"caloriesOut":0,"max":220,"min":160,"minutes":0,"name":"Peak"}
+        ],"restingHeartRate":61}},
+        {"dateTime":"2023-01-02","value":{"heartRateZones":[
+          {"max":94,"min":30,"name":"Out of Range"},
+          {"max":132,"min":94,"name":"Fat Burn"},
+          {"max":160,"min":132,"name":"Cardio"},
+          {"max":220,"min":160,"name":"Peak"}
+        ]}},
+        {"dateTime":"2023-01-03","value":{"heartRateZones":[]}}
+      ]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 3).unwrap();
+    let zones = get_heart_rate_zones(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", date, Period::OneWeek, "UTC").await.unwrap();
+
+    assert_eq!(zones.values, HashMap::from([
+      (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), HeartRateZones { out_of_range: 1338, fat_burn: 86, cardio: 12, peak: 0 }),
+      (NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(), HeartRateZones::default()),
+    ]));
+  }
+
+  #[tokio::test]
+  async fn time_series_carries_rate_limit() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/steps/date/2023-01-01/1d.json");
+      then.status(200)
+        .header("Fitbit-Rate-Limit-Remaining", "144")
+        .header("Fitbit-Rate-Limit-Reset", "1800")
+        .body(r#"{"activities-steps":[{"dateTime":"2023-01-01","value":"10"}]}"#);
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let steps = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::Steps, date, Period::OneDay, "UTC").await.unwrap();
+
+    assert_eq!(steps.rate_limit, RateLimitInfo { remaining: Some(144), limit: None, reset_seconds: Some(1800) });
+  }
+
+  #[tokio::test]
+  async fn time_series_revalidates_with_etag() {
+    let server = httpmock::MockServer::start_async().await;
+    let not_modified = server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/steps/date/2023-01-01/1d.json")
+        .header("If-None-Match", "\"v1\"");
+      then.status(304)
+        .header("Fitbit-Rate-Limit-Remaining", "143");
+    }).await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/steps/date/2023-01-01/1d.json");
+      then.status(200)
+        .header("ETag", "\"v1\"")
+        .body(r#"{"activities-steps":[{"dateTime":"2023-01-01","value":"10"}]}"#);
+    }).await;
+
+    let (client, retry, base_url) = (reqwest::Client::new(), retry(), server.base_url());
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let fetch = |etag| get_time_series_if_none_match(&client, &retry, &base_url, "USER", "token", Resource::Steps, date, Period::OneDay, "UTC", etag);
+
+    let Conditional::Modified(steps, etag) = fetch(None).await.unwrap() else {
+      panic!("expected the steps");
+    };
+
+    assert_eq!(steps.values, HashMap::from([(date, 10)]));
+    assert_eq!(etag.as_deref(), Some("\"v1\""));
+
+    let revalidated = fetch(etag.as_deref()).await.unwrap();
+
+    assert_eq!(revalidated, Conditional::NotModified(RateLimitInfo { remaining: Some(143), limit: None, reset_seconds: None }));
+    not_modified.assert_async().await;
+  }
+
+  #[tokio::test]
+  async fn get_profile_reads_profile() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/profile.json");
+      then.status(200).body(r#"{"user":{"displayName":"User","avatar":"https://static0.fitbit.com/images/profile/defaultProfile_100.png","memberSince":"2019-04-02","timezone":"America/Los_Angeles","offsetFromUTCMillis":-25200000}}"#);
+    }).await;
+
+    let (profile, _) = get_profile(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token").await.unwrap();
+
+    assert_eq!(profile.display_name, "User");
+    assert_eq!(profile.avatar, "https://static0.fitbit.com/images/profile/defaultProfile_100.png");
+    assert_eq!(profile.member_since, "2019-04-02");
+    assert_eq!(UserTimezone::from(&profile), UserTimezone { name: "America/Los_Angeles".to_string(), utc_offset_seconds: -25200 });
+  }
+
+  #[tokio::test]
+  async fn get_profile_tolerates_hidden_fields() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/profile.json");
+      then.status(200).body(r#"{"user":{"timezone":"UTC","offsetFromUTCMillis":0}}"#);
+    }).await;
+
+    let (profile, _) = get_profile(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token").await.unwrap();
+
+    assert_eq!(profile, Profile { timezone: "UTC".to_string(), ..Default::default() });
+  }
+
+  #[tokio::test]
+  async fn get_goals_reads_daily_goals() {
+    let server = httpmock::MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/goals/daily.json").header("Authorization", "Bearer token");
+      then.status(200).body(r#"{"goals":{"activeMinutes":30,"caloriesOut":2500,"distance":8.05,"steps":10000}}"#);
+    }).await;
+
+    let (goals, _) = get_goals(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token").await.unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(goals, Goals { steps: Some(10000), distance: Some(8.05), calories: Some(2500), floors: None });
+  }
+
+  #[tokio::test]
+  async fn get_devices_reads_devices() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/devices.json").header("Authorization", "Bearer token");
+      then.status(200).body(r#"[
+        {"battery":"High","batteryLevel":80,"deviceVersion":"Charge 5","id":"816713257","lastSyncTime":"2023-01-01T11:18:15.000","type":"TRACKER"},
+        {"deviceVersion":"Aria","id":"ARIA1","lastSyncTime":"2022-12-30T07:02:00.000","type":"SCALE"}
+      ]"#);
+    }).await;
+
+    let (devices, _) = get_devices(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token").await.unwrap();
+
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0].device_type, "TRACKER");
+    assert_eq!(devices[0].battery_level, Some(80));
+    assert_eq!(devices[0].last_sync(), NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(11, 18, 15));
+    assert_eq!(devices[1].battery_level, None);
+  }
+
+  #[tokio::test]
+  async fn set_step_goal_posts_the_goal() {
+    let server = httpmock::MockServer::start_async().await;
+    let mock = server.mock_async(|when, then| {
+      when.method(httpmock::Method::POST)
+        .path("/1/user/USER/activities/goals/daily.json")
+        .query_param("period", "daily")
+        .query_param("type", "steps")
+        .query_param("value", "12000")
+        .header("Authorization", "Bearer token");
+      then.status(201).body(r#"{"goals":{"steps":12000}}"#);
+    }).await;
+
+    let (goal, _) = set_step_goal(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", 12000).await.unwrap();
+
+    mock.assert_async().await;
+    assert_eq!(goal, 12000);
+  }
+
+  #[tokio::test]
+  async fn set_step_goal_needs_activity_scope() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.method(httpmock::Method::POST).path("/1/user/USER/activities/goals/daily.json");
+      then.status(403).body(error_body("insufficient_scope"));
+    }).await;
+
+    let result = set_step_goal(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", 12000).await;
+
+    assert!(matches!(result, Err(FitbitError::InsufficientScope(scope)) if scope == "activity"));
+  }
+
+  #[tokio::test]
+  async fn spo2_reads_a_range() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/spo2/date/2023-01-01/2023-01-03.json");
+      then.status(200).body(r#"[{"dateTime":"2023-01-01","value":{"avg":97.5,"min":94.0,"max":100.0}},{"dateTime":"2023-01-03","value":{"avg":96.1,"min":92.3,"max":99.0}}]"#);
+    }).await;
+
+    let start = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(2023, 1, 3).unwrap();
+
+    let (spo2, _) = get_spo2(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", start, end).await.unwrap();
+
+    assert_eq!(spo2, HashMap::from([
+      (start, Spo2Summary { avg: 97.5, min: 94.0, max: 100.0 }),
+      (end, Spo2Summary { avg: 96.1, min: 92.3, max: 99.0 }),
+    ]));
+  }
+
+  #[tokio::test]
+  async fn cardio_score_reads_single_values() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/cardioscore/date/2023-01-01/2023-01-03.json");
+      then.status(200).body(r#"{"cardioScore":[{"dateTime":"2023-01-01","value":{"vo2Max":"45.3"}},{"dateTime":"2023-01-03","value":{"vo2Max":46}}]}"#);
+    }).await;
+
+    let start = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(2023, 1, 3).unwrap();
+
+    let (scores, _) = get_cardio_score(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", start, end).await.unwrap();
+
+    assert_eq!(scores, HashMap::from([
+      (start, CardioScore { low: 45.3, high: 45.3 }),
+      (end, CardioScore { low: 46.0, high: 46.0 }),
+    ]));
+  }
+
+  #[tokio::test]
+  async fn cardio_score_reads_ranges() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/cardioscore/date/2023-01-01/2023-01-02.json");
+      then.status(200).body(r#"{"cardioScore":[{"dateTime":"2023-01-01","value":{"vo2Max":"40-44"}},{"dateTime":"2023-01-02","value":{"vo2Max":"41-45"}}]}"#);
+    }).await;
+
+    let start = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+
+    let (scores, _) = get_cardio_score(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", start, end).await.unwrap();
+
+    assert_eq!(scores, HashMap::from([
+      (start, CardioScore { low: 40.0, high: 44.0 }),
+      (end, CardioScore { low: 41.0, high: 45.0 }),
+    ]));
+
+    assert_eq!(CardioScore::parse("40-44"), Some(CardioScore { low: 40.0, high: 44.0 }));
+    assert_eq!(CardioScore::parse(" 40 - 44 "), Some(CardioScore { low: 40.0, high: 44.0 }));
+    assert_eq!(CardioScore::parse("44-40"), None);
+    assert_eq!(CardioScore::parse("high"), None);
+  }
+
+  #[tokio::test]
+  async fn cardio_score_needs_cardio_fitness_scope() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/cardioscore/date/2023-01-01/2023-01-03.json");
+      then.status(403).body(error_body("insufficient_scope"));
+    }).await;
+
+    let start = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(2023, 1, 3).unwrap();
+
+    let result = get_cardio_score(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", start, end).await;
+
+    assert!(matches!(result, Err(FitbitError::InsufficientScope(scope)) if scope == "cardio_fitness"));
+  }
+
+  #[tokio::test]
+  async fn weight_keeps_every_log_of_a_day() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/body/log/weight/date/2023-01-01/2023-01-02.json");
+      then.status(200).body(r#"{"weight":[
+        {"bmi":23.57,"date":"2023-01-01","fat":14.5,"logId":1672610400000,"source":"Aria","time":"22:00:00","weight":73.1},
+        {"bmi":23.5,"date":"2023-01-01","logId":1672563600000,"source":"API","time":"09:00:00","weight":72.8},
+        {"bmi":23.4,"date":"2023-01-02","logId":1672650000000,"source":"API","time":"09:00:00","weight":72.6}
+      ]}"#);
+    }).await;
+
+    let start = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+    let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0);
+
+    let (logs, _) = get_weight(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", start, end).await.unwrap();
+
+    assert_eq!(logs, vec![
+      WeightLog { date: start, time: time(9), weight: 72.8, fat: None },
+      WeightLog { date: start, time: time(22), weight: 73.1, fat: Some(14.5) },
+      WeightLog { date: end, time: time(9), weight: 72.6, fat: None },
+    ]);
+  }
+
+  #[tokio::test]
+  async fn spo2_needs_oxygen_saturation_scope() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/spo2/date/2023-01-01/2023-01-03.json");
+      then.status(403).body(error_body("insufficient_permissions"));
+    }).await;
+
+    let start = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(2023, 1, 3).unwrap();
+
+    let result = get_spo2(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", start, end).await;
+
+    assert!(matches!(result, Err(FitbitError::InsufficientScope(scope)) if scope == "oxygen_saturation"));
+  }
+
+  /// A Fitbit error body with a single error of the given type.
+  fn error_body(error_type: &str) -> String {
+    format!(r#"{{"errors":[{{"errorType":"{}","message":"Access token invalid"}}],"success":false}}"#, error_type)
+  }
+
+  #[tokio::test]
+  async fn time_series_maps_token_errors() {
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+
+    for (error_type, expired, rejected) in [("expired_token", true, false), ("invalid_token", false, true), ("system", false, false)] {
+      let server = httpmock::MockServer::start_async().await;
+      server.mock_async(|when, then| {
+        when.path("/1/user/USER/activities/steps/date/2023-01-01/1d.json");
+        then.status(401).body(error_body(error_type));
+      }).await;
+
+      let result = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::Steps, date, Period::OneDay, "UTC").await;
+
+      assert_eq!(matches!(result, Err(FitbitError::ExpiredToken)), expired, "{}", error_type);
+      assert_eq!(matches!(result, Err(FitbitError::RejectedToken)), rejected, "{}", error_type);
+    }
+  }
+
+  #[tokio::test]
+  async fn heart_rate_scope_is_reported() {
+    let server = httpmock::MockServer::start_async().await;
+    server.mock_async(|when, then| {
+      when.path("/1/user/USER/activities/heart/date/2023-01-01/1d.json");
+      then.status(403).body(error_body("insufficient_scope"));
+    }).await;
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let result = get_time_series(&reqwest::Client::new(), &retry(), &server.base_url(), "USER", "token", Resource::HeartRate, date, Period::OneDay, "UTC").await;
+
+    assert!(matches!(result, Err(FitbitError::InsufficientScope(scope)) if scope == "heartrate"));
+  }
+
+  #[test]
+  fn api_error_maps_the_first_error() {
+    let error = |error_type: &str| ErrorDetail { error_type: error_type.to_string(), message: "message".to_string() };
+
+    assert!(matches!(api_error(&[error("expired_token"), error("invalid_token")], "profile"), FitbitError::ExpiredToken));
+    assert!(matches!(api_error(&[error("invalid_token")], "profile"), FitbitError::RejectedToken));
+    assert!(matches!(api_error(&[error("insufficient_permissions")], "profile"), FitbitError::InsufficientScope(scope) if scope == "profile"));
+    assert!(matches!(api_error(&[error("system")], "profile"), FitbitError::FitbitApiError(message) if message == "message"));
+    assert!(matches!(api_error(&[], "profile"), FitbitError::ParsingError(_)));
+  }
+
+  #[tokio::test]
+  async fn refresh_token_rejects_dead_tokens() {
+    for (error_type, rejected) in [("invalid_grant", true), ("invalid_token", true), ("invalid_client", false)] {
+      let server = httpmock::MockServer::start_async().await;
+      server.mock_async(|when, then| {
+        when.method(httpmock::Method::POST).path("/oauth2/token");
+        then.status(400).body(error_body(error_type));
+      }).await;
+
+      let result = refresh_token(&reqwest::Client::new(), &server.base_url(), "refresh", "client", "secret").await;
+
+      assert_eq!(matches!(result, Err(FitbitError::RejectedToken)), rejected, "{}", error_type);
+    }
+  }
+
+  #[tokio::test]
+  async fn get_steps_uses_base_url() {
+    let (url, hits) = serve_statuses(vec![401]).await;
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+
+    let result = get_time_series(&reqwest::Client::new(), &retry(), &format!("{}/", url), "USER", "token", Resource::Steps, date, Period::OneWeek, "UTC").await;
+
+    // The canned response has no body, so reaching the server is as far as this gets.
+    assert!(matches!(result, Err(FitbitError::ParsingError(_))));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+  }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+use chrono::{NaiveDate, NaiveDateTime};
+use reqwest::header::HeaderMap;
+use crate::models::{Conditional, Period, Detail, Resource, RetryConfig, TimeSeriesResult, TokenResponse, Profile, Goals, Device, Spo2Summary, CardioScore, HeartRateZones, WeightLog};
+use crate::errors::FitbitError;
+use super::api;
+
+/// The Fitbit Web API, as used by `Fitbit`. Abstracting over it lets the orchestration logic be
+/// exercised against canned responses instead of `api.fitbit.com`.
+pub trait FitbitApi: Clone + Send + Sync + 'static {
+  /// Gets the daily values of a time series for the period ending on `date`, with days in the given timezone, along
+  /// with the rate limit state reported with them.
+  fn get_time_series(&self, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str) -> impl Future<Output = Result<TimeSeriesResult, FitbitError>> + Send;
+
+  /// Gets the daily values of a time series as in `get_time_series`, sending `etag`, if any, as `If-None-Match`, and
+  /// returns either the fresh values with the ETag Fitbit sent with them or word that they have not changed.
+  #[allow(clippy::too_many_arguments)]
+  fn get_time_series_if_none_match(&self, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str, etag: Option<&str>) -> impl Future<Output = Result<Conditional<TimeSeriesResult>, FitbitError>> + Send;
+
+  /// Gets the distance covered each day, in kilometers, for the period ending on `date`, with days in the given
+  /// timezone, along with the rate limit state reported with them.
+  fn get_distance(&self, user_id: &str, access_token: &str, date: NaiveDate, period: Period, timezone: &str) -> impl Future<Output = Result<TimeSeriesResult<f64>, FitbitError>> + Send;
+
+  /// Gets the minutes spent in each heart rate zone each day for the period ending on `date`, with days in the given
+  /// timezone, along with the rate limit state reported with them.
+  fn get_heart_rate_zones(&self, user_id: &str, access_token: &str, date: NaiveDate, period: Period, timezone: &str) -> impl Future<Output = Result<TimeSeriesResult<HeartRateZones>, FitbitError>> + Send;
+
+  /// Gets the user's nightly SpO2 summaries from `start` to `end`, inclusive, along with the response headers.
+  fn get_spo2(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> impl Future<Output = Result<(HashMap<NaiveDate, Spo2Summary>, HeaderMap), FitbitError>> + Send;
+
+  /// Gets the user's daily cardio fitness scores from `start` to `end`, inclusive, along with the response headers.
+  fn get_cardio_score(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> impl Future<Output = Result<(HashMap<NaiveDate, CardioScore>, HeaderMap), FitbitError>> + Send;
+
+  /// Gets the weights the user logged from `start` to `end`, inclusive, in kilograms, along with the response headers.
+  fn get_weight(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> impl Future<Output = Result<(Vec<WeightLog>, HeaderMap), FitbitError>> + Send;
+
+  /// Gets intraday step counts for a single day in the given timezone, along with the response headers.
+  fn get_intraday_steps(&self, user_id: &str, access_token: &str, date: NaiveDate, detail: Detail, timezone: &str) -> impl Future<Output = Result<(HashMap<NaiveDateTime, u32>, HeaderMap), FitbitError>> + Send;
+
+  /// Gets the user's profile, along with the response headers.
+  fn get_profile(&self, user_id: &str, access_token: &str) -> impl Future<Output = Result<(Profile, HeaderMap), FitbitError>> + Send;
+
+  /// Gets the user's daily activity goals, along with the response headers.
+  fn get_goals(&self, user_id: &str, access_token: &str) -> impl Future<Output = Result<(Goals, HeaderMap), FitbitError>> + Send;
+
+  /// Gets the devices paired with the user's account, along with the response headers.
+  fn get_devices(&self, user_id: &str, access_token: &str) -> impl Future<Output = Result<(Vec<Device>, HeaderMap), FitbitError>> + Send;
+
+  /// Sets the user's daily step goal, returning the goal Fitbit stored along with the response headers.
+  fn set_step_goal(&self, user_id: &str, access_token: &str, goal: u32) -> impl Future<Output = Result<(u32, HeaderMap), FitbitError>> + Send;
+
+  /// Exchanges a refresh token for a new access token and refresh token.
+  fn refresh_token(&self, refresh_token: &str, client_id: &str, client_secret: &str) -> impl Future<Output = Result<TokenResponse, FitbitError>> + Send;
+
+  /// Exchanges an OAuth authorization code for tokens.
+  fn exchange_code(&self, code: &str, client_id: &str, client_secret: &str) -> impl Future<Output = Result<TokenResponse, FitbitError>> + Send;
+
+  /// Revokes a token at Fitbit.
+  fn revoke_token(&self, token: &str, client_id: &str, client_secret: &str) -> impl Future<Output = Result<(), FitbitError>> + Send;
+}
+
+/// How long a request to Fitbit may take, from connecting to reading the body, unless `FITBIT_REQUEST_TIMEOUT_SECS` is set.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long connecting to Fitbit may take, unless `FITBIT_CONNECT_TIMEOUT_SECS` is set.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts building the HTTP client for `HttpFitbitApi`, with the request and connect timeouts read from
+/// `FITBIT_REQUEST_TIMEOUT_SECS` and `FITBIT_CONNECT_TIMEOUT_SECS`. Without a timeout a hung connection holds its
+/// command's concurrency permit forever. A request that times out fails with `FitbitError::HttpRequestError`.
+/// 
+/// The builder can be adjusted further before building, for example to override a timeout or add a proxy.
+pub fn client_builder() -> reqwest::ClientBuilder {
+  let timeout = |name: &str, default: Duration| {
+    env::var(name).ok()
+      .and_then(|seconds| seconds.parse::<u64>().ok())
+      .map(Duration::from_secs)
+      .unwrap_or(default)
+  };
+
+  reqwest::Client::builder()
+    .timeout(timeout("FITBIT_REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT))
+    .connect_timeout(timeout("FITBIT_CONNECT_TIMEOUT_SECS", DEFAULT_CONNECT_TIMEOUT))
+}
+
+/// The real Fitbit Web API, reached over HTTP with reqwest.
+#[derive(Clone)]
+pub struct HttpFitbitApi {
+  client: reqwest::Client,
+  retry: RetryConfig,
+  api_base_url: String,
+  oauth_base_url: String,
+}
+
+impl HttpFitbitApi {
+  pub fn new(client: reqwest::Client, retry: RetryConfig) -> Self {
+    Self::with_base_urls(client, retry, api::DEFAULT_API_BASE_URL, api::DEFAULT_OAUTH_BASE_URL)
+  }
+
+  /// Creates a client that sends requests to the given base URLs instead of Fitbit's production API,
+  /// e.g. a local mock server or Fitbit's sandbox. Trailing slashes are ignored.
+  pub fn with_base_urls(client: reqwest::Client, retry: RetryConfig, api_base_url: &str, oauth_base_url: &str) -> Self {
+    Self {
+      client,
+      retry,
+      api_base_url: api_base_url.trim_end_matches('/').to_string(),
+      oauth_base_url: oauth_base_url.trim_end_matches('/').to_string(),
+    }
+  }
+}
+
+impl FitbitApi for HttpFitbitApi {
+  async fn get_time_series(&self, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str) -> Result<TimeSeriesResult, FitbitError> {
+    api::get_time_series(&self.client, &self.retry, &self.api_base_url, user_id, access_token, resource, date, period, timezone).await
+  }
+
+  async fn get_time_series_if_none_match(&self, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str, etag: Option<&str>) -> Result<Conditional<TimeSeriesResult>, FitbitError> {
+    api::get_time_series_if_none_match(&self.client, &self.retry, &self.api_base_url, user_id, access_token, resource, date, period, timezone, etag).await
+  }
+
+  async fn get_distance(&self, user_id: &str, access_token: &str, date: NaiveDate, period: Period, timezone: &str) -> Result<TimeSeriesResult<f64>, FitbitError> {
+    api::get_distance(&self.client, &self.retry, &self.api_base_url, user_id, access_token, date, period, timezone).await
+  }
+
+  async fn get_heart_rate_zones(&self, user_id: &str, access_token: &str, date: NaiveDate, period: Period, timezone: &str) -> Result<TimeSeriesResult<HeartRateZones>, FitbitError> {
+    api::get_heart_rate_zones(&self.client, &self.retry, &self.api_base_url, user_id, access_token, date, period, timezone).await
+  }
+
+  async fn get_spo2(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<(HashMap<NaiveDate, Spo2Summary>, HeaderMap), FitbitError> {
+    api::get_spo2(&self.client, &self.retry, &self.api_base_url, user_id, access_token, start, end).await
+  }
+
+  async fn get_cardio_score(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<(HashMap<NaiveDate, CardioScore>, HeaderMap), FitbitError> {
+    api::get_cardio_score(&self.client, &self.retry, &self.api_base_url, user_id, access_token, start, end).await
+  }
+
+  async fn get_weight(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<(Vec<WeightLog>, HeaderMap), FitbitError> {
+    api::get_weight(&self.client, &self.retry, &self.api_base_url, user_id, access_token, start, end).await
+  }
+
+  async fn get_intraday_steps(&self, user_id: &str, access_token: &str, date: NaiveDate, detail: Detail, timezone: &str) -> Result<(HashMap<NaiveDateTime, u32>, HeaderMap), FitbitError> {
+    api::get_intraday_steps(&self.client, &self.retry, &self.api_base_url, user_id, access_token, date, detail, timezone).await
+  }
+
+  async fn get_profile(&self, user_id: &str, access_token: &str) -> Result<(Profile, HeaderMap), FitbitError> {
+    api::get_profile(&self.client, &self.retry, &self.api_base_url, user_id, access_token).await
+  }
+
+  async fn get_goals(&self, user_id: &str, access_token: &str) -> Result<(Goals, HeaderMap), FitbitError> {
+    api::get_goals(&self.client, &self.retry, &self.api_base_url, user_id, access_token).await
+  }
+
+  async fn get_devices(&self, user_id: &str, access_token: &str) -> Result<(Vec<Device>, HeaderMap), FitbitError> {
+    api::get_devices(&self.client, &self.retry, &self.api_base_url, user_id, access_token).await
+  }
+
+  async fn set_step_goal(&self, user_id: &str, access_token: &str, goal: u32) -> Result<(u32, HeaderMap), FitbitError> {
+    api::set_step_goal(&self.client, &self.retry, &self.api_base_url, user_id, access_token, goal).await
+  }
+
+  async fn refresh_token(&self, refresh_token: &str, client_id: &str, client_secret: &str) -> Result<TokenResponse, FitbitError> {
+    api::refresh_token(&self.client, &self.oauth_base_url, refresh_token, client_id, client_secret).await
+  }
+
+  async fn exchange_code(&self, code: &str, client_id: &str, client_secret: &str) -> Result<TokenResponse, FitbitError> {
+    api::exchange_code(&self.client, &self.oauth_base_url, code, client_id, client_secret).await
+  }
+
+  async fn revoke_token(&self, token: &str, client_id: &str, client_secret: &str) -> Result<(), FitbitError> {
+    api::revoke_token(&self.client, &self.oauth_base_url, token, client_id, client_secret).await
+  }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chrono::{NaiveDate, NaiveDateTime};
+use reqwest::header::HeaderMap;
+use crate::models::{Conditional, Period, Detail, RateLimitInfo, Resource, TimeSeriesResult, TokenResponse, Profile, Goals, Device, Spo2Summary, CardioScore, HeartRateZones, WeightLog};
+use crate::errors::FitbitError;
+use super::FitbitApi;
+
+/// A `FitbitApi` that serves canned step counts and records the access token of every data request.
+#[derive(Clone, Default)]
+pub struct MockFitbitApi {
+  steps: HashMap<NaiveDate, u32>,
+  time_series: Vec<(Resource, HashMap<NaiveDate, u32>)>,
+  distance: HashMap<NaiveDate, f64>,
+  heart_rate_zones: HashMap<NaiveDate, HeartRateZones>,
+  profile: Profile,
+  goals: Goals,
+  devices: Vec<Device>,
+  expired_tokens: Vec<String>,
+  rate_limit: Option<RateLimitInfo>,
+  etag: Option<String>,
+  refreshed_token: String,
+  requests: Arc<Mutex<Vec<String>>>,
+  refreshes: Arc<AtomicUsize>,
+}
+
+impl MockFitbitApi {
+  pub fn new() -> Self {
+    Self {
+      refreshed_token: "refreshed".to_string(),
+      ..Default::default()
+    }
+  }
+
+  pub fn with_steps(mut self, steps: HashMap<NaiveDate, u32>) -> Self {
+    self.steps = steps;
+    self
+  }
+
+  /// Values served for a time series other than steps, which are set with `with_steps`.
+  pub fn with_time_series(mut self, resource: Resource, values: HashMap<NaiveDate, u32>) -> Self {
+    self.time_series.push((resource, values));
+    self
+  }
+
+  /// Distances in kilometers.
+  pub fn with_distance(mut self, distance: HashMap<NaiveDate, f64>) -> Self {
+    self.distance = distance;
+    self
+  }
+
+  pub fn with_heart_rate_zones(mut self, zones: HashMap<NaiveDate, HeartRateZones>) -> Self {
+    self.heart_rate_zones = zones;
+    self
+  }
+
+  pub fn with_profile(mut self, profile: Profile) -> Self {
+    self.profile = profile;
+    self
+  }
+
+  pub fn with_goals(mut self, goals: Goals) -> Self {
+    self.goals = goals;
+    self
+  }
+
+  pub fn with_devices(mut self, devices: Vec<Device>) -> Self {
+    self.devices = devices;
+    self
+  }
+
+  /// Data requests made with `token` fail with `FitbitError::ExpiredToken`.
+  pub fn with_expired_token(mut self, token: &str) -> Self {
+    self.expired_tokens.push(token.to_string());
+    self
+  }
+
+  /// Every data request fails with `FitbitError::RateLimited`.
+  pub fn rate_limited(mut self, info: RateLimitInfo) -> Self {
+    self.rate_limit = Some(info);
+    self
+  }
+
+  /// Time series are sent with `etag`, and conditional requests that send it back are answered as not modified.
+  pub fn with_etag(mut self, etag: &str) -> Self {
+    self.etag = Some(etag.to_string());
+    self
+  }
+
+  /// The access tokens of the data requests made so far, in order.
+  pub fn requests(&self) -> Vec<String> {
+    self.requests.lock().unwrap().clone()
+  }
+
+  /// The number of token refreshes made so far.
+  pub fn refreshes(&self) -> usize {
+    self.refreshes.load(Ordering::SeqCst)
+  }
+
+  fn respond<K: Clone + Eq + std::hash::Hash>(&self, access_token: &str, values: &HashMap<K, u32>) -> Result<(HashMap<K, u32>, HeaderMap), FitbitError> {
+    self.requests.lock().unwrap().push(access_token.to_string());
+
+    if let Some(info) = self.rate_limit {
+      return Err(FitbitError::RateLimited(info));
+    }
+
+    if self.expired_tokens.iter().any(|token| token == access_token) {
+      return Err(FitbitError::ExpiredToken);
+    }
+
+    Ok((values.clone(), HeaderMap::new()))
+  }
+
+  fn token(&self) -> TokenResponse {
+    TokenResponse {
+      access_token: self.refreshed_token.clone(),
+      expires_in: 28800,
+      refresh_token: format!("{}-refresh", self.refreshed_token),
+      scope: "activity heartrate".to_string(),
+      token_type: "Bearer".to_string(),
+      user_id: "MOCK".to_string(),
+    }
+  }
+}
+
+impl FitbitApi for MockFitbitApi {
+  async fn get_time_series(&self, _user_id: &str, access_token: &str, resource: Resource, _date: NaiveDate, _period: Period, _timezone: &str) -> Result<TimeSeriesResult, FitbitError> {
+    let (values, _) = match resource {
+      Resource::Steps => self.respond(access_token, &self.steps)?,
+      Resource::HeartRate | Resource::Calories | Resource::Floors | Resource::MinutesFairlyActive | Resource::MinutesVeryActive => {
+        let values = self.time_series.iter().find(|(served, _)| *served == resource).map(|(_, values)| values.clone());
+
+        self.respond(access_token, &values.unwrap_or_default())?
+      },
+    };
+
+    Ok(TimeSeriesResult { values, rate_limit: RateLimitInfo::default() })
+  }
+
+  async fn get_time_series_if_none_match(&self, user_id: &str, access_token: &str, resource: Resource, date: NaiveDate, period: Period, timezone: &str, etag: Option<&str>) -> Result<Conditional<TimeSeriesResult>, FitbitError> {
+    if etag.is_some() && etag == self.etag.as_deref() {
+      self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+      return Ok(Conditional::NotModified(RateLimitInfo::default()));
+    }
+
+    let result = self.get_time_series(user_id, access_token, resource, date, period, timezone).await?;
+
+    Ok(Conditional::Modified(result, self.etag.clone()))
+  }
+
+  async fn get_distance(&self, _user_id: &str, access_token: &str, _date: NaiveDate, _period: Period, _timezone: &str) -> Result<TimeSeriesResult<f64>, FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok(TimeSeriesResult { values: self.distance.clone(), rate_limit: RateLimitInfo::default() })
+  }
+
+  async fn get_heart_rate_zones(&self, _user_id: &str, access_token: &str, _date: NaiveDate, _period: Period, _timezone: &str) -> Result<TimeSeriesResult<HeartRateZones>, FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok(TimeSeriesResult { values: self.heart_rate_zones.clone(), rate_limit: RateLimitInfo::default() })
+  }
+
+  async fn get_spo2(&self, _user_id: &str, access_token: &str, _start: NaiveDate, _end: NaiveDate) -> Result<(HashMap<NaiveDate, Spo2Summary>, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((HashMap::new(), HeaderMap::new()))
+  }
+
+  async fn get_cardio_score(&self, _user_id: &str, access_token: &str, _start: NaiveDate, _end: NaiveDate) -> Result<(HashMap<NaiveDate, CardioScore>, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((HashMap::new(), HeaderMap::new()))
+  }
+
+  async fn get_weight(&self, _user_id: &str, access_token: &str, _start: NaiveDate, _end: NaiveDate) -> Result<(Vec<WeightLog>, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((Vec::new(), HeaderMap::new()))
+  }
+
+  async fn get_intraday_steps(&self, _user_id: &str, access_token: &str, _date: NaiveDate, _detail: Detail, _timezone: &str) -> Result<(HashMap<NaiveDateTime, u32>, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::new())
+  }
+
+  async fn get_profile(&self, _user_id: &str, access_token: &str) -> Result<(Profile, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((self.profile.clone(), HeaderMap::new()))
+  }
+
+  async fn get_goals(&self, _user_id: &str, access_token: &str) -> Result<(Goals, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((self.goals.clone(), HeaderMap::new()))
+  }
+
+  async fn get_devices(&self, _user_id: &str, access_token: &str) -> Result<(Vec<Device>, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((self.devices.clone(), HeaderMap::new()))
+  }
+
+  async fn set_step_goal(&self, _user_id: &str, access_token: &str, goal: u32) -> Result<(u32, HeaderMap), FitbitError> {
+    self.respond(access_token, &HashMap::<(), u32>::new())?;
+
+    Ok((goal, HeaderMap::new()))
+  }
+
+  async fn refresh_token(&self, _refresh_token: &str, _client_id: &str, _client_secret: &str) -> Result<TokenResponse, FitbitError> {
+    self.refreshes.fetch_add(1, Ordering::SeqCst);
+
+    Ok(self.token())
+  }
+
+  async fn exchange_code(&self, _code: &str, _client_id: &str, _client_secret: &str) -> Result<TokenResponse, FitbitError> {
+    Ok(self.token())
+  }
+
+  async fn revoke_token(&self, _token: &str, _client_id: &str, _client_secret: &str) -> Result<(), FitbitError> {
+    Ok(())
+  }
+}
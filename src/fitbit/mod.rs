@@ -1,356 +1,3669 @@
 mod api;
+mod client;
+#[cfg(test)]
+pub(crate) mod mock;
+
+pub use client::{client_builder, FitbitApi, HttpFitbitApi, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT};
 
 use chrono::{Utc, NaiveDateTime, NaiveDate};
-use log::{info, error};
+use tracing::{info, warn, error, Instrument};
 use crate::utils;
-use crate::models::{Period, Range, Command, Response};
+use crate::metrics;
+use crate::models::{Period, Detail, Range, Command, ActiveMinutes, CommandOutcome, Conditional, DatabaseUser, DeadLetter, Device, DistanceUnit, Goals, Profile, Protocol, Resource, Response, ResponseFormat, Source, RateLimitInfo, RequestedRange, RetryConfig, Spo2Summary, CardioScore, HeartRateZones, StalePolicy, TimeSeriesResult, UserTimezone, WeightLog, WeightUnit};
 use crate::errors::FitbitError;
-use crate::cache::CacheHandler;
-use crate::database::DatabaseHandler;
+use crate::cache::{Cache, CacheHandler, RefreshLock, ReplyClaim};
+use crate::database::{Database, DatabaseHandler};
 use std::collections::HashMap;
 use chrono::Duration;
 use std::env;
+use std::future::Future;
+use futures_util::stream::StreamExt;
+
+tokio::task_local! {
+  /// The deadline of the command the task is executing, if it came with one.
+  static DEADLINE: NaiveDateTime;
+}
+
+/// The Fitbit API client. This is designed to be cheaply cloneable to allow for multiple requests to be handled concurrently.
+/// 
+/// Requests to Fitbit go through `A`, which is the real HTTP API outside of tests, cached values and rate limit
+/// state are kept in `C`, which is Redis unless the engine is embedded without it, and users and their tokens are
+/// kept in `D`, which is Postgres outside of tests.
+#[derive(Clone)]
+pub struct Fitbit<A: FitbitApi = HttpFitbitApi, C: Cache = CacheHandler, D: Database = DatabaseHandler> {
+  api: A,
+  cache_client: C,
+  database_client: D,
+  client_id: String,
+  client_secret: String,
+  /// The most queries a user may make to Fitbit per rate limit window.
+  rate_limit_per_hour: usize,
+  /// Seconds added to every rate limit window Fitbit reports, so queries are paced as if it resets later than reported.
+  rate_limit_buffer_secs: u64,
+  /// Queries held back below the remaining count, so the engine stops short of the limit.
+  rate_limit_slack: usize,
+  /// Whether step counts bypass both cache tiers, so every `get_steps` goes to Fitbit.
+  cache_disabled: bool,
+}
+
+impl<C: Cache, D: Database> Fitbit<HttpFitbitApi, C, D> {
+  pub fn new(reqwest_client: reqwest::Client, cache_client: C, database_client: D) -> Self {
+    let max_retries: u32 = env::var("FITBIT_MAX_RETRIES").ok().and_then(|retries| retries.parse().ok()).unwrap_or(3);
+    let base_delay: u64 = env::var("FITBIT_RETRY_BASE_DELAY_MS").ok().and_then(|delay| delay.parse().ok()).unwrap_or(200);
+
+    let retry = RetryConfig { max_retries, base_delay: std::time::Duration::from_millis(base_delay) };
+
+    let api_base_url = env::var("FITBIT_API_BASE_URL").unwrap_or_else(|_| api::DEFAULT_API_BASE_URL.to_string());
+    let oauth_base_url = env::var("FITBIT_OAUTH_BASE_URL").unwrap_or_else(|_| api::DEFAULT_OAUTH_BASE_URL.to_string());
+
+    let api = HttpFitbitApi::with_base_urls(reqwest_client, retry, api_base_url.as_str(), oauth_base_url.as_str());
+
+    Self::with_api(api, cache_client, database_client)
+  }
+}
+
+impl<A: FitbitApi, C: Cache, D: Database> Fitbit<A, C, D> {
+  /// Creates a client that sends its Fitbit requests through `api`.
+  pub fn with_api(api: A, cache_client: C, database_client: D) -> Self {
+    let client_id: String = env::var("FITBIT_CLIENT_ID").expect("FITBIT_CLIENT_ID not set");
+    let client_secret: String  = env::var("FITBIT_CLIENT_SECRET").expect("FITBIT_CLIENT_SECRET not set");
+    let rate_limit_per_hour: usize = env::var("RATE_LIMIT_PER_HOUR").ok()
+      .and_then(|limit| limit.parse().ok())
+      .filter(|limit| *limit > 0)
+      .unwrap_or(DEFAULT_RATE_LIMIT_PER_HOUR);
+    let rate_limit_buffer_secs: u64 = env::var("RATE_LIMIT_BUFFER_SECS").ok()
+      .and_then(|buffer| buffer.parse().ok())
+      .unwrap_or(DEFAULT_RATE_LIMIT_BUFFER_SECS);
+    let rate_limit_slack: usize = env::var("RATE_LIMIT_SLACK").ok()
+      .and_then(|slack| slack.parse().ok())
+      .unwrap_or(0);
+    let cache_disabled = env::var("DISABLE_CACHE").map(|disabled| disabled == "true" || disabled == "1").unwrap_or(false);
+
+    if cache_disabled {
+      warn!("DISABLE_CACHE is set: step counts will not be read from or written to the cache, and every get_steps goes to Fitbit");
+    }
+
+    Self {
+      api,
+      cache_client,
+      database_client,
+      client_id,
+      client_secret,
+      rate_limit_per_hour,
+      rate_limit_buffer_secs,
+      rate_limit_slack,
+      cache_disabled,
+    }
+  }
+
+  /// Records a completed command in the audit log in the background. Auditing is best-effort: a failed write is
+  /// logged and never holds up or fails the command.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `coordination_id` - The coordination ID the command was sent with, if it came from the request queue.
+  /// * `user_id` - The user's internal user ID, if the command is for a user.
+  /// * `command_kind` - The name of the command, as in `Command::name`.
+  /// * `outcome` - How the command ended.
+  /// * `latency` - How long the command took to execute.
+  pub fn audit_command(&self, coordination_id: Option<String>, user_id: Option<String>, command_kind: &'static str, outcome: CommandOutcome, latency: std::time::Duration) {
+    let database_client = self.database_client.clone();
+    let latency_ms = i64::try_from(latency.as_millis()).unwrap_or(i64::MAX);
+
+    tokio::spawn(async move {
+      if let Err(e) = database_client.log_command(coordination_id.as_deref(), user_id.as_deref(), command_kind, outcome, latency_ms).await {
+        error!("Failed to audit {} command: {}", command_kind, e);
+      }
+    }.in_current_span());
+  }
+
+  /// Keeps a failed command in the dead letter queue, with its error and the time it failed. Like auditing, this is
+  /// best-effort: a failed write is logged and the command is answered as usual.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `coordination_id` - The coordination ID the command was sent with.
+  /// * `message` - The command as it was read from the request queue.
+  /// * `error` - The error the command failed with.
+  pub async fn dead_letter(&self, coordination_id: ulid::Ulid, message: &str, error: &FitbitError) {
+    let entry = DeadLetter::new(&coordination_id.to_string(), message, error, Utc::now().naive_utc());
+
+    if let Err(e) = self.cache_client.dead_letter(&entry).await {
+      error!("Failed to dead-letter command {}: {}", coordination_id, e);
+    }
+  }
+
+  /// Sends the reply to a command. The command should only be acknowledged once this succeeds, so a reply that
+  /// could not be delivered is retried when the command is claimed again.
+  pub async fn reply(&self, coordination_id: ulid::Ulid, response: Response, protocol: Protocol) -> Result<(), FitbitError> {
+    let coordination_id = coordination_id.to_string();
+    let coordination_id = coordination_id.as_str();
+
+    let response = utils::encode_reply(response, protocol);
+
+    self.cache_client.send_message(coordination_id, response).await
+  }
+
+  /// Claims a command from the request queue before it is executed, so a redelivered command is answered from its
+  /// first reply instead of being executed again. If the claim cannot be checked the command is treated as new, as
+  /// delivery is at least once.
+  pub async fn claim_reply(&self, coordination_id: ulid::Ulid) -> ReplyClaim {
+    claim_reply(&self.cache_client, &coordination_id.to_string()).await
+  }
+
+  /// Releases the claim on a command that was not answered, such as one that was deferred, so it is executed when it
+  /// is next delivered. A failure is logged, and the command is answered from its claim until the claim expires.
+  pub async fn release_reply(&self, coordination_id: ulid::Ulid) {
+    if let Err(e) = self.cache_client.release_reply(&coordination_id.to_string()).await {
+      error!("Failed to release the claim on command {}: {}", coordination_id, e);
+    }
+  }
+
+  /// Sends a reply that was already encoded, such as one recorded by `claim_reply`, again.
+  pub async fn resend_reply(&self, coordination_id: ulid::Ulid, reply: Vec<u8>) -> Result<(), FitbitError> {
+    self.cache_client.send_message(&coordination_id.to_string(), reply).await
+  }
+
+  /// Executes a command and returns its reply. A token Fitbit rejected cannot be recovered by refreshing, so it is
+  /// answered with `Response::ReauthorizationRequired` rather than an error.
+  pub async fn execute_command(&self, command: Command) -> Response {
+    match self.run_command(command).await {
+      Response::Error(FitbitError::RejectedToken) => Response::ReauthorizationRequired,
+      response => response,
+    }
+  }
+
+  /// Executes a command like `execute_command`, unless `deadline` passes first. The deadline is checked before
+  /// starting and again before every request to Fitbit, so a command that waited too long for a permit or for an
+  /// earlier request fails with `FitbitError::CommandExpired` instead of spending the user's rate limit.
+  pub async fn execute_command_before(&self, command: Command, deadline: NaiveDateTime) -> Response {
+    DEADLINE.scope(deadline, async move {
+      if let Err(e) = check_deadline() {
+        return Response::Error(e);
+      }
+
+      self.execute_command(command).await
+    }).await
+  }
+
+  /// Executes a command like `execute_command_before`, but gives up once it has run for `timeout` and answers with
+  /// `FitbitError::Timeout`. This bounds commands stuck on Redis or Postgres as well as on Fitbit.
+  pub async fn execute_command_within(&self, command: Command, deadline: NaiveDateTime, timeout: std::time::Duration) -> Response {
+    within_timeout(timeout, self.execute_command_before(command, deadline)).await
+  }
+
+  async fn run_command(&self, command: Command) -> Response {
+    let response: Response;
+
+    match command {
+      Command::GetSteps(user_id, range, format, stale_policy, aggregation) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let range = match range {
+          RequestedRange::Dates(range) => range,
+          relative => match self.user_timezone(&user_id).await {
+            Ok(timezone) => relative.resolve(timezone.today(Utc::now())),
+            Err(e) => return Response::Error(e),
+          },
+        };
+
+        response = match self.get_steps_with_policy(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end, stale_policy).await {
+          Ok((steps, false)) => Response::Steps(aggregation.apply(steps), format),
+          Ok((steps, true)) if stale_policy == StalePolicy::AllowPartial => Response::PartialSteps(aggregation.apply(steps), format),
+          Ok((steps, true)) => Response::StaleSteps(aggregation.apply(steps), format),
+          Err(e) => return Response::Error(e),
+        };
+      },
+      Command::GetStepsWithSource(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.get_steps_with_source(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::StepsWithSource(steps);
+      },
+      Command::GetStepsBatch(user_ids, range) => {
+        let steps = batch_steps(user_ids, STEPS_BATCH_CONCURRENCY, |user_id| {
+          let range = &range;
+
+          async move { self.get_user_steps(&user_id, range.start, range.end).await }
+        }).await;
+
+        response = Response::StepsBatch(steps);
+      },
+      Command::RefreshSteps(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.refresh_steps(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Steps(steps, ResponseFormat::Dated);
+      },
+      Command::SyncRecent(user_id, days) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.sync_recent(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, days).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Steps(steps, ResponseFormat::Dated);
+      },
+      Command::GetStepsDense(user_id, range, format) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.get_steps_dense(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Steps(steps, format);
+      },
+      Command::GetStepsSummary(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.get_steps(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::steps_summary(&steps);
+      },
+      Command::GetStreak(user_id, range, threshold) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.get_steps(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::streak(&steps, range.start, range.end, threshold);
+      },
+      Command::GetHeartRate(user_id, range, format) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let heart_rate = match self.get_heart_rate(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(heart_rate) => heart_rate,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::HeartRate(heart_rate, format);
+      },
+      Command::GetCalories(user_id, range, format) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let calories = match self.get_calories(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(calories) => calories,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Calories(calories, format);
+      },
+      Command::GetFloors(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let floors = match self.get_floors(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(floors) => floors,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Floors(floors);
+      },
+      Command::GetDistance(user_id, range, unit) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let unit = match unit {
+          Some(unit) => unit,
+          None => match self.get_profile(&user_id).await {
+            Ok(profile) => DistanceUnit::from_locale(&profile.distance_unit),
+            Err(e) => return Response::Error(e),
+          },
+        };
+
+        let distance = match self.get_distance(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(distance) => distance,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Distance(in_unit(distance, unit));
+      },
+      Command::GetActiveMinutes(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let active_minutes = match self.get_active_minutes(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(active_minutes) => active_minutes,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::ActiveMinutes(active_minutes);
+      },
+      Command::GetSpo2(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let spo2 = match self.get_spo2(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(spo2) => spo2,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Spo2(spo2);
+      },
+      Command::GetHeartRateZones(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let zones = match self.get_heart_rate_zones(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(zones) => zones,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::HeartRateZones(zones);
+      },
+      Command::GetCardioScore(user_id, range) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let scores = match self.get_cardio_score(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(scores) => scores,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::CardioScore(scores);
+      },
+      Command::GetWeight(user_id, range, unit) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let unit = match unit {
+          Some(unit) => unit,
+          None => match self.get_profile(&user_id).await {
+            Ok(profile) => WeightUnit::from_locale(&profile.weight_unit),
+            Err(e) => return Response::Error(e),
+          },
+        };
+
+        let logs = match self.get_weight(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
+          Ok(logs) => logs,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Weight(in_weight_unit(logs, unit));
+      },
+      Command::GetIntradaySteps(user_id, date, detail) => {
+        let user = match self.database_client.get_user(&user_id).await {
+          Ok(Some(user)) => user,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        let steps = match self.get_intraday_steps(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, date, detail).await {
+          Ok(steps) => steps,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::IntradaySteps(steps);
+      },
+      Command::RefreshToken(user_id) => {
+        match self.refresh_token(&user_id).await {
+          Ok(_) => (),
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Refreshed;
+      },
+      Command::RegisterUser(user_id, code) => {
+        match self.register_user(&user_id, &code).await {
+          Ok(_) => (),
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Registered;
+      },
+      Command::RevokeToken(user_id) => {
+        match self.revoke_token(&user_id).await {
+          Ok(_) => (),
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Revoked;
+      },
+      Command::GetCoverage(user_id) => {
+        let ranges = match self.cache_client.get_steps_coverage(&user_id).await {
+          Ok(ranges) => ranges,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Coverage(ranges);
+      },
+      Command::ClearCache(user_id) => {
+        let removed = match self.cache_client.clear_user(&user_id).await {
+          Ok(removed) => removed,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::CacheCleared(removed);
+      },
+      Command::Backfill(user_id, start) => {
+        let estimated_seconds = match self.backfill(&user_id, start).await {
+          Ok(estimated_seconds) => estimated_seconds,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::BackfillScheduled(estimated_seconds);
+      },
+      Command::UserExists(user_id) => {
+        let exists = match self.database_client.user_exists(&user_id).await {
+          Ok(exists) => exists,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::UserExists(exists);
+      },
+      Command::Ping => {
+        let (redis_latency, postgres_latency) = match self.ping().await {
+          Ok(latencies) => latencies,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Pong { redis_latency, postgres_latency };
+      },
+      Command::SyncTimezone(user_id) => {
+        let timezone = match self.sync_timezone(&user_id).await {
+          Ok(timezone) => timezone,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Timezone(timezone);
+      },
+      Command::GetProfile(user_id) => {
+        let profile = match self.get_profile(&user_id).await {
+          Ok(profile) => profile,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Profile(profile);
+      },
+      Command::GetGoals(user_id) => {
+        let goals = match self.get_goals(&user_id).await {
+          Ok(goals) => goals,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Goals(goals);
+      },
+      Command::SetStepGoal(user_id, goal) => {
+        let goal = match self.set_step_goal(&user_id, goal).await {
+          Ok(goal) => goal,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::GoalSet(goal);
+      },
+      Command::GetDevices(user_id) => {
+        let devices = match self.get_devices(&user_id).await {
+          Ok(devices) => devices,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Devices(devices);
+      },
+      Command::GetRateLimit(user_id) => {
+        response = match rate_limit_status(&self.cache_client, &user_id, self.rate_limit_per_hour, Utc::now().naive_utc()).await {
+          Ok(status) => status,
+          Err(e) => return Response::Error(e),
+        };
+      },
+      Command::GetTokenStatus(user_id) => {
+        let expiry = match self.database_client.token_expiry(&user_id).await {
+          Ok(Some(expiry)) => expiry,
+          Ok(None) => return Response::Error(FitbitError::UserNotFound),
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::token_status(expiry);
+      },
+      Command::ListUsers { limit, offset } => {
+        let users = match self.database_client.list_users(limit, offset).await {
+          Ok(users) => users,
+          Err(e) => return Response::Error(e),
+        };
+
+        response = Response::Users(users);
+      },
+    }
+
+    response
+  }
+
+  /// Gets daily step counts from Fitbit within a given range, inclusive.
+  /// The result is sparse: a day is only present if a count for it was cached or fetched. Use `get_steps_dense`
+  /// to get every day in the range.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding step counts.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_steps(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let (steps, _) = self.get_steps_with_policy(user_id, fitbit_user_id, fitbit_access_token, start, end, StalePolicy::Strict).await?;
+
+    Ok(steps)
+  }
+
+  /// Gets daily step counts exactly as in `get_steps`, looking up the user's Fitbit account by their internal user ID.
+  async fn get_user_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let user = self.database_client.get_user(user_id).await?.ok_or(FitbitError::UserNotFound)?;
+
+    self.get_steps(user_id, &user.fitbit_user_id, &user.fitbit_access_token, start, end).await
+  }
+
+  /// Gets daily step counts exactly as in `get_steps`, except that with `StalePolicy::AllowStale`, a failure to reach
+  /// Fitbit returns the steps that were already cached or stored instead of an error. Steps fetched before the failure
+  /// are included. `StalePolicy::AllowPartial` does the same, but only when the failure is the rate limit.
+  /// 
+  /// # Returns
+  /// 
+  /// * `(HashMap<NaiveDate, u32>, bool)` - The step counts, and whether they are stale or partial because Fitbit was
+  ///   unavailable or the rate limit was reached.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_steps_with_policy(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate, stale_policy: StalePolicy) -> Result<(HashMap<NaiveDate, u32>, bool), FitbitError> {
+    let mut steps = self.get_tiered_steps(user_id, start, end).await?;
+    let mut live_steps = HashMap::new();
+
+    let fetched = self.fetch_live_steps(user_id, fitbit_user_id, fitbit_access_token, start, end, stale_policy, &steps, &mut live_steps).await;
+
+    steps.extend(live_steps);
+
+    match fetched {
+      Ok(partial) => Ok((steps, partial)),
+      Err(e) if stale_policy == StalePolicy::AllowStale && e.is_unavailable() => {
+        warn!("Fitbit unavailable, serving {} stale days for {}: {}", steps.len(), user_id, e);
+        Ok((steps, true))
+      },
+      Err(e) => Err(e),
+    }
+  }
+
+  /// Gets daily step counts exactly as in `get_steps`, along with whether each day came from the cache or was fetched
+  /// live from Fitbit. Days read from Postgres count as cached. A day that was cached but refreshed because it is
+  /// recent counts as live.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, (u32, Source)>` - A hashmap of dates and their step counts and sources.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_steps_with_source(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, (u32, Source)>, FitbitError> {
+    let cached_steps = self.get_tiered_steps(user_id, start, end).await?;
+    let mut live_steps = HashMap::new();
+
+    self.fetch_live_steps(user_id, fitbit_user_id, fitbit_access_token, start, end, StalePolicy::Strict, &cached_steps, &mut live_steps).await?;
+
+    Ok(with_source(cached_steps, live_steps))
+  }
+
+  /// Gets the steps in the range from Redis, falling back to Postgres for the days Redis is missing. With the cache
+  /// disabled, neither tier is read.
+  async fn get_tiered_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    if self.cache_disabled {
+      info!("Cache disabled, fetching steps for {} from Fitbit", user_id);
+      return Ok(HashMap::new());
+    }
+
+    let mut cached_steps = self.get_cached_steps(user_id, start, end).await?;
+
+    // On a Redis miss, fall back to the steps persisted in Postgres and warm Redis with them.
+    if !covers_range(&cached_steps, start, end) {
+      let stored_steps = self.get_stored_steps(user_id, start, end).await?;
+
+      self.cache(user_id, &stored_steps).await?;
+
+      cached_steps = merge_tiers(cached_steps, stored_steps);
+    }
+
+    Ok(cached_steps)
+  }
+
+  /// Fetches the days that are missing from `cached`, or are recent enough to refresh, from Fitbit into `live`, and
+  /// returns whether the rate limit cut the fetch short, as `fetch_live_windows` does.
+  #[allow(clippy::too_many_arguments)]
+  async fn fetch_live_steps(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate, stale_policy: StalePolicy, cached: &HashMap<NaiveDate, u32>, live: &mut HashMap<NaiveDate, u32>) -> Result<bool, FitbitError> {
+    let access_token = self.current_access_token(user_id, fitbit_access_token).await?;
+
+    let live_ranges = self.get_live_ranges(user_id, start, end, cached).await?;
+
+    fetch_live_windows(live_ranges, stale_policy, live, |window| {
+      self.get_time_series(Resource::Steps, user_id, fitbit_user_id, &access_token, window.start, window.end)
+    }).await
+  }
+
+  /// Fetches daily step counts within a given range, inclusive, from Fitbit without reading the cache, and overwrites
+  /// the cached and stored days with them. This picks up days Fitbit has corrected, such as after a manually logged
+  /// activity, which `get_steps` would otherwise answer from the cache until they expire. Every window fetched counts
+  /// against the rate limit.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap with every date in the range and its freshly fetched step count.
+  /// * `FitbitError` - An error if one occurs, including when the rate limit is reached.
+  pub async fn refresh_steps(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let access_token = self.current_access_token(user_id, fitbit_access_token).await?;
+
+    let mut steps = HashMap::new();
+
+    for window in utils::chunk_range(&Range { start, end }) {
+      steps.extend(self.get_time_series(Resource::Steps, user_id, fitbit_user_id, &access_token, window.start, window.end).await?);
+    }
+
+    Ok(steps)
+  }
+
+  /// Fetches the user's steps for their last `days` days, through today in their timezone, from Fitbit, and overwrites
+  /// the cached and stored days with them, as `refresh_steps` does. `days` is at most `MAX_SYNC_RECENT_DAYS`, so this
+  /// is always a single request, and the cache is never read, which keeps the nightly keep-fresh job cheap.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `days` - The number of days to fetch, including today.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap with every date in the window and its freshly fetched step count.
+  /// * `FitbitError` - An error if one occurs, including when the rate limit is reached.
+  pub async fn sync_recent(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, days: u16) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let today = self.user_timezone(user_id).await?.today(Utc::now());
+    let window = recent_window(today, days);
+
+    self.refresh_steps(user_id, fitbit_user_id, fitbit_access_token, window.start, window.end).await
+  }
+
+  /// Gets daily step counts within a given range, inclusive, with every day in the range present.
+  /// Days are fetched exactly as in `get_steps`, so days that were never fetched still trigger a live request. Any
+  /// day that is still absent afterwards, such as a day in the future, is reported as 0 steps.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap with every date in the range and its corresponding step count.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_steps_dense(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let steps = self.get_steps(user_id, fitbit_user_id, fitbit_access_token, start, end).await?;
+
+    Ok(with_known_empty_days(steps, start, end))
+  }
+
+  /// Starts fetching a user's steps from `start` through yesterday in the background, writing them to the cache
+  /// and Postgres. Windows that are already stored are skipped, so an interrupted backfill resumes where it
+  /// left off when the command is sent again. Requests are paced so the per-user rate limit is never exceeded.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `start` - The first day to backfill.
+  /// 
+  /// # Returns
+  /// 
+  /// * `u64` - An estimate of how many seconds the backfill will take.
+  /// * `FitbitError` - An error if one occurs before the backfill starts.
+  pub async fn backfill(&self, user_id: &str, start: NaiveDate) -> Result<u64, FitbitError> {
+    let yesterday = Utc::now().date_naive() - Duration::days(1);
+
+    if start > yesterday {
+      return Err(FitbitError::DateOutOfRange("Backfill must start before today".to_string()));
+    }
+
+    if self.database_client.get_user(user_id).await?.is_none() {
+      return Err(FitbitError::UserNotFound);
+    }
+
+    let windows = self.missing_windows(user_id, start, yesterday).await?;
+
+    let queries = self.cache_client.get_user_queries(user_id).await?;
+    let ratelimit_reset = self.cache_client.get_ratelimit_reset(user_id).await.unwrap_or(Utc::now().naive_local());
+    let until_ratelimit_reset: u64 = utils::safe_convert((ratelimit_reset - Utc::now().naive_local()).num_seconds());
+
+    let estimated_seconds = backfill_estimate(windows.len(), queries, self.rate_limit_per_hour, until_ratelimit_reset);
+
+    info!("Backfilling {} windows for user {}, estimated {} seconds", windows.len(), user_id, estimated_seconds);
+
+    let fitbit = self.clone();
+    let user_id = user_id.to_string();
+
+    tokio::spawn(async move {
+      match fitbit.run_backfill(&user_id, windows).await {
+        Ok(_) => info!("Backfill complete for user {}", user_id),
+        Err(e) => error!("Backfill failed for user {}: {}", user_id, e),
+      }
+    });
+
+    Ok(estimated_seconds)
+  }
+
+  /// The windows between `start` and `end`, inclusive, that are not fully present in either cache tier.
+  async fn missing_windows(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<Vec<Range>, FitbitError> {
+    let cached_steps = self.get_cached_steps(user_id, start, end).await?;
+    let stored_steps = self.get_stored_steps(user_id, start, end).await?;
+    let steps = merge_tiers(cached_steps, stored_steps);
+
+    let windows = utils::chunk_range(&Range { start, end }).into_iter()
+      .filter(|window| !covers_range(&steps, window.start, window.end))
+      .collect();
+
+    Ok(windows)
+  }
+
+  /// Fetches each window in turn, waiting for the rate limit window to reset whenever the user's limit is reached.
+  /// Backfills of at least `BACKFILL_PACING_MIN_WINDOWS` windows are paced to spread their queries over the window.
+  async fn run_backfill(&self, user_id: &str, windows: Vec<Range>) -> Result<(), FitbitError> {
+    let paced = windows.len() >= BACKFILL_PACING_MIN_WINDOWS;
+
+    paced_backfill(user_id, windows, paced, || self.backfill_quota(user_id), |window| self.backfill_window(user_id, window)).await
+  }
+
+  /// Fetches a single backfill window from Fitbit, refreshing the user's token first if it has expired.
+  async fn backfill_window(&self, user_id: &str, window: Range) -> Result<(), FitbitError> {
+    if self.check_access_token_expired(user_id).await?.unwrap_or(false) {
+      self.refresh_token(user_id).await?;
+    }
+
+    // The stored token may have been refreshed by another command since the last window.
+    let Some(user) = self.database_client.get_user(user_id).await? else {
+      return Err(FitbitError::UserNotFound);
+    };
+
+    self.get_time_series(Resource::Steps, user_id, &user.fitbit_user_id, &user.fitbit_access_token, window.start, window.end).await?;
+
+    Ok(())
+  }
+
+  /// The queries the user has left and the seconds until their rate limit window resets, if known. A cache error is
+  /// returned rather than waited out, so Fitbit is never queried blind and the backfill never waits forever.
+  async fn backfill_quota(&self, user_id: &str) -> Result<(usize, Option<u64>), FitbitError> {
+    let remaining = remaining_queries(&self.cache_client, user_id, self.rate_limit_per_hour, self.rate_limit_slack).await?;
+    let until_reset = match self.cache_client.get_ratelimit_reset(user_id).await {
+      Ok(reset) => seconds_until(reset, Utc::now().naive_local()),
+      Err(_) => None,
+    };
+
+    Ok((remaining, until_reset))
+  }
+
+  /// Gets intraday step counts from Fitbit for a single day, in the user's timezone. Intraday data is not cached.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `date` - The day to retrieve.
+  /// * `detail` - The resolution of the time series.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDateTime, u32>` - A hashmap of timestamps within the day and their corresponding step counts.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_intraday_steps(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, date: NaiveDate, detail: Detail) -> Result<HashMap<NaiveDateTime, u32>, FitbitError> {
+    let access_token = self.current_access_token(user_id, fitbit_access_token).await?;
+
+    if self.check_ratelimit(user_id).await {
+      return Err(self.rate_limit_exceeded(user_id).await);
+    }
+
+    let timezone = self.user_timezone(user_id).await?;
+
+    // Validates that the day is in the past for the user.
+    Self::period_for_range(date, date, timezone.today(Utc::now()))?;
+
+    let api = &self.api;
+    let timezone = timezone.name.as_str();
+
+    let result = with_token_refresh(&access_token, |token| async move {
+      api.get_intraday_steps(fitbit_user_id, &token, date, detail, timezone).await
+    }, || self.refreshed_access_token(user_id)).await;
+
+    let (steps, headers) = self.check_rate_limited(user_id, result).await?;
+
+    self.set_ratelimit(user_id, RateLimitInfo::from_headers(&headers)).await?;
+
+    Ok(steps)
+  }
+
+  /// Gets daily resting heart rates from Fitbit within a given range, inclusive.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding resting heart rates.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_heart_rate(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let cached = self.cache_client.get_heart_rate(user_id, start, end);
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      self.get_time_series(Resource::HeartRate, user_id, fitbit_user_id, &token, window.start, window.end).await
+    }).await
+  }
+
+  /// Gets the calories a user burned each day within a given range, inclusive, from the cache where possible.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and the calories burned on them.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_calories(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let cached = self.cache_client.get_calories(user_id, start, end);
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      self.get_time_series(Resource::Calories, user_id, fitbit_user_id, &token, window.start, window.end).await
+    }).await
+  }
+
+  /// Gets the floors a user climbed each day within a given range, inclusive, from the cache where possible. Days the
+  /// tracker did not report floors for are omitted.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and the floors climbed on them.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_floors(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let cached = self.cache_client.get_floors(user_id, start, end);
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      self.get_time_series(Resource::Floors, user_id, fitbit_user_id, &token, window.start, window.end).await
+    }).await
+  }
+
+  /// Gets the distance a user covered each day within a given range, inclusive, in kilometers, from the cache where
+  /// possible.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, f64>` - A hashmap of dates and the kilometers covered on them.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_distance(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, f64>, FitbitError> {
+    let cached = self.cache_client.get_distance(user_id, start, end);
+    let api = &self.api;
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      let distance = self.fetch_series(user_id, &token, window.start, window.end, |token, period, timezone| async move {
+        api.get_distance(fitbit_user_id, &token, window.end, period, &timezone).await
+      }).await?;
+
+      info!("Cacheing {} distances", distance.len());
+
+      match self.cache_client.add_distance_bulk(user_id, &distance).await {
+        Ok(_) => Ok(distance),
+        Err(e) => Err(FitbitError::CacheError(e.to_string())),
+      }
+    }).await
+  }
+
+  /// Gets the minutes a user spent in each heart rate zone each day within a given range, inclusive, from the cache
+  /// where possible.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, HeartRateZones>` - A hashmap of dates and the minutes spent in each zone. Days without
+  ///   zones, such as days the device was not worn, are absent.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_heart_rate_zones(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, HeartRateZones>, FitbitError> {
+    let cached = self.cache_client.get_heart_rate_zones(user_id, start, end);
+    let api = &self.api;
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      let zones = self.fetch_series(user_id, &token, window.start, window.end, |token, period, timezone| async move {
+        api.get_heart_rate_zones(fitbit_user_id, &token, window.end, period, &timezone).await
+      }).await?;
+
+      info!("Cacheing {} days of heart rate zones", zones.len());
+
+      match self.cache_client.add_heart_rate_zones_bulk(user_id, &zones).await {
+        Ok(_) => Ok(zones),
+        Err(e) => Err(FitbitError::CacheError(e.to_string())),
+      }
+    }).await
+  }
+
+  /// Gets a user's active minutes each day within a given range, inclusive, from the cache where possible. Fairly and
+  /// very active minutes are separate series at Fitbit, so each live window costs two requests, and each is checked
+  /// against and recorded in the rate limit on its own.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, ActiveMinutes>` - A hashmap of dates and their active minutes.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_active_minutes(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, ActiveMinutes>, FitbitError> {
+    let cached = self.cache_client.get_active_minutes(user_id, start, end);
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      let fairly = self.get_time_series(Resource::MinutesFairlyActive, user_id, fitbit_user_id, &token, window.start, window.end).await?;
+      let very = self.get_time_series(Resource::MinutesVeryActive, user_id, fitbit_user_id, &token, window.start, window.end).await?;
+
+      let active_minutes = utils::merge_active_minutes(fairly, very);
+
+      info!("Cacheing {} days of active minutes", active_minutes.len());
+
+      match self.cache_client.add_active_minutes_bulk(user_id, &active_minutes).await {
+        Ok(_) => Ok(active_minutes),
+        Err(e) => Err(FitbitError::CacheError(e.to_string())),
+      }
+    }).await
+  }
+
+  /// Gets a user's nightly SpO2 summaries within a given range, inclusive, from the cache where possible. Each summary
+  /// is dated by the day the sleep ended. Fitbit serves at most `api::SPO2_MAX_DAYS` days per request, so longer live
+  /// windows are fetched in chunks.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, Spo2Summary>` - A hashmap of dates and their SpO2 summaries. Nights without a reading are
+  ///   omitted.
+  /// * `FitbitError` - An error if one occurs, such as `InsufficientScope` if the user has not granted the
+  ///   `oxygen_saturation` scope.
+  pub async fn get_spo2(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, Spo2Summary>, FitbitError> {
+    let cached = self.cache_client.get_spo2(user_id, start, end);
+    let api = &self.api;
+
+    self.get_daily_series(user_id, fitbit_access_token, start, end, cached, |window, token| async move {
+      let timezone = self.user_timezone(user_id).await?;
+      let mut spo2: HashMap<NaiveDate, Spo2Summary> = HashMap::new();
+
+      for chunk in utils::chunk_range_by(&window, api::SPO2_MAX_DAYS) {
+        // Validates that the range is in the past.
+        Self::period_for_range(chunk.start, chunk.end, timezone.today(Utc::now()))?;
+
+        metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+        spo2.extend(self.send(user_id, &token, |token| async move {
+          api.get_spo2(fitbit_user_id, &token, chunk.start, chunk.end).await.map(with_rate_limit)
+        }).await?);
+      }
+
+      info!("Cacheing {} days of SpO2", spo2.len());
+
+      match self.cache_client.add_spo2_bulk(user_id, &spo2).await {
+        Ok(_) => Ok(spo2),
+        Err(e) => Err(FitbitError::CacheError(e.to_string())),
+      }
+    }).await
+  }
+
+  /// Gets a user's daily cardio fitness scores within a given range, inclusive. Scores change slowly and a range is
+  /// a single request per `api::CARDIO_SCORE_MAX_DAYS` days, so they are always fetched live rather than cached.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, CardioScore>` - A hashmap of dates and their cardio fitness scores. Days without a score
+  ///   are omitted.
+  /// * `FitbitError` - An error if one occurs, such as `InsufficientScope` if the user has not granted the
+  ///   `cardio_fitness` scope.
+  pub async fn get_cardio_score(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, CardioScore>, FitbitError> {
+    let access_token = self.current_access_token(user_id, fitbit_access_token).await?;
+
+    let timezone = self.user_timezone(user_id).await?;
+
+    // Validates that the range is in the past. Long ranges are chunked below.
+    Self::check_past_range(start, end, timezone.today(Utc::now()))?;
+
+    let api = &self.api;
+    let mut scores: HashMap<NaiveDate, CardioScore> = HashMap::new();
+
+    for window in utils::chunk_range_by(&Range { start, end }, api::CARDIO_SCORE_MAX_DAYS) {
+      metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+      scores.extend(self.send(user_id, &access_token, |token| async move {
+        api.get_cardio_score(fitbit_user_id, &token, window.start, window.end).await.map(with_rate_limit)
+      }).await?);
+    }
+
+    Ok(scores)
+  }
+
+  /// Gets the weights a user logged within a given range, inclusive, in kilograms. Logs are sparse and a range is a
+  /// single request per `api::WEIGHT_MAX_DAYS` days, so they are always fetched live rather than cached.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Vec<WeightLog>` - The logs, ordered by date and time.
+  /// * `FitbitError` - An error if one occurs, such as `InsufficientScope` if the user has not granted the `weight`
+  ///   scope.
+  pub async fn get_weight(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<Vec<WeightLog>, FitbitError> {
+    let access_token = self.current_access_token(user_id, fitbit_access_token).await?;
+
+    let timezone = self.user_timezone(user_id).await?;
+
+    // Validates that the range is in the past. Long ranges are chunked below.
+    Self::check_past_range(start, end, timezone.today(Utc::now()))?;
+
+    let api = &self.api;
+    let mut logs: Vec<WeightLog> = Vec::new();
+
+    // Chunks are in date order and each chunk is sorted, so the logs stay ordered.
+    for window in utils::chunk_range_by(&Range { start, end }, api::WEIGHT_MAX_DAYS) {
+      metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+      logs.extend(self.send(user_id, &access_token, |token| async move {
+        api.get_weight(fitbit_user_id, &token, window.start, window.end).await.map(with_rate_limit)
+      }).await?);
+    }
+
+    Ok(logs)
+  }
+
+  /// Checks that the cache and the database are reachable.
+  /// 
+  /// # Returns
+  /// 
+  /// * `(Duration, Duration)` - The round-trip times to the cache and to the database.
+  /// * `FitbitError` - The error from whichever check failed first.
+  pub async fn ping(&self) -> Result<(std::time::Duration, std::time::Duration), FitbitError> {
+    let started = std::time::Instant::now();
+    self.cache_client.ping().await?;
+    let cache_latency = started.elapsed();
+
+    let started = std::time::Instant::now();
+    self.database_client.ping().await?;
+    let database_latency = started.elapsed();
+
+    Ok((cache_latency, database_latency))
+  }
+
+  /// Gets the user's stored timezone, or UTC if they have never synced it.
+  async fn user_timezone(&self, user_id: &str) -> Result<UserTimezone, FitbitError> {
+    let timezone = self.database_client.get_timezone(user_id).await?;
+
+    Ok(timezone.unwrap_or_default())
+  }
+
+  /// Fetches the user's timezone from their Fitbit profile and stores it, so step counts are requested for the
+  /// user's local days.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `UserTimezone` - The timezone that was stored.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn sync_timezone(&self, user_id: &str) -> Result<UserTimezone, FitbitError> {
+    let profile = self.fetch_profile(user_id).await?;
+
+    Ok(UserTimezone::from(&profile))
+  }
+
+  /// Gets the user's Fitbit profile, from the cache if it is there. A profile fetched from Fitbit is cached, and its
+  /// timezone is stored as in `sync_timezone`.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Profile` - The user's profile.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_profile(&self, user_id: &str) -> Result<Profile, FitbitError> {
+    if let Some(profile) = self.cache_client.get_profile(user_id).await? {
+      metrics::DATA_SOURCE.with_label_values(&["cache"]).inc();
+      return Ok(profile);
+    }
+
+    let profile = self.fetch_profile(user_id).await?;
+
+    self.cache_client.set_profile(user_id, &profile).await?;
+
+    Ok(profile)
+  }
+
+  /// Gets the user's daily activity goals, from the cache if they are there. Goals fetched from Fitbit are cached.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Goals` - The user's goals.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_goals(&self, user_id: &str) -> Result<Goals, FitbitError> {
+    if let Some(goals) = self.cache_client.get_goals(user_id).await? {
+      metrics::DATA_SOURCE.with_label_values(&["cache"]).inc();
+      return Ok(goals);
+    }
+
+    let user = self.connected_user(user_id).await?;
+
+    let api = &self.api;
+    let fitbit_user_id = user.fitbit_user_id.as_str();
+
+    let goals = self.send(user_id, &user.fitbit_access_token, |token| async move {
+      api.get_goals(fitbit_user_id, &token).await.map(with_rate_limit)
+    }).await?;
+
+    metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+    self.cache_client.set_goals(user_id, &goals).await?;
+
+    Ok(goals)
+  }
+
+  /// Gets the devices paired with the user's account, from the cache if they are there. Devices fetched from Fitbit
+  /// are cached briefly, since they sync often.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Vec<Device>` - The user's devices.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_devices(&self, user_id: &str) -> Result<Vec<Device>, FitbitError> {
+    if let Some(devices) = self.cache_client.get_devices(user_id).await? {
+      metrics::DATA_SOURCE.with_label_values(&["cache"]).inc();
+      return Ok(devices);
+    }
+
+    let user = self.connected_user(user_id).await?;
+
+    let api = &self.api;
+    let fitbit_user_id = user.fitbit_user_id.as_str();
+
+    let devices = self.send(user_id, &user.fitbit_access_token, |token| async move {
+      api.get_devices(fitbit_user_id, &token).await.map(with_rate_limit)
+    }).await?;
+
+    metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+    self.cache_client.set_devices(user_id, &devices).await?;
+
+    Ok(devices)
+  }
+
+  /// Sets the user's daily step goal at Fitbit and drops their cached goals, so the next `get_goals` sees it.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `goal` - The new daily step goal.
+  /// 
+  /// # Returns
+  /// 
+  /// * `u32` - The step goal Fitbit stored.
+  /// * `FitbitError` - An error if one occurs, `FitbitError::InsufficientScope` if the user's token cannot write goals.
+  pub async fn set_step_goal(&self, user_id: &str, goal: u32) -> Result<u32, FitbitError> {
+    let user = self.connected_user(user_id).await?;
+
+    let api = &self.api;
+    let fitbit_user_id = user.fitbit_user_id.as_str();
+
+    let goal = self.send(user_id, &user.fitbit_access_token, |token| async move {
+      api.set_step_goal(fitbit_user_id, &token, goal).await.map(with_rate_limit)
+    }).await?;
+
+    self.cache_client.clear_goals(user_id).await?;
+
+    Ok(goal)
+  }
+
+  /// Fetches the user's profile from Fitbit, refreshing their token if it has expired, and stores its timezone.
+  async fn fetch_profile(&self, user_id: &str) -> Result<Profile, FitbitError> {
+    let user = self.connected_user(user_id).await?;
+
+    let api = &self.api;
+    let fitbit_user_id = user.fitbit_user_id.as_str();
+
+    let profile = self.send(user_id, &user.fitbit_access_token, |token| async move {
+      api.get_profile(fitbit_user_id, &token).await.map(with_rate_limit)
+    }).await?;
+
+    metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+    self.database_client.set_timezone(user_id, &UserTimezone::from(&profile)).await?;
+
+    Ok(profile)
+  }
+
+  /// Gets a connected user, refreshing their token first if it has expired.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `DatabaseUser` - The user, with their current access token.
+  /// * `FitbitError` - An error if one occurs, `FitbitError::UserNotFound` if the user is not connected.
+  async fn connected_user(&self, user_id: &str) -> Result<DatabaseUser, FitbitError> {
+    let Some(mut user) = self.database_client.get_user(user_id).await? else {
+      return Err(FitbitError::UserNotFound);
+    };
+
+    user.fitbit_access_token = self.current_access_token(user_id, &user.fitbit_access_token).await?;
+
+    Ok(user)
+  }
+
+  /// Gets a user's current access token, refreshing it first if it has expired.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `access_token` - The access token the caller was given for the user.
+  /// 
+  /// # Returns
+  /// 
+  /// * `String` - The refreshed token if the given one had expired, or the given one otherwise.
+  /// * `FitbitError` - An error if one occurs.
+  async fn current_access_token(&self, user_id: &str, access_token: &str) -> Result<String, FitbitError> {
+    if self.check_access_token_expired(user_id).await?.unwrap_or(false) {
+      let (access_token, _) = self.refresh_token(user_id).await?;
+
+      return Ok(access_token);
+    }
+
+    Ok(access_token.to_string())
+  }
+
+  /// Sends a request to Fitbit for a user. The request is not sent if the user's rate limit has been reached, is sent
+  /// again with a refreshed token if Fitbit finds the token expired, and the rate limit state Fitbit reports with the
+  /// response is recorded.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `request` - Sends the request with the given access token, answering with the rate limit state reported.
+  /// 
+  /// # Returns
+  /// 
+  /// * `T` - What Fitbit answered with.
+  /// * `FitbitError` - An error if one occurs, `FitbitError::RateLimitExceeded` if the rate limit was reached.
+  async fn send<T, R, Fut>(&self, user_id: &str, access_token: &str, request: R) -> Result<T, FitbitError>
+  where
+    R: Fn(String) -> Fut,
+    Fut: Future<Output = Result<(T, RateLimitInfo), FitbitError>>,
+  {
+    if self.check_ratelimit(user_id).await {
+      return Err(self.rate_limit_exceeded(user_id).await);
+    }
+
+    let result = with_token_refresh(access_token, request, || self.refreshed_access_token(user_id)).await;
+
+    let (value, rate_limit) = self.check_rate_limited(user_id, result).await?;
+
+    self.set_ratelimit(user_id, rate_limit).await?;
+
+    Ok(value)
+  }
+
+  /// Checks if we know the users's access token has expired.
+  /// Of course, this is not a guarantee that it has not expired, but it is nearly always the case.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Option<bool>` - `Some(true)` if the access token has expired, `Some(false)` if it has not expired, `None` if we do not know.
+  /// * `FitbitError` - An error if one occurs.
+  async fn check_access_token_expired(&self, user_id: &str) -> Result<Option<bool>, FitbitError> {
+    let expired = self.database_client.user_token_expired(user_id).await?;
+
+    Ok(expired)
+  }
+
+  /// Gets a cached daily series within a given range, inclusive, from the cache where possible. Days missing from the
+  /// cache, and recent days when they are due a refresh, are fetched from Fitbit a window at a time.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// * `access_token` - The user's Fitbit access token.
+  /// * `cached` - Reads the cached values within the range.
+  /// * `fetch` - Fetches the values within a window from Fitbit with the user's current access token and caches them.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, V>` - A hashmap of dates and their values.
+  /// * `FitbitError` - An error if one occurs.
+  async fn get_daily_series<V, R, F, FFut>(&self, user_id: &str, access_token: &str, start: NaiveDate, end: NaiveDate, cached: R, fetch: F) -> Result<HashMap<NaiveDate, V>, FitbitError>
+  where
+    V: Sync,
+    R: Future<Output = Result<HashMap<NaiveDate, V>, FitbitError>>,
+    F: Fn(Range, String) -> FFut,
+    FFut: Future<Output = Result<HashMap<NaiveDate, V>, FitbitError>>,
+  {
+    let access_token = self.current_access_token(user_id, access_token).await?;
+
+    let mut values = cached.await.map_err(|e| FitbitError::CacheError(e.to_string()))?;
+
+    if !values.is_empty() {
+      metrics::DATA_SOURCE.with_label_values(&["cache"]).inc();
+    }
+
+    for window in self.get_live_ranges(user_id, start, end, &values).await? {
+      values.extend(fetch(window, access_token.clone()).await?);
+    }
+
+    Ok(values)
+  }
+
+  /// Fetches the daily values of a series from Fitbit within the given range, inclusive. Days are in the user's
+  /// timezone, and days Fitbit sends outside the range are dropped. Unlike `get_time_series`, nothing is cached or
+  /// revalidated, so this serves series whose values are not whole numbers.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// * `request` - Requests the series with an access token, for a period ending on `end`, in the timezone with the
+  ///   given IANA name.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, V>` - A hashmap of dates and their values.
+  /// * `FitbitError` - An error if one occurs.
+  async fn fetch_series<V, R, Fut>(&self, user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate, request: R) -> Result<HashMap<NaiveDate, V>, FitbitError>
+  where
+    R: Fn(String, Period, String) -> Fut,
+    Fut: Future<Output = Result<TimeSeriesResult<V>, FitbitError>>,
+  {
+    let timezone = self.user_timezone(user_id).await?;
+
+    let period = Self::period_for_range(start, end, timezone.today(Utc::now()))?;
+
+    metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+    let values = self.send(user_id, fitbit_access_token, |token| {
+      let series = request(token, period, timezone.name.clone());
+
+      async move { series.await.map(|series| (series.values, series.rate_limit)) }
+    }).await?;
+
+    // Filters out days that are not in the range.
+    Ok(values.into_iter().filter(|(date, _)| *date >= start && *date <= end).collect())
+  }
+
+  /// Gets the daily values of a time series from Fitbit within the given range, inclusive, and caches them. Days are in
+  /// the user's timezone.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `resource` - The time series to fetch.
+  /// * `user_id` - The user's internal user ID.
+  /// * `fitbit_user_id` - The user's Fitbit user ID.
+  /// * `fitbit_access_token` - The user's Fitbit access token.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding values.
+  /// * `FitbitError` - An error if one occurs.
+  pub async fn get_time_series(&self, resource: Resource, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    if self.check_ratelimit(user_id).await {
+      return Err(self.rate_limit_exceeded(user_id).await);
+    }
+
+    let timezone = self.user_timezone(user_id).await?;
+
+    let period = Self::period_for_range(start, end, timezone.today(Utc::now()))?;
+
+    metrics::DATA_SOURCE.with_label_values(&["live"]).inc();
+
+    let api = &self.api;
+    let timezone = timezone.name.as_str();
+
+    let result = fetch_revalidating(&self.cache_client, self.cache_disabled, user_id, resource, start, end, |etag| {
+      with_token_refresh(fitbit_access_token, move |token| {
+        let etag = etag.clone();
+        async move { api.get_time_series_if_none_match(fitbit_user_id, &token, resource, end, period, timezone, etag.as_deref()).await }
+      }, || self.refreshed_access_token(user_id))
+    }).await;
+
+    let (series, modified) = self.check_rate_limited(user_id, result).await?;
+
+    // Filters out days that are not in the range.
+    let values = series.values.into_iter()
+      .filter(|(date, _)| *date >= start && *date <= end)
+      .collect();
+
+    self.set_ratelimit(user_id, series.rate_limit).await?;
+
+    // Fitbit confirmed the cached values are current, so there is nothing to store.
+    if !modified {
+      return Ok(values);
+    }
+
+    self.store(resource, user_id, values, start, end).await
+  }
+
+  /// Caches values freshly fetched for a range. Step counts that can no longer change are also written through to
+  /// Postgres, and days in the range without a step count are stored as 0 so they are not fetched again.
+  async fn store(&self, resource: Resource, user_id: &str, values: HashMap<NaiveDate, u32>, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    match resource {
+      Resource::Steps => {
+        let steps = with_known_empty_days(values, start, end);
+
+        self.cache(user_id, &steps).await?;
+        self.persist(user_id, &steps).await?;
+
+        Ok(steps)
+      },
+      Resource::HeartRate => {
+        info!("Cacheing {} heart rates", values.len());
+
+        match self.cache_client.add_heart_rate_bulk(user_id, &values).await {
+          Ok(_) => Ok(values),
+          Err(e) => Err(FitbitError::CacheError(e.to_string())),
+        }
+      },
+      Resource::Calories => {
+        info!("Cacheing {} days of calories", values.len());
+
+        match self.cache_client.add_calories_bulk(user_id, &values).await {
+          Ok(_) => Ok(values),
+          Err(e) => Err(FitbitError::CacheError(e.to_string())),
+        }
+      },
+      Resource::Floors => {
+        info!("Cacheing {} days of floors", values.len());
+
+        match self.cache_client.add_floors_bulk(user_id, &values).await {
+          Ok(_) => Ok(values),
+          Err(e) => Err(FitbitError::CacheError(e.to_string())),
+        }
+      },
+      // Cached together by `get_active_minutes` once both series are fetched.
+      Resource::MinutesFairlyActive | Resource::MinutesVeryActive => Ok(values),
+    }
+  }
+
+  /// Checks that a range, inclusive, is ordered and does not end after today. Ranges of any length pass, for callers
+  /// that chunk them.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// * `today` - The current date in the timezone the range is in.
+  /// 
+  /// # Returns
+  /// 
+  /// * `FitbitError` - If the range is inverted or in the future.
+  fn check_past_range(start: NaiveDate, end: NaiveDate, today: NaiveDate) -> Result<(), FitbitError> {
+    if start > end {
+      Err(FitbitError::DateOutOfRange("Start date must be before end date.".to_string()))?;
+    }
+
+    if end > today {
+      info!("End date: {}", end.format("%Y-%m-%d"));
+      info!("Today: {}", today.format("%Y-%m-%d"));
+      Err(FitbitError::DateOutOfRange("Dates must be in the past.".to_string()))?;
+    }
+
+    Ok(())
+  }
+
+  /// Picks the smallest Fitbit time-series period covering the given range, inclusive.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// * `today` - The current date in the timezone the range is in.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Period` - The period to request, ending on `end`.
+  /// * `FitbitError` - If the range is inverted, in the future, or longer than one year.
+  fn period_for_range(start: NaiveDate, end: NaiveDate, today: NaiveDate) -> Result<Period, FitbitError> {
+    Self::check_past_range(start, end, today)?;
+
+    let difference = end.signed_duration_since(start);
+
+    let period = match difference.num_days() {
+      0 => Period::OneDay,
+      1..=6 => Period::OneWeek,
+      7..=27 => Period::OneMonth,
+      28..=89 => Period::ThreeMonths,
+      90..=179 => Period::SixMonths,
+      180..=364 => Period::OneYear,
+      _ => Err(FitbitError::DateOutOfRange("Date range must be less than one year.".to_string()))?,
+    };
+
+    Ok(period)
+  }
+
+  async fn cache(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    cache_steps(&self.cache_client, self.cache_disabled, user_id, steps).await
+  }
+
+  /// Writes the days that can no longer change through to Postgres, where they are kept permanently.
+  async fn persist(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+    let historical = historical_days(steps, Utc::now().date_naive());
+
+    info!("Persisting {} steps", historical.len());
+
+    self.database_client.upsert_steps(user_id, &historical).await
+  }
+
+  /// Records the reset time when Fitbit rejects a request with a 429, and converts the rejection into
+  /// `FitbitError::RateLimitExceeded` carrying the real reset time so the caller can requeue.
+  async fn check_rate_limited<T>(&self, user_id: &str, result: Result<T, FitbitError>) -> Result<T, FitbitError> {
+    let Err(FitbitError::RateLimited(info)) = result else {
+      return result;
+    };
+
+    if let Some(reset_seconds) = info.reset_seconds {
+      if let Err(e) = self.cache_client.set_ratelimit_reset(user_id, reset_seconds).await {
+        error!("Failed to store rate limit reset for user {}: {}", user_id, e);
+      }
+    }
+
+    if let Err(e) = self.cache_client.set_reported_ratelimit(user_id, info, ratelimit_reset_seconds(info)).await {
+      error!("Failed to store reported rate limit for user {}: {}", user_id, e);
+    }
+
+    Err(rate_limited_error(info, Utc::now()))
+  }
+
+  /// The error for a command stopped by the per-user rate limit, carrying the time until the stored reset.
+  async fn rate_limit_exceeded(&self, user_id: &str) -> FitbitError {
+    let now = Utc::now().naive_local();
+    let retry_after = match self.cache_client.get_ratelimit_reset(user_id).await {
+      Ok(reset) => seconds_until(reset, now),
+      Err(_) => None,
+    };
+
+    FitbitError::RateLimitExceeded("Rate limit exceeded".to_string(), retry_after)
+  }
+
+  /// Records a query against the user's rate limit window, using the reset time Fitbit reported with the response,
+  /// and stores the remaining requests Fitbit reported.
+  async fn set_ratelimit(&self, user_id: &str, rate_limit: RateLimitInfo) -> Result<(), FitbitError> {
+    record_query(&self.cache_client, user_id, rate_limit, self.rate_limit_buffer_secs).await
+  }
+
+  /// Checks whether the current rate limit window has been reached, preferring the remaining requests Fitbit reported
+  /// over the count of recorded queries. Returns true if the rate limit has been reached, false otherwise.
+  async fn check_ratelimit(&self, user_id: &str) -> bool {
+    limit_reached(&self.cache_client, user_id, self.rate_limit_per_hour, self.rate_limit_slack).await
+  }
+
+  /// Takes in the date range to be queried and the days already cached, and returns the windows that should be queried from Fitbit.
+  /// Every day missing from the cache is queried. Yesterday and today, in the user's timezone, are also re-queried if they are in
+  /// the range, since Fitbit may still be updating them, but only as often as the rate limit allows.
+  /// This function assumes that it will never be passed dates that are in the future.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `range_start` - The start date of the range.
+  /// * `range_end` - The end date of the range.
+  /// * `cached` - The values already cached within the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Vec<Range>` - The windows that should be queried from Fitbit, each small enough for a single request. Empty if nothing needs to be queried.
+  /// * `FitbitError` - An error if one occurs.
+  async fn get_live_ranges<V: Sync>(&self, user_id: &str, range_start: NaiveDate, range_end: NaiveDate, cached: &HashMap<NaiveDate, V>) -> Result<Vec<Range>, FitbitError> {
+    let today = self.user_timezone(user_id).await?.today(Utc::now());
+
+    let refresh_recent = !cached.is_empty()
+      && range_end >= today - Duration::days(1)
+      && self.recent_refresh_due(user_id).await?;
+
+    Ok(live_ranges(cached, range_start, range_end, today, refresh_recent))
+  }
+
+  /// Whether enough time has passed since the user's last query to re-query recent days without exceeding the rate limit.
+  async fn recent_refresh_due(&self, user_id: &str) -> Result<bool, FitbitError> {
+    // Gets the number of queries the user has left in the current rate limit window.
+    let Ok(remaining) = remaining_queries(&self.cache_client, user_id, self.rate_limit_per_hour, self.rate_limit_slack).await else {
+      return Err(FitbitError::CacheError("Error getting user queries.".to_string()));
+    };
+
+    // Gets the date of the user's last query.
+    let Ok(last_query_datetime) = self.cache_client.get_last_user_query(user_id).await else {
+      return Err(FitbitError::CacheError("Error getting last user query.".to_string()));
+    };
+
+    let Some(last_query) = last_query_datetime else {
+      return Ok(true);
+    };
+
+    let current_datetime: NaiveDateTime = Utc::now().naive_local();
+    let ratelimit_reset = self.cache_client.get_ratelimit_reset(user_id).await.unwrap_or(Utc::now().naive_local());
+
+    let signed_until_ratelimit_reset: i64 = (ratelimit_reset - current_datetime).num_seconds();
+    let until_ratelimit_reset: u16 = utils::safe_convert(signed_until_ratelimit_reset);
+
+    if remaining == 0 {
+      return Err(FitbitError::RateLimitExceeded("Rate limit exceeded".to_string(), seconds_until(ratelimit_reset, current_datetime)));
+    }
+
+    let request_period = request_period(until_ratelimit_reset, remaining);
+
+    let since_last_query: i64 = (current_datetime - last_query).num_seconds();
+    let since_last_query: usize = utils::safe_convert(since_last_query);
+
+    info!("Last query was {} seconds ago, refresh recent days every {} seconds", since_last_query, request_period);
+
+    Ok(since_last_query >= request_period)
+  }
+
+  /// Gets daily step counts from the cache within a given range, inclusive.
+  /// Days that are not cached are omitted, so the result may have gaps.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's Fitbit user ID.
+  /// * `start` - The start date of the range.
+  /// * `end` - The end date of the range.
+  /// 
+  /// # Returns
+  /// 
+  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding step counts.
+  /// * `FitbitError` - An error if one occurs.
+  async fn get_cached_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    cached_steps(&self.cache_client, self.cache_disabled, user_id, start, end).await
+  }
+
+  /// Gets step counts persisted in Postgres within a given range, inclusive.
+  async fn get_stored_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    let steps = self.database_client.get_steps(user_id, start, end).await?;
+
+    if !steps.is_empty() {
+      metrics::DATA_SOURCE.with_label_values(&["database"]).inc();
+    }
+
+    Ok(steps)
+  }
+
+  /// Refreshes the access token using the refresh token.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `refresh_token` - The refresh token associated with the user you want a new access token for.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok((access_token, refresh_token))` - The new access token and refresh token.
+  /// * `Err(FitbitError)` - The error returned by the internal Fitbit API.
+  pub async fn refresh_token(&self, user_id: &str) -> Result<(String, String), FitbitError> {
+    with_refresh_lock(&self.cache_client, user_id, || self.refresh_token_unlocked(user_id), || self.stored_tokens(user_id)).await
+  }
+
+  /// Refreshes the user's tokens without taking the refresh lock. Only call this while holding it.
+  async fn refresh_token_unlocked(&self, user_id: &str) -> Result<(String, String), FitbitError> {
+    let user = self.database_client.get_user(user_id).await?;
+
+    let refresh_token = match user {
+      Some(user) => user.fitbit_refresh_token,
+      None => return Err(FitbitError::UserNotFound),
+    };
+
+    let updated_token = match self.api.refresh_token(refresh_token.as_str(), self.client_id.as_str(), self.client_secret.as_str()).await {
+      Ok(updated_token) => updated_token,
+      // The refresh token is dead, so flag it rather than retrying it on every command and proactive refresh.
+      Err(FitbitError::RejectedToken) => {
+        warn!("Fitbit rejected the refresh token for {}, they must re-authorize", user_id);
+        self.database_client.mark_token_rejected(user_id).await?;
+        return Err(FitbitError::RejectedToken);
+      },
+      Err(e) => return Err(e),
+    };
+
+    let access_token = updated_token.access_token;
+    let refresh_token = updated_token.refresh_token;
+    let expires_at = Utc::now().naive_local() + Duration::seconds(i64::from(updated_token.expires_in));
+
+    self.database_client.update_user_token(user_id, access_token.as_str(), refresh_token.as_str(), expires_at).await?;
+
+    Ok((access_token, refresh_token))
+  }
+
+  /// Refreshes the tokens of every user whose access token expires within `window`, so commands never have to wait on
+  /// a refresh. Each refresh starts after a random delay of up to `spread` to avoid a burst of requests to Fitbit.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `window` - How far ahead of expiry tokens are refreshed.
+  /// * `spread` - The longest delay before a refresh starts.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(count)` - The number of refreshes started.
+  /// * `Err(FitbitError)` - The error returned by the database.
+  pub async fn refresh_expiring_tokens(&self, window: Duration, spread: std::time::Duration) -> Result<usize, FitbitError> {
+    let users = self.database_client.users_expiring_before(Utc::now().naive_local() + window).await?;
+
+    for user_id in &users {
+      let fitbit = self.clone();
+      let user_id = user_id.clone();
+      let delay = refresh_jitter(spread);
+
+      tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        if let Err(e) = fitbit.refresh_token(&user_id).await {
+          error!("Failed to proactively refresh token for user {}: {}", user_id, e);
+        }
+      });
+    }
+
+    Ok(users.len())
+  }
+
+  /// Calls `refresh_expiring_tokens` every `interval` until the task is dropped. Refreshes are spread over at most half
+  /// of the interval or window, whichever is shorter, so every token is refreshed before it expires.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `interval` - How often to look for expiring tokens.
+  /// * `window` - How far ahead of expiry tokens are refreshed.
+  pub async fn refresh_tokens_periodically(self, interval: std::time::Duration, window: Duration) {
+    let spread = std::cmp::min(interval, window.to_std().unwrap_or(interval)) / 2;
+    let mut ticker = tokio::time::interval(interval);
+
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+      ticker.tick().await;
+
+      match self.refresh_expiring_tokens(window, spread).await {
+        Ok(0) => (),
+        Ok(count) => info!("Proactively refreshing {} tokens", count),
+        Err(e) => error!("Failed to look up expiring tokens: {}", e),
+      }
+    }
+  }
+
+  /// Reads the user's current tokens from the database.
+  async fn stored_tokens(&self, user_id: &str) -> Result<(String, String), FitbitError> {
+    match self.database_client.get_user(user_id).await? {
+      Some(user) => Ok((user.fitbit_access_token, user.fitbit_refresh_token)),
+      None => Err(FitbitError::UserNotFound),
+    }
+  }
+
+  /// Refreshes the user's tokens and returns only the new access token.
+  async fn refreshed_access_token(&self, user_id: &str) -> Result<String, FitbitError> {
+    let (access_token, _) = self.refresh_token(user_id).await?;
+
+    Ok(access_token)
+  }
+
+  /// Registers a user by exchanging an OAuth authorization code for tokens and storing them. Registering a user who is
+  /// already registered replaces their stored tokens.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// * `code` - The OAuth authorization code returned by Fitbit.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the user was registered or their tokens were replaced.
+  /// * `Err(FitbitError)` - The error returned by the Fitbit API or the database.
+  pub async fn register_user(&self, user_id: &str, code: &str) -> Result<(), FitbitError> {
+    let token = self.api.exchange_code(code, self.client_id.as_str(), self.client_secret.as_str()).await?;
+
+    let expires_at = Utc::now().naive_local() + Duration::seconds(i64::from(token.expires_in));
+
+    let inserted = self.database_client.upsert_user(user_id, token.user_id.as_str(), token.access_token.as_str(), token.refresh_token.as_str(), expires_at).await?;
+
+    if !inserted {
+      info!("User {} was already registered; replaced their Fitbit tokens", user_id);
+    }
+
+    Ok(())
+  }
+
+  /// Revokes the user's token at Fitbit and removes their stored credentials and cached data.
+  /// A failed revocation at Fitbit is logged but does not prevent the local data from being removed.
+  /// 
+  /// # Arguments
+  /// 
+  /// * `user_id` - The user's internal user ID.
+  /// 
+  /// # Returns
+  /// 
+  /// * `Ok(())` - If the user's local data was removed.
+  /// * `Err(FitbitError)` - The error returned by the database or the cache.
+  pub async fn revoke_token(&self, user_id: &str) -> Result<(), FitbitError> {
+    let user = match self.database_client.get_user(user_id).await? {
+      Some(user) => user,
+      None => return Err(FitbitError::UserNotFound),
+    };
+
+    if let Err(e) = self.api.revoke_token(user.fitbit_access_token.as_str(), self.client_id.as_str(), self.client_secret.as_str()).await {
+      error!("Failed to revoke token at Fitbit for user {}: {}", user_id, e);
+    }
+
+    self.database_client.delete_user(user_id).await?;
+    self.cache_client.clear_user(user_id).await?;
+
+    Ok(())
+  }
+}
+
+/// Fills in every day from `start` to `end`, inclusive, that has no step count with an explicit 0.
+/// Fitbit reports days without any recorded steps as 0 but can omit days it has no data for at all, and a day
+/// that is missing from the cache looks uncached and would be fetched again on every query. Storing the known
+/// zero for a fetched window marks the day as fetched.
+fn with_known_empty_days(mut steps: HashMap<NaiveDate, u32>, start: NaiveDate, end: NaiveDate) -> HashMap<NaiveDate, u32> {
+  for date in start.iter_days().take_while(|date| *date <= end) {
+    steps.entry(date).or_insert(0);
+  }
+
+  steps
+}
+
+/// Pairs a value Fitbit answered with with the rate limit state reported in the response headers, for `Fitbit::send`.
+fn with_rate_limit<T>((value, headers): (T, reqwest::header::HeaderMap)) -> (T, RateLimitInfo) {
+  (value, RateLimitInfo::from_headers(&headers))
+}
+
+/// Converts Fitbit's rejection of a request with a 429 into `FitbitError::RateLimitExceeded`, carrying the reset time
+/// from the response headers so the caller knows when to retry.
+fn rate_limited_error(info: RateLimitInfo, now: chrono::DateTime<Utc>) -> FitbitError {
+  let Some(reset_seconds) = info.reset_seconds else {
+    return FitbitError::RateLimitExceeded("Fitbit rejected the request; reset time unknown".to_string(), None);
+  };
+
+  let reset_at = now + Duration::seconds(i64::from(u32::try_from(reset_seconds).unwrap_or(u32::MAX)));
+
+  FitbitError::RateLimitExceeded(format!("Fitbit rejected the request; resets at {}", reset_at.to_rfc3339()), Some(reset_seconds))
+}
+
+/// The most queries a user may make to Fitbit per hour unless `RATE_LIMIT_PER_HOUR` is set. Fitbit allows 150, so
+/// this leaves a margin for queries made outside the engine.
+const DEFAULT_RATE_LIMIT_PER_HOUR: usize = 145;
+
+/// Seconds added to every rate limit window Fitbit reports unless `RATE_LIMIT_BUFFER_SECS` is set, covering latency and
+/// the rounding of Fitbit's whole-second reset header.
+const DEFAULT_RATE_LIMIT_BUFFER_SECS: u64 = 2;
+
+/// Whether the user has no queries left in the current rate limit window. A cache error counts as reached, so
+/// Fitbit is never queried blind.
+async fn limit_reached<C: Cache>(cache: &C, user_id: &str, limit: usize, slack: usize) -> bool {
+  let Ok(remaining) = remaining_queries(cache, user_id, limit, slack).await else {
+    return true;
+  };
+
+  remaining == 0
+}
+
+/// The queries the user has left in the current rate limit window. The remaining requests Fitbit reported with the
+/// last response are used when there are any, since they also count queries made outside the engine and cannot drift.
+/// Otherwise the remaining queries are estimated from the queries recorded against `limit`. Either way, `slack` queries
+/// are held back.
+async fn remaining_queries<C: Cache>(cache: &C, user_id: &str, limit: usize, slack: usize) -> Result<usize, FitbitError> {
+  if let Some(remaining) = cache.get_reported_ratelimit(user_id).await?.remaining {
+    return Ok(usize::try_from(remaining).unwrap_or(usize::MAX).saturating_sub(slack));
+  }
+
+  let queries = cache.get_user_queries(user_id).await?;
+
+  Ok(limit.saturating_sub(queries).saturating_sub(slack))
+}
+
+/// Reads how much of a user's rate limit is used from the cache, without querying Fitbit.
+/// 
+/// # Returns
+/// 
+/// * `Response::RateLimit` - The queries made in the current window, the limit, and the seconds until the window resets.
+/// * `FitbitError` - If the cache could not be read.
+async fn rate_limit_status<C: Cache>(cache: &C, user_id: &str, limit: usize, now: NaiveDateTime) -> Result<Response, FitbitError> {
+  let used = cache.get_user_queries(user_id).await?;
+  let reset = cache.get_ratelimit_reset(user_id).await?;
+
+  Ok(Response::RateLimit { used, limit, reset_in_seconds: seconds_until(reset, now) })
+}
+
+/// Reads a user's step counts from the cache within a given range, inclusive. With the cache disabled the cache is
+/// not touched, and every day is missing.
+async fn cached_steps<C: Cache>(cache: &C, disabled: bool, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+  if disabled {
+    return Ok(HashMap::new());
+  }
+
+  match cache.get_steps(user_id, start, end).await {
+    Ok(steps) => {
+      if !steps.is_empty() {
+        metrics::DATA_SOURCE.with_label_values(&["cache"]).inc();
+      }
+
+      Ok(steps)
+    },
+    Err(_) => Err(FitbitError::CacheError("Error getting cached steps.".to_string()))?,
+  }
+}
+
+/// Fetches a window of a time series with `fetch`, which is passed the ETag to send as `If-None-Match`, if any. Steps
+/// are revalidated with the ETag stored for the window when every day of it is cached, so fetching unchanged days
+/// again, as a re-run backfill does, skips the parse and leaves the cache as it is. The ETag Fitbit sends with fresh
+/// steps is stored for the next fetch of the window.
+/// 
+/// # Returns
+/// 
+/// * `Ok((series, true))` - The series Fitbit sent.
+/// * `Ok((series, false))` - The cached steps, which Fitbit answered have not been modified.
+/// * `Err(e)` - If the fetch failed or the cache could not be reached.
+async fn fetch_revalidating<C, F, Fut>(cache: &C, cache_disabled: bool, user_id: &str, resource: Resource, start: NaiveDate, end: NaiveDate, fetch: F) -> Result<(TimeSeriesResult, bool), FitbitError>
+where
+  C: Cache,
+  F: FnOnce(Option<String>) -> Fut,
+  Fut: Future<Output = Result<Conditional<TimeSeriesResult>, FitbitError>>,
+{
+  let revalidating = resource == Resource::Steps && !cache_disabled;
+  let request = format!("{}:{}:{}", resource.path(), start, end);
+
+  let mut cached = HashMap::new();
+  let mut etag = None;
+
+  if revalidating {
+    if let Some(stored) = cache.get_etag(user_id, &request).await? {
+      cached = cached_steps(cache, cache_disabled, user_id, start, end).await?;
+
+      if covers_range(&cached, start, end) {
+        etag = Some(stored);
+      }
+    }
+  }
+
+  match fetch(etag).await? {
+    Conditional::NotModified(rate_limit) => Ok((TimeSeriesResult { values: cached, rate_limit }, false)),
+    Conditional::Modified(series, etag) => {
+      if let (true, Some(etag)) = (revalidating, etag) {
+        cache.set_etag(user_id, &request, &etag).await?;
+      }
+
+      Ok((series, true))
+    },
+  }
+}
+
+/// Writes a user's step counts to the cache. With the cache disabled this does nothing.
+async fn cache_steps<C: Cache>(cache: &C, disabled: bool, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
+  if disabled {
+    return Ok(());
+  }
+
+  info!("Cacheing {} steps", steps.len());
+
+  match cache.add_steps_bulk(user_id, steps).await {
+    Ok(_) => Ok(()),
+    Err(e) => Err(FitbitError::CacheError(e.to_string())),
+  }
+}
+
+/// Claims a command by its coordination ID, treating it as new if the claim cannot be checked.
+async fn claim_reply<C: Cache>(cache: &C, coordination_id: &str) -> ReplyClaim {
+  match cache.claim_reply(coordination_id).await {
+    Ok(claim) => claim,
+    Err(e) => {
+      warn!("Failed to claim command {}, executing it anyway: {}", coordination_id, e);
+      ReplyClaim::New
+    },
+  }
+}
+
+/// How many users of a `get_steps_batch` command are fetched at once.
+const STEPS_BATCH_CONCURRENCY: usize = 8;
+
+/// Gets the steps of each user with `fetch`, at most `concurrency` users at a time. A user whose steps fail is kept
+/// with their error, so one user cannot fail the whole batch. A user named more than once is fetched once.
+async fn batch_steps<F, Fut>(user_ids: Vec<String>, concurrency: usize, fetch: F) -> HashMap<String, Result<HashMap<NaiveDate, u32>, String>>
+where
+  F: Fn(String) -> Fut,
+  Fut: Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>>,
+{
+  let mut user_ids = user_ids;
+
+  user_ids.sort();
+  user_ids.dedup();
+
+  futures_util::stream::iter(user_ids)
+    .map(|user_id| {
+      let steps = fetch(user_id.clone());
+
+      async move {
+        let steps = steps.await.map_err(|e| {
+          warn!("Failed to get steps for {} in batch: {}", user_id, e);
+          e.to_string()
+        });
+
+        (user_id, steps)
+      }
+    })
+    .buffer_unordered(concurrency)
+    .collect()
+    .await
+}
+
+/// The whole seconds from `now` until `reset`, or `None` if the reset has already passed.
+fn seconds_until(reset: NaiveDateTime, now: NaiveDateTime) -> Option<u64> {
+  u64::try_from((reset - now).num_seconds()).ok().filter(|seconds| *seconds > 0)
+}
+
+/// Days older than this are final at Fitbit and can be stored permanently.
+pub(crate) const HISTORICAL_AFTER_DAYS: i64 = 2;
+
+/// The windows to query from Fitbit: every day in the range missing from `cached`, plus yesterday and today if
+/// `refresh_recent` is set. Nearby gaps share a window so they are filled with as few requests as possible.
+fn live_ranges<V>(cached: &HashMap<NaiveDate, V>, start: NaiveDate, end: NaiveDate, today: NaiveDate, refresh_recent: bool) -> Vec<Range> {
+  let mut ranges = utils::missing_ranges(cached, start, end);
+
+  let recent = today - Duration::days(1);
+
+  if refresh_recent && end >= recent {
+    ranges.push(Range { start: std::cmp::max(start, recent), end });
+  }
+
+  utils::batch_ranges(ranges)
+}
+
+/// Estimates how many seconds `requests` live requests will take when `queries` of the `limit` have already been made
+/// in the current rate limit window, which resets in `until_ratelimit_reset` seconds.
+fn backfill_estimate(requests: usize, queries: usize, limit: usize, until_ratelimit_reset: u64) -> u64 {
+  let remaining = limit.saturating_sub(queries);
+
+  if requests <= remaining {
+    return 0;
+  }
+
+  let deferred = requests - remaining;
+  let extra_windows = (deferred - 1) / limit;
+
+  until_ratelimit_reset + 3600 * extra_windows as u64
+}
+
+/// Tags each day with where it came from. Days in both maps were refreshed from Fitbit, so the live count wins.
+fn with_source(cached: HashMap<NaiveDate, u32>, live: HashMap<NaiveDate, u32>) -> HashMap<NaiveDate, (u32, Source)> {
+  let mut steps: HashMap<NaiveDate, (u32, Source)> = cached.into_iter().map(|(date, steps)| (date, (steps, Source::Cache))).collect();
+
+  steps.extend(live.into_iter().map(|(date, steps)| (date, (steps, Source::Live))));
+
+  steps
+}
+
+/// Fetches each window with `fetch` into `steps`. With `StalePolicy::AllowPartial`, reaching the rate limit stops the
+/// fetch and keeps the steps gathered so far instead of failing.
+/// 
+/// # Returns
+/// 
+/// * `bool` - Whether the rate limit cut the fetch short.
+/// * `FitbitError` - The error of the first window that failed, unless it was the rate limit and partial steps are allowed.
+async fn fetch_live_windows<F, Fut>(windows: Vec<Range>, stale_policy: StalePolicy, steps: &mut HashMap<NaiveDate, u32>, fetch: F) -> Result<bool, FitbitError>
+where
+  F: Fn(Range) -> Fut,
+  Fut: Future<Output = Result<HashMap<NaiveDate, u32>, FitbitError>>,
+{
+  for window in windows {
+    match fetch(window).await {
+      Ok(window_steps) => steps.extend(window_steps),
+      Err(e @ (FitbitError::RateLimitExceeded(..) | FitbitError::RateLimited(_))) if stale_policy == StalePolicy::AllowPartial => {
+        warn!("Rate limit reached, serving {} partial days: {}", steps.len(), e);
+        return Ok(true);
+      },
+      Err(e) => return Err(e),
+    }
+  }
+
+  Ok(false)
+}
+
+/// Backfills with fewer windows than this are fetched back to back, since they use too little of the hourly limit to
+/// be worth spreading out.
+const BACKFILL_PACING_MIN_WINDOWS: usize = 4;
+
+/// The delay that spreads `remaining` queries evenly over the `until_reset` seconds left in the rate limit window.
+fn backfill_pace(remaining: usize, until_reset: u64) -> std::time::Duration {
+  let remaining = u64::try_from(remaining.max(1)).unwrap_or(u64::MAX);
+
+  std::time::Duration::from_millis(until_reset.saturating_mul(1000) / remaining)
+}
+
+/// Fetches each window in turn with `fetch`, using `quota` to read the queries the user has left and the seconds until
+/// their rate limit window resets. When `paced`, each window after the first waits for its share of the rest of the
+/// window, plus up to a tenth more so concurrent backfills drift apart, assuming a full hour when the reset is unknown.
+/// Whenever no queries are left, or Fitbit rejects a request anyway, it waits for the window to reset. An error
+/// reading the quota ends the backfill with that error.
+async fn paced_backfill<Q, QFut, F, FFut>(user_id: &str, windows: Vec<Range>, paced: bool, quota: Q, fetch: F) -> Result<(), FitbitError>
+where
+  Q: Fn() -> QFut,
+  QFut: Future<Output = Result<(usize, Option<u64>), FitbitError>>,
+  F: Fn(Range) -> FFut,
+  FFut: Future<Output = Result<(), FitbitError>>,
+{
+  for (index, window) in windows.into_iter().enumerate() {
+    if paced && index > 0 {
+      let (remaining, until_reset) = quota().await?;
+
+      if remaining > 0 {
+        let pace = backfill_pace(remaining, until_reset.unwrap_or(3600));
+        tokio::time::sleep(pace + refresh_jitter(pace / 10)).await;
+      }
+    }
+
+    loop {
+      let (remaining, until_reset) = quota().await?;
+
+      if remaining == 0 {
+        wait_for_ratelimit_reset(user_id, until_reset).await;
+        continue;
+      }
+
+      match fetch(window.clone()).await {
+        Ok(()) => break,
+        Err(FitbitError::RateLimitExceeded(_, retry_after)) => wait_for_ratelimit_reset(user_id, retry_after).await,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Sleeps for the `until_reset` seconds left in the user's rate limit window, and for at least a minute.
+async fn wait_for_ratelimit_reset(user_id: &str, until_reset: Option<u64>) {
+  let wait = std::time::Duration::from_secs(until_reset.unwrap_or(0).max(60));
+
+  info!("Rate limit reached for user {}, waiting {:?}", user_id, wait);
+
+  tokio::time::sleep(wait).await;
+}
+
+/// Whether `steps` has a value for every day from `start` to `end`, inclusive.
+fn covers_range(steps: &HashMap<NaiveDate, u32>, start: NaiveDate, end: NaiveDate) -> bool {
+  start.iter_days()
+    .take_while(|date| *date <= end)
+    .all(|date| steps.contains_key(&date))
+}
+
+/// Converts distances in kilometers into the given unit.
+fn in_unit(distance: HashMap<NaiveDate, f64>, unit: DistanceUnit) -> HashMap<NaiveDate, f64> {
+  distance.into_iter().map(|(date, kilometers)| (date, unit.from_kilometers(kilometers))).collect()
+}
+
+/// Converts logged weights in kilograms into the given unit.
+fn in_weight_unit(logs: Vec<WeightLog>, unit: WeightUnit) -> Vec<WeightLog> {
+  logs.into_iter().map(|log| WeightLog { weight: unit.from_kilograms(log.weight), ..log }).collect()
+}
+
+/// Merges steps from Redis over steps from Postgres. Redis wins where both have a day, since it holds the most recent fetch.
+fn merge_tiers(cached: HashMap<NaiveDate, u32>, stored: HashMap<NaiveDate, u32>) -> HashMap<NaiveDate, u32> {
+  let mut steps = stored;
+
+  steps.extend(cached);
+
+  steps
+}
+
+/// The last `days` days through `today`, or just `today` if `days` is 0.
+fn recent_window(today: NaiveDate, days: u16) -> Range {
+  let start = today - Duration::days(i64::from(days.max(1)) - 1);
+
+  Range { start, end: today }
+}
+
+/// The days in `steps` that are more than `HISTORICAL_AFTER_DAYS` before `today`.
+fn historical_days(steps: &HashMap<NaiveDate, u32>, today: NaiveDate) -> HashMap<NaiveDate, u32> {
+  let cutoff = today - Duration::days(HISTORICAL_AFTER_DAYS);
+
+  steps.iter()
+    .filter(|(date, _)| **date < cutoff)
+    .map(|(date, steps)| (*date, *steps))
+    .collect()
+}
+
+/// An estimate of how often, in seconds, the user can query Fitbit without exceeding the rate limit: the time left in
+/// the window shared between the `remaining` queries.
+fn request_period(until_ratelimit_reset: u16, remaining: usize) -> usize {
+  let request_period: i64 = (f32::from(until_ratelimit_reset) / remaining.max(1) as f32).ceil() as i64;
+
+  utils::safe_convert(request_period)
+}
+
+/// Records a query against the user's rate limit window, which is taken to reset `buffer_secs` after the reset time
+/// Fitbit reported, and stores the remaining requests Fitbit reported until then.
+async fn record_query<C: Cache>(cache: &C, user_id: &str, rate_limit: RateLimitInfo, buffer_secs: u64) -> Result<(), FitbitError> {
+  let buffer = usize::try_from(buffer_secs).unwrap_or(usize::MAX);
+  let ratelimit_reset = ratelimit_reset_seconds(rate_limit).saturating_add(buffer);
+
+  let date: NaiveDateTime = Utc::now().naive_local();
+
+  cache.add_user_query(user_id, date, ratelimit_reset).await?;
+  cache.set_reported_ratelimit(user_id, rate_limit, ratelimit_reset).await
+}
+
+/// Seconds until the current rate limit window resets, as reported by Fitbit.
+/// Falls back to a full window if the header was missing or malformed, which happens on some endpoints.
+fn ratelimit_reset_seconds(rate_limit: RateLimitInfo) -> usize {
+  const DEFAULT_RATELIMIT_WINDOW: usize = 3600;
+
+  match rate_limit.reset_seconds.map(usize::try_from) {
+    Some(Ok(reset_seconds)) => reset_seconds,
+    _ => {
+      warn!("Missing or invalid rate limit reset header, assuming {} seconds", DEFAULT_RATELIMIT_WINDOW);
+      DEFAULT_RATELIMIT_WINDOW
+    },
+  }
+}
+
+/// A random delay between zero and `spread`, inclusive.
+fn refresh_jitter(spread: std::time::Duration) -> std::time::Duration {
+  let spread = u64::try_from(spread.as_millis()).unwrap_or(u64::MAX);
+
+  std::time::Duration::from_millis(fastrand::u64(0..=spread))
+}
+
+/// How often a command waiting on another command's token refresh checks whether it has finished.
+const REFRESH_LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Runs `refresh` while holding the user's refresh lock, so only one refresh happens at a time. If another command
+/// already holds the lock, this waits for it to finish and returns the tokens it stored, read with `reread`.
+/// 
+/// # Arguments
+/// 
+/// * `lock` - The lock shared by every command.
+/// * `user_id` - The user whose tokens are being refreshed.
+/// * `refresh` - Refreshes the tokens, returning the new tokens.
+/// * `reread` - Reads the current tokens from storage.
+/// 
+/// # Returns
+/// 
+/// * `Ok(T)` - The tokens from the refresh, or from storage if another command refreshed them.
+/// * `Err(FitbitError)` - The error from the lock, the refresh, or the read.
+async fn with_refresh_lock<T, L, R, RFut, W, WFut>(lock: &L, user_id: &str, refresh: R, reread: W) -> Result<T, FitbitError>
+where
+  L: RefreshLock,
+  R: FnOnce() -> RFut,
+  RFut: Future<Output = Result<T, FitbitError>>,
+  W: FnOnce() -> WFut,
+  WFut: Future<Output = Result<T, FitbitError>>,
+{
+  let token = ulid::Ulid::new().to_string();
+
+  if lock.acquire_refresh_lock(user_id, &token).await? {
+    let result = refresh().await;
+
+    if let Err(e) = lock.release_refresh_lock(user_id, &token).await {
+      warn!("Failed to release refresh lock for user {}: {}", user_id, e);
+    }
+
+    return result;
+  }
+
+  info!("Tokens for user {} are already being refreshed, waiting", user_id);
+
+  // The lock expires on its own, so this never waits much longer than its TTL.
+  let polls = CacheHandler::REFRESH_LOCK_TTL.as_millis() / REFRESH_LOCK_POLL_INTERVAL.as_millis() + 1;
+
+  for _ in 0..polls {
+    tokio::time::sleep(REFRESH_LOCK_POLL_INTERVAL).await;
+
+    if !lock.refresh_lock_held(user_id).await? {
+      break;
+    }
+  }
+
+  reread().await
+}
+
+/// Waits for `command` for at most `timeout`, dropping it and answering with `FitbitError::Timeout` if it is not done.
+async fn within_timeout<F: Future<Output = Response>>(timeout: std::time::Duration, command: F) -> Response {
+  match tokio::time::timeout(timeout, command).await {
+    Ok(response) => response,
+    Err(_) => Response::Error(FitbitError::Timeout(timeout.as_secs())),
+  }
+}
+
+/// Runs `request` with the given access token. If Fitbit reports the token as expired, the token is
+/// refreshed with `refresh` and the request is retried exactly once with the new token. Every request to Fitbit goes
+/// through here, so the command's deadline is checked first.
+/// 
+/// # Errors
+/// 
+/// Returns `FitbitError::CommandExpired` without making the request if the command's deadline has passed.
+/// 
+/// # Arguments
+/// 
+/// * `access_token` - The access token to try first.
+/// * `request` - The request to run, given an access token.
+/// * `refresh` - Refreshes the token, returning the new access token.
+/// 
+/// # Returns
+/// 
+/// * `Ok(T)` - The result of the first successful request.
+/// * `Err(FitbitError)` - The error from the request, the refresh, or the single retry.
+async fn with_token_refresh<T, R, RFut, F, FFut>(access_token: &str, request: R, refresh: F) -> Result<T, FitbitError>
+where
+  R: Fn(String) -> RFut,
+  RFut: Future<Output = Result<T, FitbitError>>,
+  F: FnOnce() -> FFut,
+  FFut: Future<Output = Result<String, FitbitError>>,
+{
+  check_deadline()?;
+
+  match request(access_token.to_string()).await {
+    Err(FitbitError::ExpiredToken) => {
+      info!("Access token expired; refreshing and retrying once");
+
+      let access_token = refresh().await?;
+
+      request(access_token).await
+    },
+    result => result,
+  }
+}
+
+/// Fails if the executing command has a deadline and it has passed. Commands without one, such as HTTP requests and
+/// background backfills, never expire.
+fn check_deadline() -> Result<(), FitbitError> {
+  let expired = DEADLINE.try_with(|deadline| *deadline < Utc::now().naive_utc()).unwrap_or(false);
+
+  if expired {
+    return Err(FitbitError::CommandExpired);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::mock::MockFitbitApi;
+  use crate::cache::{MemoryCache, QueuedCommand};
+  use crate::database::MemoryDatabase;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  /// A client over `api`, with in-memory storage holding one connected user, `user`, whose Fitbit user ID is
+  /// `FITBIT` and whose access token, `token`, has not expired.
+  async fn fitbit(api: MockFitbitApi) -> Fitbit<MockFitbitApi, MemoryCache, MemoryDatabase> {
+    let database_client = MemoryDatabase::new();
+    let expires_at = Utc::now().naive_utc() + Duration::hours(8);
+    database_client.upsert_user("user", "FITBIT", "token", "refresh", expires_at).await.unwrap();
+
+    Fitbit {
+      api,
+      cache_client: MemoryCache::new(),
+      database_client,
+      client_id: "client".to_string(),
+      client_secret: "secret".to_string(),
+      rate_limit_per_hour: DEFAULT_RATE_LIMIT_PER_HOUR,
+      rate_limit_buffer_secs: DEFAULT_RATE_LIMIT_BUFFER_SECS,
+      rate_limit_slack: 0,
+      cache_disabled: false,
+    }
+  }
+
+  #[tokio::test]
+  async fn retries_once_after_refresh() {
+    let refreshes = AtomicUsize::new(0);
+
+    let result = with_token_refresh("expired", |token| async move {
+      match token.as_str() {
+        "expired" => Err(FitbitError::ExpiredToken),
+        _ => Ok(token),
+      }
+    }, || async {
+      refreshes.fetch_add(1, Ordering::SeqCst);
+      Ok("fresh".to_string())
+    }).await;
+
+    assert_eq!(result.unwrap(), "fresh");
+    assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn does_not_retry_twice() {
+    let requests = AtomicUsize::new(0);
+
+    let result: Result<(), FitbitError> = with_token_refresh("expired", |_| {
+      requests.fetch_add(1, Ordering::SeqCst);
+      async { Err(FitbitError::ExpiredToken) }
+    }, || async { Ok("still-expired".to_string()) }).await;
+
+    assert!(matches!(result, Err(FitbitError::ExpiredToken)));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn ratelimit_reset_defaults_when_header_missing() {
+    let headers = reqwest::header::HeaderMap::new();
+
+    assert_eq!(ratelimit_reset_seconds(RateLimitInfo::from_headers(&headers)), 3600);
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("fitbit-rate-limit-reset", "not-a-number".parse().unwrap());
+
+    assert_eq!(ratelimit_reset_seconds(RateLimitInfo::from_headers(&headers)), 3600);
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("fitbit-rate-limit-reset", "42".parse().unwrap());
+
+    assert_eq!(ratelimit_reset_seconds(RateLimitInfo::from_headers(&headers)), 42);
+  }
+
+  #[tokio::test]
+  async fn does_not_refresh_on_other_errors() {
+    let result: Result<(), FitbitError> = with_token_refresh("token", |_| async {
+      Err(FitbitError::UserNotFound)
+    }, || async {
+      panic!("Should not refresh");
+    }).await;
+
+    assert!(matches!(result, Err(FitbitError::UserNotFound)));
+  }
+
+  fn date(day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(2023, 1, day).unwrap()
+  }
+
+  #[tokio::test]
+  async fn refreshes_expired_token_through_api() {
+    let api = MockFitbitApi::new()
+      .with_steps(HashMap::from([(date(1), 100), (date(2), 200)]))
+      .with_expired_token("expired");
+
+    let result = with_token_refresh("expired", |token| {
+      let api = &api;
+      async move { api.get_time_series("FITBIT", &token, Resource::Steps, date(2), Period::OneWeek, "UTC").await }
+    }, || async {
+      let token = api.refresh_token("refresh", "id", "secret").await?;
+      Ok(token.access_token)
+    }).await;
+
+    let steps = result.unwrap().values;
+
+    assert_eq!(steps.get(&date(2)), Some(&200));
+    assert_eq!(api.requests(), vec!["expired".to_string(), "refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn surfaces_rate_limit_from_api() {
+    let info = RateLimitInfo { remaining: Some(0), limit: None, reset_seconds: Some(120) };
+    let api = MockFitbitApi::new().rate_limited(info);
+
+    let result = with_token_refresh("token", |token| {
+      let api = &api;
+      async move { api.get_time_series("FITBIT", &token, Resource::HeartRate, date(2), Period::OneDay, "UTC").await }
+    }, || async {
+      panic!("Should not refresh");
+    }).await;
+
+    assert!(matches!(result, Err(FitbitError::RateLimited(limit)) if limit == info));
+    assert_eq!(api.requests().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn throttled_commands_report_retry_time() {
+    let info = RateLimitInfo { remaining: Some(0), limit: None, reset_seconds: Some(120) };
+    let api = MockFitbitApi::new().rate_limited(info);
+
+    let Err(FitbitError::RateLimited(info)) = api.get_time_series("FITBIT", "token", Resource::Steps, date(2), Period::OneDay, "UTC").await else {
+      panic!("Expected a rate limit");
+    };
+
+    let error = rate_limited_error(info, Utc::now());
+
+    assert_eq!(error.retry_after_seconds(), Some(120));
+    assert!(error.to_string().ends_with("retry after 120 seconds"));
+
+    let reply: serde_json::Value = serde_json::from_str(&utils::encode_response(Response::Error(error), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["code"], "rate_limit_exceeded");
+    assert_eq!(reply["retry_after_seconds"], 120);
+  }
+
+  #[test]
+  fn today_follows_the_users_local_day() {
+    let los_angeles = UserTimezone { name: "America/Los_Angeles".to_string(), utc_offset_seconds: -8 * 3600 };
+    let tokyo = UserTimezone { name: "Asia/Tokyo".to_string(), utc_offset_seconds: 9 * 3600 };
+
+    // 03:00 UTC on the 2nd is still the evening of the 1st in Los Angeles.
+    let early = date(2).and_hms_opt(3, 0, 0).unwrap().and_utc();
+
+    assert_eq!(UserTimezone::utc().today(early), date(2));
+    assert_eq!(los_angeles.today(early), date(1));
+
+    // 20:00 UTC on the 2nd is already the morning of the 3rd in Tokyo.
+    let late = date(2).and_hms_opt(20, 0, 0).unwrap().and_utc();
+
+    assert_eq!(UserTimezone::utc().today(late), date(2));
+    assert_eq!(tokyo.today(late), date(3));
+  }
+
+  #[test]
+  fn ranges_may_end_on_the_users_today() {
+    let tokyo = UserTimezone { name: "Asia/Tokyo".to_string(), utc_offset_seconds: 9 * 3600 };
+    let now = date(2).and_hms_opt(20, 0, 0).unwrap().and_utc();
+
+    assert!(matches!(Fitbit::<MockFitbitApi>::period_for_range(date(1), date(3), tokyo.today(now)), Ok(Period::OneWeek)));
+    assert!(matches!(Fitbit::<MockFitbitApi>::period_for_range(date(1), date(3), UserTimezone::utc().today(now)), Err(FitbitError::DateOutOfRange(_))));
+  }
+
+  #[tokio::test]
+  async fn profile_timezone_is_stored_for_the_user() {
+    let profile = Profile { timezone: "Europe/Paris".to_string(), offset_from_utc_millis: 3_600_000, ..Default::default() };
+    let fitbit = fitbit(MockFitbitApi::new().with_profile(profile)).await;
+
+    assert_eq!(fitbit.user_timezone("user").await.unwrap(), UserTimezone::utc());
+
+    fitbit.get_profile("user").await.unwrap();
+
+    let paris = UserTimezone { name: "Europe/Paris".to_string(), utc_offset_seconds: 3600 };
+    assert_eq!(fitbit.user_timezone("user").await.unwrap(), paris);
+
+    // The profile is cached, so the second read does not go to Fitbit, and the timezone stays stored.
+    fitbit.get_profile("user").await.unwrap();
+    assert_eq!(fitbit.user_timezone("user").await.unwrap(), paris);
+    assert_eq!(fitbit.api.requests(), vec!["token".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn calories_are_read_from_the_cache_once_fetched() {
+    let calories = HashMap::from([(date(1), 2100), (date(2), 2300), (date(3), 1900)]);
+    let fitbit = fitbit(MockFitbitApi::new().with_time_series(Resource::Calories, calories.clone())).await;
+
+    assert_eq!(fitbit.get_calories("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), calories);
+    assert_eq!(fitbit.get_calories("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), calories);
+
+    assert_eq!(fitbit.api.requests().len(), 1);
+    assert_eq!(fitbit.cache_client.get_calories("user", date(1), date(3)).await.unwrap(), calories);
+  }
+
+  #[tokio::test]
+  async fn calories_are_fetched_with_a_refreshed_token() {
+    let calories = HashMap::from([(date(1), 2100)]);
+    let fitbit = fitbit(MockFitbitApi::new().with_time_series(Resource::Calories, calories.clone())).await;
+    let expired_at = Utc::now().naive_utc() - Duration::hours(1);
+    fitbit.database_client.upsert_user("user", "FITBIT", "token", "refresh", expired_at).await.unwrap();
+
+    assert_eq!(fitbit.get_calories("user", "FITBIT", "token", date(1), date(1)).await.unwrap(), calories);
+
+    assert_eq!(fitbit.api.refreshes(), 1);
+    assert_eq!(fitbit.api.requests(), vec!["refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn distance_is_read_from_the_cache_once_fetched() {
+    let distance = HashMap::from([(date(1), 5.25), (date(2), 0.5), (date(3), 12.0)]);
+    let fitbit = fitbit(MockFitbitApi::new().with_distance(distance.clone())).await;
+
+    assert_eq!(fitbit.get_distance("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), distance);
+    assert_eq!(fitbit.get_distance("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), distance);
+
+    assert_eq!(fitbit.api.requests().len(), 1);
+    assert_eq!(fitbit.cache_client.get_distance("user", date(1), date(3)).await.unwrap(), distance);
+  }
+
+  #[tokio::test]
+  async fn active_minutes_are_merged_and_cached() {
+    let fairly = HashMap::from([(date(1), 10), (date(2), 0)]);
+    let very = HashMap::from([(date(1), 5), (date(2), 30)]);
+    let api = MockFitbitApi::new()
+      .with_time_series(Resource::MinutesFairlyActive, fairly.clone())
+      .with_time_series(Resource::MinutesVeryActive, very.clone());
+    let fitbit = fitbit(api).await;
+
+    let expected = utils::merge_active_minutes(fairly, very);
+
+    assert_eq!(fitbit.get_active_minutes("user", "FITBIT", "token", date(1), date(2)).await.unwrap(), expected);
+    assert_eq!(fitbit.get_active_minutes("user", "FITBIT", "token", date(1), date(2)).await.unwrap(), expected);
+
+    // One request per intensity, and none once cached.
+    assert_eq!(fitbit.api.requests().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn spo2_is_fetched_in_chunks() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let end = date(1) + Duration::days(api::SPO2_MAX_DAYS * 2);
+
+    fitbit.get_spo2("user", "FITBIT", "token", date(1), end).await.unwrap();
+
+    assert_eq!(fitbit.api.requests().len(), 3);
+    assert_eq!(fitbit.cache_client.get_user_queries("user").await.unwrap(), 3);
+  }
+
+  #[tokio::test]
+  async fn floors_are_read_from_the_cache_once_fetched() {
+    let floors = HashMap::from([(date(1), 12), (date(2), 0), (date(3), 4)]);
+    let fitbit = fitbit(MockFitbitApi::new().with_time_series(Resource::Floors, floors.clone())).await;
+
+    assert_eq!(fitbit.get_floors("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), floors);
+    assert_eq!(fitbit.get_floors("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), floors);
+
+    assert_eq!(fitbit.api.requests().len(), 1);
+    assert_eq!(fitbit.cache_client.get_floors("user", date(1), date(3)).await.unwrap(), floors);
+  }
+
+  #[tokio::test]
+  async fn cardio_scores_over_a_year_are_fetched_in_chunks() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let end = date(1) + Duration::days(399);
+
+    fitbit.get_cardio_score("user", "FITBIT", "token", date(1), end).await.unwrap();
+
+    let chunks = utils::chunk_range_by(&Range { start: date(1), end }, api::CARDIO_SCORE_MAX_DAYS).len();
+    assert_eq!(fitbit.api.requests().len(), chunks);
+  }
+
+  #[tokio::test]
+  async fn weights_over_a_year_are_fetched_in_chunks() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let end = date(1) + Duration::days(399);
+
+    assert!(fitbit.get_weight("user", "FITBIT", "token", date(1), end).await.unwrap().is_empty());
+
+    let chunks = utils::chunk_range_by(&Range { start: date(1), end }, api::WEIGHT_MAX_DAYS).len();
+    assert_eq!(fitbit.api.requests().len(), chunks);
+  }
+
+  #[tokio::test]
+  async fn weights_must_be_in_the_past() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let today = Utc::now().date_naive();
+
+    let result = fitbit.get_weight("user", "FITBIT", "token", today, today + Duration::days(2)).await;
+
+    assert!(matches!(result, Err(FitbitError::DateOutOfRange(_))));
+    assert!(fitbit.api.requests().is_empty());
+  }
+
+  #[tokio::test]
+  async fn heart_rate_zones_are_read_from_the_cache_once_fetched() {
+    let zones = HashMap::from([
+      (date(1), HeartRateZones { out_of_range: 1338, fat_burn: 86, cardio: 12, peak: 0 }),
+      (date(2), HeartRateZones { out_of_range: 1400, fat_burn: 30, cardio: 8, peak: 2 }),
+    ]);
+    let fitbit = fitbit(MockFitbitApi::new().with_heart_rate_zones(zones.clone())).await;
+
+    assert_eq!(fitbit.get_heart_rate_zones("user", "FITBIT", "token", date(1), date(2)).await.unwrap(), zones);
+    assert_eq!(fitbit.get_heart_rate_zones("user", "FITBIT", "token", date(1), date(2)).await.unwrap(), zones);
+
+    assert_eq!(fitbit.api.requests().len(), 1);
+    assert_eq!(fitbit.cache_client.get_heart_rate_zones("user", date(1), date(2)).await.unwrap(), zones);
+  }
+
+  #[tokio::test]
+  async fn recent_days_are_the_users_local_days() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let timezone = UserTimezone { name: "Etc/GMT+12".to_string(), utc_offset_seconds: -12 * 60 * 60 };
+    fitbit.database_client.set_timezone("user", &timezone).await.unwrap();
+
+    // The user's yesterday is refreshed even when it is already two days ago in UTC.
+    let yesterday = timezone.today(Utc::now()) - Duration::days(1);
+    let cached = HashMap::from([(yesterday, 100)]);
+
+    assert_eq!(fitbit.get_live_ranges("user", yesterday, yesterday, &cached).await.unwrap(), vec![Range { start: yesterday, end: yesterday }]);
+  }
+
+  #[tokio::test]
+  async fn goals_are_fetched_once_and_counted() {
+    let goals = Goals { steps: Some(10000), floors: Some(10), ..Default::default() };
+    let fitbit = fitbit(MockFitbitApi::new().with_goals(goals.clone())).await;
+
+    assert_eq!(fitbit.get_goals("user").await.unwrap(), goals);
+    assert_eq!(fitbit.get_goals("user").await.unwrap(), goals);
+
+    // The second read is served from the cache, so only the first counts against the rate limit.
+    assert_eq!(fitbit.api.requests(), vec!["token".to_string()]);
+    assert_eq!(fitbit.cache_client.get_user_queries("user").await.unwrap(), 1);
+  }
+
+  #[tokio::test]
+  async fn goals_are_fetched_with_a_refreshed_token() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let expired_at = Utc::now().naive_utc() - Duration::hours(1);
+    fitbit.database_client.upsert_user("user", "FITBIT", "token", "refresh", expired_at).await.unwrap();
+
+    fitbit.get_goals("user").await.unwrap();
+
+    assert_eq!(fitbit.api.refreshes(), 1);
+    assert_eq!(fitbit.api.requests(), vec!["refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn steps_are_refreshed_with_a_refreshed_token() {
+    let fitbit = fitbit(MockFitbitApi::new().with_steps(HashMap::from([(date(1), 1200)]))).await;
+    let expired_at = Utc::now().naive_utc() - Duration::hours(1);
+    fitbit.database_client.upsert_user("user", "FITBIT", "token", "refresh", expired_at).await.unwrap();
+
+    fitbit.refresh_steps("user", "FITBIT", "token", date(1), date(1)).await.unwrap();
+
+    assert_eq!(fitbit.api.refreshes(), 1);
+    assert_eq!(fitbit.api.requests(), vec!["refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn cardio_scores_are_fetched_with_a_refreshed_token() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let expired_at = Utc::now().naive_utc() - Duration::hours(1);
+    fitbit.database_client.upsert_user("user", "FITBIT", "token", "refresh", expired_at).await.unwrap();
+
+    fitbit.get_cardio_score("user", "FITBIT", "token", date(1), date(1)).await.unwrap();
+
+    assert_eq!(fitbit.api.refreshes(), 1);
+    assert_eq!(fitbit.api.requests(), vec!["refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn weight_is_fetched_with_a_refreshed_token() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let expired_at = Utc::now().naive_utc() - Duration::hours(1);
+    fitbit.database_client.upsert_user("user", "FITBIT", "token", "refresh", expired_at).await.unwrap();
+
+    fitbit.get_weight("user", "FITBIT", "token", date(1), date(1)).await.unwrap();
+
+    assert_eq!(fitbit.api.refreshes(), 1);
+    assert_eq!(fitbit.api.requests(), vec!["refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn steps_are_fetched_with_a_refreshed_token() {
+    let fitbit = fitbit(MockFitbitApi::new().with_steps(HashMap::from([(date(1), 1200)]))).await;
+    let expired_at = Utc::now().naive_utc() - Duration::hours(1);
+    fitbit.database_client.upsert_user("user", "FITBIT", "token", "refresh", expired_at).await.unwrap();
+
+    fitbit.get_steps("user", "FITBIT", "token", date(1), date(1)).await.unwrap();
+
+    assert_eq!(fitbit.api.refreshes(), 1);
+    assert_eq!(fitbit.api.requests(), vec!["refreshed".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn rate_limited_goals_report_the_reset() {
+    let info = RateLimitInfo { remaining: Some(0), limit: None, reset_seconds: Some(120) };
+    let fitbit = fitbit(MockFitbitApi::new().rate_limited(info)).await;
+
+    let error = fitbit.get_goals("user").await.unwrap_err();
+
+    assert_eq!(error.retry_after_seconds(), Some(120));
+    assert_eq!(fitbit.cache_client.get_goals("user").await.unwrap(), None);
+
+    // Fitbit reported no requests remaining, so the next read is refused without asking it.
+    assert!(matches!(fitbit.get_goals("user").await, Err(FitbitError::RateLimitExceeded(..))));
+    assert_eq!(fitbit.api.requests().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn setting_the_step_goal_drops_cached_goals() {
+    let fitbit = fitbit(MockFitbitApi::new().with_goals(Goals { steps: Some(10000), ..Default::default() })).await;
+
+    fitbit.get_goals("user").await.unwrap();
+
+    assert_eq!(fitbit.set_step_goal("user", 12000).await.unwrap(), 12000);
+    assert_eq!(fitbit.cache_client.get_goals("user").await.unwrap(), None);
+    assert_eq!(fitbit.cache_client.get_user_queries("user").await.unwrap(), 2);
+  }
+
+  #[tokio::test]
+  async fn goals_of_unknown_users_are_not_fetched() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+
+    assert!(matches!(fitbit.get_goals("unknown").await, Err(FitbitError::UserNotFound)));
+    assert!(fitbit.api.requests().is_empty());
+  }
+
+  #[tokio::test]
+  async fn devices_are_fetched_once_and_counted() {
+    let device = Device { id: "816713257".to_string(), device_type: "TRACKER".to_string(), battery_level: Some(80), last_sync_time: "2023-01-01T11:18:15.000".to_string() };
+    let fitbit = fitbit(MockFitbitApi::new().with_devices(vec![device.clone()])).await;
+
+    assert_eq!(fitbit.get_devices("user").await.unwrap(), vec![device.clone()]);
+    assert_eq!(fitbit.get_devices("user").await.unwrap(), vec![device]);
+
+    assert_eq!(fitbit.api.requests(), vec!["token".to_string()]);
+    assert_eq!(fitbit.cache_client.get_user_queries("user").await.unwrap(), 1);
+  }
+
+  #[tokio::test]
+  async fn devices_are_fetched_again_when_fitbit_expires_the_token() {
+    let fitbit = fitbit(MockFitbitApi::new().with_expired_token("token")).await;
+
+    fitbit.get_devices("user").await.unwrap();
+
+    assert_eq!(fitbit.api.requests(), vec!["token".to_string(), "refreshed".to_string()]);
+    assert_eq!(fitbit.database_client.get_user("user").await.unwrap().unwrap().fitbit_access_token, "refreshed");
+  }
+
+  #[tokio::test]
+  async fn expired_commands_skip_fitbit() {
+    let api = MockFitbitApi::new();
+    let deadline = Utc::now().naive_utc() + Duration::milliseconds(50);
+
+    let fetch = || with_token_refresh("token", |token| {
+      let api = &api;
+      async move { api.get_profile("FITBIT", &token).await }
+    }, || async { Ok("refreshed".to_string()) });
+
+    let (before, after) = DEADLINE.scope(deadline, async {
+      let before = fetch().await;
+
+      // The command waits past its TTL, for example for a concurrency permit.
+      tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+      (before, fetch().await)
+    }).await;
+
+    assert!(before.is_ok());
+    assert!(matches!(after, Err(FitbitError::CommandExpired)));
+    assert_eq!(api.requests(), vec!["token".to_string()]);
+
+    // Without a deadline, nothing expires.
+    assert!(fetch().await.is_ok());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn slow_commands_time_out_and_release_their_permit() {
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+    let timeout = std::time::Duration::from_secs(5);
+
+    let slow = {
+      let semaphore = semaphore.clone();
+
+      async move {
+        let _permit = semaphore.acquire().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(3600)).await;
+        Response::UserExists(true)
+      }
+    };
+
+    let started = tokio::time::Instant::now();
+
+    assert!(matches!(within_timeout(timeout, slow).await, Response::Error(FitbitError::Timeout(5))));
+    assert_eq!(started.elapsed(), timeout);
+    assert_eq!(semaphore.available_permits(), 1);
+
+    assert!(matches!(within_timeout(timeout, async { Response::UserExists(true) }).await, Response::UserExists(true)));
+  }
+
+  #[test]
+  fn retry_time_counts_down_to_reset() {
+    let now = date(2).and_hms_opt(12, 0, 0).unwrap();
+
+    assert_eq!(seconds_until(now + Duration::seconds(90), now), Some(90));
+    assert_eq!(seconds_until(now, now), None);
+    assert_eq!(seconds_until(now - Duration::seconds(90), now), None);
+  }
+
+  #[tokio::test]
+  async fn redis_hit_skips_postgres() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    let cached = HashMap::from([(date(1), 100), (date(2), 200), (date(3), 300)]);
+    fitbit.cache_client.add_steps_bulk("user", &cached).await.unwrap();
+
+    assert_eq!(fitbit.get_steps("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), cached);
+
+    assert_eq!(fitbit.database_client.step_reads(), 0);
+    assert!(fitbit.api.requests().is_empty());
+  }
+
+  #[tokio::test]
+  async fn postgres_fills_redis_misses() {
+    let fitbit = fitbit(MockFitbitApi::new()).await;
+    fitbit.cache_client.add_steps_bulk("user", &HashMap::from([(date(3), 300)])).await.unwrap();
+    fitbit.database_client.upsert_steps("user", &HashMap::from([(date(1), 100), (date(2), 200)])).await.unwrap();
+
+    let steps = HashMap::from([(date(1), 100), (date(2), 200), (date(3), 300)]);
+
+    assert_eq!(fitbit.get_steps("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), steps);
+
+    assert_eq!(fitbit.database_client.step_reads(), 1);
+    assert!(fitbit.api.requests().is_empty());
+    // Redis is warmed with the stored days, so the next read stays in Redis.
+    assert_eq!(fitbit.cache_client.get_steps("user", date(1), date(3)).await.unwrap(), steps);
+  }
+
+  #[test]
+  fn redis_wins_over_postgres() {
+    let cached = HashMap::from([(date(2), 250)]);
+    let stored = HashMap::from([(date(1), 100), (date(2), 200)]);
+
+    assert_eq!(merge_tiers(cached, stored), HashMap::from([(date(1), 100), (date(2), 250)]));
+  }
 
-/// The Fitbit API client. This is designed to be cheaply cloneable to allow for multiple requests to be handled concurrently.
-#[derive(Clone)]
-pub struct Fitbit {
-  reqwest_client: reqwest::Client,
-  cache_client: CacheHandler,
-  database_client: DatabaseHandler,
-  client_id: String,
-  client_secret: String,
-}
+  #[tokio::test]
+  async fn distance_is_converted_to_either_unit() {
+    let api = MockFitbitApi::new().with_distance(HashMap::from([(date(1), 10.0), (date(2), 0.0)]));
 
-impl Fitbit {
-  pub fn new(reqwest_client: reqwest::Client, cache_client: CacheHandler, database_client: DatabaseHandler) -> Self {
-    let client_id: String = env::var("FITBIT_CLIENT_ID").expect("FITBIT_CLIENT_ID not set");
-    let client_secret: String  = env::var("FITBIT_CLIENT_SECRET").expect("FITBIT_CLIENT_SECRET not set");
+    let distance = api.get_distance("FITBIT", "token", date(2), Period::OneWeek, "UTC").await.unwrap().values;
 
-    Self {
-      reqwest_client,
-      cache_client,
-      database_client,
-      client_id: client_id,
-      client_secret: client_secret,
-    }
+    assert_eq!(in_unit(distance.clone(), DistanceUnit::Kilometers), distance);
+
+    let miles = in_unit(distance, DistanceUnit::Miles);
+
+    assert!((miles[&date(1)] - 6.21371).abs() < 1e-9);
+    assert_eq!(miles[&date(2)], 0.0);
   }
 
-  pub async fn reply(&self, coordination_id: ulid::Ulid, response: Response) {
-    let coordination_id = coordination_id.to_string();
-    let coordination_id = coordination_id.as_str();
+  #[test]
+  fn account_distance_unit_follows_locale() {
+    assert_eq!(DistanceUnit::from_locale("en_US"), DistanceUnit::Miles);
+    assert_eq!(DistanceUnit::from_locale("METRIC"), DistanceUnit::Kilometers);
+    assert_eq!(DistanceUnit::from_locale("en_GB"), DistanceUnit::Kilometers);
+    assert_eq!(DistanceUnit::from_locale(""), DistanceUnit::Kilometers);
+  }
 
-    let response = utils::encode_response(response);
+  #[test]
+  fn weights_convert_to_the_account_unit() {
+    let log = |weight| WeightLog { date: date(1), time: None, weight, fat: Some(20.0) };
 
-    match self.cache_client.send_message(coordination_id, response).await {
-      Ok(_) => (),
-      Err(e) => error!("Failed to send message to response list: {}", e),
-    };
+    assert_eq!(WeightUnit::from_locale("en_US"), WeightUnit::Pounds);
+    assert_eq!(WeightUnit::from_locale("en_GB"), WeightUnit::Stone);
+    assert_eq!(WeightUnit::from_locale("METRIC"), WeightUnit::Kilograms);
+
+    assert_eq!(in_weight_unit(vec![log(70.0)], WeightUnit::Kilograms), vec![log(70.0)]);
+
+    let pounds = in_weight_unit(vec![log(70.0)], WeightUnit::Pounds);
+    let stone = in_weight_unit(vec![log(70.0)], WeightUnit::Stone);
+
+    assert!((pounds[0].weight - 154.32).abs() < 0.01);
+    assert!((stone[0].weight - 11.02).abs() < 0.01);
+    assert_eq!(pounds[0].fat, Some(20.0));
   }
 
-  pub async fn execute_command(&self, command: Command) -> Response {
-    let response: Response;
+  #[test]
+  fn only_historical_days_are_persisted() {
+    let live = HashMap::from([(date(7), 700), (date(8), 800), (date(9), 900), (date(10), 1000)]);
 
-    match command {
-      Command::GetSteps(user_id, range) => {
-        let user = match self.database_client.get_user(&user_id).await {
-          Ok(Some(user)) => user,
-          Ok(None) => return Response::Error(FitbitError::UserNotFound),
-          Err(e) => return Response::Error(e),
-        };
+    assert_eq!(historical_days(&live, date(10)), HashMap::from([(date(7), 700)]));
+    assert!(historical_days(&HashMap::new(), date(10)).is_empty());
+  }
 
-        let steps = match self.get_steps(&user_id, &user.fitbit_user_id, &user.fitbit_access_token, range.start, range.end).await {
-          Ok(steps) => steps,
-          Err(e) => return Response::Error(e),
-        };
+  #[test]
+  fn backfill_within_limit_is_immediate() {
+    assert_eq!(backfill_estimate(0, 0, 145, 1200), 0);
+    assert_eq!(backfill_estimate(2, 0, 145, 1200), 0);
+    assert_eq!(backfill_estimate(5, 140, 145, 1200), 0);
+  }
 
-        response = Response::Steps(steps);
-      },
-      Command::RefreshToken(user_id) => {
-        match self.refresh_token(&user_id).await {
-          Ok(_) => (),
-          Err(e) => return Response::Error(e),
-        };
+  #[test]
+  fn backfill_beyond_limit_waits_for_reset() {
+    assert_eq!(backfill_estimate(6, 140, 145, 1200), 1200);
+    assert_eq!(backfill_estimate(145, 145, 145, 1200), 1200);
+    assert_eq!(backfill_estimate(146, 145, 145, 1200), 1200 + 3600);
+    assert_eq!(backfill_estimate(1, 200, 145, 30), 30);
+    assert_eq!(backfill_estimate(5, 0, 2, 30), 30 + 3600);
+  }
 
-        response = Response::Refreshed;
-      },
+  /// Fetches each window through `limit`, as one query against the rate limit, and answers with a day of steps.
+  async fn fetch_within_limit(cache: &MemoryCache, limit: usize, window: Range) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+    if limit_reached(cache, "user", limit, 0).await {
+      return Err(FitbitError::RateLimitExceeded("Rate limit exceeded".to_string(), None));
     }
 
-    response
+    cache.add_user_query("user", Utc::now().naive_utc(), 3600).await?;
+
+    Ok(HashMap::from([(window.start, 100)]))
   }
 
-  /// Gets daily step counts from Fitbit within a given range, inclusive.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// * `access_token` - The user's Fitbit access token.
-  /// * `start` - The start date of the range.
-  /// * `end` - The end date of the range.
-  /// 
-  /// # Returns
-  /// 
-  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding step counts.
-  /// * `FitbitError` - An error if one occurs.
-  pub async fn get_steps(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
-    let token_expired = self.check_access_token_expired(user_id).await?;
+  #[tokio::test]
+  async fn recent_windows_are_a_single_request() {
+    let today = date(31);
 
-    let token_expired = token_expired.unwrap_or(false);
+    assert_eq!(recent_window(today, 1), Range { start: today, end: today });
+    assert_eq!(recent_window(today, 7), Range { start: date(25), end: today });
 
-    if token_expired {
-      self.refresh_token(user_id).await?;
+    for days in 1..=utils::MAX_SYNC_RECENT_DAYS {
+      let window = recent_window(today, days);
+
+      assert_eq!((window.end - window.start).num_days() + 1, i64::from(days));
+
+      let fitbit = fitbit(MockFitbitApi::new()).await;
+
+      fitbit.sync_recent("user", "FITBIT", "token", days).await.unwrap();
+
+      assert_eq!(fitbit.api.requests().len(), 1, "syncing {} days", days);
     }
+  }
 
-    let cached_steps = self.get_cached_steps(user_id, start, end).await?;
-    let last_cache_date: Option<NaiveDate> = cached_steps.keys().max().copied();
+  #[tokio::test]
+  async fn not_modified_steps_are_served_from_the_cache() {
+    let cache = MemoryCache::new();
+    let api = MockFitbitApi::new()
+      .with_steps(HashMap::from([(date(1), 100), (date(2), 200)]))
+      .with_etag("\"v1\"");
 
-    let live_range = match self.get_live_range(user_id, start, end, last_cache_date).await {
-      Ok(Some(range)) => range,
-      Ok(None) => return Ok(cached_steps),
-      Err(e) => return Err(e),
+    let fetch = |etag: Option<String>| {
+      let api = &api;
+      async move { api.get_time_series_if_none_match("FITBIT", "token", Resource::Steps, date(2), Period::OneWeek, "UTC", etag.as_deref()).await }
     };
 
-    let difference = (live_range.end - live_range.start).num_days() as f64;
+    // Nothing is cached yet, so the window is fetched in full and its ETag kept.
+    let (series, modified) = fetch_revalidating(&cache, false, "user", Resource::Steps, date(1), date(2), fetch).await.unwrap();
 
-    let mut steps: HashMap<NaiveDate, u32> = cached_steps;
+    assert!(modified);
+    assert_eq!(series.values, HashMap::from([(date(1), 100), (date(2), 200)]));
 
-    let requests: u32 = if difference > 364.0 {
-      (difference / 364.0).ceil() as u32
-    } else {
-      1
-    };
+    // Differs from what Fitbit would send, to show which side answered.
+    let cached = HashMap::from([(date(1), 90), (date(2), 190)]);
+    cache.add_steps_bulk("user", &cached).await.unwrap();
 
-    let mut days_left = difference as i64;
+    let (series, modified) = fetch_revalidating(&cache, false, "user", Resource::Steps, date(1), date(2), fetch).await.unwrap();
 
-    for i in 0..requests {
-      let start = start + chrono::Duration::days(i64::from(i * 364));
-      let end = start + chrono::Duration::days(std::cmp::min(days_left, 364));
+    assert!(!modified);
+    assert_eq!(series.values, cached);
+    assert_eq!(api.requests().len(), 2);
 
-      days_left -= 364;
+    // A window that is no longer fully cached is fetched in full again.
+    let cache = MemoryCache::new();
+    cache.set_etag("user", "steps:2023-01-01:2023-01-02", "\"v1\"").await.unwrap();
+    cache.add_steps("user", date(1), 90).await.unwrap();
 
-      steps.extend(self.get_steps_for_range(user_id, fitbit_user_id, fitbit_access_token, start, end).await?);
-    }
+    let (_, modified) = fetch_revalidating(&cache, false, "user", Resource::Steps, date(1), date(2), fetch).await.unwrap();
 
-    Ok(steps)
+    assert!(modified);
   }
 
-  /// Checks if we know the users's access token has expired.
-  /// Of course, this is not a guarantee that it has not expired, but it is nearly always the case.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// 
-  /// # Returns
-  /// 
-  /// * `Option<bool>` - `Some(true)` if the access token has expired, `Some(false)` if it has not expired, `None` if we do not know.
-  /// * `FitbitError` - An error if one occurs.
-  async fn check_access_token_expired(&self, user_id: &str) -> Result<Option<bool>, FitbitError> {
-    let expired = self.database_client.user_token_expired(user_id).await?;
+  #[tokio::test]
+  async fn partial_fetch_keeps_windows_before_the_rate_limit() {
+    let cache = MemoryCache::new();
+    let windows = backfill_windows(4);
+    let mut steps = HashMap::new();
 
-    Ok(expired)
+    let partial = fetch_live_windows(windows.clone(), StalePolicy::AllowPartial, &mut steps, |window| fetch_within_limit(&cache, 1, window)).await;
+
+    assert!(partial.unwrap());
+    assert_eq!(steps, HashMap::from([(windows[0].start, 100)]));
   }
 
-  /// Gets daily step counts from Fitbit within the given range, inclusive.
-  async fn get_steps_for_range(&self, user_id: &str, fitbit_user_id: &str, fitbit_access_token: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
-    if self.check_ratelimit(user_id).await {
-      return Err(FitbitError::RateLimitExceeded(format!("Rate limit exceeded")))?;
+  #[tokio::test]
+  async fn strict_fetch_fails_at_the_rate_limit() {
+    let cache = MemoryCache::new();
+    let mut steps = HashMap::new();
+
+    let result = fetch_live_windows(backfill_windows(4), StalePolicy::Strict, &mut steps, |window| fetch_within_limit(&cache, 1, window)).await;
+
+    assert!(matches!(result, Err(FitbitError::RateLimitExceeded(..))));
+  }
+
+  #[tokio::test]
+  async fn complete_fetch_is_not_partial() {
+    let cache = MemoryCache::new();
+    let mut steps = HashMap::new();
+
+    let partial = fetch_live_windows(backfill_windows(4), StalePolicy::AllowPartial, &mut steps, |window| fetch_within_limit(&cache, 10, window)).await;
+
+    assert!(!partial.unwrap());
+    assert_eq!(steps.len(), 4);
+  }
+
+  #[test]
+  fn backfill_pace_spreads_remaining_queries_over_the_window() {
+    assert_eq!(backfill_pace(145, 3600), std::time::Duration::from_millis(24_827));
+    assert_eq!(backfill_pace(2, 60), std::time::Duration::from_secs(30));
+    assert_eq!(backfill_pace(0, 60), std::time::Duration::from_secs(60));
+    assert_eq!(backfill_pace(10, 0), std::time::Duration::ZERO);
+  }
+
+  /// A stand-in for Fitbit's per-user limit that allows `limit` queries per minute, starting from the first query.
+  struct SimulatedLimit {
+    limit: usize,
+    window_start: Option<tokio::time::Instant>,
+    used: usize,
+    fetched: Vec<Range>,
+    rejected: usize,
+  }
+
+  impl SimulatedLimit {
+    fn new(limit: usize) -> Self {
+      SimulatedLimit { limit, window_start: None, used: 0, fetched: Vec::new(), rejected: 0 }
     }
 
-    if start > end {
-      Err(FitbitError::DateOutOfRange("Start date must be before end date.".to_string()))?;
+    fn window_end(&mut self) -> Option<tokio::time::Instant> {
+      let end = self.window_start? + std::time::Duration::from_secs(60);
+
+      if tokio::time::Instant::now() >= end {
+        self.window_start = None;
+        self.used = 0;
+        return None;
+      }
+
+      Some(end)
     }
 
-    let now = Utc::now().naive_local().date();
+    fn quota(&mut self) -> (usize, Option<u64>) {
+      let until_reset = self.window_end().map(|end| (end - tokio::time::Instant::now()).as_secs());
 
-    if end > now {
-      info!("End date: {}", end.format("%Y-%m-%d"));
-      info!("Now: {}", now.format("%Y-%m-%d"));
-      Err(FitbitError::DateOutOfRange("Dates must be UTC and in the past.".to_string()))?;
+      (self.limit - self.used, until_reset)
     }
 
-    let difference = end.signed_duration_since(start);
+    fn fetch(&mut self, window: Range) -> Result<(), FitbitError> {
+      self.window_end();
 
-    let period = match difference.num_days() {
-      0 => Period::OneDay,
-      1..=6 => Period::OneWeek,
-      7..=27 => Period::OneMonth,
-      28..=89 => Period::ThreeMonths,
-      90..=179 => Period::SixMonths,
-      180..=364 => Period::OneYear,
-      _ => Err(FitbitError::DateOutOfRange("Date range must be less than one year.".to_string()))?,
-    };
+      if self.used == self.limit {
+        self.rejected += 1;
+        return Err(FitbitError::RateLimitExceeded("Rate limit exceeded".to_string(), None));
+      }
 
-    let (steps, headers) = api::get_steps(&self.reqwest_client, fitbit_user_id, fitbit_access_token, end, period).await?;
+      self.window_start.get_or_insert_with(tokio::time::Instant::now);
+      self.used += 1;
+      self.fetched.push(window);
 
-    // Filters out days that are not in the range.
-    let steps = steps.into_iter()
-      .filter(|(date, _)| *date >= start && *date <= end)
+      Ok(())
+    }
+  }
+
+  fn backfill_windows(count: u32) -> Vec<Range> {
+    (0..count).map(|week| {
+      let start = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap() + Duration::weeks(week.into());
+      Range { start, end: start + Duration::days(6) }
+    }).collect()
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn paced_backfill_stays_within_a_tiny_limit() {
+    let limit = std::sync::Mutex::new(SimulatedLimit::new(2));
+    let windows = backfill_windows(6);
+
+    let result = paced_backfill(
+      "user",
+      windows.clone(),
+      true,
+      || std::future::ready(Ok(limit.lock().unwrap().quota())),
+      |window| std::future::ready(limit.lock().unwrap().fetch(window)),
+    ).await;
+
+    assert!(result.is_ok());
+
+    let limit = limit.into_inner().unwrap();
+    assert_eq!(limit.fetched, windows);
+    assert_eq!(limit.rejected, 0);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn backfill_stops_when_the_quota_cannot_be_read() {
+    let fetched = AtomicUsize::new(0);
+
+    let result = paced_backfill(
+      "user",
+      backfill_windows(4),
+      true,
+      || std::future::ready(Err(FitbitError::CacheError("unreachable".to_string()))),
+      |_| {
+        fetched.fetch_add(1, Ordering::SeqCst);
+        std::future::ready(Ok(()))
+      },
+    ).await;
+
+    assert!(matches!(result, Err(FitbitError::CacheError(_))));
+    assert_eq!(fetched.load(Ordering::SeqCst), 0);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn unpaced_backfill_fetches_back_to_back() {
+    let limit = std::sync::Mutex::new(SimulatedLimit::new(2));
+    let started = tokio::time::Instant::now();
+
+    let result = paced_backfill(
+      "user",
+      backfill_windows(2),
+      false,
+      || std::future::ready(Ok(limit.lock().unwrap().quota())),
+      |window| std::future::ready(limit.lock().unwrap().fetch(window)),
+    ).await;
+
+    assert!(result.is_ok());
+    assert_eq!(started.elapsed(), std::time::Duration::ZERO);
+    assert_eq!(limit.into_inner().unwrap().fetched.len(), 2);
+  }
+
+  #[test]
+  fn live_ranges_fill_interior_gaps() {
+    let cached: HashMap<NaiveDate, u32> = (1..=30)
+      .filter(|day| *day != 3 && *day != 20)
+      .map(|day| (date(day), day * 100))
       .collect();
 
-    self.set_ratelimit(user_id, &headers).await;
-    self.cache(user_id, &steps).await?;
+    // Both gaps fit in a single request.
+    assert_eq!(live_ranges(&cached, date(1), date(30), date(31), false), vec![Range { start: date(3), end: date(20) }]);
 
-    Ok(steps)
+    let mut steps = cached.clone();
+    steps.extend([(date(3), 300), (date(20), 2000)]);
+
+    assert!(covers_range(&steps, date(1), date(30)));
+    assert!(live_ranges(&steps, date(1), date(30), date(31), false).is_empty());
   }
 
-  async fn cache(&self, user_id: &str, steps: &HashMap<NaiveDate, u32>) -> Result<(), FitbitError> {
-    info!("Cacheing {} steps", steps.len());
+  #[test]
+  fn live_ranges_refresh_recent_days() {
+    let cached: HashMap<NaiveDate, u32> = (1..=10).map(|day| (date(day), day * 100)).collect();
 
-    for (date, steps) in steps {
-      match self.cache_client.add_steps(user_id, *date, *steps).await {
-        Ok(_) => (),
-        Err(e) => return Err(FitbitError::CacheError(e.to_string()))?,
-      }
-    }
+    assert!(live_ranges(&cached, date(1), date(10), date(10), false).is_empty());
+    assert_eq!(live_ranges(&cached, date(1), date(10), date(10), true), vec![Range { start: date(9), end: date(10) }]);
+    assert_eq!(live_ranges(&cached, date(10), date(10), date(10), true), vec![Range { start: date(10), end: date(10) }]);
+    assert!(live_ranges(&cached, date(1), date(5), date(10), true).is_empty());
+  }
 
-    Ok(())
+  #[tokio::test]
+  async fn zero_step_days_are_served_from_cache() {
+    // Fitbit reports day 1 as 0 steps and leaves out day 3 entirely.
+    let fitbit = fitbit(MockFitbitApi::new().with_steps(HashMap::from([(date(1), 0), (date(2), 500)]))).await;
+    let steps = HashMap::from([(date(1), 0), (date(2), 500), (date(3), 0)]);
+
+    assert_eq!(fitbit.get_steps("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), steps);
+    assert_eq!(fitbit.api.requests().len(), 1);
+
+    // The second query finds every day cached, zeros included, and makes no live request.
+    assert_eq!(fitbit.get_steps("user", "FITBIT", "token", date(1), date(3)).await.unwrap(), steps);
+    assert_eq!(fitbit.api.requests().len(), 1);
   }
 
-  async fn set_ratelimit(&self, user_id: &str, headers: &reqwest::header::HeaderMap) -> bool {
-    // Seconds until the current rate limit window resets.
-    let ratelimit_reset = headers.get("fitbit-rate-limit-reset").unwrap().to_str().unwrap().parse::<i64>().unwrap() as usize;
+  #[tokio::test]
+  async fn throttling_starts_at_the_limit() {
+    let cache = MemoryCache::new();
+    let now = Utc::now().naive_utc();
+
+    assert!(!limit_reached(&cache, "user", 2, 0).await);
+
+    cache.add_user_query("user", now, 600).await.unwrap();
 
-    let date: NaiveDateTime = Utc::now().naive_local();
+    assert!(!limit_reached(&cache, "user", 2, 0).await);
 
-    self.cache_client.add_user_query(user_id, date, ratelimit_reset).await.is_ok()
+    cache.add_user_query("user", now, 600).await.unwrap();
+
+    assert!(limit_reached(&cache, "user", 2, 0).await);
+    assert!(!limit_reached(&cache, "user", DEFAULT_RATE_LIMIT_PER_HOUR, 0).await);
   }
 
-  /// Checks whether the current rate limit window has been reached.
-  /// Returns true if the rate limit has been reached, false otherwise.
-  async fn check_ratelimit(&self, user_id: &str) -> bool {
-    let queries = self.cache_client.get_user_queries(user_id);
+  #[tokio::test]
+  async fn reported_remaining_overrides_the_estimate() {
+    let cache = MemoryCache::new();
+    let now = Utc::now().naive_utc();
 
-    let Ok(queries) = queries.await else {
-      return true;
-    };
+    for _ in 0..3 {
+      cache.add_user_query("user", now, 600).await.unwrap();
+    }
+
+    // Estimated from the three recorded queries.
+    assert_eq!(remaining_queries(&cache, "user", 3, 0).await.unwrap(), 0);
+    assert!(limit_reached(&cache, "user", 3, 0).await);
+
+    // Fitbit reports requests left although the count has drifted to the limit.
+    let reported = RateLimitInfo { remaining: Some(12), limit: Some(150), reset_seconds: Some(600) };
+    cache.set_reported_ratelimit("user", reported, 600).await.unwrap();
+
+    assert_eq!(remaining_queries(&cache, "user", 3, 0).await.unwrap(), 12);
+    assert!(!limit_reached(&cache, "user", 3, 0).await);
+
+    // And reports none left although few queries were counted.
+    let reported = RateLimitInfo { remaining: Some(0), limit: Some(150), reset_seconds: Some(600) };
+    cache.set_reported_ratelimit("user", reported, 600).await.unwrap();
 
-    // RATELIMIT: 145 queries per user per hour.
-    queries > 145
+    assert!(limit_reached(&cache, "user", DEFAULT_RATE_LIMIT_PER_HOUR, 0).await);
+
+    // A response without the header falls back to the estimate.
+    cache.set_reported_ratelimit("user", RateLimitInfo::default(), 600).await.unwrap();
+
+    assert_eq!(remaining_queries(&cache, "user", DEFAULT_RATE_LIMIT_PER_HOUR, 0).await.unwrap(), DEFAULT_RATE_LIMIT_PER_HOUR - 3);
   }
 
-  /// Takes in the date range to be queried and returns the date range that should be queried from Fitbit, or None if the entire range is already cached.
-  /// This function assumes that it will never be passed dates that are in the future.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// * `start` - The start date of the range.
-  /// * `end` - The end date of the range.
-  /// 
-  /// # Returns
-  /// 
-  /// * `Option<(NaiveDate, NaiveDate)>` - The date range that should be queried from Fitbit, or None if the entire range is already cached.
-  /// * `FitbitError` - An error if one occurs.
-  async fn get_live_range(&self, user_id: &str, range_start: NaiveDate, range_end: NaiveDate, cache_end: Option<NaiveDate>) -> Result<Option<Range>, FitbitError> {
-    let Some(cache_end) = cache_end else {
-      return Ok(Some(Range { start: range_start, end: range_end } ));
-    };
+  #[tokio::test]
+  async fn slack_holds_back_queries_below_the_limit() {
+    let cache = MemoryCache::new();
+    let now = Utc::now().naive_utc();
 
-    // Gets the number of queries made by the user since the current rate limit window started.
-    let Ok(queries) = self.cache_client.get_user_queries(user_id).await else {
-      return Err(FitbitError::CacheError("Error getting user queries.".to_string()));
-    };
+    cache.add_user_query("user", now, 600).await.unwrap();
 
-    // Gets the date of the user's last query.
-    let Ok(last_query_datetime) = self.cache_client.get_last_user_query(user_id).await else {
-      return Err(FitbitError::CacheError("Error getting last user query.".to_string()));
-    };
+    assert_eq!(remaining_queries(&cache, "user", 5, 0).await.unwrap(), 4);
+    assert_eq!(remaining_queries(&cache, "user", 5, 3).await.unwrap(), 1);
+    assert!(!limit_reached(&cache, "user", 5, 3).await);
+    assert!(limit_reached(&cache, "user", 5, 4).await);
 
-    // RATELIMIT: 145 queries per user per hour.
-    let remaining = 145.0 - queries as f32;
+    // The slack applies to the count Fitbit reported as well.
+    let reported = RateLimitInfo { remaining: Some(2), limit: Some(150), reset_seconds: Some(600) };
+    cache.set_reported_ratelimit("user", reported, 600).await.unwrap();
 
-    let current_datetime: NaiveDateTime = Utc::now().naive_local();
-    let ratelimit_reset = self.cache_client.get_ratelimit_reset().await.unwrap_or(Utc::now().naive_local());
+    assert_eq!(remaining_queries(&cache, "user", 5, 1).await.unwrap(), 1);
+    assert!(limit_reached(&cache, "user", 5, 2).await);
+  }
 
-    let signed_until_ratelimit_reset: i64 = (ratelimit_reset - current_datetime).num_seconds();
-    let until_ratelimit_reset: u16 = utils::safe_convert(signed_until_ratelimit_reset);
+  #[tokio::test]
+  async fn a_larger_buffer_paces_queries_more_conservatively() {
+    let info = RateLimitInfo { remaining: Some(100), limit: Some(150), reset_seconds: Some(600) };
 
-     if remaining == 0.0 {
-      return Err(FitbitError::RateLimitExceeded("Rate limit exceeded.".to_string()));
+    let mut periods = Vec::new();
+
+    for buffer_secs in [DEFAULT_RATE_LIMIT_BUFFER_SECS, 300] {
+      let cache = MemoryCache::new();
+
+      record_query(&cache, "user", info, buffer_secs).await.unwrap();
+
+      let reset = cache.get_ratelimit_reset("user").await.unwrap();
+      let until_reset: u16 = utils::safe_convert((reset - Utc::now().naive_utc()).num_seconds());
+      let remaining = remaining_queries(&cache, "user", DEFAULT_RATE_LIMIT_PER_HOUR, 0).await.unwrap();
+
+      periods.push(request_period(until_reset, remaining));
+    }
+
+    assert!(periods[0] <= 7);
+    assert!(periods[1] > periods[0]);
+  }
+
+  #[tokio::test]
+  async fn redelivered_commands_are_answered_once() {
+    let fitbit = fitbit(MockFitbitApi::new().with_steps(HashMap::from([(date(1), 1200)]))).await;
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(1));
+    let coordination_id = ulid::Ulid::new().to_string();
+    let ttl = Utc::now().timestamp() + 60;
+    let message = format!("{}:get_steps:user,1672531200,1672531200:{}", coordination_id, ttl);
+
+    for id in ["1-0", "2-0"] {
+      let command = QueuedCommand { id: id.to_string(), message: message.clone(), defer: false };
+
+      crate::consumer::handle_message(command, fitbit.cache_client.clone(), fitbit.clone(), semaphore.clone(), false, std::time::Duration::from_secs(5)).await;
     }
 
-    let request_period: i64 = (f32::from(until_ratelimit_reset) / remaining).ceil() as i64;
+    assert_eq!(fitbit.api.requests().len(), 1);
+    assert_eq!(fitbit.cache_client.take_reply(&coordination_id), Some(b"0:1200".to_vec()));
+    assert_eq!(fitbit.cache_client.acked(), vec!["1-0".to_string(), "2-0".to_string()]);
+
+    let unanswered = ulid::Ulid::new().to_string();
+
+    assert_eq!(claim_reply(&fitbit.cache_client, &unanswered).await, ReplyClaim::New);
+    assert_eq!(claim_reply(&fitbit.cache_client, &unanswered).await, ReplyClaim::InFlight);
 
-    // This is an estimate of how often we can query Fitbit without exceeding the rate limit.
-    let request_period: usize = utils::safe_convert(request_period);
+    fitbit.cache_client.release_reply(&unanswered).await.unwrap();
 
-    match last_query_datetime {
-      Some(last_query) => {
-        let since_last_query: i64 = (current_datetime - last_query).num_seconds();
-        let since_last_query: usize = utils::safe_convert(since_last_query);
+    assert_eq!(claim_reply(&fitbit.cache_client, &unanswered).await, ReplyClaim::New);
+  }
 
-        if since_last_query < request_period {
-          Ok(None)
-        } else {
-          info!("Last query was {} seconds ago, check if live query is needed", (current_datetime - last_query).num_seconds());
+  #[tokio::test]
+  async fn batch_steps_fail_per_user() {
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let fetched = AtomicUsize::new(0);
 
-          // If the last cached day is within 2 days of the end day, return the day before and the end day.
-          if range_end == current_datetime.date() && (cache_end - range_end).num_days() > -2 {
-            info!("Cache is partially up to date, but since the TTL is 2 days, ensure the last 2 days are up to date");
-            info!(" Days saved: {}", (cache_end - range_end).num_days());
-            // Cache is partially up to date, but since the TTL is 2 days, ensure the last 2 days are up to date.
-            return Ok(Some( Range { start: current_datetime.date() - Duration::days(1), end: current_datetime.date() } ))
-          } else if range_end == cache_end {
-            info!("Cache is up to date");
-            return Ok(None);
-          }
+    let steps = batch_steps(vec!["a".to_string(), "missing".to_string(), "b".to_string(), "a".to_string()], 2, |user_id| {
+      fetched.fetch_add(1, Ordering::SeqCst);
 
-          info!("Cache is out of date; query from {} to {}", cache_end, range_end);
-          Ok(Some( Range { start: cache_end, end: range_end } ))
+      async move {
+        match user_id.as_str() {
+          "missing" => Err(FitbitError::UserNotFound),
+          _ => Ok(HashMap::from([(date, user_id.len() as u32 * 1000)])),
         }
-      },
-      None => Ok(Some( Range { start: range_start, end: range_end } )),
-    }
+      }
+    }).await;
+
+    assert_eq!(fetched.load(Ordering::SeqCst), 3);
+    assert_eq!(steps.len(), 3);
+    assert_eq!(steps["a"], Ok(HashMap::from([(date, 1000)])));
+    assert_eq!(steps["b"], Ok(HashMap::from([(date, 1000)])));
+    assert_eq!(steps["missing"], Err(FitbitError::UserNotFound.to_string()));
   }
 
-  /// Gets daily step counts from the cache within a given range, inclusive.
-  /// Will return the longest range possible from the cache, always starting from the start date.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `user_id` - The user's Fitbit user ID.
-  /// * `start` - The start date of the range.
-  /// * `end` - The end date of the range.
-  /// 
-  /// # Returns
-  /// 
-  /// * `HashMap<NaiveDate, u32>` - A hashmap of dates and their corresponding step counts.
-  /// * `FitbitError` - An error if one occurs.
-  async fn get_cached_steps(&self, user_id: &str, start: NaiveDate, end: NaiveDate) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
-    let steps = self.cache_client.get_steps(user_id, start, end).await;
+  #[tokio::test]
+  async fn disabled_cache_is_bypassed() {
+    let cache = MemoryCache::new();
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+
+    cache.add_steps_bulk("user", &HashMap::from([(date(1), 1000)])).await.unwrap();
+
+    assert!(cached_steps(&cache, true, "user", date(1), date(2)).await.unwrap().is_empty());
 
-    match steps {
-      Ok(steps) => Ok(steps),
-      Err(_) => Err(FitbitError::CacheError("Error getting cached steps.".to_string()))?,
+    cache_steps(&cache, true, "user", &HashMap::from([(date(2), 2000)])).await.unwrap();
+
+    assert_eq!(cache.get_steps("user", date(1), date(2)).await.unwrap(), HashMap::from([(date(1), 1000)]));
+
+    cache_steps(&cache, false, "user", &HashMap::from([(date(2), 2000)])).await.unwrap();
+
+    assert_eq!(cached_steps(&cache, false, "user", date(1), date(2)).await.unwrap(), HashMap::from([(date(1), 1000), (date(2), 2000)]));
+  }
+
+  #[tokio::test]
+  async fn partially_cached_steps_are_tagged_with_their_source() {
+    let fitbit = fitbit(MockFitbitApi::new().with_steps(HashMap::from([(date(3), 3000), (date(4), 3000)]))).await;
+
+    fitbit.cache_client.add_steps_bulk("user", &HashMap::from([(date(1), 1000), (date(2), 2000)])).await.unwrap();
+
+    let steps = fitbit.get_steps_with_source("user", "FITBIT", "token", date(1), date(4)).await.unwrap();
+
+    assert_eq!(steps, HashMap::from([
+      (date(1), (1000, Source::Cache)),
+      (date(2), (2000, Source::Cache)),
+      (date(3), (3000, Source::Live)),
+      (date(4), (3000, Source::Live)),
+    ]));
+    assert_eq!(fitbit.api.requests().len(), 1);
+  }
+
+  #[test]
+  fn refreshed_days_are_live() {
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+
+    let steps = with_source(HashMap::from([(date, 1000)]), HashMap::from([(date, 1200)]));
+
+    assert_eq!(steps, HashMap::from([(date, (1200, Source::Live))]));
+  }
+
+  #[tokio::test]
+  async fn rate_limit_status_counts_queries() {
+    let cache = MemoryCache::new();
+    let now = Utc::now().naive_utc();
+
+    for _ in 0..3 {
+      cache.add_user_query("user", now, 600).await.unwrap();
     }
+
+    let Response::RateLimit { used, limit, reset_in_seconds } = rate_limit_status(&cache, "user", DEFAULT_RATE_LIMIT_PER_HOUR, now).await.unwrap() else {
+      panic!("Expected a rate limit status");
+    };
+
+    assert_eq!((used, limit), (3, DEFAULT_RATE_LIMIT_PER_HOUR));
+    assert!(reset_in_seconds.is_some_and(|seconds| (590..=600).contains(&seconds)), "{reset_in_seconds:?}");
+
+    let Response::RateLimit { used, reset_in_seconds, .. } = rate_limit_status(&cache, "other", DEFAULT_RATE_LIMIT_PER_HOUR, now).await.unwrap() else {
+      panic!("Expected a rate limit status");
+    };
+
+    assert_eq!((used, reset_in_seconds), (0, None));
   }
 
-  /// Refreshes the access token using the refresh token.
-  /// 
-  /// # Arguments
-  /// 
-  /// * `refresh_token` - The refresh token associated with the user you want a new access token for.
-  /// 
-  /// # Returns
-  /// 
-  /// * `Ok((access_token, refresh_token))` - The new access token and refresh token.
-  /// * `Err(FitbitError)` - The error returned by the internal Fitbit API.
-  pub async fn refresh_token(&self, user_id: &str) -> Result<(String, String), FitbitError> {
-    let user = self.database_client.get_user(user_id).await?;
+  #[tokio::test(start_paused = true)]
+  async fn concurrent_refreshes_call_fitbit_once() {
+    let lock = MemoryCache::new();
+    let api = MockFitbitApi::new();
+    let stored = std::sync::Mutex::new("stale".to_string());
 
-    let refresh_token = match user {
-      Some(user) => user.fitbit_refresh_token,
-      None => return Err(FitbitError::UserNotFound),
+    let refresh = || async {
+      let token = api.refresh_token("refresh", "id", "secret").await?;
+
+      // Stand in for the round trip to Fitbit so the second refresh arrives while the first is in flight.
+      tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+      *stored.lock().unwrap() = token.access_token.clone();
+
+      Ok(token.access_token)
     };
+    let reread = || async { Ok(stored.lock().unwrap().clone()) };
 
-    let updated_token = api::refresh_token(&self.reqwest_client, refresh_token.as_str(), self.client_id.as_str(), self.client_secret.as_str()).await?;
+    let (first, second) = tokio::join!(
+      with_refresh_lock(&lock, "user", refresh, reread),
+      with_refresh_lock(&lock, "user", refresh, reread),
+    );
 
-    let access_token = updated_token.access_token;
-    let refresh_token = updated_token.refresh_token;
-    let expires_at = Utc::now().naive_local() + Duration::seconds(i64::from(updated_token.expires_in));
+    assert_eq!(first.unwrap(), "refreshed");
+    assert_eq!(second.unwrap(), "refreshed");
+    assert_eq!(api.refreshes(), 1);
+    assert!(!lock.refresh_lock_held("user").await.unwrap());
+  }
 
-    self.database_client.update_user_token(user_id, access_token.as_str(), refresh_token.as_str(), expires_at).await?;
+  #[test]
+  fn refresh_jitter_stays_within_spread() {
+    let spread = std::time::Duration::from_secs(5);
 
-    Ok((access_token, refresh_token))
+    for _ in 0..1000 {
+      assert!(refresh_jitter(spread) <= spread);
+    }
+
+    assert_eq!(refresh_jitter(std::time::Duration::ZERO), std::time::Duration::ZERO);
   }
-}
\ No newline at end of file
+}
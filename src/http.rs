@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+use axum::{Router, routing::get};
+use axum::extract::{Path, Query, State};
+use axum::extract::rejection::QueryRejection;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use tracing::{info, error};
+use crate::errors::FitbitError;
+use crate::fitbit::Fitbit;
+use crate::metrics;
+use crate::models::{Command, CommandOutcome, Protocol, RequestedRange, Response, StepsQuery};
+use crate::utils;
+
+/// The routes of the HTTP front end. Each route builds the same `Command` a queued message would decode to and runs
+/// it through `Fitbit::execute_command`, so both front ends behave alike.
+pub fn router(fitbit_client: Fitbit) -> Router {
+  Router::new()
+    .route("/users/:user_id/steps", get(get_steps))
+    .with_state(fitbit_client)
+}
+
+/// Serves the HTTP front end on the given port until the process exits.
+///
+/// # Arguments
+///
+/// * `fitbit_client` - The client commands are executed with, shared with the Redis consumer.
+/// * `port` - The port to bind on all interfaces.
+pub async fn serve(fitbit_client: Fitbit, port: u16) {
+  let address = SocketAddr::from(([0, 0, 0, 0], port));
+
+  info!("Serving HTTP requests on {}", address);
+
+  if let Err(e) = axum::Server::bind(&address).serve(router(fitbit_client).into_make_service()).await {
+    error!("HTTP server stopped: {}", e);
+  }
+}
+
+/// `GET /users/{id}/steps?start=...&end=...[&format=counts|dated][&allow_stale=true|&allow_partial=true][&with_source=true]`
+async fn get_steps(State(fitbit_client): State<Fitbit>, Path(user_id): Path<String>, query: Result<Query<StepsQuery>, QueryRejection>) -> (StatusCode, HeaderMap, String) {
+  let command = query
+    .map_err(|e| FitbitError::InvalidMessage(format!("While decoding get_steps request, invalid query: {}", e)))
+    .and_then(|Query(query)| steps_command(user_id, query));
+
+  let reply = match command {
+    Ok(command) => execute(&fitbit_client, command).await,
+    Err(e) => Response::Error(e),
+  };
+
+  respond(reply)
+}
+
+/// Builds the `GetSteps` command for a steps request.
+///
+/// # Arguments
+///
+/// * `user_id` - The user from the request path.
+/// * `query` - The decoded query string.
+///
+/// # Returns
+///
+/// * `Ok(command)` - If the timestamps are valid.
+/// * `Err(e)` - If either timestamp could not be converted to a date, the range is out of order or too long, or both
+///   `allow_stale` and `allow_partial` are set.
+fn steps_command(user_id: String, query: StepsQuery) -> Result<Command, FitbitError> {
+  let range = utils::checked_range("get_steps", utils::timestamp_date("get_steps", "start", query.start)?, utils::timestamp_date("get_steps", "end", query.end)?)?;
+
+  let stale_policy = utils::stale_policy("get_steps", query.allow_stale, query.allow_partial)?;
+
+  if query.with_source {
+    utils::check_with_source("get_steps", stale_policy, query.aggregation)?;
+
+    return Ok(Command::GetStepsWithSource(user_id, range));
+  }
+
+  Ok(Command::GetSteps(user_id, RequestedRange::Dates(range), query.format, stale_policy, query.aggregation))
+}
+
+/// Executes a command, recording the same metrics and audit entry as commands from the request queue. HTTP requests
+/// have no coordination ID.
+async fn execute(fitbit_client: &Fitbit, command: Command) -> Response {
+  let command_name = command.name();
+  let user_id = command.user_id().map(str::to_string);
+
+  metrics::COMMANDS_RECEIVED.with_label_values(&[command_name]).inc();
+
+  let started = Instant::now();
+  let timer = metrics::COMMAND_LATENCY.with_label_values(&[command_name]).start_timer();
+  let reply = fitbit_client.execute_command(command).await;
+  timer.observe_duration();
+
+  fitbit_client.audit_command(None, user_id, command_name, CommandOutcome::of(&reply), started.elapsed());
+
+  reply
+}
+
+/// Encodes a reply as a JSON protocol reply, with a status code matching its error if it has one.
+fn respond(reply: Response) -> (StatusCode, HeaderMap, String) {
+  let mut headers = HeaderMap::new();
+  headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+  let status = match &reply {
+    Response::Error(e) => {
+      metrics::ERRORS.with_label_values(&[e.name()]).inc();
+
+      if let Some(retry_after) = e.retry_after_seconds() {
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+      }
+
+      status_code(e)
+    },
+    Response::ReauthorizationRequired => StatusCode::FORBIDDEN,
+    _ => StatusCode::OK,
+  };
+
+  (status, headers, utils::encode_response(reply, Protocol::Json))
+}
+
+/// The HTTP status for an error. Problems with the user's Fitbit connection are reported as a bad gateway, since the
+/// request itself was fine.
+fn status_code(error: &FitbitError) -> StatusCode {
+  match error {
+    FitbitError::InvalidMessage(_) | FitbitError::DateOutOfRange(_) | FitbitError::InvalidAuthorizationCode(_) => StatusCode::BAD_REQUEST,
+    FitbitError::InsufficientScope(_) => StatusCode::FORBIDDEN,
+    FitbitError::UserNotFound => StatusCode::NOT_FOUND,
+    FitbitError::RateLimitExceeded(..) | FitbitError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+    FitbitError::HttpRequestError(_) | FitbitError::FitbitApiError(_) | FitbitError::ExpiredToken | FitbitError::RejectedToken | FitbitError::ParsingError(_) => StatusCode::BAD_GATEWAY,
+    FitbitError::PostgresPoolTimeout => StatusCode::SERVICE_UNAVAILABLE,
+    FitbitError::CommandExpired => StatusCode::REQUEST_TIMEOUT,
+    FitbitError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+    FitbitError::CacheError(_) | FitbitError::RedisError(_) | FitbitError::RedisPoolError(_) | FitbitError::PostgresError(_) | FitbitError::TypeConversionError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::NaiveDate;
+  use crate::models::{Aggregation, Range, ResponseFormat, StalePolicy};
+
+  #[test]
+  fn steps_query_builds_get_steps() {
+    let query = StepsQuery { start: 1672531200, end: 1673049600, format: ResponseFormat::Dated, allow_stale: true, allow_partial: false, aggregation: Aggregation::Daily, with_source: false };
+
+    let Ok(Command::GetSteps(user_id, range, format, stale_policy, _)) = steps_command("user".to_string(), query) else {
+      panic!("Expected GetSteps");
+    };
+
+    assert_eq!(user_id, "user");
+    assert_eq!(range, RequestedRange::Dates(Range { start: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), end: NaiveDate::from_ymd_opt(2023, 1, 7).unwrap() }));
+    assert_eq!(format, ResponseFormat::Dated);
+    assert_eq!(stale_policy, StalePolicy::AllowStale);
+  }
+
+  #[test]
+  fn steps_query_rejects_invalid_timestamps() {
+    let query = StepsQuery { start: i64::MAX, end: 0, format: ResponseFormat::Counts, allow_stale: false, allow_partial: false, aggregation: Aggregation::Daily, with_source: false };
+
+    assert!(matches!(steps_command("user".to_string(), query), Err(FitbitError::InvalidMessage(_))));
+  }
+
+  #[test]
+  fn steps_query_rejects_stale_and_partial_together() {
+    let query = StepsQuery { start: 1672531200, end: 1673049600, format: ResponseFormat::Counts, allow_stale: true, allow_partial: true, aggregation: Aggregation::Daily, with_source: false };
+
+    assert!(matches!(steps_command("user".to_string(), query), Err(FitbitError::InvalidMessage(_))));
+  }
+
+  #[test]
+  fn errors_carry_status_and_retry_after() {
+    let (status, headers, body) = respond(Response::Error(FitbitError::RateLimitExceeded("limited".to_string(), Some(30))));
+
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(headers[header::RETRY_AFTER], "30");
+    assert!(body.contains("\"code\":\"rate_limit_exceeded\""));
+
+    let (status, headers, _) = respond(Response::Error(FitbitError::UserNotFound));
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(!headers.contains_key(header::RETRY_AFTER));
+  }
+}
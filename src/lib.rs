@@ -0,0 +1,17 @@
+pub mod fitbit;
+pub mod cache;
+pub mod consumer;
+pub mod database;
+pub mod errors;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod models;
+pub mod utils;
+
+pub use fitbit::Fitbit;
+pub use cache::CacheHandler;
+pub use database::DatabaseHandler;
+pub use models::{Command, Response};
+pub use errors::FitbitError;
@@ -0,0 +1,140 @@
+use std::io::IsTerminal;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The log level when `LOG_LEVEL` is unset or invalid.
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// Initializes the tracing subscriber. The level is read from `LOG_LEVEL`, which must be one of `off`, `error`, `warn`,
+/// `info`, `debug` or `trace`, defaulting to `info`; an invalid level is reported and replaced by the default.
+/// `RUST_LOG` may then override the level for individual modules with `EnvFilter` directives, for example
+/// `lalune_engine::cache=debug`. The style is read from `LOG_STYLE`, `always`, `never` or `auto`, defaulting to
+/// `always`. Lines written inside a span, such as the `command` span each command executes in, are tagged with the
+/// span's fields, so the output of concurrent commands can be told apart. Records from dependencies that log with the
+/// `log` crate are forwarded to the subscriber.
+pub fn init() {
+  let (level, invalid) = log_level(std::env::var("LOG_LEVEL").ok().as_deref());
+  let filters = std::env::var("RUST_LOG").unwrap_or_default();
+  let ansi = ansi(std::env::var("LOG_STYLE").ok().as_deref(), std::io::stderr().is_terminal());
+
+  subscriber(level, &filters, ansi, std::io::stderr).init();
+
+  if let Some(invalid) = invalid {
+    warn!("Invalid LOG_LEVEL, expected off, error, warn, info, debug or trace, got {}; using {}", invalid, level);
+  }
+}
+
+/// Builds the subscriber `init` installs, writing to `writer`.
+fn subscriber<W>(level: LevelFilter, filters: &str, ansi: bool, writer: W) -> tracing_subscriber::fmt::SubscriberBuilder<tracing_subscriber::fmt::format::DefaultFields, tracing_subscriber::fmt::format::Format, EnvFilter, W>
+where
+  W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+  let filter = EnvFilter::builder()
+    .with_default_directive(level.into())
+    .parse_lossy(filters);
+
+  tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_ansi(ansi)
+    .with_writer(writer)
+}
+
+/// The base log level for a `LOG_LEVEL` value, along with the value if it was invalid and the default was used.
+fn log_level(value: Option<&str>) -> (LevelFilter, Option<String>) {
+  match value.map(str::trim) {
+    None | Some("") => (DEFAULT_LOG_LEVEL, None),
+    Some(value) => match value.parse::<LevelFilter>() {
+      Ok(level) => (level, None),
+      Err(_) => (DEFAULT_LOG_LEVEL, Some(value.to_string())),
+    },
+  }
+}
+
+/// Whether to color the output for a `LOG_STYLE` value. `auto` colors it only when writing to a terminal.
+fn ansi(style: Option<&str>, terminal: bool) -> bool {
+  match style.map(str::trim) {
+    Some("never") => false,
+    Some("auto") => terminal,
+    _ => true,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+  use tracing::{info, info_span, Instrument};
+
+  /// Collects everything the subscriber writes.
+  #[derive(Clone, Default)]
+  struct Output(Arc<Mutex<Vec<u8>>>);
+
+  impl std::io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Output {
+    fn lines(&self) -> Vec<String> {
+      String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+    }
+  }
+
+  #[tokio::test]
+  async fn lines_in_a_command_are_tagged_with_it() {
+    let output = Output::default();
+    let subscriber = subscriber(LevelFilter::INFO, "", false, {
+      let output = output.clone();
+      move || output.clone()
+    }).finish();
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let span = info_span!("command", coordination_id = "01H2XK", user_id = "user");
+
+    async {
+      info!("inside");
+      tokio::spawn(async { info!("spawned") }.in_current_span()).await.unwrap();
+    }.instrument(span).await;
+
+    info!("outside");
+
+    let lines = output.lines();
+
+    assert!(lines[0].contains("command{coordination_id=\"01H2XK\" user_id=\"user\"}") && lines[0].ends_with("inside"));
+    assert!(lines[1].contains("command{coordination_id=\"01H2XK\" user_id=\"user\"}") && lines[1].ends_with("spawned"));
+    assert!(!lines[2].contains("command{") && lines[2].ends_with("outside"));
+  }
+
+  #[test]
+  fn log_level_defaults_to_info() {
+    assert_eq!(log_level(None), (LevelFilter::INFO, None));
+    assert_eq!(log_level(Some("")), (LevelFilter::INFO, None));
+    assert_eq!(log_level(Some("debug")), (LevelFilter::DEBUG, None));
+    assert_eq!(log_level(Some("WARN")), (LevelFilter::WARN, None));
+    assert_eq!(log_level(Some("off")), (LevelFilter::OFF, None));
+  }
+
+  #[test]
+  fn invalid_log_level_falls_back() {
+    assert_eq!(log_level(Some("verbose")), (LevelFilter::INFO, Some("verbose".to_string())));
+    // Module directives belong in RUST_LOG.
+    assert_eq!(log_level(Some("lalune_engine=debug")), (LevelFilter::INFO, Some("lalune_engine=debug".to_string())));
+  }
+
+  #[test]
+  fn log_style_defaults_to_color() {
+    assert!(ansi(None, false));
+    assert!(ansi(Some("always"), false));
+    assert!(!ansi(Some("never"), true));
+    assert!(ansi(Some("auto"), true));
+    assert!(!ansi(Some("auto"), false));
+  }
+}
@@ -1,97 +1,190 @@
-use log::{info, error};
-use env_logger::Env;
+use tracing::{info, error};
 use dotenv::dotenv;
-use sqlx::PgPool;
 use tokio_stream::wrappers::ReceiverStream;
 use futures_util::stream::StreamExt;
-use bb8::Pool;
-use bb8_redis::RedisConnectionManager;
-
-mod fitbit;
-mod cache;
-mod database;
-mod errors;
-mod models;
-mod utils;
-
-// TODO
-// - [ ] Implement refresh token request
-// - [ ] Implement communication across redis
-// - [ ] Implement Fitbit Errors
-// - [ ] Refactor to not use tuples for return values
-// - [ ] Don't use 'as' for type conversions
-// - [ ] Switch timestamps to ISO strings for memory efficiency
+use lalune_engine::{cache, consumer, database, fitbit, logging, metrics};
+use lalune_engine::cache::Cache;
+use lalune_engine::database::Database;
+use std::env;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+const DEFAULT_MAX_CONCURRENT_COMMANDS: usize = 32;
+const DEFAULT_TOKEN_REFRESH_WINDOW_SECS: i64 = 30 * 60;
+/// How long a command may run before it is abandoned, unless `COMMAND_TIMEOUT_SECS` is set.
+const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 120;
+/// How long Redis and Postgres each have to answer at startup before the engine gives up.
+const STARTUP_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// TODO: Don't use 'as' for type conversions
 
 #[tokio::main]
 async fn main() {
   dotenv().ok();
 
-  let env = Env::default()
-    .filter_or("LOG_LEVEL", "trace")
-    .write_style_or("LOG_STYLE", "always");
+  logging::init();
 
-  env_logger::init_from_env(env);
-
-  let redis_pool = cache::CacheHandler::build_pool().await;
-  let database_pool = database::DatabaseHandler::build_pool().await;
-  
-  let mut command_stream = cache::CacheHandler::get_stream(&redis_pool).await;
-
-  info!("Listening for redis stream...");
-
-  match listen(&mut command_stream, redis_pool, database_pool).await {
-    Ok(_) => info!("Stream terminated"),
-    Err(e) => error!("Error: {:?}", e),
+  // The metrics server only binds a port when METRICS_PORT is set.
+  if let Ok(port) = env::var("METRICS_PORT") {
+    match port.parse::<u16>() {
+      Ok(port) => { tokio::spawn(metrics::serve(port)); },
+      Err(_) => error!("Invalid METRICS_PORT, expected a port number, got {}", port),
+    }
   }
-}
-
 
+  let redis_pool = cache::CacheHandler::build_pool().await.unwrap_or_else(|e| exit_with(&e));
+  let database_pool = database::DatabaseHandler::build_pool().unwrap_or_else(|e| exit_with(&e));
 
-async fn listen<'a>(command_stream: &mut ReceiverStream<String>, redis_pool: Pool<RedisConnectionManager>, database_pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {  
-  let reqwest_client = reqwest::Client::new();
-  
   let cache_client = cache::CacheHandler::new(redis_pool);
+
+  let reqwest_client = fitbit::client_builder().build().expect("Failed to build HTTP client");
   let database_client = database::DatabaseHandler::new(database_pool);
-  
+
+  if let Err(e) = check_readiness(&cache_client, &database_client).await {
+    exit_with(&e);
+  }
+
   let fitbit_client = fitbit::Fitbit::new(
     reqwest_client,
-    cache_client,
+    cache_client.clone(),
     database_client,
   );
 
-  command_stream.for_each_concurrent(None, move |message| {
-    let fitbit_client = fitbit_client.clone();
+  // The HTTP front end shares the Fitbit client with the Redis consumer, and only binds a port when HTTP_PORT is set.
+  #[cfg(feature = "http")]
+  if let Ok(port) = env::var("HTTP_PORT") {
+    match port.parse::<u16>() {
+      Ok(port) => { tokio::spawn(lalune_engine::http::serve(fitbit_client.clone(), port)); },
+      Err(_) => error!("Invalid HTTP_PORT, expected a port number, got {}", port),
+    }
+  }
 
-    tokio::spawn(async move {
-      info!("Received message: {:?}", message);
+  let mut command_stream = cache_client.get_stream().await;
 
-      let Some(message) = utils::decode_message(message) else {
-        info!("Error decoding message");
-        return futures_util::future::ready(())
-      };
+  info!("Listening for redis stream...");
 
-      info!("Message parsed: {:?}", message);
+  match listen(&mut command_stream, cache_client, fitbit_client).await {
+    Ok(_) => info!("Stream terminated"),
+    Err(e) => error!("Error: {:?}", e),
+  }
+}
+
+/// Checks that Redis answers `PING` and Postgres answers `SELECT 1`. Both pools connect lazily, so without this a bad
+/// URL or an unreachable server would only show up once the first command fails.
+/// 
+/// # Returns
+/// 
+/// * `Ok(())` - If both answered within `STARTUP_CHECK_TIMEOUT`.
+/// * `Err(message)` - Which check failed, and why.
+async fn check_readiness(cache_client: &cache::CacheHandler, database_client: &database::DatabaseHandler) -> Result<(), String> {
+  match tokio::time::timeout(STARTUP_CHECK_TIMEOUT, cache_client.ping()).await {
+    Ok(Ok(())) => info!("Redis is ready"),
+    Ok(Err(e)) => return Err(format!("Redis is not ready, PING failed: {}", e)),
+    Err(_) => return Err(format!("Redis is not ready, PING did not answer within {:?}", STARTUP_CHECK_TIMEOUT)),
+  }
 
-      let coordination_id = message.0;
-      let command = match message.1 {
-        Ok(command) => command,
-        Err(e) => {
-          fitbit_client.reply(coordination_id, models::Response::Error(e)).await;
-          return futures_util::future::ready(())
-        },
-      };
-    
-      let reply = fitbit_client.execute_command(command).await;
+  match tokio::time::timeout(STARTUP_CHECK_TIMEOUT, database_client.ping()).await {
+    Ok(Ok(())) => info!("Postgres is ready"),
+    Ok(Err(e)) => return Err(format!("Postgres is not ready, SELECT 1 failed: {}", e)),
+    Err(_) => return Err(format!("Postgres is not ready, SELECT 1 did not answer within {:?}", STARTUP_CHECK_TIMEOUT)),
+  }
 
-      info!("Sending reply: {:?}", reply);
-      
-      fitbit_client.reply(coordination_id, reply).await;
-      
-      futures_util::future::ready(())
-    });
+  Ok(())
+}
 
-    futures_util::future::ready(())
-  }).await;
+/// Logs a fatal startup error and exits with a non-zero status.
+fn exit_with(message: &str) -> ! {
+  error!("Fatal: {}", message);
+  std::process::exit(1);
+}
+
+async fn listen(command_stream: &mut ReceiverStream<cache::QueuedCommand>, cache_client: cache::CacheHandler, fitbit_client: fitbit::Fitbit) -> Result<(), Box<dyn std::error::Error>> {
+  // Tokens are only refreshed ahead of expiry when TOKEN_REFRESH_INTERVAL_SECS is set.
+  if let Ok(interval) = env::var("TOKEN_REFRESH_INTERVAL_SECS") {
+    let window = env::var("TOKEN_REFRESH_WINDOW_SECS")
+      .ok()
+      .and_then(|window| window.parse::<i64>().ok())
+      .unwrap_or(DEFAULT_TOKEN_REFRESH_WINDOW_SECS);
+
+    match interval.parse::<u64>() {
+      Ok(interval) if interval > 0 => {
+        info!("Refreshing tokens expiring within {} seconds every {} seconds", window, interval);
+
+        tokio::spawn(fitbit_client.clone().refresh_tokens_periodically(std::time::Duration::from_secs(interval), chrono::Duration::seconds(window)));
+      },
+      _ => error!("Invalid TOKEN_REFRESH_INTERVAL_SECS, expected a positive number of seconds, got {}", interval),
+    }
+  }
+
+  // Bounds the number of commands executing at once; the rest wait for a permit.
+  let max_concurrent_commands = env::var("MAX_CONCURRENT_COMMANDS")
+    .ok()
+    .and_then(|max| max.parse::<usize>().ok())
+    .unwrap_or(DEFAULT_MAX_CONCURRENT_COMMANDS);
+
+  info!("Executing at most {} commands concurrently", max_concurrent_commands);
+
+  let semaphore = Arc::new(Semaphore::new(max_concurrent_commands));
+
+  // A command that runs longer than this is answered with a timeout, so one stuck on Redis, Postgres or Fitbit
+  // releases its permit.
+  let command_timeout = match env::var("COMMAND_TIMEOUT_SECS") {
+    Ok(timeout) => match timeout.parse::<u64>() {
+      Ok(timeout) if timeout > 0 => timeout,
+      _ => {
+        error!("Invalid COMMAND_TIMEOUT_SECS, expected a positive number of seconds, got {}", timeout);
+        DEFAULT_COMMAND_TIMEOUT_SECS
+      },
+    },
+    Err(_) => DEFAULT_COMMAND_TIMEOUT_SECS,
+  };
+
+  let command_timeout = std::time::Duration::from_secs(command_timeout);
+
+  // With REPLY_ENCODING=msgpack every command is answered in MessagePack, whatever its framing.
+  let msgpack_replies = match env::var("REPLY_ENCODING") {
+    Ok(encoding) if encoding == "msgpack" => true,
+    Ok(encoding) if encoding == "text" => false,
+    Ok(encoding) => {
+      error!("Invalid REPLY_ENCODING, expected text or msgpack, got {}", encoding);
+      false
+    },
+    Err(_) => false,
+  };
+
+  if msgpack_replies {
+    info!("Encoding every reply as MessagePack");
+  }
+
+  // With COMMAND_SHARDS set, commands are split across that many consumers by user, and each consumer executes its
+  // commands one at a time, so commands for the same user run in order and never race to refresh the same token.
+  let shards = match env::var("COMMAND_SHARDS") {
+    Ok(shards) => match shards.parse::<usize>() {
+      Ok(shards) if shards > 0 => Some(shards),
+      _ => {
+        error!("Invalid COMMAND_SHARDS, expected a positive number, got {}", shards);
+        None
+      },
+    },
+    Err(_) => None,
+  };
+
+  let handle = move |message| consumer::handle_message(message, cache_client.clone(), fitbit_client.clone(), semaphore.clone(), msgpack_replies, command_timeout);
+
+  match shards {
+    Some(shards) => {
+      info!("Executing commands in order per user across {} shards", shards);
+
+      cache::process_sharded(command_stream, shards, cache::QueuedCommand::shard_key, handle).await;
+    },
+    None => {
+      command_stream.for_each_concurrent(None, move |message| {
+        tokio::spawn(handle(message));
+
+        futures_util::future::ready(())
+      }).await;
+    },
+  }
 
   Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,68 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use axum::{Router, routing::get};
+use prometheus::{Encoder, HistogramVec, IntCounterVec, TextEncoder, register_histogram_vec, register_int_counter_vec};
+use tracing::{info, error};
+
+/// Commands received from the request queue, labeled by command type.
+pub static COMMANDS_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  register_int_counter_vec!("lalune_commands_received_total", "Commands received from the request queue.", &["command"]).unwrap()
+});
+
+/// Time spent in `execute_command`, labeled by command type.
+pub static COMMAND_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+  register_histogram_vec!("lalune_command_latency_seconds", "Time spent executing a command.", &["command"]).unwrap()
+});
+
+/// Whether data was served from the cache, the database, or fetched live from Fitbit, labeled by `source`.
+pub static DATA_SOURCE: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  register_int_counter_vec!("lalune_data_source_total", "Cache hits, database hits, and live Fitbit fetches.", &["source"]).unwrap()
+});
+
+/// Errors returned by commands, labeled by `FitbitError` variant.
+pub static ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  register_int_counter_vec!("lalune_errors_total", "Errors returned by commands.", &["error"]).unwrap()
+});
+
+/// Renders all registered metrics in the Prometheus text format.
+pub fn render() -> String {
+  let mut buffer = Vec::new();
+
+  if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+    error!("Failed to encode metrics: {}", e);
+  }
+
+  String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Serves the metrics at `/metrics` on the given port until the process exits.
+/// 
+/// # Arguments
+/// 
+/// * `port` - The port to bind on all interfaces.
+pub async fn serve(port: u16) {
+  let app = Router::new().route("/metrics", get(|| async { render() }));
+  let address = SocketAddr::from(([0, 0, 0, 0], port));
+
+  info!("Serving metrics on {}", address);
+
+  if let Err(e) = axum::Server::bind(&address).serve(app.into_make_service()).await {
+    error!("Metrics server stopped: {}", e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn render_includes_labeled_counters() {
+    COMMANDS_RECEIVED.with_label_values(&["get_steps"]).inc();
+    DATA_SOURCE.with_label_values(&["cache"]).inc();
+
+    let rendered = render();
+
+    assert!(rendered.contains("lalune_commands_received_total{command=\"get_steps\"}"));
+    assert!(rendered.contains("lalune_data_source_total{source=\"cache\"}"));
+  }
+}
@@ -1,10 +1,12 @@
 use std::fmt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use reqwest::header::HeaderMap;
 use std::collections::HashMap;
-use chrono::{NaiveDate, NaiveDateTime};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use crate::errors;
 
 /// Time periods for which to retrieve steps.
+#[derive(Debug, Clone, Copy)]
 pub enum Period {
   OneDay,
   OneWeek,
@@ -40,6 +42,262 @@ impl Period {
   }
 }
 
+/// Intraday detail levels. Not every resource supports every level; see `Detail::supported_by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Detail {
+  /// Only served for heart rate.
+  #[serde(rename = "1sec")]
+  OneSec,
+  #[serde(rename = "1min")]
+  OneMin,
+  #[serde(rename = "5min")]
+  FiveMin,
+  #[serde(rename = "15min")]
+  FifteenMin,
+}
+
+impl fmt::Display for Detail {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.to_str())
+  }
+}
+
+impl Detail {
+  pub fn to_str(&self) -> &str {
+    match self {
+      Detail::OneSec => "1sec",
+      Detail::OneMin => "1min",
+      Detail::FiveMin => "5min",
+      Detail::FifteenMin => "15min",
+    }
+  }
+
+  /// Parses `1sec`, `1min`, `5min`, or `15min`.
+  pub fn parse(detail: &str) -> Option<Self> {
+    match detail {
+      "1sec" => Some(Detail::OneSec),
+      "1min" => Some(Detail::OneMin),
+      "5min" => Some(Detail::FiveMin),
+      "15min" => Some(Detail::FifteenMin),
+      _ => None,
+    }
+  }
+
+  /// Whether Fitbit serves `resource` at this detail level. Heart rate goes down to the second, and the activity
+  /// resources to the minute.
+  pub fn supported_by(&self, resource: Resource) -> bool {
+    match self {
+      Detail::OneSec => resource == Resource::HeartRate,
+      Detail::OneMin | Detail::FiveMin | Detail::FifteenMin => true,
+    }
+  }
+}
+
+/// How daily values are encoded in a response.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+  /// Only the values, ordered by date. Consumers must already know which dates are present.
+  #[default]
+  Counts,
+  /// `date=value` pairs with ISO dates, so gaps are preserved.
+  Dated,
+}
+
+/// The unit distances are reported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DistanceUnit {
+  #[serde(rename = "km")]
+  Kilometers,
+  #[serde(rename = "miles")]
+  Miles,
+}
+
+impl DistanceUnit {
+  const MILES_PER_KILOMETER: f64 = 0.621371;
+
+  /// The unit of a Fitbit account's `distanceUnit` setting. Only `en_US` uses miles.
+  pub fn from_locale(locale: &str) -> Self {
+    match locale {
+      "en_US" => DistanceUnit::Miles,
+      _ => DistanceUnit::Kilometers,
+    }
+  }
+
+  /// Converts a distance in kilometers, as Fitbit reports it, into this unit.
+  pub fn from_kilometers(&self, kilometers: f64) -> f64 {
+    match self {
+      DistanceUnit::Kilometers => kilometers,
+      DistanceUnit::Miles => kilometers * Self::MILES_PER_KILOMETER,
+    }
+  }
+}
+
+/// The unit weights are reported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WeightUnit {
+  #[serde(rename = "kg")]
+  Kilograms,
+  #[serde(rename = "lbs")]
+  Pounds,
+  #[serde(rename = "stone")]
+  Stone,
+}
+
+impl WeightUnit {
+  const POUNDS_PER_KILOGRAM: f64 = 2.204623;
+  const POUNDS_PER_STONE: f64 = 14.0;
+
+  /// The unit of a Fitbit account's `weightUnit` setting. `en_US` uses pounds and `en_GB` uses stone.
+  pub fn from_locale(locale: &str) -> Self {
+    match locale {
+      "en_US" => WeightUnit::Pounds,
+      "en_GB" => WeightUnit::Stone,
+      _ => WeightUnit::Kilograms,
+    }
+  }
+
+  /// Converts a weight in kilograms, as Fitbit reports it, into this unit.
+  pub fn from_kilograms(&self, kilograms: f64) -> f64 {
+    match self {
+      WeightUnit::Kilograms => kilograms,
+      WeightUnit::Pounds => kilograms * Self::POUNDS_PER_KILOGRAM,
+      WeightUnit::Stone => kilograms * Self::POUNDS_PER_KILOGRAM / Self::POUNDS_PER_STONE,
+    }
+  }
+}
+
+/// What to do when fresh data cannot be fetched because Fitbit is unavailable.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StalePolicy {
+  /// Fail the command.
+  #[default]
+  Strict,
+  /// Answer with whatever is already cached or stored, flagged as stale.
+  AllowStale,
+  /// Answer with whatever is already cached or stored, plus the windows fetched before the rate limit was reached,
+  /// flagged as partial. Any other failure still fails the command.
+  AllowPartial,
+}
+
+/// Where a day of a `get_steps` reply asking for sources came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+  /// Read from Redis or Postgres.
+  Cache,
+  /// Fetched from Fitbit while answering the command.
+  Live,
+}
+
+impl Source {
+  /// `cache` or `live`.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Source::Cache => "cache",
+      Source::Live => "live",
+    }
+  }
+}
+
+/// How daily step counts are bucketed before they are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+  /// One value per day.
+  #[default]
+  Daily,
+  /// Sums per ISO week, keyed by the Monday the week starts on, as Fitbit starts its weeks.
+  Weekly,
+  /// Sums per calendar month, keyed by the first of the month.
+  Monthly,
+}
+
+impl Aggregation {
+  /// Parses `daily`, `weekly`, or `monthly`.
+  pub fn parse(aggregation: &str) -> Option<Self> {
+    match aggregation {
+      "daily" => Some(Aggregation::Daily),
+      "weekly" => Some(Aggregation::Weekly),
+      "monthly" => Some(Aggregation::Monthly),
+      _ => None,
+    }
+  }
+
+  /// The first day of the bucket `date` falls in.
+  fn bucket(&self, date: NaiveDate) -> NaiveDate {
+    match self {
+      Aggregation::Daily => date,
+      Aggregation::Weekly => date - Duration::days(i64::from(date.weekday().num_days_from_monday())),
+      Aggregation::Monthly => date.with_day(1).unwrap_or(date),
+    }
+  }
+
+  /// Sums daily values into buckets keyed by the bucket's first day. Buckets cut off by the edges of the range are
+  /// still included, with only the days inside the range summed, so the first key may fall before the range starts.
+  pub fn apply(&self, values: HashMap<NaiveDate, u32>) -> HashMap<NaiveDate, u32> {
+    if *self == Aggregation::Daily {
+      return values;
+    }
+
+    let mut buckets: HashMap<NaiveDate, u32> = HashMap::new();
+
+    for (date, value) in values {
+      let total = buckets.entry(self.bucket(date)).or_default();
+      *total = total.saturating_add(value);
+    }
+
+    buckets
+  }
+}
+
+/// Rate limit state reported by Fitbit in response headers. Any field is `None` if the header was missing or malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub struct RateLimitInfo {
+  /// Requests remaining in the current window.
+  pub remaining: Option<u32>,
+  /// Requests allowed per window.
+  pub limit: Option<u32>,
+  /// Seconds until the current window resets.
+  pub reset_seconds: Option<u64>,
+}
+
+impl RateLimitInfo {
+  /// Reads `Fitbit-Rate-Limit-Remaining`, `Fitbit-Rate-Limit-Limit` and `Fitbit-Rate-Limit-Reset`, falling back to
+  /// `Retry-After` for the reset.
+  pub fn from_headers(headers: &HeaderMap) -> Self {
+    fn parse<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+      headers.get(name)?.to_str().ok()?.trim().parse::<T>().ok()
+    }
+
+    Self {
+      remaining: parse(headers, "fitbit-rate-limit-remaining"),
+      limit: parse(headers, "fitbit-rate-limit-limit"),
+      reset_seconds: parse(headers, "fitbit-rate-limit-reset").or_else(|| parse(headers, "retry-after")),
+    }
+  }
+}
+
+/// Retry policy for idempotent requests to Fitbit.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+  /// The number of retries after the first attempt.
+  pub max_retries: u32,
+  /// The delay before the first retry, doubled for each subsequent retry.
+  pub base_delay: std::time::Duration,
+}
+
+impl RetryConfig {
+  /// The delay before the given retry (zero-indexed): exponential backoff plus up to `base_delay` of jitter.
+  pub fn delay(&self, attempt: u32) -> std::time::Duration {
+    let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+    let jitter = self.base_delay.mul_f64(fastrand::f64());
+
+    backoff + jitter
+  }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ErrorDetail {
   #[serde(rename = "errorType")]
@@ -64,11 +322,381 @@ pub struct TokenResponse {
   pub user_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct HeartRateValue {
+  #[serde(rename = "restingHeartRate")]
+  pub resting_heart_rate: Option<u32>,
+  #[serde(rename = "heartRateZones", default)]
+  pub heart_rate_zones: Vec<HeartRateZone>,
+}
+
+/// One of the zones Fitbit lists under `heartRateZones`, by its display name.
+#[derive(Debug, Deserialize)]
+pub struct HeartRateZone {
+  pub name: String,
+  #[serde(default)]
+  pub minutes: u32,
+}
+
+/// The minutes a user spent in each of Fitbit's heart rate zones on a day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct HeartRateZones {
+  pub out_of_range: u32,
+  pub fat_burn: u32,
+  pub cardio: u32,
+  pub peak: u32,
+}
+
+impl HeartRateZones {
+  /// Collects the minutes of Fitbit's default zones, or `None` if the day has no zones, such as when the device was
+  /// not worn. Custom zones are ignored.
+  pub fn from_zones(zones: &[HeartRateZone]) -> Option<Self> {
+    if zones.is_empty() {
+      return None;
+    }
+
+    let mut minutes = HeartRateZones::default();
+
+    for zone in zones {
+      match zone.name.as_str() {
+        "Out of Range" => minutes.out_of_range = zone.minutes,
+        "Fat Burn" => minutes.fat_burn = zone.minutes,
+        "Cardio" => minutes.cardio = zone.minutes,
+        "Peak" => minutes.peak = zone.minutes,
+        _ => {},
+      }
+    }
+
+    Some(minutes)
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeartRateDay {
+  #[serde(rename = "dateTime")]
+  pub date_time: String,
+  pub value: HeartRateValue,
+}
+
+/// A night's blood oxygen saturation, as percentages.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Spo2Summary {
+  pub avg: f64,
+  pub min: f64,
+  pub max: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Spo2Day {
+  #[serde(rename = "dateTime")]
+  pub date_time: String,
+  pub value: Spo2Summary,
+}
+
+/// The reply to a SpO2 range request, which is a bare array of days rather than an object.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Spo2Response {
+  Success(Vec<Spo2Day>),
+  Error(ErrorResponse),
+}
+
+/// A weight the user logged, by hand or from a connected scale. A user may log any number of weights a day.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WeightLog {
+  pub date: NaiveDate,
+  /// The time of day the weight was logged, in the user's timezone, if Fitbit reports it.
+  pub time: Option<NaiveTime>,
+  /// In kilograms as Fitbit reports it, or in the unit the command asked for once converted.
+  pub weight: f64,
+  /// The body fat percentage measured with the weight, if any.
+  pub fat: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WeightLogEntry {
+  pub date: String,
+  #[serde(default)]
+  pub time: Option<String>,
+  pub weight: f64,
+  #[serde(default)]
+  pub fat: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WeightLogs {
+  pub weight: Vec<WeightLogEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum WeightResponse {
+  Success(WeightLogs),
+  Error(ErrorResponse),
+}
+
+/// A day's cardio fitness score, the user's estimated VO2 max in mL/kg/min. Fitbit reports a range such as `40-44`
+/// when it estimates the score from resting heart rate, and a single value when it has GPS runs to go on, in which
+/// case `low` and `high` are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct CardioScore {
+  pub low: f64,
+  pub high: f64,
+}
+
+impl CardioScore {
+  /// Parses a `vo2Max` value, either a single number or a `low-high` range.
+  pub fn parse(value: &str) -> Option<Self> {
+    let (low, high) = value.split_once('-').unwrap_or((value, value));
+    let (low, high) = (low.trim().parse::<f64>().ok()?, high.trim().parse::<f64>().ok()?);
+
+    (low <= high).then_some(CardioScore { low, high })
+  }
+}
+
+/// A `vo2Max` value, which Fitbit sends as a number or as a string holding a number or a range.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Vo2Max {
+  Number(f64),
+  Text(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CardioScoreValue {
+  #[serde(rename = "vo2Max")]
+  pub vo2_max: Vo2Max,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CardioScoreDay {
+  #[serde(rename = "dateTime")]
+  pub date_time: String,
+  pub value: CardioScoreValue,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CardioScoreSeries {
+  #[serde(rename = "cardioScore")]
+  pub cardio_score: Vec<CardioScoreDay>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CardioScoreResponse {
+  Success(CardioScoreSeries),
+  Error(ErrorResponse),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntradayDataPoint {
+  pub time: String,
+  pub value: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntradayDataset {
+  pub dataset: Vec<IntradayDataPoint>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntradayStepsResponse {
+  #[serde(rename = "activities-steps-intraday")]
+  pub intraday: IntradayDataset,
+}
+
+/// The parts of a user's Fitbit profile the engine uses. Fields Fitbit omits, for example because of the user's
+/// privacy settings, are empty. Serialized with Fitbit's field names, which is also how it is cached.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct Profile {
+  #[serde(rename = "displayName", default)]
+  pub display_name: String,
+  /// The URL of the user's avatar.
+  #[serde(default)]
+  pub avatar: String,
+  /// The day the user joined Fitbit, as `YYYY-MM-DD`.
+  #[serde(rename = "memberSince", default)]
+  pub member_since: String,
+  pub timezone: String,
+  #[serde(rename = "offsetFromUTCMillis")]
+  pub offset_from_utc_millis: i64,
+  /// The unit system distances are shown in on the user's account, such as `en_US` or `METRIC`.
+  #[serde(rename = "distanceUnit", default)]
+  pub distance_unit: String,
+  /// The unit system weights are shown in on the user's account, such as `en_US`, `en_GB` or `METRIC`.
+  #[serde(rename = "weightUnit", default)]
+  pub weight_unit: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileResponse {
+  pub user: Profile,
+}
+
+/// The user's daily activity goals. A goal is `None` if Fitbit does not report it, for example floors on a device
+/// without an altimeter. Serialized with Fitbit's field names, which is also how it is cached.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct Goals {
+  #[serde(default)]
+  pub steps: Option<u32>,
+  /// In the unit system of the user's profile, kilometers by default.
+  #[serde(default)]
+  pub distance: Option<f64>,
+  #[serde(rename = "caloriesOut", default)]
+  pub calories: Option<u32>,
+  #[serde(default)]
+  pub floors: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GoalsResponse {
+  pub goals: Goals,
+}
+
+/// A device paired with the user's Fitbit account. Serialized with Fitbit's field names, which is also how it is
+/// cached.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Device {
+  pub id: String,
+  /// The kind of device, such as `TRACKER` or `SCALE`.
+  #[serde(rename = "type")]
+  pub device_type: String,
+  /// The battery charge as a percentage, if the device reports one.
+  #[serde(rename = "batteryLevel", default)]
+  pub battery_level: Option<u8>,
+  /// When the device last synced, in the user's local time, as `YYYY-MM-DDTHH:MM:SS.sss`.
+  #[serde(rename = "lastSyncTime", default)]
+  pub last_sync_time: String,
+}
+
+impl Device {
+  /// When the device last synced, in the user's local time, or `None` if it never has or Fitbit sent a time that
+  /// cannot be read.
+  pub fn last_sync(&self) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(&self.last_sync_time, "%Y-%m-%dT%H:%M:%S%.f").ok()
+  }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum FitbitSuccess {
-  Steps(HashMap<String, Vec<HashMap<String, String>>>),
+  IntradaySteps(IntradayStepsResponse),
+  /// The days of a `Resource`, under its `activities-{resource}` key. Each resource shapes its values differently.
+  TimeSeries(HashMap<String, Vec<serde_json::Value>>),
   Refresh(TokenResponse),
+  Profile(ProfileResponse),
+  Goals(GoalsResponse),
+  Devices(Vec<Device>),
+}
+
+/// The daily values of a time series fetched from Fitbit, along with the rate limit state reported with them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeSeriesResult<V = u32> {
+  pub values: HashMap<NaiveDate, V>,
+  pub rate_limit: RateLimitInfo,
+}
+
+/// The answer to a request sent with `If-None-Match`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conditional<T> {
+  /// The resource changed, or no ETag was sent: the fresh result, and the ETag Fitbit sent with it, if any.
+  Modified(T, Option<String>),
+  /// Fitbit answered `304 Not Modified`, so what was fetched with the ETag is still current. Carries the rate limit
+  /// state reported with the answer.
+  NotModified(RateLimitInfo),
+}
+
+/// A daily time series served by Fitbit's `activities/{resource}/date/{date}/{period}.json` endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+  Steps,
+  /// Resting heart rate.
+  HeartRate,
+  /// Calories burned, including the basal metabolic rate.
+  Calories,
+  /// Floors climbed. Trackers without an altimeter do not report floors.
+  Floors,
+  /// Minutes of moderate activity, which count towards active minutes.
+  MinutesFairlyActive,
+  /// Minutes of vigorous activity, which count towards active minutes.
+  MinutesVeryActive,
+}
+
+impl Resource {
+  /// The resource's segment of the endpoint path.
+  pub fn path(&self) -> &'static str {
+    match self {
+      Resource::Steps => "steps",
+      Resource::HeartRate => "heart",
+      Resource::Calories => "calories",
+      Resource::Floors => "floors",
+      Resource::MinutesFairlyActive => "minutesFairlyActive",
+      Resource::MinutesVeryActive => "minutesVeryActive",
+    }
+  }
+}
+
+impl fmt::Display for Resource {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Resource::Steps => write!(f, "steps"),
+      Resource::HeartRate => write!(f, "heart rate"),
+      Resource::Calories => write!(f, "calories"),
+      Resource::Floors => write!(f, "floors"),
+      Resource::MinutesFairlyActive => write!(f, "fairly active minutes"),
+      Resource::MinutesVeryActive => write!(f, "very active minutes"),
+    }
+  }
+}
+
+/// A day's active minutes, made up of fairly and very active minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActiveMinutes {
+  pub fairly: u32,
+  pub very: u32,
+  pub total: u32,
+}
+
+impl ActiveMinutes {
+  pub fn new(fairly: u32, very: u32) -> Self {
+    Self { fairly, very, total: fairly.saturating_add(very) }
+  }
+}
+
+/// A user's timezone, as set in their Fitbit profile. Fitbit attributes steps to the user's local day.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct UserTimezone {
+  /// The IANA name, e.g. `America/Los_Angeles`.
+  pub name: String,
+  /// The offset from UTC when the timezone was last synced. Daylight saving changes are only picked up by syncing again.
+  pub utc_offset_seconds: i32,
+}
+
+impl UserTimezone {
+  /// The timezone assumed for users who have not synced theirs.
+  pub fn utc() -> Self {
+    Self { name: "UTC".to_string(), utc_offset_seconds: 0 }
+  }
+
+  /// The user's local date at the given instant.
+  pub fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+    (now.naive_utc() + Duration::seconds(i64::from(self.utc_offset_seconds))).date()
+  }
+}
+
+impl Default for UserTimezone {
+  fn default() -> Self {
+    Self::utc()
+  }
+}
+
+impl From<&Profile> for UserTimezone {
+  fn from(profile: &Profile) -> Self {
+    Self {
+      name: profile.timezone.clone(),
+      utc_offset_seconds: i32::try_from(profile.offset_from_utc_millis / 1000).unwrap_or(0),
+    }
+  }
 }
 
 #[derive(Debug, Deserialize)]
@@ -78,26 +706,619 @@ pub enum FitbitResponse {
   Error(ErrorResponse),
 }
 
-#[derive(Debug)]
+/// The framing of a command, which is also used for its reply.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Protocol {
+  /// `coordination_id:command:payload:TTL`, answered with `indication:content`.
+  #[default]
+  Legacy,
+  /// A JSON `RequestEnvelope`, answered with a JSON object.
+  Json,
+  /// A JSON `RequestEnvelope` with a `reply_encoding` of `msgpack`, answered with the JSON object encoded as
+  /// MessagePack.
+  MsgPack,
+}
+
+/// The part of a `RequestEnvelope` that selects how the reply is encoded.
+#[derive(Deserialize)]
+struct ReplyEncoding {
+  #[serde(default)]
+  reply_encoding: Option<String>,
+}
+
+impl Protocol {
+  /// Detects the framing of a message. JSON messages are objects, so they start with `{`.
+  pub fn detect(message: &str) -> Self {
+    if !message.trim_start().starts_with('{') {
+      return Protocol::Legacy;
+    }
+
+    match serde_json::from_str::<ReplyEncoding>(message) {
+      Ok(ReplyEncoding { reply_encoding: Some(encoding) }) if encoding == "msgpack" => Protocol::MsgPack,
+      _ => Protocol::Json,
+    }
+  }
+}
+
+/// A command in the JSON protocol. The payload is decoded according to the command, so an invalid payload can still
+/// be answered on the coordination ID.
+#[derive(Debug, Deserialize)]
+pub struct RequestEnvelope {
+  pub coordination_id: String,
+  pub command: String,
+  #[serde(default)]
+  pub payload: serde_json::Value,
+  /// The UNIX timestamp after which the command is dropped.
+  pub ttl: i64,
+}
+
+/// The payload of the date range commands. Timestamps are UNIX timestamps.
+#[derive(Debug, Deserialize)]
+pub struct RangePayload {
+  pub user_id: String,
+  pub start: i64,
+  pub end: i64,
+  #[serde(default)]
+  pub format: ResponseFormat,
+  /// Whether stale data may be returned when Fitbit is unavailable. Only `get_steps` accepts this.
+  #[serde(default)]
+  pub allow_stale: bool,
+  /// Whether the days fetched before the rate limit was reached may be returned. Only `get_steps` accepts this.
+  #[serde(default)]
+  pub allow_partial: bool,
+  /// How the steps are bucketed. Only `get_steps` accepts this.
+  #[serde(default)]
+  pub aggregation: Aggregation,
+  /// Whether each day is answered with where it came from. Only `get_steps` accepts this.
+  #[serde(default)]
+  pub with_source: bool,
+}
+
+/// The query string of the HTTP `GET /users/{id}/steps` route. Timestamps are UNIX timestamps, as in `RangePayload`.
+#[derive(Debug, Deserialize)]
+pub struct StepsQuery {
+  pub start: i64,
+  pub end: i64,
+  #[serde(default)]
+  pub format: ResponseFormat,
+  #[serde(default)]
+  pub allow_stale: bool,
+  #[serde(default)]
+  pub allow_partial: bool,
+  #[serde(default)]
+  pub aggregation: Aggregation,
+  #[serde(default)]
+  pub with_source: bool,
+}
+
+/// The payload of `get_intraday_steps`. The date is a UNIX timestamp.
+#[derive(Debug, Deserialize)]
+pub struct IntradayPayload {
+  pub user_id: String,
+  pub date: i64,
+  /// A detail level as parsed by `Detail::parse`.
+  pub detail: String,
+}
+
+/// The payload of the commands that only name a user.
+#[derive(Debug, Deserialize)]
+pub struct UserPayload {
+  pub user_id: String,
+}
+
+/// The payload of `list_users`. The offset defaults to the first page.
+#[derive(Debug, Deserialize)]
+pub struct ListUsersPayload {
+  pub limit: u32,
+  #[serde(default)]
+  pub offset: u32,
+}
+
+/// The payload of `sync_recent`.
+#[derive(Debug, Deserialize)]
+pub struct SyncRecentPayload {
+  pub user_id: String,
+  pub days: u16,
+}
+
+/// The payload of `get_distance`. Timestamps are UNIX timestamps, as in `RangePayload`.
+#[derive(Debug, Deserialize)]
+pub struct DistancePayload {
+  pub user_id: String,
+  pub start: i64,
+  pub end: i64,
+  #[serde(default)]
+  pub unit: Option<DistanceUnit>,
+}
+
+/// The payload of `get_weight`. Timestamps are UNIX timestamps, as in `RangePayload`.
+#[derive(Debug, Deserialize)]
+pub struct WeightPayload {
+  pub user_id: String,
+  pub start: i64,
+  pub end: i64,
+  #[serde(default)]
+  pub unit: Option<WeightUnit>,
+}
+
+/// The payload of `get_steps_batch`. Timestamps are UNIX timestamps, as in `RangePayload`.
+#[derive(Debug, Deserialize)]
+pub struct BatchPayload {
+  pub user_ids: Vec<String>,
+  pub start: i64,
+  pub end: i64,
+}
+
+/// The payload of `get_streak`. Timestamps are UNIX timestamps, as in `RangePayload`.
+#[derive(Debug, Deserialize)]
+pub struct StreakPayload {
+  pub user_id: String,
+  pub start: i64,
+  pub end: i64,
+  /// The steps a day needs to count towards a streak.
+  pub threshold: u32,
+}
+
+/// The payload of `set_step_goal`.
+#[derive(Debug, Deserialize)]
+pub struct StepGoalPayload {
+  pub user_id: String,
+  pub goal: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterPayload {
+  pub user_id: String,
+  pub code: String,
+}
+
+/// The payload of `backfill`. The start is a UNIX timestamp.
+#[derive(Debug, Deserialize)]
+pub struct BackfillPayload {
+  pub user_id: String,
+  pub start: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Range {
   pub start: NaiveDate,
   pub end: NaiveDate,
 }
 
-#[derive(Debug)]
+/// A day named relative to the user's today, in place of a timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelativeDate {
+  Today,
+  Yesterday,
+  /// The first of the last 7 days, today included.
+  #[serde(rename = "last_7_days")]
+  Last7Days,
+  /// The first of the last 30 days, today included.
+  #[serde(rename = "last_30_days")]
+  Last30Days,
+}
+
+impl RelativeDate {
+  /// Parses `today`, `yesterday`, `last_7_days`, or `last_30_days`.
+  pub fn parse(keyword: &str) -> Option<Self> {
+    match keyword {
+      "today" => Some(RelativeDate::Today),
+      "yesterday" => Some(RelativeDate::Yesterday),
+      "last_7_days" => Some(RelativeDate::Last7Days),
+      "last_30_days" => Some(RelativeDate::Last30Days),
+      _ => None,
+    }
+  }
+
+  /// How many days before today this day is.
+  pub fn days_ago(&self) -> i64 {
+    match self {
+      RelativeDate::Today => 0,
+      RelativeDate::Yesterday => 1,
+      RelativeDate::Last7Days => 6,
+      RelativeDate::Last30Days => 29,
+    }
+  }
+
+  pub fn resolve(&self, today: NaiveDate) -> NaiveDate {
+    today - Duration::days(self.days_ago())
+  }
+}
+
+/// The range a command asked for, either as dates or as days relative to the user's today, which is only known once
+/// the user's timezone has been looked up.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum RequestedRange {
+  Dates(Range),
+  Relative(RelativeDate, RelativeDate),
+}
+
+impl RequestedRange {
+  /// The dates of the range, given the user's today.
+  pub fn resolve(self, today: NaiveDate) -> Range {
+    match self {
+      RequestedRange::Dates(range) => range,
+      RequestedRange::Relative(start, end) => Range { start: start.resolve(today), end: end.resolve(today) },
+    }
+  }
+}
+
+/// Serializable with serde for use outside the engine, such as by Rust consumers. The wire format keeps the command
+/// names and payload fields consumers already send, so `utils::decode_message` decodes it into the serde forms of
+/// the payloads rather than of the enum.
+#[derive(Debug, Deserialize, Serialize)]
 pub enum Command {
-  GetSteps(String, Range),
+  GetSteps(String, RequestedRange, ResponseFormat, StalePolicy, Aggregation),
+  /// Like `GetSteps`, but every day in the range is included, with 0 for days without a count.
+  GetStepsDense(String, Range, ResponseFormat),
+  /// Like `GetSteps`, but each day is answered with whether it came from the cache or was fetched live. This is
+  /// `get_steps` with `with_source` set.
+  GetStepsWithSource(String, Range),
+  /// Gets the steps of several users over the same range. Each user succeeds or fails on their own.
+  GetStepsBatch(Vec<String>, Range),
+  /// Fetches a user's steps from Fitbit even if they are cached, overwriting the cache, for days Fitbit has corrected.
+  RefreshSteps(String, Range),
+  /// Fetches the user's steps for their last given number of days, through today, from Fitbit in a single request,
+  /// overwriting the cache. This is `RefreshSteps` over a window that ends now.
+  SyncRecent(String, u16),
+  /// Gets a user's resting heart rate each day. Days without one, such as days the device was not worn, are omitted
+  /// rather than reported as 0.
+  GetHeartRate(String, Range, ResponseFormat),
+  /// Gets the minutes a user spent in each heart rate zone each day. Days without zones are omitted.
+  GetHeartRateZones(String, Range),
+  /// Gets the calories a user burned each day.
+  GetCalories(String, Range, ResponseFormat),
+  /// Gets the floors a user climbed each day.
+  GetFloors(String, Range),
+  /// Gets the distance a user covered each day, in the given unit or else the unit of the user's account.
+  GetDistance(String, Range, Option<DistanceUnit>),
+  /// Gets the fairly and very active minutes a user logged each day.
+  GetActiveMinutes(String, Range),
+  /// Gets a user's nightly blood oxygen saturation summaries.
+  GetSpo2(String, Range),
+  /// Gets a user's daily cardio fitness scores.
+  GetCardioScore(String, Range),
+  /// Gets the weights a user logged, in the given unit or else the unit of the user's account.
+  GetWeight(String, Range, Option<WeightUnit>),
+  GetIntradaySteps(String, NaiveDate, Detail),
   RefreshToken(String),
+  RegisterUser(String, String),
+  RevokeToken(String),
+  /// Gets aggregate statistics over a user's daily step counts.
+  GetStepsSummary(String, Range),
+  /// Gets a user's current and longest runs of consecutive days with at least the given steps.
+  GetStreak(String, Range, u32),
+  /// Reports which days of a user's steps are cached.
+  GetCoverage(String),
+  /// Removes a user's cached data and query history.
+  ClearCache(String),
+  /// Fetches a user's steps from the given day through yesterday in the background.
+  Backfill(String, NaiveDate),
+  /// Checks whether a user has connected Fitbit.
+  UserExists(String),
+  /// Fetches the user's timezone from their Fitbit profile and stores it.
+  SyncTimezone(String),
+  /// Gets the user's Fitbit profile, from the cache if it is there.
+  GetProfile(String),
+  /// Gets the user's daily activity goals, from the cache if they are there.
+  GetGoals(String),
+  /// Sets the user's daily step goal at Fitbit.
+  SetStepGoal(String, u32),
+  /// Lists the devices paired with the user's Fitbit account, from the cache if they are there.
+  GetDevices(String),
+  /// Reports how much of the user's rate limit is used, from the cache alone.
+  GetRateLimit(String),
+  /// Reports when the user's access token expires, from the database alone, without refreshing it.
+  GetTokenStatus(String),
+  /// Lists a page of the connected users, ordered by internal user ID, skipping the first `offset`.
+  ListUsers { limit: u32, offset: u32 },
+  /// Checks that the engine is consuming commands and can reach Redis and Postgres.
+  Ping,
+}
+
+impl Command {
+  /// The internal ID of the user the command is for, or `None` if it is not for a user.
+  pub fn user_id(&self) -> Option<&str> {
+    let user_id = match self {
+      Command::GetSteps(user_id, ..)
+      | Command::RefreshSteps(user_id, _)
+      | Command::SyncRecent(user_id, _)
+      | Command::GetStepsDense(user_id, ..)
+      | Command::GetStepsWithSource(user_id, _)
+      | Command::GetHeartRate(user_id, ..)
+      | Command::GetHeartRateZones(user_id, _)
+      | Command::GetCalories(user_id, ..)
+      | Command::GetFloors(user_id, _)
+      | Command::GetDistance(user_id, ..)
+      | Command::GetActiveMinutes(user_id, _)
+      | Command::GetSpo2(user_id, _)
+      | Command::GetCardioScore(user_id, _)
+      | Command::GetWeight(user_id, ..)
+      | Command::GetIntradaySteps(user_id, ..)
+      | Command::RefreshToken(user_id)
+      | Command::RegisterUser(user_id, _)
+      | Command::RevokeToken(user_id)
+      | Command::GetStepsSummary(user_id, _)
+      | Command::GetStreak(user_id, ..)
+      | Command::GetCoverage(user_id)
+      | Command::ClearCache(user_id)
+      | Command::Backfill(user_id, _)
+      | Command::UserExists(user_id)
+      | Command::SyncTimezone(user_id)
+      | Command::GetProfile(user_id)
+      | Command::GetGoals(user_id)
+      | Command::SetStepGoal(user_id, _)
+      | Command::GetDevices(user_id)
+      | Command::GetRateLimit(user_id)
+      | Command::GetTokenStatus(user_id) => user_id,
+      Command::GetStepsBatch(..) | Command::ListUsers { .. } | Command::Ping => return None,
+    };
+
+    Some(user_id)
+  }
+
+  /// The name of the command, as used in the message framing.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Command::GetSteps(..) | Command::GetStepsWithSource(..) => "get_steps",
+      Command::RefreshSteps(..) => "refresh_steps",
+      Command::SyncRecent(..) => "sync_recent",
+      Command::GetStepsDense(..) => "get_steps_dense",
+      Command::GetStepsBatch(..) => "get_steps_batch",
+      Command::GetHeartRate(..) => "get_heart_rate",
+      Command::GetHeartRateZones(..) => "get_heart_rate_zones",
+      Command::GetCalories(..) => "get_calories",
+      Command::GetFloors(..) => "get_floors",
+      Command::GetDistance(..) => "get_distance",
+      Command::GetActiveMinutes(..) => "get_active_minutes",
+      Command::GetSpo2(..) => "get_spo2",
+      Command::GetCardioScore(..) => "get_cardio_score",
+      Command::GetWeight(..) => "get_weight",
+      Command::GetIntradaySteps(..) => "get_intraday_steps",
+      Command::RefreshToken(..) => "refresh",
+      Command::RegisterUser(..) => "register",
+      Command::RevokeToken(..) => "revoke",
+      Command::GetStepsSummary(..) => "get_steps_summary",
+      Command::GetStreak(..) => "get_streak",
+      Command::GetCoverage(..) => "get_coverage",
+      Command::ClearCache(..) => "clear_cache",
+      Command::Backfill(..) => "backfill",
+      Command::UserExists(..) => "user_exists",
+      Command::SyncTimezone(..) => "sync_timezone",
+      Command::GetProfile(..) => "get_profile",
+      Command::GetGoals(..) => "get_goals",
+      Command::SetStepGoal(..) => "set_step_goal",
+      Command::GetDevices(..) => "get_devices",
+      Command::GetRateLimit(..) => "get_rate_limit",
+      Command::GetTokenStatus(..) => "get_token_status",
+      Command::ListUsers { .. } => "list_users",
+      Command::Ping => "ping",
+    }
+  }
 }
 
+/// A command from the request queue that failed, kept in the dead letter queue for operators to inspect.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+  pub coordination_id: String,
+  /// The command as it was read from the request queue.
+  pub message: String,
+  /// The error's stable name, as in `FitbitError::name`.
+  pub code: &'static str,
+  pub error: String,
+  /// The UNIX timestamp of the failure.
+  pub failed_at: i64,
+}
+
+impl DeadLetter {
+  pub fn new(coordination_id: &str, message: &str, error: &errors::FitbitError, failed_at: NaiveDateTime) -> Self {
+    Self {
+      coordination_id: coordination_id.to_string(),
+      message: message.to_string(),
+      code: error.name(),
+      error: error.to_string(),
+      failed_at: failed_at.timestamp(),
+    }
+  }
+}
+
+/// A command from the request queue, with the TTL it was sent with. The caller stops waiting for a reply at the
+/// deadline, so work on the command stops there too.
 #[derive(Debug)]
+pub struct TimedCommand {
+  pub command: Command,
+  pub deadline: NaiveDateTime,
+}
+
+/// Serializable with serde for use outside the engine, such as by Rust consumers. `utils::encode_response` encodes
+/// each variant's data from its serde form where that matches the reply consumers already parse, and by hand where it
+/// does not, such as the legacy framing's comma-separated fields.
+#[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
-  Steps(HashMap<NaiveDate, u32>),
+  Steps(HashMap<NaiveDate, u32>, ResponseFormat),
+  /// Steps that could not be refreshed from Fitbit, so days may be missing or out of date.
+  StaleSteps(HashMap<NaiveDate, u32>, ResponseFormat),
+  /// Steps fetched until the rate limit was reached, so later days in the range may be missing.
+  PartialSteps(HashMap<NaiveDate, u32>, ResponseFormat),
+  /// Steps along with whether each day came from the cache or was fetched live.
+  StepsWithSource(HashMap<NaiveDate, (u32, Source)>),
+  /// The steps of each user in a batch, or the error that user's steps failed with.
+  StepsBatch(HashMap<String, Result<HashMap<NaiveDate, u32>, String>>),
+  HeartRate(HashMap<NaiveDate, u32>, ResponseFormat),
+  /// Minutes in each heart rate zone. Days without zones are absent.
+  HeartRateZones(HashMap<NaiveDate, HeartRateZones>),
+  Calories(HashMap<NaiveDate, u32>, ResponseFormat),
+  /// Floors climbed. Days the tracker did not report floors for are absent, not 0.
+  Floors(HashMap<NaiveDate, u32>),
+  Distance(HashMap<NaiveDate, f64>),
+  ActiveMinutes(HashMap<NaiveDate, ActiveMinutes>),
+  Spo2(HashMap<NaiveDate, Spo2Summary>),
+  /// Cardio fitness scores. Days without a score are absent.
+  CardioScore(HashMap<NaiveDate, CardioScore>),
+  /// Logged weights, ordered by when they were logged. Unlike daily values there may be none or several a day.
+  Weight(Vec<WeightLog>),
+  IntradaySteps(HashMap<NaiveDateTime, u32>),
   Refreshed,
+  Registered,
+  Revoked,
+  /// Aggregate statistics over the days with a step count. `max_date` is the earliest day with the most steps, or `None` if there were no days.
+  StepsSummary { total: u64, average: f64, max: u32, max_date: Option<NaiveDate>, days: usize },
+  /// The run of goal days ending on the last day with steps, and the longest run in the range, with its dates.
+  Streak { current: u32, longest: u32, longest_start: Option<NaiveDate>, longest_end: Option<NaiveDate> },
+  /// The ranges of days that are cached, sorted by start date.
+  Coverage(Vec<Range>),
+  /// A user's cache was cleared, with the number of keys removed.
+  CacheCleared(usize),
+  /// A backfill was started, with an estimate of how many seconds it will take.
+  BackfillScheduled(u64),
+  /// Whether the user has connected Fitbit.
+  UserExists(bool),
+  /// The timezone stored for the user.
+  Timezone(UserTimezone),
+  Profile(Profile),
+  Goals(Goals),
+  /// The step goal Fitbit stored.
+  GoalSet(u32),
+  Devices(Vec<Device>),
+  /// The queries a user has made in the current rate limit window, the most they may make, and the seconds until the
+  /// window resets, or `None` if no window is open.
+  RateLimit { used: usize, limit: usize, reset_in_seconds: Option<u64> },
+  /// When the user's access token expires, in UTC, whether it already has, and the whole seconds until it does, 0
+  /// once it has.
+  TokenStatus { expires_at: NaiveDateTime, expired: bool, seconds_remaining: u64 },
+  /// A page of the connected users.
+  Users(Vec<UserSummary>),
+  /// The round-trip times to Redis and Postgres.
+  Pong {
+    redis_latency: std::time::Duration,
+    postgres_latency: std::time::Duration,
+  },
+  /// Fitbit rejected the user's token, because they revoked access or it is otherwise dead. Only connecting Fitbit
+  /// again with `register` can recover.
+  ReauthorizationRequired,
   Error(errors::FitbitError),
 }
 
-#[derive(Debug)]
+impl Response {
+  /// Reports a stored token expiry.
+  pub fn token_status(expiry: TokenExpiry) -> Self {
+    Response::TokenStatus {
+      expires_at: expiry.expires_at,
+      expired: expiry.expired(),
+      seconds_remaining: u64::try_from(expiry.expires_in).unwrap_or(0),
+    }
+  }
+
+  /// Summarizes daily step counts. The average is 0 when there are no days.
+  pub fn steps_summary(steps: &HashMap<NaiveDate, u32>) -> Self {
+    let days = steps.len();
+    let total: u64 = steps.values().map(|steps| u64::from(*steps)).sum();
+
+    let average = if days == 0 { 0.0 } else { total as f64 / days as f64 };
+
+    let max_day = steps.iter()
+      .max_by(|(a_date, a_steps), (b_date, b_steps)| a_steps.cmp(b_steps).then(b_date.cmp(a_date)));
+
+    Response::StepsSummary {
+      total,
+      average,
+      max: max_day.map(|(_, steps)| *steps).unwrap_or(0),
+      max_date: max_day.map(|(date, _)| *date),
+      days,
+    }
+  }
+
+  /// Finds the runs of consecutive days from `start` to `end`, inclusive, with at least `threshold` steps. A day
+  /// without a count breaks a run just like a day under the threshold, but days without counts at the end of the
+  /// range, such as today before it has synced, are not held against the current streak. The earliest of equally
+  /// long runs is the longest.
+  pub fn streak(steps: &HashMap<NaiveDate, u32>, start: NaiveDate, end: NaiveDate, threshold: u32) -> Self {
+    let last_known = start.iter_days()
+      .take_while(|date| *date <= end)
+      .filter(|date| steps.contains_key(date))
+      .last();
+
+    let mut run: Option<(NaiveDate, u32)> = None;
+    let mut longest: Option<(NaiveDate, NaiveDate, u32)> = None;
+
+    for date in start.iter_days().take_while(|date| Some(*date) <= last_known) {
+      if steps.get(&date).is_none_or(|steps| *steps < threshold) {
+        run = None;
+        continue;
+      }
+
+      let (run_start, length) = run.map_or((date, 1), |(run_start, length)| (run_start, length + 1));
+
+      run = Some((run_start, length));
+
+      if longest.is_none_or(|(_, _, longest)| length > longest) {
+        longest = Some((run_start, date, length));
+      }
+    }
+
+    Response::Streak {
+      current: run.map_or(0, |(_, length)| length),
+      longest: longest.map_or(0, |(_, _, length)| length),
+      longest_start: longest.map(|(start, _, _)| start),
+      longest_end: longest.map(|(_, end, _)| end),
+    }
+  }
+}
+
+/// How a command ended, as recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandOutcome {
+  Success,
+  /// The command succeeded with stale data.
+  Stale,
+  /// The command succeeded with the data fetched before the rate limit was reached.
+  Partial,
+  /// The command failed, with the error code it was answered with.
+  Error(&'static str),
+}
+
+impl CommandOutcome {
+  pub fn of(response: &Response) -> Self {
+    match response {
+      Response::StaleSteps(..) => CommandOutcome::Stale,
+      Response::PartialSteps(..) => CommandOutcome::Partial,
+      Response::ReauthorizationRequired => CommandOutcome::Error("reauthorization_required"),
+      Response::Error(e) => CommandOutcome::Error(e.name()),
+      _ => CommandOutcome::Success,
+    }
+  }
+
+  /// `ok`, `stale`, `partial` or `error`.
+  pub fn status(&self) -> &'static str {
+    match self {
+      CommandOutcome::Success => "ok",
+      CommandOutcome::Stale => "stale",
+      CommandOutcome::Partial => "partial",
+      CommandOutcome::Error(_) => "error",
+    }
+  }
+
+  pub fn error(&self) -> Option<&'static str> {
+    match self {
+      CommandOutcome::Error(code) => Some(code),
+      _ => None,
+    }
+  }
+}
+
+/// A response decoded from the Redis list, as sent by `encode_response`.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+  Success(String),
+  /// A successful reply whose data may be incomplete.
+  Stale(String),
+  /// A successful reply whose data stops where the rate limit was reached.
+  Partial(String),
+  Error(String),
+}
+
+#[derive(Debug, Clone)]
 pub struct DatabaseUser {
   pub id: String,
   pub fitbit_user_id: String,
@@ -105,3 +1326,27 @@ pub struct DatabaseUser {
   pub fitbit_refresh_token: String,
   pub fitbit_token_expires_at: NaiveDateTime,
 }
+
+/// A connected user, without their tokens.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct UserSummary {
+  pub id: String,
+  pub fitbit_user_id: String,
+  /// When the user's access token expires, in UTC.
+  pub token_expires_at: NaiveDateTime,
+}
+
+/// When a user's access token expires, as stored in the database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenExpiry {
+  /// In UTC.
+  pub expires_at: NaiveDateTime,
+  /// The whole seconds from when it was read until the token expires, negative once it has.
+  pub expires_in: i64,
+}
+
+impl TokenExpiry {
+  pub fn expired(&self) -> bool {
+    self.expires_in < 0
+  }
+}
@@ -1,56 +1,231 @@
 use chrono::{NaiveDate, NaiveDateTime};
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use crate::models::{Command, Range, Response};
+use crate::models::{ActiveMinutes, Aggregation, BackfillPayload, BatchPayload, CardioScore, Command, Detail, Device, DistanceUnit, HeartRateZones, DistancePayload, IntradayPayload, ListUsersPayload, Profile, Protocol, RangePayload, RegisterPayload, RelativeDate, RequestedRange, StepGoalPayload, StreakPayload, SyncRecentPayload, Range, Reply, RequestEnvelope, Resource, Response, ResponseFormat, Source, Spo2Summary, StalePolicy, TimedCommand, UserPayload, UserSummary, UserTimezone, WeightLog, WeightPayload, WeightUnit};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
 use crate::errors::FitbitError;
-use ulid;
-use log::info;
+use tracing::info;
 
-/// Parses a vector of tuples containing the date and the number of steps for that date into a vector of tuples containing the date and the number of steps for that date.
+/// The most days a date range command may span, about five years. Fitbit's own limits are far shorter, so anything
+/// longer is a mistake by the caller.
+pub(crate) const MAX_RANGE_DAYS: i64 = 5 * 366;
+
+/// Builds the range of a date range command, checking that it is in order and not absurdly long, so a bad range fails
+/// before any cache or database work. Ranges are checked again against the user's today once they are executed.
+/// 
+/// # Arguments
+/// 
+/// * `command` - The name of the command being decoded, used in error messages.
+/// * `start` - The first day of the range.
+/// * `end` - The last day of the range, inclusive.
+/// 
+/// # Returns
+/// 
+/// * `Ok(range)` - If the range is valid.
+/// * `Err(FitbitError::DateOutOfRange(_))` - If the range ends before it starts or spans more than `MAX_RANGE_DAYS`.
+pub(crate) fn checked_range(command: &str, start: NaiveDate, end: NaiveDate) -> Result<Range, FitbitError> {
+  if start > end {
+    return Err(FitbitError::DateOutOfRange(format!("While decoding {} command, start {} is after end {}", command, start, end)));
+  }
+
+  let days = (end - start).num_days() + 1;
+
+  if days > MAX_RANGE_DAYS {
+    return Err(FitbitError::DateOutOfRange(format!("While decoding {} command, range spans {} days, more than the {} allowed", command, days, MAX_RANGE_DAYS)));
+  }
+
+  Ok(Range { start, end })
+}
+
+/// The most users `list_users` may return at once, so a reply stays small however many users are connected.
+pub(crate) const MAX_LIST_USERS_LIMIT: u32 = 500;
+
+/// Builds a `list_users` command, checking that the page size is between 1 and `MAX_LIST_USERS_LIMIT`.
+/// 
+/// # Returns
+/// 
+/// * `Ok(command)` - If the page size is valid.
+/// * `Err(FitbitError::InvalidMessage(_))` - If it is 0 or too large.
+pub(crate) fn list_users(limit: u32, offset: u32) -> Result<Command, FitbitError> {
+  if limit == 0 || limit > MAX_LIST_USERS_LIMIT {
+    return Err(FitbitError::InvalidMessage(format!("While decoding list_users command, expected a limit from 1 to {}, got {}", MAX_LIST_USERS_LIMIT, limit)));
+  }
+
+  Ok(Command::ListUsers { limit, offset })
+}
+
+/// The most days `sync_recent` may fetch, which Fitbit always serves in a single request.
+pub(crate) const MAX_SYNC_RECENT_DAYS: u16 = 35;
+
+/// Builds a `sync_recent` command, checking that the number of days is between 1 and `MAX_SYNC_RECENT_DAYS`.
+/// 
+/// # Returns
+/// 
+/// * `Ok(command)` - If the number of days is valid.
+/// * `Err(FitbitError::InvalidMessage(_))` - If it is 0 or too large.
+pub(crate) fn sync_recent(user_id: String, days: u16) -> Result<Command, FitbitError> {
+  if days == 0 || days > MAX_SYNC_RECENT_DAYS {
+    return Err(FitbitError::InvalidMessage(format!("While decoding sync_recent command, expected from 1 to {} days, got {}", MAX_SYNC_RECENT_DAYS, days)));
+  }
+
+  Ok(Command::SyncRecent(user_id, days))
+}
+
+/// Finds the ranges of days between `start` and `end`, inclusive, that have no value.
 /// 
 /// # Arguments
 /// 
-/// * `steps` - A vector of tuples containing the date and the number of steps for that date.
+/// * `values` - The values that are present, keyed by date.
+/// * `start` - The start date of the range.
+/// * `end` - The end date of the range.
 /// 
 /// # Returns
 /// 
-/// * `Vec<(NaiveDate, u32)>` - A vector of tuples containing the date and the number of steps for that date.
-pub fn parse_steps(steps: Vec<(u32, i64)>) -> Vec<(NaiveDate, u32)> {
-  steps.into_iter().map(|(steps, date)| (NaiveDateTime::from_timestamp_opt(date, 0).unwrap().date(), steps)).collect()
+/// * `Vec<Range>` - The missing ranges, inclusive and sorted by start date. Empty if every day has a value.
+pub fn missing_ranges<V>(values: &HashMap<NaiveDate, V>, start: NaiveDate, end: NaiveDate) -> Vec<Range> {
+  let missing = start.iter_days()
+    .take_while(|date| *date <= end)
+    .filter(|date| !values.contains_key(date))
+    .collect();
+
+  coalesce_dates(missing)
 }
 
-/// Finds the longest range of consecutive dates in a vector of tuples containing the date and the number of steps for that date.
+/// Groups ranges into as few request windows as possible. Ranges are merged while the merged window spans at most
+/// `MAX_WINDOW_DAYS` days, so scattered gaps can be filled by one request instead of one request each.
 /// 
 /// # Arguments
 /// 
-/// * `start_date` - The start date of the range.
-/// * `steps` - A vector of tuples containing the date and the number of steps for that date.
+/// * `ranges` - The ranges to group, in any order. They may overlap.
 /// 
 /// # Returns
 /// 
-/// * `HashMap<NaiveDate, u32>` - A hashmap containing the date and the number of steps for that date.
-pub fn longest_range(start_date: NaiveDate, steps: Vec<(NaiveDate, u32)>) -> HashMap<NaiveDate, u32> {
-  let mut range: HashMap<NaiveDate, u32> = HashMap::new();
-  let mut current_date = start_date;
+/// * `Vec<Range>` - Non-overlapping windows of at most `MAX_WINDOW_DAYS` days that cover every range, in order.
+pub fn batch_ranges(mut ranges: Vec<Range>) -> Vec<Range> {
+  ranges.sort_by_key(|range| range.start);
 
-  for (date, steps) in steps {
-    if date == current_date {
-      range.insert(date, steps);
-    } else {
-      break;
+  let mut batches: Vec<Range> = Vec::new();
+
+  for range in ranges {
+    match batches.last_mut() {
+      Some(batch) if (range.end - batch.start).num_days() < MAX_WINDOW_DAYS => batch.end = std::cmp::max(batch.end, range.end),
+      _ => batches.push(range),
+    }
+  }
+
+  batches.iter().flat_map(chunk_range).collect()
+}
+
+/// Coalesces dates into ranges of consecutive days.
+/// 
+/// # Arguments
+/// 
+/// * `dates` - The dates to coalesce, in any order. Duplicates are ignored.
+/// 
+/// # Returns
+/// 
+/// * `Vec<Range>` - The inclusive ranges, sorted by start date.
+pub fn coalesce_dates(mut dates: Vec<NaiveDate>) -> Vec<Range> {
+  dates.sort();
+  dates.dedup();
+
+  let mut ranges: Vec<Range> = Vec::new();
+
+  for date in dates {
+    match ranges.last_mut() {
+      Some(range) if range.end.succ_opt() == Some(date) => range.end = date,
+      _ => ranges.push(Range { start: date, end: date }),
     }
+  }
+
+  ranges
+}
+
+/// Merges fairly and very active minutes into each day's active minutes. Only days present in both series are kept,
+/// so a day is never reported with half of its minutes.
+/// 
+/// # Arguments
+/// 
+/// * `fairly` - Fairly active minutes, keyed by date.
+/// * `very` - Very active minutes, keyed by date.
+/// 
+/// # Returns
+/// 
+/// * `HashMap<NaiveDate, ActiveMinutes>` - A hashmap of dates and their active minutes.
+pub fn merge_active_minutes(fairly: HashMap<NaiveDate, u32>, very: HashMap<NaiveDate, u32>) -> HashMap<NaiveDate, ActiveMinutes> {
+  fairly.into_iter()
+    .filter_map(|(date, fairly)| very.get(&date).map(|very| (date, ActiveMinutes::new(fairly, *very))))
+    .collect()
+}
+
+/// The most days Fitbit will return in a single time series request.
+pub const MAX_WINDOW_DAYS: i64 = 364;
+
+/// Splits an inclusive date range into consecutive, non-overlapping inclusive windows of at most `MAX_WINDOW_DAYS` days.
+/// 
+/// # Arguments
+/// 
+/// * `range` - The range to split.
+/// 
+/// # Returns
+/// 
+/// * `Vec<Range>` - The windows, in order. Empty if the range is inverted.
+pub fn chunk_range(range: &Range) -> Vec<Range> {
+  chunk_range_by(range, MAX_WINDOW_DAYS)
+}
+
+/// Splits a range into consecutive windows of at most `days` days, for endpoints with a smaller limit than
+/// `MAX_WINDOW_DAYS`.
+pub fn chunk_range_by(range: &Range, days: i64) -> Vec<Range> {
+  let mut windows: Vec<Range> = Vec::new();
+  let mut cursor = range.start;
+
+  while cursor <= range.end {
+    let window_end = std::cmp::min(cursor + chrono::Duration::days(days - 1), range.end);
 
-    current_date = current_date.succ_opt().unwrap();
+    windows.push(Range { start: cursor, end: window_end });
+
+    let Some(next) = window_end.succ_opt() else {
+      break;
+    };
+
+    cursor = next;
   }
 
-  range    
+  windows
+}
+
+/// Decodes a message like `decode_message`, keeping the command's TTL as its deadline.
+pub fn decode_timed_message(message: String) -> Option<(ulid::Ulid, Result<TimedCommand, FitbitError>)> {
+  let deadline = message_ttl(&message);
+  let (coordination_id, command) = decode_message(message)?;
+
+  let command = command.and_then(|command| match deadline {
+    Some(deadline) => Ok(TimedCommand { command, deadline }),
+    None => Err(FitbitError::InvalidMessage("While decoding command, could not read TTL".to_string())),
+  });
+
+  Some((coordination_id, command))
+}
+
+/// Reads the TTL of a message in either framing, or `None` if it is missing or not a UNIX timestamp.
+fn message_ttl(message: &str) -> Option<NaiveDateTime> {
+  let ttl = match Protocol::detect(message) {
+    Protocol::Json | Protocol::MsgPack => serde_json::from_str::<RequestEnvelope>(message).ok()?.ttl,
+    Protocol::Legacy => message.rsplit_once(':')?.1.parse::<i64>().ok()?,
+  };
+
+  NaiveDateTime::from_timestamp_opt(ttl, 0)
 }
 
 /// Decodes a message from the Redis list into a command. The message is a vector of tuples containing the field and the value of the field.
+/// Messages starting with `{` are decoded as a JSON `RequestEnvelope`; anything else uses the legacy framing.
 /// 
 /// # Arguments
 /// 
-/// * `message` - The message to decode, colon-separated, as such: `coordination_id:command:payload:TTL`.
+/// * `message` - The message to decode, colon-separated, as such: `coordination_id:command:payload:TTL`. The payload may itself contain colons.
 ///   * `coordination_id` - A ULID used to coordinate the command.
 ///   * `command` - The command to execute.
 ///   * `payload` - The payload of the command, colon-separated.
@@ -62,7 +237,19 @@ pub fn longest_range(start_date: NaiveDate, steps: Vec<(NaiveDate, u32)>) -> Has
 /// * `Ok((coordination_id, Err(e)))` - If the message was decoded successfully, but the command could not be parsed.
 /// * `Err(e)` - If the message could not be decoded.
 pub fn decode_message(message: String) -> Option<(ulid::Ulid, Result<Command, FitbitError>)> {
-  let message_vector: Vec<&str> = message.split(":").collect();
+  if Protocol::detect(&message) != Protocol::Legacy {
+    return decode_json_message(&message);
+  }
+
+  // The coordination ID and command never contain colons and the TTL is always last, so only
+  // those are split off, leaving any colons inside the payload intact.
+  let message_vector: Vec<&str> = match message.splitn(3, ':').collect::<Vec<&str>>()[..] {
+    [coordination_id, command, rest] => match rest.rsplit_once(':') {
+      Some((payload, ttl)) => vec![coordination_id, command, payload, ttl],
+      None => vec![coordination_id, command, rest],
+    },
+    ref fields => fields.to_vec(),
+  };
 
   info!("Split message: {:?}", message_vector);
 
@@ -105,41 +292,113 @@ pub fn decode_message(message: String) -> Option<(ulid::Ulid, Result<Command, Fi
 
   match command {
     "get_steps" => {
-      let parts: Vec<&str> = payload.split(",").collect();
+      let (user_id, range, format, stale_policy, aggregation) = match decode_steps_payload(payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetSteps(user_id, range, format, stale_policy, aggregation);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_steps_dense" => {
+      let (user_id, range, format) = match decode_range_payload("get_steps_dense", payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetStepsDense(user_id, range, format);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_steps_batch" => {
+      let parts: Vec<&str> = payload.split(',').collect();
 
       if parts.len() != 3 {
-        let message = format!("While decoding get_steps command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        let message = format!("While decoding get_steps_batch command, expected user_id;user_id;...,start_timestamp,end_timestamp, got {}", payload);
         return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
       }
 
-      let user_id = parts[0].to_string();
+      let (user_ids, range) = match decode_range_fields("get_steps_batch", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
 
-      let Ok(start_timestamp) = parts[1].parse::<i64>() else {
-        let message = format!("While decoding get_steps command, could not parse start_timestamp to integer. Expected UNIX timestamp, got {}", parts[1]);
-        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      let user_ids = match batch_user_ids(user_ids.split(';').map(str::to_string).collect()) {
+        Ok(user_ids) => user_ids,
+        Err(e) => return Some((coordination_id, Err(e))),
       };
 
-      let Some(start) = NaiveDateTime::from_timestamp_opt(start_timestamp, 0) else {
-        let message = format!("While decoding get_steps command, could not parse start_timestamp to NaiveDateTime. Expected UNIX timestamp, got {}", parts[1]);
-        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      let command = Command::GetStepsBatch(user_ids, range);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_heart_rate" => {
+      let (user_id, range, format) = match decode_range_payload("get_heart_rate", payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetHeartRate(user_id, range, format);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_calories" => {
+      let (user_id, range, format) = match decode_range_payload("get_calories", payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetCalories(user_id, range, format);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_distance" => {
+      let (user_id, range, unit) = match decode_distance_payload(payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
       };
 
-      let Ok(end_timestamp) = parts[2].parse::<i64>() else {
-        let message = format!("While decoding get_steps command, could not parse end_timestamp to integer. Expected UNIX timestamp, got {}", parts[2]);
+      let command = Command::GetDistance(user_id, range, unit);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_weight" => {
+      let (user_id, range, unit) = match decode_weight_payload(payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetWeight(user_id, range, unit);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_intraday_steps" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding get_intraday_steps command, expected user_id,timestamp,detail, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let Ok(timestamp) = parts[1].parse::<i64>() else {
+        let message = format!("While decoding get_intraday_steps command, could not parse timestamp to integer. Expected UNIX timestamp, got {}", parts[1]);
         return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
       };
 
-      let Some(end) = NaiveDateTime::from_timestamp_opt(end_timestamp, 0) else {
-        let message = format!("While decoding get_steps command, could not parse end_timestamp to NaiveDateTime. Expected UNIX timestamp, got {}", parts[2]);
+      let Some(date) = NaiveDateTime::from_timestamp_opt(timestamp, 0) else {
+        let message = format!("While decoding get_intraday_steps command, could not parse timestamp to NaiveDateTime. Expected UNIX timestamp, got {}", parts[1]);
         return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
       };
 
-      let range = Range {
-        start: start.date(),
-        end: end.date(),
+      let detail = match decode_detail("get_intraday_steps", Resource::Steps, parts[2]) {
+        Ok(detail) => detail,
+        Err(e) => return Some((coordination_id, Err(e))),
       };
 
-      let command = Command::GetSteps(user_id, range);
+      let command = Command::GetIntradaySteps(user_id, date.date(), detail);
 
       Some((coordination_id, Ok(command)))
     },
@@ -157,80 +416,2661 @@ pub fn decode_message(message: String) -> Option<(ulid::Ulid, Result<Command, Fi
 
       Some((coordination_id, Ok(command)))
     },
-    _ => Some((coordination_id, Err(FitbitError::InvalidMessage(format!("Unknown command, got {}", command))))),
-  }
-}
+    "register" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
 
-struct ListResponse {
-  indication: String,
-  content: String,
-}
+      if parts.len() != 2 {
+        let message = format!("While decoding register command, expected user_id,code, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
 
-/// Encodes a response to be sent to the Redis list.
-/// 
-/// # Arguments
-/// 
-/// * `response` - The response to encode.
-/// 
-/// # Returns
-/// 
-/// * `Vec<String>` - The encoded response, as a string.
-///     - The first element is the indication, which is either `0` for no error or `1` for an error.
-///     - The second element is the content, which is either the response or the error message.
-pub fn encode_response(response: Response) -> String {
-  info!("Encoding response: {:?}", response);
-  let response: ListResponse = match response {
-    Response::Steps(steps) => {
-      // Order the steps by date and convert to a vector of only the step count
-      let mut steps = steps.into_iter().map(|(date, step_count)| {
-        let Ok(step_count) = i32::try_from(step_count) else {
-          return (date, 0);
-        };
+      let user_id = parts[0].to_string();
+      let code = parts[1].to_string();
 
-        (date, step_count)
-      }).collect::<Vec<(NaiveDate, i32)>>();
+      let command = Command::RegisterUser(user_id, code);
 
-      steps.sort_by(|a, b| a.0.cmp(&b.0));
+      Some((coordination_id, Ok(command)))
+    },
+    "revoke" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
 
-      ListResponse {
-        indication: String::from("0"),
-        content: steps.into_iter().map(|(_, step_count)| format!("{step_count}")).collect::<Vec<String>>().join(","),
+      if parts.len() != 1 {
+        let message = format!("While decoding revoke command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
       }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::RevokeToken(user_id);
+
+      Some((coordination_id, Ok(command)))
     },
-    Response::Refreshed => ListResponse {
-      indication: String::from("0"),
-      content: String::from("refreshed"),
+    "get_steps_summary" => {
+      let (user_id, range, _) = match decode_range_payload("get_steps_summary", payload) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetStepsSummary(user_id, range);
+
+      Some((coordination_id, Ok(command)))
     },
-    Response::Error(error) => ListResponse {
-      indication: String::from("1"),
-      content: error.to_string(),
+    "get_active_minutes" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding get_active_minutes command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (user_id, range) = match decode_range_fields("get_active_minutes", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetActiveMinutes(user_id, range);
+
+      Some((coordination_id, Ok(command)))
     },
-  };
+    "get_streak" => {
+      let parts: Vec<&str> = payload.split(',').collect();
 
-  // Escape the content
-  let content = response.content.replace("\\", "\\\\")
-    .replace(",", "\\,")
-    .replace(":", "\\:")
-    .replace("\n", "\\n");
+      if parts.len() != 4 {
+        let message = format!("While decoding get_streak command, expected user_id,start_timestamp,end_timestamp,threshold, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
 
-  format!("{}:{}", response.indication, content)
-}
+      let (user_id, range) = match decode_range_fields("get_streak", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
 
-/// Converts from i64 to T, clamping to the maximum value of T if the value is too large.
-/// 
-/// # Arguments
-/// 
-/// * `value` - The value to convert.
-/// 
-/// # Returns
-/// 
-/// * `T` - The converted value.
-pub fn safe_convert<T: TryFrom<i64> + From<u16>>(value: i64) -> T {
-  if value < 0 {
-    T::from(0)
-  } else if let Ok(v) = T::try_from(value) {
-      v
-  } else {
-    T::from(u16::max_value())
-  }
-}
\ No newline at end of file
+      let Ok(threshold) = parts[3].parse::<u32>() else {
+        let message = format!("While decoding get_streak command, could not parse threshold to integer, got {}", parts[3]);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      };
+
+      let command = Command::GetStreak(user_id, range, threshold);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_spo2" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding get_spo2 command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (user_id, range) = match decode_range_fields("get_spo2", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetSpo2(user_id, range);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_heart_rate_zones" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding get_heart_rate_zones command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (user_id, range) = match decode_range_fields("get_heart_rate_zones", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetHeartRateZones(user_id, range);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_cardio_score" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding get_cardio_score command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (user_id, range) = match decode_range_fields("get_cardio_score", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetCardioScore(user_id, range);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "refresh_steps" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding refresh_steps command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (user_id, range) = match decode_range_fields("refresh_steps", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::RefreshSteps(user_id, range);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "sync_recent" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 2 {
+        let message = format!("While decoding sync_recent command, expected user_id,days, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let Ok(days) = parts[1].parse::<u16>() else {
+        let message = format!("While decoding sync_recent command, expected a whole number of days, got {}", parts[1]);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      };
+
+      Some((coordination_id, sync_recent(user_id, days)))
+    },
+    "get_floors" => {
+      let parts: Vec<&str> = payload.split(',').collect();
+
+      if parts.len() != 3 {
+        let message = format!("While decoding get_floors command, expected user_id,start_timestamp,end_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (user_id, range) = match decode_range_fields("get_floors", &parts) {
+        Ok(decoded) => decoded,
+        Err(e) => return Some((coordination_id, Err(e))),
+      };
+
+      let command = Command::GetFloors(user_id, range);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_coverage" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding get_coverage command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::GetCoverage(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "clear_cache" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding clear_cache command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::ClearCache(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "backfill" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 2 {
+        let message = format!("While decoding backfill command, expected user_id,start_timestamp, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let Ok(start_timestamp) = parts[1].parse::<i64>() else {
+        let message = format!("While decoding backfill command, could not parse start_timestamp to integer. Expected UNIX timestamp, got {}", parts[1]);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      };
+
+      let Some(start) = NaiveDateTime::from_timestamp_opt(start_timestamp, 0) else {
+        let message = format!("While decoding backfill command, could not parse start_timestamp to NaiveDateTime. Expected UNIX timestamp, got {}", parts[1]);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      };
+
+      let command = Command::Backfill(user_id, start.date());
+
+      Some((coordination_id, Ok(command)))
+    },
+    "user_exists" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding user_exists command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::UserExists(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "ping" => {
+      if !payload.is_empty() {
+        let message = format!("While decoding ping command, expected an empty payload, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      Some((coordination_id, Ok(Command::Ping)))
+    },
+    "sync_timezone" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding sync_timezone command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::SyncTimezone(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_goals" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding get_goals command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::GetGoals(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_devices" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding get_devices command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::GetDevices(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_rate_limit" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding get_rate_limit command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::GetRateLimit(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_token_status" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding get_token_status command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::GetTokenStatus(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "list_users" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 2 {
+        let message = format!("While decoding list_users command, expected limit,offset, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let (Ok(limit), Ok(offset)) = (parts[0].parse::<u32>(), parts[1].parse::<u32>()) else {
+        let message = format!("While decoding list_users command, expected whole numbers, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      };
+
+      Some((coordination_id, list_users(limit, offset)))
+    },
+    "set_step_goal" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 2 {
+        let message = format!("While decoding set_step_goal command, expected user_id,goal, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let Ok(goal) = parts[1].parse::<u32>() else {
+        let message = format!("While decoding set_step_goal command, expected a whole number of steps, got {}", parts[1]);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      };
+
+      let command = Command::SetStepGoal(user_id, goal);
+
+      Some((coordination_id, Ok(command)))
+    },
+    "get_profile" => {
+      let parts = payload.split(',').collect::<Vec<&str>>();
+
+      if parts.len() != 1 {
+        let message = format!("While decoding get_profile command, expected user_id, got {}", payload);
+        return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+      }
+
+      let user_id = parts[0].to_string();
+
+      let command = Command::GetProfile(user_id);
+
+      Some((coordination_id, Ok(command)))
+    },
+    _ => Some((coordination_id, Err(FitbitError::InvalidMessage(format!("Unknown command, got {}", command))))),
+  }
+}
+
+/// Decodes a JSON `RequestEnvelope` into a command, with the payload decoded according to the command.
+/// 
+/// # Arguments
+/// 
+/// * `message` - The JSON envelope, as such: `{"coordination_id": ..., "command": ..., "payload": {...}, "ttl": ...}`.
+/// 
+/// # Returns
+/// 
+/// The same as `decode_message`.
+fn decode_json_message(message: &str) -> Option<(ulid::Ulid, Result<Command, FitbitError>)> {
+  let envelope: RequestEnvelope = match serde_json::from_str(message) {
+    Ok(envelope) => envelope,
+    Err(e) => {
+      info!("Couldn't decode JSON envelope: {}", e);
+      return None;
+    },
+  };
+
+  let Ok(coordination_id) = ulid::Ulid::from_string(&envelope.coordination_id) else {
+    info!("Couldn't decode into ULID");
+    return None;
+  };
+
+  let Some(ttl) = NaiveDateTime::from_timestamp_opt(envelope.ttl, 0) else {
+    let message = format!("While decoding command, could not parse TTL to NaiveDateTime. Expected UNIX timestamp, got {}", envelope.ttl);
+    return Some((coordination_id, Err(FitbitError::InvalidMessage(message))));
+  };
+
+  if (ttl - chrono::Utc::now().naive_utc()).num_seconds() < 0 {
+    return None;
+  }
+
+  let command = envelope.command.as_str();
+  let payload = envelope.payload;
+
+  let command = match command {
+    "get_steps" | "get_steps_dense" | "get_heart_rate" | "get_heart_rate_zones" | "get_calories" | "get_active_minutes" | "get_spo2" | "get_cardio_score" | "get_floors" | "refresh_steps" | "get_steps_summary" => {
+      decode_json_payload::<RangePayload>(command, payload).and_then(|payload| {
+        let range = checked_range(command, timestamp_date(command, "start", payload.start)?, timestamp_date(command, "end", payload.end)?)?;
+
+        let stale_policy = stale_policy(command, payload.allow_stale, payload.allow_partial)?;
+
+        if payload.allow_stale && command != "get_steps" {
+          return Err(FitbitError::InvalidMessage(format!("While decoding {} command, allow_stale is only supported by get_steps", command)));
+        }
+
+        if payload.allow_partial && command != "get_steps" {
+          return Err(FitbitError::InvalidMessage(format!("While decoding {} command, allow_partial is only supported by get_steps", command)));
+        }
+
+        if payload.aggregation != Aggregation::Daily && command != "get_steps" {
+          return Err(FitbitError::InvalidMessage(format!("While decoding {} command, aggregation is only supported by get_steps", command)));
+        }
+
+        if payload.with_source {
+          if command != "get_steps" {
+            return Err(FitbitError::InvalidMessage(format!("While decoding {} command, with_source is only supported by get_steps", command)));
+          }
+
+          check_with_source(command, stale_policy, payload.aggregation)?;
+
+          return Ok(Command::GetStepsWithSource(payload.user_id, range));
+        }
+
+        Ok(match command {
+          "get_steps" => Command::GetSteps(payload.user_id, RequestedRange::Dates(range), payload.format, stale_policy, payload.aggregation),
+          "get_steps_dense" => Command::GetStepsDense(payload.user_id, range, payload.format),
+          "get_heart_rate" => Command::GetHeartRate(payload.user_id, range, payload.format),
+          "get_calories" => Command::GetCalories(payload.user_id, range, payload.format),
+          "get_active_minutes" => Command::GetActiveMinutes(payload.user_id, range),
+          "get_spo2" => Command::GetSpo2(payload.user_id, range),
+          "get_heart_rate_zones" => Command::GetHeartRateZones(payload.user_id, range),
+          "get_cardio_score" => Command::GetCardioScore(payload.user_id, range),
+          "get_floors" => Command::GetFloors(payload.user_id, range),
+          "refresh_steps" => Command::RefreshSteps(payload.user_id, range),
+          _ => Command::GetStepsSummary(payload.user_id, range),
+        })
+      })
+    },
+    "get_distance" => decode_json_payload::<DistancePayload>(command, payload).and_then(|payload| {
+      let range = checked_range(command, timestamp_date(command, "start", payload.start)?, timestamp_date(command, "end", payload.end)?)?;
+
+      Ok(Command::GetDistance(payload.user_id, range, payload.unit))
+    }),
+    "get_weight" => decode_json_payload::<WeightPayload>(command, payload).and_then(|payload| {
+      let range = checked_range(command, timestamp_date(command, "start", payload.start)?, timestamp_date(command, "end", payload.end)?)?;
+
+      Ok(Command::GetWeight(payload.user_id, range, payload.unit))
+    }),
+    "get_streak" => decode_json_payload::<StreakPayload>(command, payload).and_then(|payload| {
+      let range = checked_range(command, timestamp_date(command, "start", payload.start)?, timestamp_date(command, "end", payload.end)?)?;
+
+      Ok(Command::GetStreak(payload.user_id, range, payload.threshold))
+    }),
+    "get_steps_batch" => decode_json_payload::<BatchPayload>(command, payload).and_then(|payload| {
+      let range = checked_range(command, timestamp_date(command, "start", payload.start)?, timestamp_date(command, "end", payload.end)?)?;
+
+      Ok(Command::GetStepsBatch(batch_user_ids(payload.user_ids)?, range))
+    }),
+    "get_intraday_steps" => decode_json_payload::<IntradayPayload>(command, payload).and_then(|payload| {
+      let detail = decode_detail(command, Resource::Steps, &payload.detail)?;
+
+      Ok(Command::GetIntradaySteps(payload.user_id, timestamp_date(command, "date", payload.date)?, detail))
+    }),
+    "refresh" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::RefreshToken(payload.user_id)),
+    "register" => decode_json_payload::<RegisterPayload>(command, payload).map(|payload| Command::RegisterUser(payload.user_id, payload.code)),
+    "revoke" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::RevokeToken(payload.user_id)),
+    "get_coverage" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::GetCoverage(payload.user_id)),
+    "clear_cache" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::ClearCache(payload.user_id)),
+    "user_exists" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::UserExists(payload.user_id)),
+    "sync_timezone" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::SyncTimezone(payload.user_id)),
+    "get_profile" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::GetProfile(payload.user_id)),
+    "get_goals" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::GetGoals(payload.user_id)),
+    "get_devices" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::GetDevices(payload.user_id)),
+    "get_rate_limit" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::GetRateLimit(payload.user_id)),
+    "get_token_status" => decode_json_payload::<UserPayload>(command, payload).map(|payload| Command::GetTokenStatus(payload.user_id)),
+    "set_step_goal" => decode_json_payload::<StepGoalPayload>(command, payload).map(|payload| Command::SetStepGoal(payload.user_id, payload.goal)),
+    "sync_recent" => decode_json_payload::<SyncRecentPayload>(command, payload).and_then(|payload| sync_recent(payload.user_id, payload.days)),
+    "list_users" => decode_json_payload::<ListUsersPayload>(command, payload).and_then(|payload| list_users(payload.limit, payload.offset)),
+    "ping" => Ok(Command::Ping),
+    "backfill" => decode_json_payload::<BackfillPayload>(command, payload).and_then(|payload| {
+      Ok(Command::Backfill(payload.user_id, timestamp_date(command, "start", payload.start)?))
+    }),
+    _ => Err(FitbitError::InvalidMessage(format!("Unknown command, got {}", command))),
+  };
+
+  Some((coordination_id, command))
+}
+
+/// Decodes the payload of a JSON command into the type that command expects.
+fn decode_json_payload<T: DeserializeOwned>(command: &str, payload: Value) -> Result<T, FitbitError> {
+  serde_json::from_value(payload).map_err(|e| {
+    FitbitError::InvalidMessage(format!("While decoding {} command, invalid payload: {}", command, e))
+  })
+}
+
+/// Converts a UNIX timestamp from a JSON payload into the date it falls on.
+pub(crate) fn timestamp_date(command: &str, field: &str, timestamp: i64) -> Result<NaiveDate, FitbitError> {
+  match NaiveDateTime::from_timestamp_opt(timestamp, 0) {
+    Some(date) => Ok(date.date()),
+    None => {
+      let message = format!("While decoding {} command, could not parse {} to NaiveDateTime. Expected UNIX timestamp, got {}", command, field, timestamp);
+      Err(FitbitError::InvalidMessage(message))
+    },
+  }
+}
+
+/// Converts a stored UNIX timestamp into a `NaiveDateTime`, so a corrupted or out of range value is an error rather
+/// than a panic.
+pub(crate) fn stored_datetime(timestamp: i64) -> Result<NaiveDateTime, FitbitError> {
+  NaiveDateTime::from_timestamp_opt(timestamp, 0).ok_or_else(|| {
+    FitbitError::ParsingError(format!("Could not parse stored timestamp to NaiveDateTime, got {}", timestamp))
+  })
+}
+
+/// The stale policy of a JSON or HTTP `get_steps` request, from its `allow_stale` and `allow_partial` flags. At most
+/// one of them may be set, since stale data already includes everything a partial answer would.
+pub(crate) fn stale_policy(command: &str, allow_stale: bool, allow_partial: bool) -> Result<StalePolicy, FitbitError> {
+  match (allow_stale, allow_partial) {
+    (true, true) => Err(FitbitError::InvalidMessage(format!("While decoding {} command, allow_stale and allow_partial cannot both be set", command))),
+    (true, false) => Ok(StalePolicy::AllowStale),
+    (false, true) => Ok(StalePolicy::AllowPartial),
+    (false, false) => Ok(StalePolicy::Strict),
+  }
+}
+
+/// Checks that a `get_steps` request for the source of each day does not also allow stale or partial data, which is
+/// never tagged with sources, or aggregate the days, which would mix their sources.
+pub(crate) fn check_with_source(command: &str, stale_policy: StalePolicy, aggregation: Aggregation) -> Result<(), FitbitError> {
+  if stale_policy != StalePolicy::Strict {
+    return Err(FitbitError::InvalidMessage(format!("While decoding {} command, with_source cannot be combined with allow_stale or allow_partial", command)));
+  }
+
+  if aggregation != Aggregation::Daily {
+    return Err(FitbitError::InvalidMessage(format!("While decoding {} command, with_source cannot be combined with aggregation", command)));
+  }
+
+  Ok(())
+}
+
+/// Decodes a `get_steps` payload, which is a date range payload optionally followed by a stale policy: `strict` (the
+/// default), `stale` or `partial`, and then an aggregation: `daily` (the default), `weekly` or `monthly`. Each field must be
+/// given for the ones after it to be. The start and end may both be `RelativeDate` keywords instead of timestamps.
+/// 
+/// # Arguments
+/// 
+/// * `payload` - The comma-separated payload.
+/// 
+/// # Returns
+/// 
+/// * `Ok((user_id, range, format, stale_policy, aggregation))` - If the payload was decoded successfully.
+/// * `Err(e)` - If the payload was malformed.
+fn decode_steps_payload(payload: &str) -> Result<(String, RequestedRange, ResponseFormat, StalePolicy, Aggregation), FitbitError> {
+  let (payload, aggregation) = match payload.split(',').count() {
+    6 => {
+      let Some((payload, aggregation)) = payload.rsplit_once(',') else {
+        unreachable!("a payload with six fields contains a comma");
+      };
+
+      let Some(aggregation) = Aggregation::parse(aggregation) else {
+        let message = format!("While decoding get_steps command, expected aggregation of daily, weekly or monthly, got {}", aggregation);
+        return Err(FitbitError::InvalidMessage(message));
+      };
+
+      (payload, aggregation)
+    },
+    _ => (payload, Aggregation::Daily),
+  };
+
+  if payload.split(',').count() != 5 {
+    let (user_id, range, format) = decode_steps_range(payload)?;
+
+    return Ok((user_id, range, format, StalePolicy::Strict, aggregation));
+  }
+
+  let Some((payload, stale_policy)) = payload.rsplit_once(',') else {
+    unreachable!("a payload with five fields contains a comma");
+  };
+
+  let stale_policy = match stale_policy {
+    "strict" => StalePolicy::Strict,
+    "stale" => StalePolicy::AllowStale,
+    "partial" => StalePolicy::AllowPartial,
+    _ => {
+      let message = format!("While decoding get_steps command, expected stale policy of strict, stale or partial, got {}", stale_policy);
+      return Err(FitbitError::InvalidMessage(message));
+    },
+  };
+
+  let (user_id, range, format) = decode_steps_range(payload)?;
+
+  Ok((user_id, range, format, stale_policy, aggregation))
+}
+
+/// Decodes the `user_id,start,end[,format]` part of a `get_steps` payload, where the start and end are either both
+/// UNIX timestamps or both `RelativeDate` keywords, such as `last_7_days,today`.
+/// 
+/// # Arguments
+/// 
+/// * `payload` - The comma-separated payload, without the stale policy or aggregation.
+/// 
+/// # Returns
+/// 
+/// * `Ok((user_id, range, format))` - If the payload was decoded successfully.
+/// * `Err(e)` - If the payload was malformed, mixed a keyword with a timestamp, or named a range that ends before it
+///   starts.
+fn decode_steps_range(payload: &str) -> Result<(String, RequestedRange, ResponseFormat), FitbitError> {
+  let parts: Vec<&str> = payload.split(',').collect();
+
+  let start = parts.get(1).and_then(|start| RelativeDate::parse(start));
+  let end = parts.get(2).and_then(|end| RelativeDate::parse(end));
+
+  let (start, end) = match (start, end) {
+    (None, None) => {
+      let (user_id, range, format) = decode_range_payload("get_steps", payload)?;
+
+      return Ok((user_id, RequestedRange::Dates(range), format));
+    },
+    (Some(start), Some(end)) => (start, end),
+    _ => {
+      let message = format!("While decoding get_steps command, expected start and end to both be timestamps or both be keywords, got {}", payload);
+      return Err(FitbitError::InvalidMessage(message));
+    },
+  };
+
+  if parts.len() != 3 && parts.len() != 4 {
+    let message = format!("While decoding get_steps command, expected user_id,start,end[,format], got {}", payload);
+    return Err(FitbitError::InvalidMessage(message));
+  }
+
+  if start.days_ago() < end.days_ago() {
+    return Err(FitbitError::DateOutOfRange(format!("While decoding get_steps command, start {} is after end {}", parts[1], parts[2])));
+  }
+
+  let format = decode_format("get_steps", parts.get(3).copied())?;
+
+  Ok((parts[0].to_string(), RequestedRange::Relative(start, end), format))
+}
+
+/// Decodes a `user_id,start_timestamp,end_timestamp[,format]` payload shared by the date range commands.
+/// The format is either `counts` (the default) or `dated`.
+/// 
+/// # Arguments
+/// 
+/// * `command` - The name of the command being decoded, used in error messages.
+/// * `payload` - The comma-separated payload.
+/// 
+/// # Returns
+/// 
+/// * `Ok((user_id, range, format))` - If the payload was decoded successfully.
+/// * `Err(e)` - If the payload was malformed.
+fn decode_range_payload(command: &str, payload: &str) -> Result<(String, Range, ResponseFormat), FitbitError> {
+  let parts: Vec<&str> = payload.split(',').collect();
+
+  if parts.len() != 3 && parts.len() != 4 {
+    let message = format!("While decoding {} command, expected user_id,start_timestamp,end_timestamp[,format], got {}", command, payload);
+    return Err(FitbitError::InvalidMessage(message));
+  }
+
+  let (user_id, range) = decode_range_fields(command, &parts)?;
+
+  let format = decode_format(command, parts.get(3).copied())?;
+
+  Ok((user_id, range, format))
+}
+
+/// Decodes the optional format field of a date range payload: `counts` (the default) or `dated`.
+fn decode_format(command: &str, format: Option<&str>) -> Result<ResponseFormat, FitbitError> {
+  match format {
+    None | Some("counts") => Ok(ResponseFormat::Counts),
+    Some("dated") => Ok(ResponseFormat::Dated),
+    Some(format) => {
+      let message = format!("While decoding {} command, expected format of counts or dated, got {}", command, format);
+      Err(FitbitError::InvalidMessage(message))
+    },
+  }
+}
+
+/// Decodes the `user_id,start_timestamp,end_timestamp[,unit]` payload of `get_distance`. The unit is either `km` or
+/// `miles`, and is `None` if left out.
+/// 
+/// # Arguments
+/// 
+/// * `payload` - The comma-separated payload.
+/// 
+/// # Returns
+/// 
+/// * `Ok((user_id, range, unit))` - If the payload was decoded successfully.
+/// * `Err(e)` - If the payload was malformed.
+fn decode_distance_payload(payload: &str) -> Result<(String, Range, Option<DistanceUnit>), FitbitError> {
+  let parts: Vec<&str> = payload.split(',').collect();
+
+  if parts.len() != 3 && parts.len() != 4 {
+    let message = format!("While decoding get_distance command, expected user_id,start_timestamp,end_timestamp[,unit], got {}", payload);
+    return Err(FitbitError::InvalidMessage(message));
+  }
+
+  let (user_id, range) = decode_range_fields("get_distance", &parts)?;
+
+  let unit = match parts.get(3) {
+    None => None,
+    Some(&"km") => Some(DistanceUnit::Kilometers),
+    Some(&"miles") => Some(DistanceUnit::Miles),
+    Some(unit) => {
+      let message = format!("While decoding get_distance command, expected unit of km or miles, got {}", unit);
+      return Err(FitbitError::InvalidMessage(message));
+    },
+  };
+
+  Ok((user_id, range, unit))
+}
+
+/// Decodes the `user_id,start_timestamp,end_timestamp[,unit]` payload of `get_weight`. The unit is `kg`, `lbs` or
+/// `stone`, and is `None` if left out.
+/// 
+/// # Arguments
+/// 
+/// * `payload` - The comma-separated payload.
+/// 
+/// # Returns
+/// 
+/// * `Ok((user_id, range, unit))` - If the payload was decoded successfully.
+/// * `Err(e)` - If the payload was malformed.
+fn decode_weight_payload(payload: &str) -> Result<(String, Range, Option<WeightUnit>), FitbitError> {
+  let parts: Vec<&str> = payload.split(',').collect();
+
+  if parts.len() != 3 && parts.len() != 4 {
+    let message = format!("While decoding get_weight command, expected user_id,start_timestamp,end_timestamp[,unit], got {}", payload);
+    return Err(FitbitError::InvalidMessage(message));
+  }
+
+  let (user_id, range) = decode_range_fields("get_weight", &parts)?;
+
+  let unit = match parts.get(3) {
+    None => None,
+    Some(&"kg") => Some(WeightUnit::Kilograms),
+    Some(&"lbs") => Some(WeightUnit::Pounds),
+    Some(&"stone") => Some(WeightUnit::Stone),
+    Some(unit) => {
+      let message = format!("While decoding get_weight command, expected unit of kg, lbs or stone, got {}", unit);
+      return Err(FitbitError::InvalidMessage(message));
+    },
+  };
+
+  Ok((user_id, range, unit))
+}
+
+/// Checks the user IDs of a `get_steps_batch` command, which must name at least one user and no empty IDs.
+fn batch_user_ids(user_ids: Vec<String>) -> Result<Vec<String>, FitbitError> {
+  if user_ids.is_empty() || user_ids.iter().any(String::is_empty) {
+    return Err(FitbitError::InvalidMessage("While decoding get_steps_batch command, expected at least one user_id and no empty ones".to_string()));
+  }
+
+  Ok(user_ids)
+}
+
+/// Decodes the leading `user_id,start_timestamp,end_timestamp` fields of a date range payload, checking the range.
+fn decode_range_fields(command: &str, parts: &[&str]) -> Result<(String, Range), FitbitError> {
+  let user_id = parts[0].to_string();
+
+  let Ok(start_timestamp) = parts[1].parse::<i64>() else {
+    let message = format!("While decoding {} command, could not parse start_timestamp to integer. Expected UNIX timestamp, got {}", command, parts[1]);
+    return Err(FitbitError::InvalidMessage(message));
+  };
+
+  let Some(start) = NaiveDateTime::from_timestamp_opt(start_timestamp, 0) else {
+    let message = format!("While decoding {} command, could not parse start_timestamp to NaiveDateTime. Expected UNIX timestamp, got {}", command, parts[1]);
+    return Err(FitbitError::InvalidMessage(message));
+  };
+
+  let Ok(end_timestamp) = parts[2].parse::<i64>() else {
+    let message = format!("While decoding {} command, could not parse end_timestamp to integer. Expected UNIX timestamp, got {}", command, parts[2]);
+    return Err(FitbitError::InvalidMessage(message));
+  };
+
+  let Some(end) = NaiveDateTime::from_timestamp_opt(end_timestamp, 0) else {
+    let message = format!("While decoding {} command, could not parse end_timestamp to NaiveDateTime. Expected UNIX timestamp, got {}", command, parts[2]);
+    return Err(FitbitError::InvalidMessage(message));
+  };
+
+  let range = checked_range(command, start.date(), end.date())?;
+
+  Ok((user_id, range))
+}
+
+/// Parses the detail level of an intraday command, which must be one that Fitbit serves `resource` at.
+fn decode_detail(command: &str, resource: Resource, detail: &str) -> Result<Detail, FitbitError> {
+  let Some(parsed) = Detail::parse(detail) else {
+    return Err(FitbitError::InvalidMessage(format!("While decoding {} command, expected detail of 1sec, 1min, 5min or 15min, got {}", command, detail)));
+  };
+
+  if !parsed.supported_by(resource) {
+    return Err(FitbitError::InvalidMessage(format!("While decoding {} command, {} is not served at {} detail", command, resource, detail)));
+  }
+
+  Ok(parsed)
+}
+
+struct ListResponse {
+  indication: String,
+  content: String,
+}
+
+/// Encodes a response to be sent to the Redis list, in the protocol the command was sent with.
+/// 
+/// # Arguments
+/// 
+/// * `response` - The response to encode.
+/// * `protocol` - The protocol of the command being answered.
+/// 
+/// # Returns
+/// 
+/// * `String` - The encoded response.
+///     - For the legacy protocol, `indication:content`, where the indication is `0` for no error, `1` for an error, or
+///       `2` for stale data,
+///       and the content is either the response or the error message.
+///     - For the JSON protocol, `{"status": "ok", "data": ...}` or `{"status": "error", "code": "...", "error": "..."}`,
+///       where the code is the stable `FitbitError::name` of the error. Rate limit errors also carry `retry_after_seconds`
+///       when the reset time is known.
+pub fn encode_response(response: Response, protocol: Protocol) -> String {
+  info!("Encoding response: {:?}", response);
+
+  match protocol {
+    Protocol::Legacy => encode_legacy_response(response),
+    Protocol::Json | Protocol::MsgPack => json_reply(response).to_string(),
+  }
+}
+
+/// Encodes a reply to be sent to the Redis list. Replies to the MessagePack protocol carry the same object as JSON
+/// replies, encoded with field names as MessagePack; every other reply is the UTF-8 text of `encode_response`.
+/// 
+/// # Arguments
+/// 
+/// * `response` - The response to encode.
+/// * `protocol` - The protocol of the command being answered.
+/// 
+/// # Returns
+/// 
+/// * `Vec<u8>` - The encoded reply.
+pub fn encode_reply(response: Response, protocol: Protocol) -> Vec<u8> {
+  match protocol {
+    Protocol::MsgPack => {
+      info!("Encoding response: {:?}", response);
+
+      // A `Value` only holds strings, numbers, booleans, arrays and objects, all of which MessagePack can encode.
+      rmp_serde::to_vec_named(&json_reply(response)).expect("JSON values are always encodable as MessagePack")
+    },
+    _ => encode_response(response, protocol).into_bytes(),
+  }
+}
+
+/// The error message of `Response::ReauthorizationRequired`.
+const REAUTHORIZATION_REQUIRED: &str = "Reauthorization required: Fitbit rejected the user's token, connect Fitbit again";
+
+fn encode_legacy_response(response: Response) -> String {
+  let response: ListResponse = match response {
+    Response::Steps(steps, format) => ListResponse {
+      indication: String::from("0"),
+      content: encode_daily_values(steps, format),
+    },
+    Response::StaleSteps(steps, format) => ListResponse {
+      indication: String::from("2"),
+      content: encode_daily_values(steps, format),
+    },
+    Response::PartialSteps(steps, format) => ListResponse {
+      indication: String::from("3"),
+      content: encode_daily_values(steps, format),
+    },
+    Response::HeartRate(heart_rate, format) => ListResponse {
+      indication: String::from("0"),
+      content: encode_daily_values(heart_rate, format),
+    },
+    Response::Calories(calories, format) => ListResponse {
+      indication: String::from("0"),
+      content: encode_daily_values(calories, format),
+    },
+    Response::Floors(floors) => ListResponse {
+      indication: String::from("0"),
+      content: encode_daily_values(floors, ResponseFormat::Dated),
+    },
+    Response::Distance(distance) => ListResponse {
+      indication: String::from("0"),
+      content: encode_distance(distance),
+    },
+    Response::StepsBatch(steps) => ListResponse {
+      indication: String::from("0"),
+      content: encode_steps_batch(&steps).to_string(),
+    },
+    Response::StepsWithSource(steps) => ListResponse {
+      indication: String::from("0"),
+      content: encode_steps_with_source(&steps).to_string(),
+    },
+    Response::ActiveMinutes(active_minutes) => ListResponse {
+      indication: String::from("0"),
+      content: encode_active_minutes(&active_minutes).to_string(),
+    },
+    Response::Spo2(spo2) => ListResponse {
+      indication: String::from("0"),
+      content: encode_spo2(&spo2).to_string(),
+    },
+    Response::HeartRateZones(zones) => ListResponse {
+      indication: String::from("0"),
+      content: encode_heart_rate_zones(&zones).to_string(),
+    },
+    Response::CardioScore(scores) => ListResponse {
+      indication: String::from("0"),
+      content: encode_cardio_score(&scores).to_string(),
+    },
+    Response::IntradaySteps(steps) => {
+      let mut steps = steps.into_iter().collect::<Vec<(NaiveDateTime, u32)>>();
+
+      steps.sort_by_key(|(time, _)| *time);
+
+      ListResponse {
+        indication: String::from("0"),
+        content: steps.into_iter().map(|(_, step_count)| format!("{step_count}")).collect::<Vec<String>>().join(","),
+      }
+    },
+    Response::Refreshed => ListResponse {
+      indication: String::from("0"),
+      content: String::from("refreshed"),
+    },
+    Response::Registered => ListResponse {
+      indication: String::from("0"),
+      content: String::from("registered"),
+    },
+    Response::Revoked => ListResponse {
+      indication: String::from("0"),
+      content: String::from("revoked"),
+    },
+    Response::StepsSummary { total, average, max, max_date, days } => ListResponse {
+      indication: String::from("0"),
+      content: format!("{},{:.2},{},{},{}", total, average, max, max_date.map(|date| date.format("%Y-%m-%d").to_string()).unwrap_or_default(), days),
+    },
+    Response::Streak { current, longest, longest_start, longest_end } => ListResponse {
+      indication: String::from("0"),
+      content: format!("{},{},{},{}", current, longest, longest_start.map(|date| date.format("%Y-%m-%d").to_string()).unwrap_or_default(), longest_end.map(|date| date.format("%Y-%m-%d").to_string()).unwrap_or_default()),
+    },
+    Response::Coverage(ranges) => ListResponse {
+      indication: String::from("0"),
+      content: ranges.into_iter().map(|range| format!("{}/{}", range.start.format("%Y-%m-%d"), range.end.format("%Y-%m-%d"))).collect::<Vec<String>>().join(","),
+    },
+    Response::CacheCleared(removed) => ListResponse {
+      indication: String::from("0"),
+      content: format!("cleared,{removed}"),
+    },
+    Response::BackfillScheduled(estimated_seconds) => ListResponse {
+      indication: String::from("0"),
+      content: format!("scheduled,{estimated_seconds}"),
+    },
+    Response::UserExists(exists) => ListResponse {
+      indication: String::from("0"),
+      content: exists.to_string(),
+    },
+    Response::Timezone(timezone) => ListResponse {
+      indication: String::from("0"),
+      content: format!("{},{}", timezone.name, timezone.utc_offset_seconds),
+    },
+    Response::Profile(profile) => ListResponse {
+      indication: String::from("0"),
+      content: encode_profile(&profile).to_string(),
+    },
+    Response::Devices(devices) => ListResponse {
+      indication: String::from("0"),
+      content: encode_devices(&devices).to_string(),
+    },
+    Response::Weight(logs) => ListResponse {
+      indication: String::from("0"),
+      content: encode_weight(&logs).to_string(),
+    },
+    Response::GoalSet(goal) => ListResponse {
+      indication: String::from("0"),
+      content: goal.to_string(),
+    },
+    Response::Goals(goals) => {
+      let goal = |goal: Option<String>| goal.unwrap_or_default();
+
+      ListResponse {
+        indication: String::from("0"),
+        content: format!("{},{},{},{}", goal(goals.steps.map(|steps| steps.to_string())), goal(goals.distance.map(|distance| distance.to_string())), goal(goals.calories.map(|calories| calories.to_string())), goal(goals.floors.map(|floors| floors.to_string()))),
+      }
+    },
+    Response::RateLimit { used, limit, reset_in_seconds } => ListResponse {
+      indication: String::from("0"),
+      content: format!("{},{},{}", used, limit, reset_in_seconds.map(|seconds| seconds.to_string()).unwrap_or_default()),
+    },
+    Response::TokenStatus { expires_at, expired, seconds_remaining } => ListResponse {
+      indication: String::from("0"),
+      content: format!("{},{},{}", expires_at.format("%Y-%m-%dT%H:%M:%S"), expired, seconds_remaining),
+    },
+    Response::Users(users) => ListResponse {
+      indication: String::from("0"),
+      content: encode_users(&users).to_string(),
+    },
+    Response::Pong { redis_latency, postgres_latency } => ListResponse {
+      indication: String::from("0"),
+      content: format!("pong,{:.3},{:.3}", milliseconds(redis_latency), milliseconds(postgres_latency)),
+    },
+    Response::ReauthorizationRequired => ListResponse {
+      indication: String::from("1"),
+      content: String::from(REAUTHORIZATION_REQUIRED),
+    },
+    Response::Error(error) => ListResponse {
+      indication: String::from("1"),
+      content: error.to_string(),
+    },
+  };
+
+  let content = escape_content(&response.content);
+
+  format!("{}:{}", response.indication, content)
+}
+
+/// Builds the JSON reply to a response, shared by the JSON and MessagePack protocols.
+fn json_reply(response: Response) -> Value {
+  let stale = matches!(response, Response::StaleSteps(..));
+  let partial = matches!(response, Response::PartialSteps(..));
+
+  let data = match response {
+    Response::Steps(values, format) | Response::StaleSteps(values, format) | Response::PartialSteps(values, format) | Response::HeartRate(values, format) | Response::Calories(values, format) => encode_json_daily_values(values, format),
+    Response::Floors(floors) => encode_json_daily_values(floors, ResponseFormat::Dated),
+    Response::Distance(distance) => encode_json_daily_values(distance, ResponseFormat::Dated),
+    Response::StepsBatch(steps) => encode_steps_batch(&steps),
+    Response::StepsWithSource(steps) => encode_steps_with_source(&steps),
+    Response::ActiveMinutes(active_minutes) => encode_active_minutes(&active_minutes),
+    Response::Spo2(spo2) => encode_spo2(&spo2),
+    Response::HeartRateZones(zones) => encode_heart_rate_zones(&zones),
+    Response::CardioScore(scores) => encode_cardio_score(&scores),
+    Response::IntradaySteps(steps) => {
+      let mut steps = steps.into_iter().collect::<Vec<(NaiveDateTime, u32)>>();
+
+      steps.sort_by_key(|(time, _)| *time);
+
+      json!(steps.into_iter().map(|(_, step_count)| step_count).collect::<Vec<u32>>())
+    },
+    Response::Refreshed => json!("refreshed"),
+    Response::Registered => json!("registered"),
+    Response::Revoked => json!("revoked"),
+    Response::StepsSummary { total, average, max, max_date, days } => json!({
+      "total": total,
+      "average": average,
+      "max": max,
+      "max_date": max_date.map(|date| date.format("%Y-%m-%d").to_string()),
+      "days": days,
+    }),
+    Response::Streak { current, longest, longest_start, longest_end } => json!({
+      "current": current,
+      "longest": longest,
+      "longest_start": longest_start.map(|date| date.format("%Y-%m-%d").to_string()),
+      "longest_end": longest_end.map(|date| date.format("%Y-%m-%d").to_string()),
+    }),
+    Response::Coverage(ranges) => json!(ranges),
+    Response::CacheCleared(removed) => json!({ "cleared": removed }),
+    Response::BackfillScheduled(estimated_seconds) => json!({ "estimated_seconds": estimated_seconds }),
+    Response::UserExists(exists) => json!(exists),
+    Response::Timezone(timezone) => json!({ "timezone": timezone.name, "utc_offset_seconds": timezone.utc_offset_seconds }),
+    Response::Profile(profile) => encode_profile(&profile),
+    Response::GoalSet(goal) => json!({ "steps": goal }),
+    Response::Devices(devices) => encode_devices(&devices),
+    Response::Weight(logs) => encode_weight(&logs),
+    Response::Goals(goals) => json!({ "steps": goals.steps, "distance": goals.distance, "calories": goals.calories, "floors": goals.floors }),
+    Response::RateLimit { used, limit, reset_in_seconds } => json!({ "used": used, "limit": limit, "reset_in_seconds": reset_in_seconds }),
+    Response::TokenStatus { expires_at, expired, seconds_remaining } => json!({ "expires_at": expires_at.format("%Y-%m-%dT%H:%M:%S").to_string(), "expired": expired, "seconds_remaining": seconds_remaining }),
+    Response::Users(users) => encode_users(&users),
+    Response::Pong { redis_latency, postgres_latency } => json!({ "redis_ms": milliseconds(redis_latency), "postgres_ms": milliseconds(postgres_latency) }),
+    Response::ReauthorizationRequired => {
+      return json!({ "status": "error", "code": "reauthorization_required", "error": REAUTHORIZATION_REQUIRED });
+    },
+    Response::Error(error) => {
+      let mut reply = json!({ "status": "error", "code": error.name(), "error": error.to_string() });
+
+      if let Some(retry_after) = error.retry_after_seconds() {
+        reply["retry_after_seconds"] = json!(retry_after);
+      }
+
+      return reply;
+    },
+  };
+
+  if stale {
+    return json!({ "status": "ok", "stale": true, "data": data });
+  }
+
+  if partial {
+    return json!({ "status": "ok", "partial": true, "data": data });
+  }
+
+  json!({ "status": "ok", "data": data })
+}
+
+/// Encodes a profile as a JSON object with snake case fields. The legacy framing carries the same object as its content.
+fn encode_profile(profile: &Profile) -> Value {
+  json!({
+    "display_name": profile.display_name,
+    "avatar": profile.avatar,
+    "member_since": profile.member_since,
+    "timezone": profile.timezone,
+    "utc_offset_seconds": UserTimezone::from(profile).utc_offset_seconds,
+  })
+}
+
+/// Encodes devices as a JSON array of objects with snake case fields. The last sync time is an ISO date and time in
+/// the user's local time, or `null` if the device has never synced. The legacy framing carries the same array as its
+/// content.
+fn encode_devices(devices: &[Device]) -> Value {
+  devices.iter().map(|device| json!({
+    "id": device.id,
+    "type": device.device_type,
+    "battery_level": device.battery_level,
+    "last_sync_time": device.last_sync().map(|time| time.format("%Y-%m-%dT%H:%M:%S").to_string()),
+  })).collect()
+}
+
+/// Encodes users as a JSON array of objects with the internal and Fitbit user IDs and when the token expires, in UTC.
+/// The legacy framing carries the same array as its content.
+fn encode_users(users: &[UserSummary]) -> Value {
+  users.iter().map(|user| json!({
+    "id": user.id,
+    "fitbit_user_id": user.fitbit_user_id,
+    "token_expires_at": user.token_expires_at.format("%Y-%m-%dT%H:%M:%S").to_string(),
+  })).collect()
+}
+
+/// Encodes logged weights as a JSON array of objects in the order they were logged, with the ISO date, the time of
+/// day or `null`, the weight and the body fat percentage or `null`. This is the serde form of `WeightLog`. The legacy
+/// framing carries the same array as its content.
+fn encode_weight(logs: &[WeightLog]) -> Value {
+  json!(logs)
+}
+
+/// Encodes steps with their sources as a JSON object keyed by ISO date, where each day has its `steps` and its `source`,
+/// `cache` or `live`. The legacy framing carries the same object as its content.
+fn encode_steps_with_source(steps: &HashMap<NaiveDate, (u32, Source)>) -> Value {
+  Value::Object(steps.iter().map(|(date, (steps, source))| {
+    (date.format("%Y-%m-%d").to_string(), json!({ "steps": steps, "source": source.name() }))
+  }).collect())
+}
+
+/// Encodes the steps of a batch as a JSON object keyed by user ID. Each user has either `steps`, an object keyed by ISO
+/// date, or the `error` their steps failed with. The legacy framing carries the same object as its content.
+fn encode_steps_batch(steps: &HashMap<String, Result<HashMap<NaiveDate, u32>, String>>) -> Value {
+  Value::Object(steps.iter().map(|(user_id, result)| {
+    let value = match result {
+      Ok(steps) => json!({ "steps": encode_json_daily_values(steps.clone(), ResponseFormat::Dated) }),
+      Err(error) => json!({ "error": error }),
+    };
+
+    (user_id.clone(), value)
+  }).collect())
+}
+
+/// Encodes active minutes as a JSON object keyed by ISO date, with the fairly, very and total active minutes of each
+/// day. This is the serde form of the map. The legacy framing carries the same object as its content.
+fn encode_active_minutes(active_minutes: &HashMap<NaiveDate, ActiveMinutes>) -> Value {
+  json!(active_minutes)
+}
+
+/// Encodes SpO2 summaries as a JSON object keyed by ISO date, with the average, minimum and maximum of each night.
+/// This is the serde form of the map. The legacy framing carries the same object as its content.
+fn encode_spo2(spo2: &HashMap<NaiveDate, Spo2Summary>) -> Value {
+  json!(spo2)
+}
+
+/// Encodes heart rate zone minutes as a JSON object keyed by ISO date, with the minutes of each zone. This is the serde
+/// form of the map. The legacy framing carries the same object as its content.
+fn encode_heart_rate_zones(zones: &HashMap<NaiveDate, HeartRateZones>) -> Value {
+  json!(zones)
+}
+
+/// Encodes cardio fitness scores as a JSON object keyed by ISO date, with the low and high ends of each day's VO2 max
+/// estimate, which are equal when Fitbit reported a single value. The legacy framing carries the same object as its
+/// content.
+fn encode_cardio_score(scores: &HashMap<NaiveDate, CardioScore>) -> Value {
+  Value::Object(scores.iter().map(|(date, score)| {
+    (date.format("%Y-%m-%d").to_string(), json!({ "vo2_max_low": score.low, "vo2_max_high": score.high }))
+  }).collect())
+}
+
+/// A duration in fractional milliseconds, for reporting latencies.
+fn milliseconds(duration: std::time::Duration) -> f64 {
+  duration.as_secs_f64() * 1000.0
+}
+
+/// Encodes daily values as a JSON array of values ordered by date, or an object keyed by ISO date.
+fn encode_json_daily_values<V: Serialize>(values: HashMap<NaiveDate, V>, format: ResponseFormat) -> Value {
+  let mut values = values.into_iter().collect::<Vec<(NaiveDate, V)>>();
+
+  values.sort_by_key(|(date, _)| *date);
+
+  match format {
+    ResponseFormat::Counts => json!(values.into_iter().map(|(_, value)| value).collect::<Vec<V>>()),
+    ResponseFormat::Dated => Value::Object(values.into_iter().map(|(date, value)| (date.format("%Y-%m-%d").to_string(), json!(value))).collect()),
+  }
+}
+
+/// Escapes backslashes, commas, colons, and newlines so the content can be framed safely.
+/// 
+/// # Arguments
+/// 
+/// * `content` - The content to escape.
+/// 
+/// # Returns
+/// 
+/// * `String` - The escaped content.
+pub fn escape_content(content: &str) -> String {
+  // Backslashes must be escaped first so the escapes added below are not themselves escaped.
+  content.replace('\\', "\\\\")
+    .replace(',', "\\,")
+    .replace(':', "\\:")
+    .replace('\n', "\\n")
+}
+
+/// Reverses `escape_content`. Escapes are resolved in a single pass so an escaped backslash is never mistaken for the start of another escape.
+/// 
+/// # Arguments
+/// 
+/// * `content` - The escaped content.
+/// 
+/// # Returns
+/// 
+/// * `String` - The original content.
+pub fn unescape_content(content: &str) -> String {
+  let mut unescaped = String::with_capacity(content.len());
+  let mut chars = content.chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      unescaped.push(c);
+      continue;
+    }
+
+    match chars.next() {
+      Some('n') => unescaped.push('\n'),
+      Some(escaped) => unescaped.push(escaped),
+      None => unescaped.push('\\'),
+    }
+  }
+
+  unescaped
+}
+
+/// Decodes a response from the Redis list, as produced by `encode_response`.
+/// 
+/// # Arguments
+/// 
+/// * `message` - The encoded response, as such: `indication:content`.
+/// 
+/// # Returns
+/// 
+/// * `Ok(Reply::Success(content))` - If the indication was `0`.
+/// * `Ok(Reply::Error(content))` - If the indication was `1`.
+/// * `Ok(Reply::Stale(content))` - If the indication was `2`.
+/// * `Err(e)` - If the response could not be decoded.
+pub fn decode_response(message: &str) -> Result<Reply, FitbitError> {
+  let Some((indication, content)) = message.split_once(':') else {
+    let message = format!("While decoding response, expected indication:content, got {}", message);
+    return Err(FitbitError::InvalidMessage(message));
+  };
+
+  let content = unescape_content(content);
+
+  match indication {
+    "0" => Ok(Reply::Success(content)),
+    "1" => Ok(Reply::Error(content)),
+    "2" => Ok(Reply::Stale(content)),
+    "3" => Ok(Reply::Partial(content)),
+    _ => Err(FitbitError::InvalidMessage(format!("While decoding response, expected indication of 0, 1, 2 or 3, got {}", indication))),
+  }
+}
+
+/// Orders daily values by date and joins them, comma-separated, in the requested format.
+fn encode_daily_values(values: HashMap<NaiveDate, u32>, format: ResponseFormat) -> String {
+  let mut values = values.into_iter().map(|(date, value)| {
+    let Ok(value) = i32::try_from(value) else {
+      return (date, 0);
+    };
+
+    (date, value)
+  }).collect::<Vec<(NaiveDate, i32)>>();
+
+  values.sort_by_key(|(date, _)| *date);
+
+  match format {
+    ResponseFormat::Counts => values.into_iter().map(|(_, value)| format!("{value}")).collect::<Vec<String>>().join(","),
+    ResponseFormat::Dated => values.into_iter().map(|(date, value)| format!("{}={value}", date.format("%Y-%m-%d"))).collect::<Vec<String>>().join(","),
+  }
+}
+
+/// Orders daily distances by date and joins them as comma-separated `date=value` pairs, so gaps are preserved.
+fn encode_distance(distance: HashMap<NaiveDate, f64>) -> String {
+  let mut distance = distance.into_iter().collect::<Vec<(NaiveDate, f64)>>();
+
+  distance.sort_by_key(|(date, _)| *date);
+
+  distance.into_iter().map(|(date, value)| format!("{}={value}", date.format("%Y-%m-%d"))).collect::<Vec<String>>().join(",")
+}
+
+/// Parses the unescaped content of a dated response back into daily values.
+/// 
+/// # Arguments
+/// 
+/// * `content` - Comma-separated `date=value` pairs, with ISO dates.
+/// 
+/// # Returns
+/// 
+/// * `Ok(values)` - A hashmap of dates and their corresponding values.
+/// * `Err(e)` - If a pair could not be parsed.
+pub fn decode_daily_values(content: &str) -> Result<HashMap<NaiveDate, u32>, FitbitError> {
+  let mut values: HashMap<NaiveDate, u32> = HashMap::new();
+
+  if content.is_empty() {
+    return Ok(values);
+  }
+
+  for pair in content.split(',') {
+    let Some((date, value)) = pair.split_once('=') else {
+      return Err(FitbitError::ParsingError(format!("Expected date=value, got {}", pair)));
+    };
+
+    let Ok(date) = NaiveDate::parse_from_str(date, "%Y-%m-%d") else {
+      return Err(FitbitError::ParsingError(format!("Could not parse date, got {}", date)));
+    };
+
+    let Ok(value) = value.parse::<u32>() else {
+      return Err(FitbitError::ParsingError(format!("Could not parse value, got {}", value)));
+    };
+
+    values.insert(date, value);
+  }
+
+  Ok(values)
+}
+
+/// Converts from i64 to T, clamping to the maximum value of T if the value is too large.
+/// 
+/// # Arguments
+/// 
+/// * `value` - The value to convert.
+/// 
+/// # Returns
+/// 
+/// * `T` - The converted value.
+pub fn safe_convert<T: TryFrom<i64> + From<u16>>(value: i64) -> T {
+  if value < 0 {
+    T::from(0)
+  } else if let Ok(v) = T::try_from(value) {
+      v
+  } else {
+    T::from(u16::MAX)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+  use crate::models::{CommandOutcome, Goals, TokenExpiry};
+
+  fn frame(command: &str, payload: &str) -> String {
+    let ttl = chrono::Utc::now().timestamp() + 60;
+
+    format!("{}:{}:{}:{}", ulid::Ulid::new(), command, payload, ttl)
+  }
+
+  #[test]
+  fn decode_keeps_colons_in_payload() {
+    let message = frame("get_steps", "dXNlcjox:,1672531200,1672617600");
+
+    let Some((_, Ok(Command::GetSteps(user_id, RequestedRange::Dates(range), format, ..)))) = decode_message(message) else {
+      panic!("Expected a get_steps command");
+    };
+
+    assert_eq!(user_id, "dXNlcjox:");
+    assert_eq!(range.start, NaiveDate::from_ymd_opt(2023, 1, 1).unwrap());
+    assert_eq!(range.end, NaiveDate::from_ymd_opt(2023, 1, 2).unwrap());
+    assert_eq!(format, ResponseFormat::Counts);
+  }
+
+  #[test]
+  fn decode_reads_response_format() {
+    let message = frame("get_steps", "user,1672531200,1672617600,dated");
+
+    let Some((_, Ok(Command::GetSteps(_, _, format, ..)))) = decode_message(message) else {
+      panic!("Expected a get_steps command");
+    };
+
+    assert_eq!(format, ResponseFormat::Dated);
+
+    let message = frame("get_steps", "user,1672531200,1672617600,weekly");
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn dated_steps_roundtrip_with_gaps() {
+    let steps = HashMap::from([
+      (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 1200),
+      (NaiveDate::from_ymd_opt(2023, 1, 4).unwrap(), 0),
+      (NaiveDate::from_ymd_opt(2023, 1, 9).unwrap(), 9001),
+    ]);
+
+    let response = encode_response(Response::Steps(steps.clone(), ResponseFormat::Dated), Protocol::Legacy);
+
+    let Ok(Reply::Success(content)) = decode_response(&response) else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(content, "2023-01-01=1200,2023-01-04=0,2023-01-09=9001");
+    assert_eq!(decode_daily_values(&content).unwrap(), steps);
+  }
+
+  #[test]
+  fn counts_steps_keep_legacy_format() {
+    let steps = HashMap::from([
+      (NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(), 20),
+      (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 10),
+    ]);
+
+    assert_eq!(encode_response(Response::Steps(steps, ResponseFormat::Counts), Protocol::Legacy), "0:10\\,20");
+  }
+
+  #[test]
+  fn missing_ranges_finds_interior_gaps() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+
+    let cached: HashMap<NaiveDate, u32> = (1..=30)
+      .filter(|day| *day != 3 && !(10..=12).contains(day))
+      .map(|day| (date(day), day * 100))
+      .collect();
+
+    assert_eq!(missing_ranges(&cached, date(1), date(30)), vec![
+      Range { start: date(3), end: date(3) },
+      Range { start: date(10), end: date(12) },
+    ]);
+    assert_eq!(missing_ranges(&cached, date(4), date(9)), Vec::new());
+    assert_eq!(missing_ranges(&HashMap::<NaiveDate, u32>::new(), date(1), date(2)), vec![Range { start: date(1), end: date(2) }]);
+  }
+
+  #[test]
+  fn batch_ranges_shares_windows_between_gaps() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, 1).unwrap() + chrono::Duration::days(day);
+
+    let batches = batch_ranges(vec![
+      Range { start: date(20), end: date(25) },
+      Range { start: date(2), end: date(2) },
+      Range { start: date(400), end: date(401) },
+      Range { start: date(24), end: date(30) },
+    ]);
+
+    assert_eq!(batches, vec![
+      Range { start: date(2), end: date(30) },
+      Range { start: date(400), end: date(401) },
+    ]);
+
+    let batches = batch_ranges(vec![Range { start: date(0), end: date(799) }]);
+
+    assert_eq!(batches.len(), 3);
+    assert!(batches.iter().all(|batch| (batch.end - batch.start).num_days() < MAX_WINDOW_DAYS));
+    assert!(batch_ranges(Vec::new()).is_empty());
+  }
+
+  #[test]
+  fn coalesce_dates_merges_consecutive_days() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+
+    let ranges = coalesce_dates(vec![date(5), date(2), date(1), date(3), date(3), date(9), date(6)]);
+
+    assert_eq!(ranges, vec![
+      Range { start: date(1), end: date(3) },
+      Range { start: date(5), end: date(6) },
+      Range { start: date(9), end: date(9) },
+    ]);
+    assert!(coalesce_dates(Vec::new()).is_empty());
+  }
+
+  #[test]
+  fn active_minutes_merge_by_date() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+
+    let fairly = HashMap::from([(date(1), 12), (date(2), 0), (date(3), 30)]);
+    let very = HashMap::from([(date(2), 45), (date(1), 20), (date(4), 5)]);
+
+    assert_eq!(merge_active_minutes(fairly, very), HashMap::from([
+      (date(1), ActiveMinutes { fairly: 12, very: 20, total: 32 }),
+      (date(2), ActiveMinutes { fairly: 0, very: 45, total: 45 }),
+    ]));
+  }
+
+  #[test]
+  fn get_active_minutes_roundtrips() {
+    assert!(matches!(decode_message(frame("get_active_minutes", "user,1672531200,1672617600")), Some((_, Ok(Command::GetActiveMinutes(user_id, _)))) if user_id == "user"));
+    assert!(matches!(decode_message(frame("get_active_minutes", "user,1672531200,1672617600,dated")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_active_minutes", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetActiveMinutes(..))))));
+
+    let active_minutes = || HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), ActiveMinutes::new(12, 20))]);
+    let expected = json!({ "2023-01-01": { "fairly": 12, "very": 20, "total": 32 } });
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::ActiveMinutes(active_minutes()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::ActiveMinutes(active_minutes()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn streaks_break_on_gaps() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+
+    // Days 4 and 9 were never fetched, and day 6 was fetched with no steps; days 11 and 12 have not synced yet.
+    let steps = HashMap::from([
+      (date(1), 8000), (date(2), 9000), (date(3), 12000),
+      (date(5), 10000), (date(6), 0), (date(7), 11000), (date(8), 10500),
+      (date(10), 15000),
+    ]);
+
+    let Response::Streak { current, longest, longest_start, longest_end } = Response::streak(&steps, date(1), date(12), 8000) else {
+      panic!("Expected a streak");
+    };
+
+    assert_eq!((current, longest, longest_start, longest_end), (1, 3, Some(date(1)), Some(date(3))));
+
+    let Response::Streak { current, longest, longest_start, .. } = Response::streak(&steps, date(5), date(8), 10000) else {
+      panic!("Expected a streak");
+    };
+
+    assert_eq!((current, longest, longest_start), (2, 2, Some(date(7))));
+
+    let Response::Streak { current, longest, longest_start, longest_end } = Response::streak(&steps, date(1), date(8), 20000) else {
+      panic!("Expected a streak");
+    };
+
+    assert_eq!((current, longest, longest_start, longest_end), (0, 0, None, None));
+  }
+
+  #[test]
+  fn get_streak_roundtrips() {
+    assert!(matches!(decode_message(frame("get_streak", "user,1672531200,1672617600,10000")), Some((_, Ok(Command::GetStreak(_, _, 10000))))));
+    assert!(matches!(decode_message(frame("get_streak", "user,1672531200,1672617600")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(frame("get_streak", "user,1672531200,1672617600,lots")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_streak", r#"{"user_id":"user","start":1672531200,"end":1672617600,"threshold":8000}"#)), Some((_, Ok(Command::GetStreak(_, _, 8000))))));
+
+    let streak = || Response::Streak {
+      current: 2,
+      longest: 3,
+      longest_start: NaiveDate::from_ymd_opt(2023, 1, 1),
+      longest_end: NaiveDate::from_ymd_opt(2023, 1, 3),
+    };
+
+    assert_eq!(decode_response(&encode_response(streak(), Protocol::Legacy)).unwrap(), Reply::Success("2,3,2023-01-01,2023-01-03".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(streak(), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "current": 2, "longest": 3, "longest_start": "2023-01-01", "longest_end": "2023-01-03" }));
+  }
+
+  #[test]
+  fn intraday_detail_levels_are_validated() {
+    for (level, detail) in [("1min", Detail::OneMin), ("5min", Detail::FiveMin), ("15min", Detail::FifteenMin)] {
+      assert!(matches!(decode_message(frame("get_intraday_steps", &format!("user,1672531200,{}", level))), Some((_, Ok(Command::GetIntradaySteps(_, _, d)))) if d == detail));
+      assert_eq!(Detail::parse(level).unwrap().to_str(), level);
+    }
+
+    // Only heart rate is served to the second.
+    assert!(Detail::OneSec.supported_by(Resource::HeartRate));
+    assert!(!Detail::OneSec.supported_by(Resource::Steps));
+    assert!(matches!(decode_message(frame("get_intraday_steps", "user,1672531200,1sec")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(frame("get_intraday_steps", "user,1672531200,30min")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_intraday_steps", r#"{"user_id":"user","date":1672531200,"detail":"5min"}"#)), Some((_, Ok(Command::GetIntradaySteps(_, _, Detail::FiveMin))))));
+    assert!(matches!(decode_message(envelope("get_intraday_steps", r#"{"user_id":"user","date":1672531200,"detail":"1sec"}"#)), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_intraday_steps", r#"{"user_id":"user","date":1672531200,"detail":"1MIN"}"#)), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn get_steps_batch_roundtrips() {
+    let Some((_, Ok(Command::GetStepsBatch(user_ids, range)))) = decode_message(frame("get_steps_batch", "a;b;c,1672531200,1672617600")) else {
+      panic!("Expected a get_steps_batch command");
+    };
+
+    assert_eq!(user_ids, vec!["a", "b", "c"]);
+    assert_eq!(range.start, NaiveDate::from_ymd_opt(2023, 1, 1).unwrap());
+    assert!(matches!(decode_message(frame("get_steps_batch", "a;;c,1672531200,1672617600")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_steps_batch", r#"{"user_ids":["a","b"],"start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetStepsBatch(user_ids, _)))) if user_ids.len() == 2));
+    assert!(matches!(decode_message(envelope("get_steps_batch", r#"{"user_ids":[],"start":1672531200,"end":1672617600}"#)), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let steps = || HashMap::from([
+      ("a".to_string(), Ok(HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 1200)]))),
+      ("b".to_string(), Err(FitbitError::UserNotFound.to_string())),
+    ]);
+    let expected = json!({
+      "a": { "steps": { "2023-01-01": 1200 } },
+      "b": { "error": FitbitError::UserNotFound.to_string() },
+    });
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::StepsBatch(steps()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::StepsBatch(steps()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn get_spo2_roundtrips() {
+    assert!(matches!(decode_message(frame("get_spo2", "user,1672531200,1672617600")), Some((_, Ok(Command::GetSpo2(user_id, _)))) if user_id == "user"));
+    assert!(matches!(decode_message(frame("get_spo2", "user,1672531200")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_spo2", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetSpo2(..))))));
+
+    let spo2 = || HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), Spo2Summary { avg: 96.5, min: 93.0, max: 99.5 })]);
+    let expected = json!({ "2023-01-01": { "avg": 96.5, "min": 93.0, "max": 99.5 } });
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::Spo2(spo2()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Spo2(spo2()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn get_heart_rate_zones_roundtrips() {
+    assert!(matches!(decode_message(frame("get_heart_rate_zones", "user,1672531200,1672617600")), Some((_, Ok(Command::GetHeartRateZones(user_id, _)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("get_heart_rate_zones", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetHeartRateZones(..))))));
+
+    let zones = || HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), HeartRateZones { out_of_range: 1338, fat_burn: 86, cardio: 12, peak: 3 })]);
+    let expected = json!({ "2023-01-01": { "out_of_range": 1338, "fat_burn": 86, "cardio": 12, "peak": 3 } });
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::HeartRateZones(zones()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::HeartRateZones(zones()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn get_cardio_score_roundtrips() {
+    assert!(matches!(decode_message(frame("get_cardio_score", "user,1672531200,1672617600")), Some((_, Ok(Command::GetCardioScore(user_id, _)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("get_cardio_score", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetCardioScore(..))))));
+
+    let scores = || HashMap::from([
+      (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), CardioScore { low: 40.0, high: 44.0 }),
+      (NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(), CardioScore { low: 45.3, high: 45.3 }),
+    ]);
+    let expected = json!({
+      "2023-01-01": { "vo2_max_low": 40.0, "vo2_max_high": 44.0 },
+      "2023-01-02": { "vo2_max_low": 45.3, "vo2_max_high": 45.3 },
+    });
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::CardioScore(scores()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::CardioScore(scores()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn refresh_steps_decodes() {
+    let range = Range { start: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), end: NaiveDate::from_ymd_opt(2023, 1, 2).unwrap() };
+
+    assert!(matches!(decode_message(frame("refresh_steps", "user,1672531200,1672617600")), Some((_, Ok(Command::RefreshSteps(user_id, r)))) if user_id == "user" && r == range));
+    assert!(matches!(decode_message(frame("refresh_steps", "user,1672531200,1672617600,dated")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("refresh_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::RefreshSteps(_, r)))) if r == range));
+  }
+
+  #[test]
+  fn sync_recent_decodes() {
+    assert!(matches!(decode_message(frame("sync_recent", "user,7")), Some((_, Ok(Command::SyncRecent(user_id, 7)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("sync_recent", r#"{"user_id":"user","days":35}"#)), Some((_, Ok(Command::SyncRecent(_, 35))))));
+    assert!(matches!(decode_message(frame("sync_recent", "user,0")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("sync_recent", r#"{"user_id":"user","days":36}"#)), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn get_floors_roundtrips() {
+    assert!(matches!(decode_message(frame("get_floors", "user,1672531200,1672617600")), Some((_, Ok(Command::GetFloors(user_id, _)))) if user_id == "user"));
+    assert!(matches!(decode_message(frame("get_floors", "user,1672531200,1672617600,counts")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_floors", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetFloors(..))))));
+
+    let floors = || HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 12)]);
+
+    assert_eq!(decode_response(&encode_response(Response::Floors(floors()), Protocol::Legacy)).unwrap(), Reply::Success("2023-01-01=12".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Floors(floors()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "2023-01-01": 12 }));
+  }
+
+  #[test]
+  fn steps_summary_of_empty_range() {
+    let Response::StepsSummary { total, average, max, max_date, days } = Response::steps_summary(&HashMap::new()) else {
+      panic!("Expected a steps summary");
+    };
+
+    assert_eq!((total, max, max_date, days), (0, 0, None, 0));
+    assert_eq!(average, 0.0);
+
+    let response = encode_response(Response::steps_summary(&HashMap::new()), Protocol::Legacy);
+
+    assert_eq!(decode_response(&response).unwrap(), Reply::Success("0,0.00,0,,0".to_string()));
+  }
+
+  #[test]
+  fn steps_summary_finds_earliest_max_day() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+    let steps = HashMap::from([(date(1), 100), (date(2), 400), (date(3), 400), (date(4), 0)]);
+
+    let Response::StepsSummary { total, average, max, max_date, days } = Response::steps_summary(&steps) else {
+      panic!("Expected a steps summary");
+    };
+
+    assert_eq!((total, max, max_date, days), (900, 400, Some(date(2)), 4));
+    assert_eq!(average, 225.0);
+
+    let response = encode_response(Response::steps_summary(&steps), Protocol::Legacy);
+
+    assert_eq!(decode_response(&response).unwrap(), Reply::Success("900,225.00,400,2023-01-02,4".to_string()));
+  }
+
+  #[test]
+  fn encode_coverage_as_intervals() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+
+    let response = encode_response(Response::Coverage(vec![
+      Range { start: date(1), end: date(3) },
+      Range { start: date(5), end: date(5) },
+    ]), Protocol::Legacy);
+
+    assert_eq!(decode_response(&response).unwrap(), Reply::Success("2023-01-01/2023-01-03,2023-01-05/2023-01-05".to_string()));
+  }
+
+  #[test]
+  fn decode_reads_dense_steps() {
+    let message = frame("get_steps_dense", "user,1672531200,1672617600,dated");
+
+    let Some((_, Ok(Command::GetStepsDense(user_id, range, format)))) = decode_message(message) else {
+      panic!("Expected a get_steps_dense command");
+    };
+
+    assert_eq!(user_id, "user");
+    assert_eq!(range.end, NaiveDate::from_ymd_opt(2023, 1, 2).unwrap());
+    assert_eq!(format, ResponseFormat::Dated);
+  }
+
+  #[test]
+  fn decode_reads_backfill() {
+    let message = frame("backfill", "user,1672531200");
+
+    let Some((_, Ok(Command::Backfill(user_id, start)))) = decode_message(message) else {
+      panic!("Expected a backfill command");
+    };
+
+    assert_eq!(user_id, "user");
+    assert_eq!(start, NaiveDate::from_ymd_opt(2023, 1, 1).unwrap());
+  }
+
+  #[test]
+  fn decode_keeps_commas_and_colons_in_payload() {
+    let message = frame("register", "user:1,code:abc");
+
+    let Some((_, Ok(Command::RegisterUser(user_id, code)))) = decode_message(message) else {
+      panic!("Expected a register command");
+    };
+
+    assert_eq!(user_id, "user:1");
+    assert_eq!(code, "code:abc");
+  }
+
+  #[test]
+  fn decode_rejects_missing_ttl() {
+    let message = format!("{}:refresh", ulid::Ulid::new());
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn chunk_range_splits_into_exact_windows() {
+    let start = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+    let end = start + chrono::Duration::days(799);
+
+    let windows = chunk_range(&Range { start, end });
+
+    assert_eq!(windows, vec![
+      Range { start, end: start + chrono::Duration::days(363) },
+      Range { start: start + chrono::Duration::days(364), end: start + chrono::Duration::days(727) },
+      Range { start: start + chrono::Duration::days(728), end },
+    ]);
+  }
+
+  #[test]
+  fn chunk_range_handles_single_day_and_inverted_ranges() {
+    let day = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+
+    assert_eq!(chunk_range(&Range { start: day, end: day }), vec![Range { start: day, end: day }]);
+    assert!(chunk_range(&Range { start: day, end: day.pred_opt().unwrap() }).is_empty());
+  }
+
+  #[test]
+  fn decode_response_unescapes_content() {
+    let response = encode_response(Response::Error(FitbitError::InvalidMessage("a\\b,c:d\ne".to_string())), Protocol::Legacy);
+
+    assert_eq!(decode_response(&response).unwrap(), Reply::Error("Invalid message: a\\b,c:d\ne".to_string()));
+  }
+
+  fn envelope(command: &str, payload: &str) -> String {
+    let ttl = chrono::Utc::now().timestamp() + 60;
+
+    format!(r#"{{"coordination_id":"{}","command":"{}","payload":{},"ttl":{}}}"#, ulid::Ulid::new(), command, payload, ttl)
+  }
+
+  #[test]
+  fn decode_reads_json_envelope() {
+    let message = envelope("get_steps", r#"{"user_id":"a:b,c","start":1672531200,"end":1672617600,"format":"dated"}"#);
+
+    assert_eq!(Protocol::detect(&message), Protocol::Json);
+
+    let Some((_, Ok(Command::GetSteps(user_id, RequestedRange::Dates(range), format, ..)))) = decode_message(message) else {
+      panic!("Expected a get_steps command");
+    };
+
+    assert_eq!(user_id, "a:b,c");
+    assert_eq!(range, Range { start: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), end: NaiveDate::from_ymd_opt(2023, 1, 2).unwrap() });
+    assert_eq!(format, ResponseFormat::Dated);
+
+    let message = envelope("get_intraday_steps", r#"{"user_id":"user","date":1672531200,"detail":"15min"}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetIntradaySteps(_, _, Detail::FifteenMin))))));
+
+    let message = frame("refresh", "user");
+
+    assert_eq!(Protocol::detect(&message), Protocol::Legacy);
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::RefreshToken(_))))));
+  }
+
+  #[test]
+  fn timed_commands_keep_their_ttl() {
+    let ttl = chrono::Utc::now().naive_utc().timestamp() + 60;
+
+    let legacy = format!("01H2XK0000000000000000000A:refresh:user:{}", ttl);
+    let json = format!(r#"{{"coordination_id":"01H2XK0000000000000000000A","command":"refresh","payload":{{"user_id":"user"}},"ttl":{}}}"#, ttl);
+
+    for message in [legacy, json] {
+      let Some((_, Ok(TimedCommand { command: Command::RefreshToken(_), deadline }))) = decode_timed_message(message) else {
+        panic!("Expected a timed refresh command");
+      };
+
+      assert_eq!(deadline.timestamp(), ttl);
+    }
+  }
+
+  #[test]
+  fn decode_rejects_inverted_ranges() {
+    // 2023-01-02 to 2023-01-01.
+    let legacy = frame("get_steps", "user,1672617600,1672531200");
+    let json = envelope("get_heart_rate", r#"{"user_id":"user","start":1672617600,"end":1672531200}"#);
+
+    assert!(matches!(decode_message(legacy), Some((_, Err(FitbitError::DateOutOfRange(_))))));
+    assert!(matches!(decode_message(json), Some((_, Err(FitbitError::DateOutOfRange(_))))));
+
+    let day = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+
+    assert_eq!(checked_range("get_steps", day, day).unwrap(), Range { start: day, end: day });
+  }
+
+  #[test]
+  fn decode_rejects_oversized_ranges() {
+    // 2000-01-01 to 2023-01-01.
+    let legacy = frame("get_steps_summary", "user,946684800,1672531200");
+    let json = envelope("get_steps", r#"{"user_id":"user","start":946684800,"end":1672531200}"#);
+
+    assert!(matches!(decode_message(legacy), Some((_, Err(FitbitError::DateOutOfRange(_))))));
+    assert!(matches!(decode_message(json), Some((_, Err(FitbitError::DateOutOfRange(_))))));
+
+    let start = NaiveDate::from_ymd_opt(2018, 1, 1).unwrap();
+
+    assert!(checked_range("get_steps", start, start + chrono::Duration::days(MAX_RANGE_DAYS - 1)).is_ok());
+    assert!(checked_range("get_steps", start, start + chrono::Duration::days(MAX_RANGE_DAYS)).is_err());
+  }
+
+  #[test]
+  fn decode_rejects_invalid_json_payload() {
+    let message = envelope("get_steps", r#"{"user_id":"user","start":"yesterday","end":1672617600}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = envelope("dance", r#"{"user_id":"user"}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let expired = format!(r#"{{"coordination_id":"{}","command":"refresh","payload":{{"user_id":"user"}},"ttl":0}}"#, ulid::Ulid::new());
+
+    assert!(decode_message(expired).is_none());
+    assert!(decode_message(String::from("{not json")).is_none());
+  }
+
+  #[test]
+  fn encode_json_responses() {
+    let steps = HashMap::from([
+      (NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(), 20),
+      (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 10),
+    ]);
+
+    let counts: Value = serde_json::from_str(&encode_response(Response::Steps(steps.clone(), ResponseFormat::Counts), Protocol::Json)).unwrap();
+    let dated: Value = serde_json::from_str(&encode_response(Response::Steps(steps, ResponseFormat::Dated), Protocol::Json)).unwrap();
+    let error: Value = serde_json::from_str(&encode_response(Response::Error(FitbitError::UserNotFound), Protocol::Json)).unwrap();
+
+    assert_eq!(counts, json!({ "status": "ok", "data": [10, 20] }));
+    assert_eq!(dated, json!({ "status": "ok", "data": { "2023-01-01": 10, "2023-01-02": 20 } }));
+    assert_eq!(error["status"], "error");
+    assert_eq!(error["error"], FitbitError::UserNotFound.to_string());
+  }
+
+  #[test]
+  fn reauthorization_is_an_error_with_its_own_code() {
+    let legacy = decode_response(&encode_response(Response::ReauthorizationRequired, Protocol::Legacy)).unwrap();
+    let json: Value = serde_json::from_str(&encode_response(Response::ReauthorizationRequired, Protocol::Json)).unwrap();
+
+    assert_eq!(legacy, Reply::Error(REAUTHORIZATION_REQUIRED.to_string()));
+    assert_eq!(json["status"], "error");
+    assert_eq!(json["code"], "reauthorization_required");
+  }
+
+  #[test]
+  fn msgpack_replies_roundtrip() {
+    #[derive(serde::Deserialize)]
+    struct StepsReply {
+      status: String,
+      data: HashMap<String, u32>,
+    }
+
+    let steps = HashMap::from([
+      (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 10),
+      (NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(), 20),
+    ]);
+
+    let reply = encode_reply(Response::Steps(steps.clone(), ResponseFormat::Dated), Protocol::MsgPack);
+    let reply: StepsReply = rmp_serde::from_slice(&reply).unwrap();
+    let expected: HashMap<String, u32> = steps.into_iter().map(|(date, steps)| (date.format("%Y-%m-%d").to_string(), steps)).collect();
+
+    assert_eq!(reply.status, "ok");
+    assert_eq!(reply.data, expected);
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#).replacen('{', r#"{"reply_encoding":"msgpack","#, 1);
+
+    assert_eq!(Protocol::detect(&message), Protocol::MsgPack);
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(..))))));
+    assert_eq!(encode_reply(Response::UserExists(true), Protocol::Legacy), b"0:true");
+  }
+
+  #[test]
+  fn outcomes_name_their_error() {
+    assert_eq!(CommandOutcome::of(&Response::Refreshed), CommandOutcome::Success);
+    assert_eq!(CommandOutcome::of(&Response::StaleSteps(HashMap::new(), ResponseFormat::Counts)).status(), "stale");
+    assert_eq!(CommandOutcome::of(&Response::ReauthorizationRequired).error(), Some("reauthorization_required"));
+
+    let outcome = CommandOutcome::of(&Response::Error(FitbitError::UserNotFound));
+
+    assert_eq!((outcome.status(), outcome.error()), ("error", Some("user_not_found")));
+  }
+
+  #[test]
+  fn user_exists_roundtrips() {
+    assert!(matches!(decode_message(frame("user_exists", "user:1")), Some((_, Ok(Command::UserExists(user_id)))) if user_id == "user:1"));
+    assert!(matches!(decode_message(envelope("user_exists", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::UserExists(_))))));
+    assert!(matches!(decode_message(frame("user_exists", "user,extra")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    assert_eq!(encode_response(Response::UserExists(true), Protocol::Legacy), "0:true");
+    assert_eq!(encode_response(Response::UserExists(false), Protocol::Legacy), "0:false");
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::UserExists(false), Protocol::Json)).unwrap();
+
+    assert_eq!(reply, json!({ "status": "ok", "data": false }));
+  }
+
+  #[test]
+  fn ping_roundtrips() {
+    let Some((_, Ok(command))) = decode_message(frame("ping", "")) else {
+      panic!("Expected ping to decode");
+    };
+
+    assert!(matches!(command, Command::Ping));
+    assert_eq!(command.user_id(), None);
+    assert!(matches!(decode_message(envelope("ping", "{}")), Some((_, Ok(Command::Ping)))));
+    assert!(matches!(decode_message(frame("ping", "user")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let pong = || Response::Pong {
+      redis_latency: std::time::Duration::from_micros(1500),
+      postgres_latency: std::time::Duration::from_millis(3),
+    };
+
+    assert_eq!(decode_response(&encode_response(pong(), Protocol::Legacy)).unwrap(), Reply::Success("pong,1.500,3.000".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(pong(), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "redis_ms": 1.5, "postgres_ms": 3.0 }));
+  }
+
+  #[test]
+  fn sync_timezone_roundtrips() {
+    assert!(matches!(decode_message(frame("sync_timezone", "user")), Some((_, Ok(Command::SyncTimezone(user_id)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("sync_timezone", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::SyncTimezone(_))))));
+
+    let timezone = UserTimezone { name: "America/Los_Angeles".to_string(), utc_offset_seconds: -25200 };
+
+    assert_eq!(decode_response(&encode_response(Response::Timezone(timezone.clone()), Protocol::Legacy)).unwrap(), Reply::Success("America/Los_Angeles,-25200".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Timezone(timezone), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "timezone": "America/Los_Angeles", "utc_offset_seconds": -25200 }));
+  }
+
+  #[test]
+  fn get_profile_roundtrips() {
+    assert!(matches!(decode_message(frame("get_profile", "user:1")), Some((_, Ok(Command::GetProfile(user_id)))) if user_id == "user:1"));
+    assert!(matches!(decode_message(envelope("get_profile", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::GetProfile(_))))));
+    assert!(matches!(decode_message(frame("get_profile", "user,extra")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let profile = || Profile {
+      display_name: "Lune, the Moon".to_string(),
+      avatar: "https://example.com/avatar.png".to_string(),
+      member_since: "2019-04-02".to_string(),
+      timezone: "Europe/Paris".to_string(),
+      offset_from_utc_millis: 3_600_000,
+      distance_unit: "METRIC".to_string(),
+      weight_unit: "METRIC".to_string(),
+    };
+
+    let expected = json!({
+      "display_name": "Lune, the Moon",
+      "avatar": "https://example.com/avatar.png",
+      "member_since": "2019-04-02",
+      "timezone": "Europe/Paris",
+      "utc_offset_seconds": 3600,
+    });
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::Profile(profile()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Profile(profile()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn get_goals_roundtrips() {
+    assert!(matches!(decode_message(frame("get_goals", "user")), Some((_, Ok(Command::GetGoals(user_id)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("get_goals", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::GetGoals(_))))));
+
+    let goals = || Goals { steps: Some(10000), distance: Some(8.05), calories: Some(2500), floors: None };
+
+    // Goals Fitbit does not report are left empty.
+    assert_eq!(decode_response(&encode_response(Response::Goals(goals()), Protocol::Legacy)).unwrap(), Reply::Success("10000,8.05,2500,".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Goals(goals()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "steps": 10000, "distance": 8.05, "calories": 2500, "floors": null }));
+  }
+
+  #[test]
+  fn get_calories_decodes() {
+    let day = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let range = Range { start: day, end: NaiveDate::from_ymd_opt(2023, 1, 2).unwrap() };
+
+    assert!(matches!(decode_message(frame("get_calories", "user,1672531200,1672617600,counts")), Some((_, Ok(Command::GetCalories(user_id, r, ResponseFormat::Counts)))) if user_id == "user" && r == range));
+    assert!(matches!(decode_message(envelope("get_calories", r#"{"user_id":"user","start":1672531200,"end":1672617600}"#)), Some((_, Ok(Command::GetCalories(_, r, _)))) if r == range));
+
+    let calories = HashMap::from([(day, 2345)]);
+
+    assert_eq!(decode_response(&encode_response(Response::Calories(calories, ResponseFormat::Counts), Protocol::Legacy)).unwrap(), Reply::Success("2345".to_string()));
+  }
+
+  #[test]
+  fn get_distance_decodes_units() {
+    assert!(matches!(decode_message(frame("get_distance", "user,1672531200,1672617600")), Some((_, Ok(Command::GetDistance(_, _, None))))));
+    assert!(matches!(decode_message(frame("get_distance", "user,1672531200,1672617600,miles")), Some((_, Ok(Command::GetDistance(_, _, Some(DistanceUnit::Miles)))))));
+    assert!(matches!(decode_message(frame("get_distance", "user,1672531200,1672617600,dated")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_distance", r#"{"user_id":"user","start":1672531200,"end":1672617600,"unit":"km"}"#)), Some((_, Ok(Command::GetDistance(_, _, Some(DistanceUnit::Kilometers)))))));
+    assert!(matches!(decode_message(envelope("get_distance", r#"{"user_id":"user","start":1672531200,"end":1672617600,"unit":"furlongs"}"#)), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let distance = || HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(), 3.25), (NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 5.0)]);
+
+    assert_eq!(decode_response(&encode_response(Response::Distance(distance()), Protocol::Legacy)).unwrap(), Reply::Success("2023-01-01=5,2023-01-02=3.25".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Distance(distance()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "2023-01-01": 5.0, "2023-01-02": 3.25 }));
+  }
+
+  #[test]
+  fn get_weight_roundtrips() {
+    assert!(matches!(decode_message(frame("get_weight", "user,1672531200,1672617600")), Some((_, Ok(Command::GetWeight(_, _, None))))));
+    assert!(matches!(decode_message(frame("get_weight", "user,1672531200,1672617600,stone")), Some((_, Ok(Command::GetWeight(_, _, Some(WeightUnit::Stone)))))));
+    assert!(matches!(decode_message(frame("get_weight", "user,1672531200,1672617600,miles")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("get_weight", r#"{"user_id":"user","start":1672531200,"end":1672617600,"unit":"lbs"}"#)), Some((_, Ok(Command::GetWeight(_, _, Some(WeightUnit::Pounds)))))));
+
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let logs = || vec![
+      WeightLog { date, time: chrono::NaiveTime::from_hms_opt(9, 0, 0), weight: 72.8, fat: None },
+      WeightLog { date, time: chrono::NaiveTime::from_hms_opt(22, 0, 0), weight: 73.1, fat: Some(14.5) },
+    ];
+    let expected = json!([
+      { "date": "2023-01-01", "time": "09:00:00", "weight": 72.8, "fat": null },
+      { "date": "2023-01-01", "time": "22:00:00", "weight": 73.1, "fat": 14.5 },
+    ]);
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::Weight(logs()), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Weight(logs()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn get_rate_limit_roundtrips() {
+    assert!(matches!(decode_message(frame("get_rate_limit", "user")), Some((_, Ok(Command::GetRateLimit(user_id)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("get_rate_limit", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::GetRateLimit(_))))));
+
+    let status = |reset_in_seconds| Response::RateLimit { used: 12, limit: 145, reset_in_seconds };
+
+    assert_eq!(decode_response(&encode_response(status(Some(1800)), Protocol::Legacy)).unwrap(), Reply::Success("12,145,1800".to_string()));
+    assert_eq!(decode_response(&encode_response(status(None), Protocol::Legacy)).unwrap(), Reply::Success("12,145,".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(status(None), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "used": 12, "limit": 145, "reset_in_seconds": null }));
+  }
+
+  #[test]
+  fn get_token_status_roundtrips() {
+    assert!(matches!(decode_message(frame("get_token_status", "user")), Some((_, Ok(Command::GetTokenStatus(user_id)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("get_token_status", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::GetTokenStatus(_))))));
+
+    let expires_at = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let valid = || Response::token_status(TokenExpiry { expires_at, expires_in: 1800 });
+    let expired = || Response::token_status(TokenExpiry { expires_at, expires_in: -60 });
+
+    assert_eq!(decode_response(&encode_response(valid(), Protocol::Legacy)).unwrap(), Reply::Success("2023-01-01T12:00:00,false,1800".to_string()));
+    assert_eq!(decode_response(&encode_response(expired(), Protocol::Legacy)).unwrap(), Reply::Success("2023-01-01T12:00:00,true,0".to_string()));
+
+    let reply: Value = serde_json::from_str(&encode_response(expired(), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], json!({ "expires_at": "2023-01-01T12:00:00", "expired": true, "seconds_remaining": 0 }));
+  }
+
+  #[test]
+  fn list_users_roundtrips() {
+    assert!(matches!(decode_message(frame("list_users", "50,100")), Some((_, Ok(Command::ListUsers { limit: 50, offset: 100 })))));
+    assert!(matches!(decode_message(envelope("list_users", r#"{"limit":50}"#)), Some((_, Ok(Command::ListUsers { limit: 50, offset: 0 })))));
+    assert!(matches!(decode_message(frame("list_users", "0,0")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+    assert!(matches!(decode_message(envelope("list_users", r#"{"limit":501,"offset":0}"#)), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let token_expires_at = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let users = || Response::Users(vec![UserSummary { id: "user".to_string(), fitbit_user_id: "FITBIT".to_string(), token_expires_at }]);
+    let expected = json!([{ "id": "user", "fitbit_user_id": "FITBIT", "token_expires_at": "2023-01-01T12:00:00" }]);
+
+    let Ok(Reply::Success(content)) = decode_response(&encode_response(users(), Protocol::Legacy)) else {
+      panic!("expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(users(), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn get_devices_roundtrips() {
+    assert!(matches!(decode_message(frame("get_devices", "user")), Some((_, Ok(Command::GetDevices(user_id)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("get_devices", r#"{"user_id":"user"}"#)), Some((_, Ok(Command::GetDevices(_))))));
+
+    let devices = || vec![
+      Device { id: "816713257".to_string(), device_type: "TRACKER".to_string(), battery_level: Some(80), last_sync_time: "2023-01-01T11:18:15.000".to_string() },
+      Device { id: "ARIA1".to_string(), device_type: "SCALE".to_string(), battery_level: None, last_sync_time: String::new() },
+    ];
+
+    let expected = json!([
+      { "id": "816713257", "type": "TRACKER", "battery_level": 80, "last_sync_time": "2023-01-01T11:18:15" },
+      { "id": "ARIA1", "type": "SCALE", "battery_level": null, "last_sync_time": null },
+    ]);
+
+    let Ok(Reply::Success(content)) = decode_response(&encode_response(Response::Devices(devices()), Protocol::Legacy)) else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::Devices(devices()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply["data"], expected);
+  }
+
+  #[test]
+  fn set_step_goal_roundtrips() {
+    assert!(matches!(decode_message(frame("set_step_goal", "user,12000")), Some((_, Ok(Command::SetStepGoal(user_id, 12000)))) if user_id == "user"));
+    assert!(matches!(decode_message(envelope("set_step_goal", r#"{"user_id":"user","goal":12000}"#)), Some((_, Ok(Command::SetStepGoal(_, 12000))))));
+    assert!(matches!(decode_message(frame("set_step_goal", "user,-5")), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    assert_eq!(decode_response(&encode_response(Response::GoalSet(12000), Protocol::Legacy)).unwrap(), Reply::Success("12000".to_string()));
+    assert!(encode_response(Response::GoalSet(12000), Protocol::Json).contains(r#""data":{"steps":12000}"#));
+  }
+
+  #[test]
+  fn commands_name_their_user() {
+    let Some((_, Ok(command))) = decode_message(frame("backfill", "user:1,1672531200")) else {
+      panic!("Expected a backfill command");
+    };
+
+    assert_eq!(command.user_id(), Some("user:1"));
+
+    let Some((_, Ok(command))) = decode_message(frame("get_steps", "user:2,1672531200,1672617600")) else {
+      panic!("Expected a get_steps command");
+    };
+
+    assert_eq!(command.user_id(), Some("user:2"));
+  }
+
+  #[test]
+  fn decode_reads_relative_dates() {
+    let today = NaiveDate::from_ymd_opt(2023, 1, 31).unwrap();
+    let decoded = |payload| match decode_message(frame("get_steps", payload)) {
+      Some((_, Ok(Command::GetSteps(_, range, ..)))) => Ok(range.resolve(today)),
+      Some((_, Err(e))) => Err(e),
+      _ => panic!("Expected a get_steps command"),
+    };
+    let range = |start, end| Range { start: NaiveDate::from_ymd_opt(2023, 1, start).unwrap(), end: NaiveDate::from_ymd_opt(2023, 1, end).unwrap() };
+
+    assert_eq!(decoded("user,today,today").unwrap(), range(31, 31));
+    assert_eq!(decoded("user,yesterday,yesterday").unwrap(), range(30, 30));
+    assert_eq!(decoded("user,last_7_days,today").unwrap(), range(25, 31));
+    assert_eq!(decoded("user,last_30_days,yesterday,dated,stale,weekly").unwrap(), range(2, 30));
+    assert_eq!(decoded("user,1672531200,1672617600").unwrap(), range(1, 2));
+
+    assert!(matches!(decoded("user,last_7_days,1675123200"), Err(FitbitError::InvalidMessage(_))));
+    assert!(matches!(decoded("user,1672531200,today"), Err(FitbitError::InvalidMessage(_))));
+    assert!(matches!(decoded("user,today,yesterday"), Err(FitbitError::DateOutOfRange(_))));
+    assert!(matches!(decoded("user,last_week,today"), Err(FitbitError::InvalidMessage(_))));
+  }
+
+  #[test]
+  fn decode_reads_aggregation() {
+    let message = frame("get_steps", "user,1672531200,1672617600,dated,strict,weekly");
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, StalePolicy::Strict, Aggregation::Weekly))))));
+
+    let message = frame("get_steps", "user,1672531200,1672617600,dated,stale");
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, StalePolicy::AllowStale, Aggregation::Daily))))));
+
+    let message = frame("get_steps", "user,1672531200,1672617600,dated,strict,yearly");
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"aggregation":"monthly"}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, _, Aggregation::Monthly))))));
+
+    let message = envelope("get_calories", r#"{"user_id":"user","start":1672531200,"end":1672617600,"aggregation":"weekly"}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn weekly_aggregation_starts_on_monday() {
+    // Thursday 2023-01-05 through Tuesday 2023-01-17, so both edge weeks are partial.
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+    let steps = (5..=17).map(|day| (date(day), day)).collect::<HashMap<NaiveDate, u32>>();
+
+    let weekly = Aggregation::Weekly.apply(steps);
+
+    assert_eq!(weekly, HashMap::from([(date(2), 5 + 6 + 7 + 8), (date(9), (9..=15).sum()), (date(16), 16 + 17)]));
+  }
+
+  #[test]
+  fn monthly_aggregation_starts_on_the_first() {
+    let date = |month, day| NaiveDate::from_ymd_opt(2023, month, day).unwrap();
+    let steps = HashMap::from([(date(1, 30), 100), (date(1, 31), 200), (date(2, 1), 300), (date(2, 28), 400), (date(3, 1), 500)]);
+
+    let monthly = Aggregation::Monthly.apply(steps);
+
+    assert_eq!(monthly, HashMap::from([(date(1, 1), 300), (date(2, 1), 700), (date(3, 1), 500)]));
+  }
+
+  #[test]
+  fn daily_aggregation_keeps_days() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+    let steps = HashMap::from([(date(1), 100), (date(2), 200)]);
+
+    assert_eq!(Aggregation::Daily.apply(steps.clone()), steps);
+  }
+
+  #[test]
+  fn decode_reads_stale_policy() {
+    let message = frame("get_steps", "user,1672531200,1672617600,dated,stale");
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, ResponseFormat::Dated, StalePolicy::AllowStale, _))))));
+
+    let message = frame("get_steps", "user,1672531200,1672617600");
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, StalePolicy::Strict, _))))));
+
+    let message = frame("get_steps", "user,1672531200,1672617600,counts,sometimes");
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = frame("get_heart_rate", "user,1672531200,1672617600,counts,stale");
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"allow_stale":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, StalePolicy::AllowStale, _))))));
+
+    let message = envelope("get_steps_summary", r#"{"user_id":"user","start":1672531200,"end":1672617600,"allow_stale":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn decode_reads_partial_policy() {
+    let message = frame("get_steps", "user,1672531200,1672617600,counts,partial");
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, StalePolicy::AllowPartial, _))))));
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"allow_partial":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetSteps(_, _, _, StalePolicy::AllowPartial, _))))));
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"allow_stale":true,"allow_partial":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = envelope("get_calories", r#"{"user_id":"user","start":1672531200,"end":1672617600,"allow_partial":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn out_of_range_stored_timestamps_are_errors() {
+    assert!(matches!(stored_datetime(i64::MAX), Err(FitbitError::ParsingError(_))));
+    assert!(matches!(stored_datetime(i64::MIN), Err(FitbitError::ParsingError(_))));
+    assert_eq!(stored_datetime(1672531200).unwrap(), NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap());
+  }
+
+  #[test]
+  fn decode_reads_with_source() {
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"with_source":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Ok(Command::GetStepsWithSource(user_id, _)))) if user_id == "user"));
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"with_source":true,"allow_stale":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = envelope("get_steps", r#"{"user_id":"user","start":1672531200,"end":1672617600,"with_source":true,"aggregation":"weekly"}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+
+    let message = envelope("get_heart_rate", r#"{"user_id":"user","start":1672531200,"end":1672617600,"with_source":true}"#);
+
+    assert!(matches!(decode_message(message), Some((_, Err(FitbitError::InvalidMessage(_))))));
+  }
+
+  #[test]
+  fn steps_with_source_encode_each_day() {
+    let date = |day| NaiveDate::from_ymd_opt(2023, 1, day).unwrap();
+    let steps = HashMap::from([(date(1), (1000, Source::Cache)), (date(2), (2000, Source::Live))]);
+    let expected = json!({
+      "2023-01-01": { "steps": 1000, "source": "cache" },
+      "2023-01-02": { "steps": 2000, "source": "live" },
+    });
+
+    let reply: Value = serde_json::from_str(&encode_response(Response::StepsWithSource(steps.clone()), Protocol::Json)).unwrap();
+
+    assert_eq!(reply, json!({ "status": "ok", "data": expected }));
+
+    let Reply::Success(content) = decode_response(&encode_response(Response::StepsWithSource(steps), Protocol::Legacy)).unwrap() else {
+      panic!("Expected a successful reply");
+    };
+
+    assert_eq!(serde_json::from_str::<Value>(&content).unwrap(), expected);
+  }
+
+  #[test]
+  fn partial_steps_are_flagged() {
+    let steps = HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 10)]);
+
+    let legacy = encode_response(Response::PartialSteps(steps.clone(), ResponseFormat::Counts), Protocol::Legacy);
+    let json: Value = serde_json::from_str(&encode_response(Response::PartialSteps(steps, ResponseFormat::Counts), Protocol::Json)).unwrap();
+
+    assert_eq!(decode_response(&legacy).unwrap(), Reply::Partial(String::from("10")));
+    assert_eq!(json, json!({ "status": "ok", "partial": true, "data": [10] }));
+    assert_eq!(CommandOutcome::of(&Response::PartialSteps(HashMap::new(), ResponseFormat::Counts)).status(), "partial");
+  }
+
+  #[test]
+  fn stale_steps_are_flagged() {
+    let steps = HashMap::from([(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 10)]);
+
+    let legacy = encode_response(Response::StaleSteps(steps.clone(), ResponseFormat::Counts), Protocol::Legacy);
+    let json: Value = serde_json::from_str(&encode_response(Response::StaleSteps(steps, ResponseFormat::Counts), Protocol::Json)).unwrap();
+
+    assert_eq!(decode_response(&legacy).unwrap(), Reply::Stale(String::from("10")));
+    assert_eq!(json, json!({ "status": "ok", "stale": true, "data": [10] }));
+  }
+
+  #[test]
+  fn only_fitbit_failures_allow_stale_data() {
+    assert!(FitbitError::RateLimitExceeded(String::from("resets soon"), None).is_unavailable());
+    assert!(FitbitError::FitbitApiError(String::from("down")).is_unavailable());
+    assert!(!FitbitError::UserNotFound.is_unavailable());
+    assert!(!FitbitError::CacheError(String::from("down")).is_unavailable());
+  }
+
+  #[test]
+  fn json_errors_carry_stable_codes() {
+    let http_error = reqwest::Client::new().get("not a url").build().unwrap_err();
+
+    let errors = vec![
+      (FitbitError::HttpRequestError(http_error), "http_request_error"),
+      (FitbitError::FitbitApiError(String::new()), "fitbit_api_error"),
+      (FitbitError::CacheError(String::new()), "cache_error"),
+      (FitbitError::ExpiredToken, "expired_token"),
+      (FitbitError::RejectedToken, "rejected_token"),
+      (FitbitError::ParsingError(String::new()), "parsing_error"),
+      (FitbitError::DateOutOfRange(String::new()), "date_out_of_range"),
+      (FitbitError::RateLimitExceeded(String::new(), None), "rate_limit_exceeded"),
+      (FitbitError::RedisError(redis::RedisError::from((redis::ErrorKind::IoError, "down"))), "redis_error"),
+      (FitbitError::RedisPoolError(bb8::RunError::TimedOut), "redis_pool_error"),
+      (FitbitError::PostgresError(sqlx::Error::RowNotFound), "postgres_error"),
+      (FitbitError::PostgresPoolTimeout, "postgres_pool_timeout"),
+      (FitbitError::InsufficientScope("activity".to_string()), "insufficient_scope"),
+      (FitbitError::TypeConversionError(String::new()), "type_conversion_error"),
+      (FitbitError::InvalidMessage(String::new()), "invalid_message"),
+      (FitbitError::UserNotFound, "user_not_found"),
+      (FitbitError::InvalidAuthorizationCode(String::new()), "invalid_authorization_code"),
+      (FitbitError::RateLimited(crate::models::RateLimitInfo { remaining: None, limit: None, reset_seconds: None }), "rate_limited"),
+      (FitbitError::CommandExpired, "command_expired"),
+      (FitbitError::Timeout(60), "timeout"),
+    ];
+
+    for (error, code) in errors {
+      let reply: Value = serde_json::from_str(&encode_response(Response::Error(error), Protocol::Json)).unwrap();
+
+      assert_eq!(reply["status"], "error");
+      assert_eq!(reply["code"], code);
+    }
+  }
+
+  /// Serializes a value, reads it back and serializes it again, and checks nothing changed along the way.
+  fn assert_serde_roundtrip<T: Serialize + DeserializeOwned>(value: &T) {
+    let serialized = serde_json::to_value(value).unwrap();
+    let deserialized: T = serde_json::from_value(serialized.clone()).unwrap();
+
+    assert_eq!(serde_json::to_value(&deserialized).unwrap(), serialized);
+  }
+
+  #[test]
+  fn commands_roundtrip_through_serde() {
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let range = || Range { start: date, end: NaiveDate::from_ymd_opt(2023, 1, 7).unwrap() };
+    let user = || "dXNlcjox".to_string();
+
+    let commands = vec![
+      Command::GetSteps(user(), RequestedRange::Dates(range()), ResponseFormat::Dated, StalePolicy::AllowStale, Aggregation::Weekly),
+      Command::GetSteps(user(), RequestedRange::Relative(RelativeDate::Last7Days, RelativeDate::Today), ResponseFormat::Counts, StalePolicy::Strict, Aggregation::Daily),
+      Command::GetStepsDense(user(), range(), ResponseFormat::Counts),
+      Command::GetStepsWithSource(user(), range()),
+      Command::GetStepsBatch(vec![user(), "dXNlcjoy".to_string()], range()),
+      Command::RefreshSteps(user(), range()),
+      Command::SyncRecent(user(), 7),
+      Command::GetHeartRate(user(), range(), ResponseFormat::Dated),
+      Command::GetHeartRateZones(user(), range()),
+      Command::GetCalories(user(), range(), ResponseFormat::Counts),
+      Command::GetFloors(user(), range()),
+      Command::GetDistance(user(), range(), Some(DistanceUnit::Miles)),
+      Command::GetDistance(user(), range(), None),
+      Command::GetActiveMinutes(user(), range()),
+      Command::GetSpo2(user(), range()),
+      Command::GetCardioScore(user(), range()),
+      Command::GetWeight(user(), range(), Some(WeightUnit::Stone)),
+      Command::GetIntradaySteps(user(), date, Detail::FifteenMin),
+      Command::RefreshToken(user()),
+      Command::RegisterUser(user(), "code".to_string()),
+      Command::RevokeToken(user()),
+      Command::GetStepsSummary(user(), range()),
+      Command::GetStreak(user(), range(), 10000),
+      Command::GetCoverage(user()),
+      Command::ClearCache(user()),
+      Command::Backfill(user(), date),
+      Command::UserExists(user()),
+      Command::SyncTimezone(user()),
+      Command::GetProfile(user()),
+      Command::GetGoals(user()),
+      Command::SetStepGoal(user(), 8000),
+      Command::GetDevices(user()),
+      Command::GetRateLimit(user()),
+      Command::GetTokenStatus(user()),
+      Command::ListUsers { limit: 100, offset: 200 },
+      Command::Ping,
+    ];
+
+    for command in &commands {
+      assert_serde_roundtrip(command);
+    }
+  }
+
+  #[test]
+  fn responses_roundtrip_through_serde() {
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let time = date.and_hms_opt(8, 15, 0).unwrap();
+    let counts = || HashMap::from([(date, 1234)]);
+
+    let responses = vec![
+      Response::Steps(counts(), ResponseFormat::Dated),
+      Response::StaleSteps(counts(), ResponseFormat::Counts),
+      Response::PartialSteps(counts(), ResponseFormat::Dated),
+      Response::StepsWithSource(HashMap::from([(date, (1234, Source::Cache))])),
+      Response::StepsBatch(HashMap::from([("dXNlcjox".to_string(), Ok(counts())), ("dXNlcjoy".to_string(), Err("User not found".to_string()))])),
+      Response::HeartRate(counts(), ResponseFormat::Counts),
+      Response::HeartRateZones(HashMap::from([(date, HeartRateZones { out_of_range: 1200, fat_burn: 30, cardio: 10, peak: 2 })])),
+      Response::Calories(counts(), ResponseFormat::Dated),
+      Response::Floors(counts()),
+      Response::Distance(HashMap::from([(date, 5.25)])),
+      Response::ActiveMinutes(HashMap::from([(date, ActiveMinutes::new(20, 15))])),
+      Response::Spo2(HashMap::from([(date, Spo2Summary { avg: 96.5, min: 93.0, max: 99.0 })])),
+      Response::CardioScore(HashMap::from([(date, CardioScore { low: 40.0, high: 44.0 })])),
+      Response::Weight(vec![WeightLog { date, time: Some(time.time()), weight: 72.5, fat: Some(18.5) }, WeightLog { date, time: None, weight: 72.0, fat: None }]),
+      Response::IntradaySteps(HashMap::from([(time, 42)])),
+      Response::Refreshed,
+      Response::Registered,
+      Response::Revoked,
+      Response::StepsSummary { total: 1234, average: 1234.0, max: 1234, max_date: Some(date), days: 1 },
+      Response::Streak { current: 0, longest: 0, longest_start: None, longest_end: None },
+      Response::Coverage(vec![Range { start: date, end: date }]),
+      Response::CacheCleared(3),
+      Response::BackfillScheduled(90),
+      Response::UserExists(true),
+      Response::Timezone(UserTimezone { name: "Europe/London".to_string(), utc_offset_seconds: 3600 }),
+      Response::Profile(Profile { display_name: "Jo".to_string(), timezone: "UTC".to_string(), ..Profile::default() }),
+      Response::Goals(Goals { steps: Some(10000), distance: Some(8.05), calories: None, floors: Some(10) }),
+      Response::GoalSet(8000),
+      Response::Devices(vec![Device { id: "123".to_string(), device_type: "TRACKER".to_string(), battery_level: Some(80), last_sync_time: "2023-01-01T08:15:00.000".to_string() }]),
+      Response::RateLimit { used: 10, limit: 150, reset_in_seconds: Some(1800) },
+      Response::TokenStatus { expires_at: time, expired: false, seconds_remaining: 3600 },
+      Response::Users(vec![UserSummary { id: "dXNlcjox".to_string(), fitbit_user_id: "ABC123".to_string(), token_expires_at: time }]),
+      Response::Pong { redis_latency: std::time::Duration::from_micros(250), postgres_latency: std::time::Duration::from_millis(2) },
+      Response::ReauthorizationRequired,
+      Response::Error(FitbitError::RateLimitExceeded("Rate limit exceeded".to_string(), Some(30))),
+      Response::Error(FitbitError::RateLimited(crate::models::RateLimitInfo { remaining: Some(0), limit: Some(150), reset_seconds: Some(30) })),
+      Response::Error(FitbitError::InvalidMessage("Missing user ID".to_string())),
+      Response::Error(FitbitError::Timeout(60)),
+      Response::Error(FitbitError::UserNotFound),
+    ];
+
+    for response in &responses {
+      assert_serde_roundtrip(response);
+    }
+  }
+
+  #[test]
+  fn json_replies_carry_the_serde_form_of_their_data() {
+    let date = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+    let weight = vec![WeightLog { date, time: chrono::NaiveTime::from_hms_opt(8, 15, 0), weight: 72.5, fat: None }];
+    let coverage = vec![Range { start: date, end: date }];
+
+    let data = |response| serde_json::from_str::<Value>(&encode_response(response, Protocol::Json)).unwrap()["data"].clone();
+
+    assert_eq!(data(Response::Weight(weight.clone())), json!(weight));
+    assert_eq!(data(Response::Weight(weight)), json!([{ "date": "2023-01-01", "time": "08:15:00", "weight": 72.5, "fat": null }]));
+    assert_eq!(data(Response::Coverage(coverage)), json!([{ "start": "2023-01-01", "end": "2023-01-01" }]));
+    assert_eq!(data(Response::ActiveMinutes(HashMap::from([(date, ActiveMinutes::new(20, 15))]))), json!({ "2023-01-01": { "fairly": 20, "very": 15, "total": 35 } }));
+    assert_eq!(data(Response::Spo2(HashMap::from([(date, Spo2Summary { avg: 96.5, min: 93.0, max: 99.0 })]))), json!({ "2023-01-01": { "avg": 96.5, "min": 93.0, "max": 99.0 } }));
+  }
+
+  #[test]
+  fn errors_serialize_with_their_code() {
+    let errors = [
+      FitbitError::DateOutOfRange("Start date is after end date".to_string()),
+      FitbitError::Timeout(60),
+      FitbitError::PostgresError(sqlx::Error::RowNotFound),
+    ];
+
+    for error in errors {
+      let serialized = serde_json::to_value(&error).unwrap();
+
+      assert_eq!(serialized["code"], error.name());
+    }
+  }
+
+  #[test]
+  fn wrapped_errors_deserialize_from_their_message() {
+    let postgres = serde_json::to_value(FitbitError::PostgresError(sqlx::Error::RowNotFound)).unwrap();
+    let deserialized: FitbitError = serde_json::from_value(postgres.clone()).unwrap();
+
+    assert!(matches!(deserialized, FitbitError::PostgresError(sqlx::Error::Protocol(_))));
+    assert_eq!(serde_json::to_value(&deserialized).unwrap()["detail"], postgres["detail"]);
+
+    let http: FitbitError = serde_json::from_value(json!({ "code": "http_request_error", "detail": "connection refused" })).unwrap();
+    let redis: FitbitError = serde_json::from_value(json!({ "code": "redis_error", "detail": "broken pipe" })).unwrap();
+
+    assert!(matches!(http, FitbitError::FitbitApiError(message) if message == "connection refused"));
+    assert!(matches!(redis, FitbitError::CacheError(message) if message == "broken pipe"));
+  }
+
+  proptest! {
+    #[test]
+    fn escape_roundtrip(content in ".*") {
+      prop_assert_eq!(unescape_content(&escape_content(&content)), content);
+    }
+
+    #[test]
+    fn encode_response_roundtrip(content in ".*") {
+      let response = encode_response(Response::Error(FitbitError::InvalidMessage(content.clone())), Protocol::Legacy);
+
+      prop_assert_eq!(decode_response(&response).unwrap(), Reply::Error(format!("Invalid message: {content}")));
+    }
+  }
+}